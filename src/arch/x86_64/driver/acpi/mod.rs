@@ -24,7 +24,8 @@ use self::hpet::Hpet;
 use self::rxsdt::Rxsdt;
 use self::rsdp::RSDP;
 
-use self::aml::{parse_aml_table, AmlError, AmlValue};
+use self::aml::{parse_aml_table, AmlError};
+pub use self::aml::AmlValue;
 
 pub mod hpet;
 mod dmar;
@@ -42,8 +42,15 @@ pub struct SerialPort<T: Io<Value = u8>> {
     line_sts: ReadOnly<T>,
     /// Modem status
     modem_sts: ReadOnly<T>,
+    /// Received bytes not yet read (ring buffer)
+    rx_buf: [u8; RX_BUF_SIZE],
+    rx_start: usize,
+    rx_len: usize,
 }
 
+/// Size of the receive buffer, bytes received while it is full are dropped
+const RX_BUF_SIZE: usize = 256;
+
 type Serial = SerialPort<Pio<u8>>;
 
 impl SerialPort<Pio<u8>> {
@@ -55,7 +62,10 @@ impl SerialPort<Pio<u8>> {
             line_ctrl: Pio::new(base + 3),
             modem_ctrl: Pio::new(base + 4),
             line_sts: ReadOnly::new(Pio::new(base + 5)),
-            modem_sts: ReadOnly::new(Pio::new(base + 6))
+            modem_sts: ReadOnly::new(Pio::new(base + 6)),
+            rx_buf: [0; RX_BUF_SIZE],
+            rx_start: 0,
+            rx_len: 0,
         }
     }
 }
@@ -69,7 +79,10 @@ impl SerialPort<Mmio<u8>> {
             line_ctrl: Mmio::new(),
             modem_ctrl: Mmio::new(),
             line_sts: ReadOnly::new(Mmio::new()),
-            modem_sts: ReadOnly::new(Mmio::new())
+            modem_sts: ReadOnly::new(Mmio::new()),
+            rx_buf: [0; RX_BUF_SIZE],
+            rx_start: 0,
+            rx_len: 0,
         }
     }
 }
@@ -91,12 +104,26 @@ impl<T: Io<Value = u8>> SerialPort<T> {
         LineStsFlags::from_bits_truncate(self.line_sts.read())
     }
 
+    /// Move received bytes into the receive buffer (called from the IRQ handler)
     pub fn receive(&mut self) {
         while self.line_sts().contains(LineStsFlags::INPUT_FULL) {
             let data = self.data.read();
-            write!(self, "serial receive {}", data);
-            // TODO handle received data
+            if self.rx_len < RX_BUF_SIZE {
+                self.rx_buf[(self.rx_start + self.rx_len) % RX_BUF_SIZE] = data;
+                self.rx_len += 1;
+            }
+        }
+    }
+
+    /// Take bytes from the receive buffer (non-blocking), returns the number of bytes read
+    pub fn read(&mut self, dst: &mut [u8]) -> usize {
+        let count = ::core::cmp::min(dst.len(), self.rx_len);
+        for d in dst[..count].iter_mut() {
+            *d = self.rx_buf[self.rx_start];
+            self.rx_start = (self.rx_start + 1) % RX_BUF_SIZE;
         }
+        self.rx_len -= count;
+        count
     }
 
     fn wait(&self) {
@@ -37,6 +37,48 @@ use consts::irq::*;
 use spin::Mutex;
 static IN_TIMER: Mutex<Option<bool>> = Mutex::new(Some(false));
 
+/// Number of IRQ lines counted by `irq_count`
+pub const IRQ_COUNT: usize = 32;
+/// Times each IRQ has been handled (only written with interrupts disabled)
+static mut IRQ_COUNTS: [usize; IRQ_COUNT] = [0; IRQ_COUNT];
+
+/// Number of times `irq` has been handled since boot
+pub fn irq_count(irq: u8) -> usize {
+    unsafe{ IRQ_COUNTS.get(irq as usize).cloned().unwrap_or(0) }
+}
+
+/// IRQs handed out by `alloc_handler` (not wired to legacy devices)
+const DYNAMIC_IRQS: ::core::ops::Range<u8> = 20 .. 31;
+/// Handlers registered at runtime (e.g. for PCI MSI), for IRQs without a fixed handler
+static IRQ_HANDLERS: Mutex<[Option<fn()>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Allocate an unused IRQ for `handler`, returns the IRQ number (vector is `T_IRQ0 + irq`)
+pub fn alloc_handler(handler: fn()) -> Option<u8> {
+    super::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        for irq in DYNAMIC_IRQS {
+            if handlers[irq as usize].is_none() {
+                handlers[irq as usize] = Some(handler);
+                return Some(irq);
+            }
+        }
+        None
+    })
+}
+
+/// Release an IRQ allocated by `alloc_handler`
+pub fn free_handler(irq: u8) {
+    super::without_interrupts(|| IRQ_HANDLERS.lock()[irq as usize] = None);
+}
+
+fn dynamic(irq: u8) {
+    let handler = IRQ_HANDLERS.lock().get(irq as usize).cloned().and_then(|h| h);
+    match handler {
+        Some(h) => h(),
+        None => panic!("Invalid IRQ number."),
+    }
+}
+
 fn keyboard() {
     // use arch::driver::keyboard;
     // debug!("\nInterupt: Keyboard");
@@ -75,6 +117,11 @@ fn com2() {
     COM2.lock().receive();
 }
 
+fn ide(irq: u8) {
+    use ata::io::handle_irq;
+    handle_irq((irq - IRQ_IDE1) as usize);
+}
+
 fn timer(tf: &mut TrapFrame, rsp: &mut usize) {
 
     static mut tick: usize = 0;
@@ -103,6 +150,11 @@ fn fork(tf: &mut TrapFrame) {
     }
 }
 
+fn yield_cpu(rsp: &mut usize) {
+    use process;
+    process::schedule(rsp);
+}
+
 fn to_user(tf: &mut TrapFrame, rsp: &mut usize) {
     use arch::gdt;
     debug!("\nInterupt: To User");
@@ -132,19 +184,24 @@ pub extern fn rust_trap(tf: &mut TrapFrame) -> usize {
         T_GPFLT => general_protection_fault(),
         T_IRQ0...64 => {
             let irq = tf.trap_num as u8 - T_IRQ0;
+            if let Some(c) = unsafe{ IRQ_COUNTS.get_mut(irq as usize) } {
+                *c += 1;
+            }
             match irq {
                 IRQ_TIMER => timer(tf, &mut rsp),
                 IRQ_KBD => keyboard(),
                 IRQ_MOUSE => mouse(),
                 IRQ_COM1 => com1(),
                 IRQ_COM2 => com2(),
-                _ => panic!("Invalid IRQ number."),
+                IRQ_IDE1 | IRQ_IDE2 => ide(irq),
+                _ => dynamic(irq),
             }
             ack(irq);
         }
         T_SWITCH_TOK => to_kernel(tf),
         T_SWITCH_TOU => to_user(tf,&mut rsp),
         T_FORK => fork(tf),
+        T_YIELD => yield_cpu(&mut rsp),
         // T_SYSCALL => syscall(tf, &mut rsp),
         // 0x80 => syscall32(tf, &mut rsp),
         _ => panic!("Unhandled interrupt {:x}", tf.trap_num),
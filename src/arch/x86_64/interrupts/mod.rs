@@ -16,6 +16,27 @@ pub unsafe fn disable() {
     x86_64::instructions::interrupts::disable();
 }
 
+/// Returns true if interrupts are enabled (IF is set)
+#[inline(always)]
+pub fn enabled() -> bool {
+    let rflags: usize;
+    unsafe { asm!("pushfq; popq $0" : "=r"(rflags) : : "memory" : "volatile"); }
+    rflags & (1 << 9) != 0
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards
+///
+/// Use this around locks that are also taken by interrupt handlers.
+pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+    let were_enabled = enabled();
+    unsafe { disable(); }
+    let rv = f();
+    if were_enabled {
+        unsafe { enable(); }
+    }
+    rv
+}
+
 #[inline(always)]
 pub fn enable_irq(irq: u8) {
     if cfg!(feature = "use_apic") {
@@ -13,4 +13,9 @@ pub fn switch_to_kernel() {
 pub fn fork() {
     unsafe { int!(T_FORK); }
     debug!("finish syscall fork");
-}
\ No newline at end of file
+}
+
+/// Switch to the next runnable process (the caller is resumed when it is next scheduled)
+pub fn yield_cpu() {
+    unsafe { int!(T_YIELD); }
+}
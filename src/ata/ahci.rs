@@ -0,0 +1,683 @@
+// "Tifflin" Kernel - ATA Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_ata/ahci.rs
+//! AHCI (SATA) host bus adapter driver
+//!
+//! Each port with an ATA disk is registered as a physical volume. Commands are issued through the
+//! port's command list, with the PRDT pointing directly at the caller's buffer. Disks that support
+//! NCQ get one command per tag (up to the HBA's slot count), others one command at a time.
+//!
+//! Completion is signalled by MSI when the HBA supports it, otherwise the port is polled.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+use mylib::mem::Arc;
+use memory::{ActivePageTable,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use metadevs::storage;
+use device_manager::{self,pci,IOBinding};
+use process::WaitQueue;
+use super::{AtaClass,AtaIdentifyData};
+use super::io::SECTOR_SIZE;
+
+// HBA registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const HBA_VS: usize = 0x10;
+const CAP_S64A: u32 = 1 << 31;
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_HR: u32 = 1 << 0;
+
+// Port registers (relative to the port's base)
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0C;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+const TFD_BSY: u32 = 0x80;
+const TFD_DRQ: u32 = 0x08;
+/// Task file, host bus fatal, host bus data and interface fatal errors
+const IS_ERRORS: u32 = (1 << 30) | (1 << 29) | (1 << 28) | (1 << 27);
+/// Completion interrupts (D2H register, PIO setup, DMA setup and set device bits FISes) and errors
+const IE_MASK: u32 = IS_ERRORS | 0xF;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xEB14_0101;
+
+const CMD_IDENTIFY: u8 = 0xEC;
+const CMD_READ_DMA: u8 = 0xC8;
+const CMD_WRITE_DMA: u8 = 0xCA;
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_READ_FPDMA: u8 = 0x60;
+const CMD_WRITE_FPDMA: u8 = 0x61;
+const FIS_TYPE_H2D: u8 = 0x27;
+
+/// Size of a command header in the command list
+const HEADER_SIZE: usize = 32;
+/// Offset of the received FIS area in the port's page (after the 32-entry command list)
+const RFIS_OFS: usize = 32 * HEADER_SIZE;
+/// Size of each slot's command table (CFIS/ACMD, then the PRDT)
+const TABLE_SIZE: usize = 0x400;
+const PRDT_OFS: usize = 0x80;
+const PRDT_ENTRIES: usize = (TABLE_SIZE - PRDT_OFS) / 16;
+/// Largest transfer per command (a page-fragmented buffer can be misaligned, so one entry is spare)
+const MAX_SECTORS: usize = (PRDT_ENTRIES - 1) * PAGE_SIZE / SECTOR_SIZE;
+/// LBA28 commands transfer at most 256 sectors (count 0)
+const MAX_SECTORS_28: usize = 256;
+
+/// Register polls before giving up on the HBA or a port
+const POLL_LIMIT: usize = 1_000_000;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+/// Mass storage, SATA, AHCI 1.0
+static S_MATCHES: [pci::Match; 1] = [pci::Match::class(0x01_06_01, 0xFF_FF_FF)];
+
+/// HBAs serviced by the MSI handler
+static S_HBAS: Mutex<Option<Vec<Arc<Hba>>>> = Mutex::new(None);
+/// Next volume number (volumes are named `AHCI<n>`)
+static S_NEXT_VOLUME: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A bound HBA, keeps the volumes registered
+struct Instance
+{
+	_hba: Arc<Hba>,
+	_volumes: Vec<storage::PhysicalVolumeReg>,
+}
+impl device_manager::DriverInstance for Instance {}
+
+struct Hba
+{
+	regs: Arc<IOBinding>,
+	ports: Vec<Arc<Port>>,
+}
+
+struct Port
+{
+	regs: Arc<IOBinding>,
+	/// Offset of the port's registers
+	base: usize,
+	index: usize,
+	/// HBA supports 64-bit addresses
+	s64a: bool,
+	/// Command slots used (1 without NCQ)
+	depth: usize,
+	/// Commands are issued with the NCQ commands
+	ncq: bool,
+	/// Completion is signalled by interrupt
+	irq: bool,
+	/// Command list and received FIS area
+	list: KernelMapping,
+	/// Command tables (`TABLE_SIZE` bytes per slot)
+	tables: KernelMapping,
+	state: Mutex<PortState>,
+	/// Held while issuing a command or restarting the port (never taken by the IRQ handler)
+	issue: Mutex<()>,
+	/// Woken when a command completes (or a slot is freed)
+	completed: WaitQueue,
+}
+#[derive(Default)]
+struct PortState
+{
+	/// Slots in use
+	busy: u32,
+	/// Slots written to the HBA (a claimed slot isn't issued until its command is set up)
+	issued: u32,
+	/// Slots issued to the HBA that have completed
+	done: u32,
+	/// Completed slots that failed
+	failed: u32,
+	/// An error stopped the port, restart it before issuing the next command
+	///
+	/// Restarting polls the port for a while, so it is left to command context (not `service`).
+	restart: bool,
+}
+
+pub struct AhciVolume
+{
+	name: String,
+	port: Arc<Port>,
+	lba48: bool,
+	size: u64,
+}
+
+impl device_manager::Driver for Driver
+{
+	fn name(&self) -> &str { "ahci" }
+	fn matches(&self) -> &[pci::Match] { &S_MATCHES }
+	fn bind(&self, dev: &pci::Device) -> Option<Box<device_manager::DriverInstance>> {
+		let regs = match dev.map_bar(5)
+			{
+			Some(v) => Arc::new(v),
+			None => {
+				println!("warning: AHCI {}: No ABAR", dev.addr);
+				return None;
+				},
+			};
+		dev.enable(false, true, true);
+		if !reset_hba(&regs) {
+			println!("warning: AHCI {}: HBA reset timed out", dev.addr);
+			return None;
+		}
+		// SAFE: HBA registers
+		let (cap, pi, vs) = unsafe { (regs.read_32(HBA_CAP), regs.read_32(HBA_PI), regs.read_32(HBA_VS)) };
+		let slots = ((cap >> 8) & 0x1F) as usize + 1;
+		println!("log: AHCI {}: Version {:x}.{:x}, {} ports (mask {:#x}), {} slots{}", dev.addr,
+			vs >> 16, vs & 0xFFFF, (cap & 0x1F) + 1, pi, slots, if cap & CAP_SNCQ != 0 { ", NCQ" } else { "" });
+
+		let irq = dev.enable_msi(handle_irq).is_some();
+		if !irq {
+			println!("log: AHCI {}: No MSI, polling for completion", dev.addr);
+		}
+
+		let mut ports = Vec::new();
+		let mut new_volumes = Vec::new();
+		for i in (0 .. 32).filter(|i| pi & (1 << i) != 0)
+		{
+			let port = match Port::new(regs.clone(), i, cap)
+				{
+				Some(v) => v,
+				None => continue,
+				};
+			let (class, ident) = port.identify();
+			match class
+			{
+			AtaClass::Native if ident.supports_lba() => {
+				let port = Arc::new(port.with_ncq(cap, &ident, slots).with_irq(irq));
+				let sectors = ident.sector_count();
+				let name = format!("AHCI{}", S_NEXT_VOLUME.fetch_add(1, Ordering::Relaxed));
+				println!("log: AHCI {} port {}: {} '{}' (serial '{}'), {} sectors, {}{}", dev.addr, i, name, ident.model(),
+					ident.serial(), sectors, storage::SizePrinter(sectors * SECTOR_SIZE as u64),
+					if port.ncq { format!(", NCQ depth {}", port.depth) } else { String::new() });
+				new_volumes.push(AhciVolume {
+					name: name,
+					port: port.clone(),
+					lba48: ident.supports_lba48(),
+					size: sectors,
+					});
+				ports.push(port);
+				},
+			AtaClass::ATAPI => println!("log: AHCI {} port {}: ATAPI, not supported", dev.addr, i),
+			_ => println!("warning: AHCI {} port {}: No usable disk", dev.addr, i),
+			}
+		}
+
+		let hba = Arc::new(Hba { regs: regs, ports: ports });
+		if irq {
+			::arch::interrupts::without_interrupts(|| S_HBAS.lock().get_or_insert_with(|| Vec::new()).push(hba.clone()));
+			// SAFE: HBA registers
+			unsafe {
+				hba.regs.write_32(HBA_IS, !0);
+				hba.regs.write_32(HBA_GHC, GHC_AE | GHC_IE);
+			}
+		}
+		// - Registering probes the partition tables, so the interrupt must be enabled first
+		let volumes = new_volumes.into_iter().map(|v| storage::register_pv(Box::new(v))).collect();
+		Some(Box::new(Instance { _hba: hba, _volumes: volumes }))
+	}
+}
+
+/// Reset the HBA and put it in AHCI mode, returns false on timeout
+fn reset_hba(regs: &IOBinding) -> bool
+{
+	// SAFE: HBA registers
+	unsafe {
+		regs.write_32(HBA_GHC, GHC_AE);
+		regs.write_32(HBA_GHC, GHC_AE | GHC_HR);
+		if !poll(|| regs.read_32(HBA_GHC) & GHC_HR == 0) {
+			return false;
+		}
+		regs.write_32(HBA_GHC, GHC_AE);
+	}
+	true
+}
+
+/// Poll `cond` until it's true, returns false on timeout
+fn poll<F: FnMut()->bool>(mut cond: F) -> bool
+{
+	(0 .. POLL_LIMIT).any(|_| cond())
+}
+
+/// MSI handler, services every port of every HBA
+fn handle_irq()
+{
+	if let Some(ref hbas) = *S_HBAS.lock()
+	{
+		for hba in hbas.iter()
+		{
+			// SAFE: HBA registers
+			let is = unsafe { hba.regs.read_32(HBA_IS) };
+			for port in hba.ports.iter().filter(|p| is & (1 << p.index) != 0) {
+				port.service();
+			}
+			// - Port status is cleared first, otherwise the HBA bit is set again
+			// SAFE: HBA registers
+			unsafe { hba.regs.write_32(HBA_IS, is) };
+		}
+	}
+}
+
+impl Port
+{
+	/// Initialise port `index`, returns None if there's no device attached
+	///
+	/// The port is polled until `with_irq` is called (the HBA's interrupt isn't enabled yet).
+	fn new(regs: Arc<IOBinding>, index: usize, cap: u32) -> Option<Port> {
+		let base = PORT_BASE + index * PORT_SIZE;
+		// SAFE: Port registers
+		let ssts = unsafe { regs.read_32(base + PX_SSTS) };
+		// - Device present with communication established, and the interface active
+		if ssts & 0xF != 3 || (ssts >> 8) & 0xF != 1 {
+			return None;
+		}
+		let (list, tables) = match ( kmap::alloc_mapped(1), kmap::alloc_mapped(32 * TABLE_SIZE / PAGE_SIZE) )
+			{
+			(Some(l), Some(t)) => (l, t),
+			_ => {
+				println!("warning: AHCI port {}: Unable to allocate command memory", index);
+				return None;
+				},
+			};
+		let rv = Port {
+			regs: regs,
+			base: base,
+			index: index,
+			s64a: cap & CAP_S64A != 0,
+			depth: 1,
+			ncq: false,
+			irq: false,
+			list: list,
+			tables: tables,
+			state: Mutex::new(Default::default()),
+			issue: Mutex::new(()),
+			completed: WaitQueue::new(),
+			};
+		if !rv.start() {
+			println!("warning: AHCI port {}: Timeout starting the port", index);
+			return None;
+		}
+		Some(rv)
+	}
+
+	/// Enable NCQ if both the HBA and the device support it
+	fn with_ncq(mut self, cap: u32, ident: &AtaIdentifyData, slots: usize) -> Port {
+		if cap & CAP_SNCQ != 0 && ident.supports_ncq() && ident.supports_lba48() {
+			self.ncq = true;
+			self.depth = ::core::cmp::min(slots, ident.ncq_depth());
+		}
+		self
+	}
+
+	/// Enable the port's completion interrupts
+	fn with_irq(mut self, irq: bool) -> Port {
+		self.irq = irq;
+		if irq {
+			self.write(PX_IE, IE_MASK);
+		}
+		self
+	}
+
+	fn read(&self, reg: usize) -> u32 {
+		// SAFE: Port registers
+		unsafe { self.regs.read_32(self.base + reg) }
+	}
+	fn write(&self, reg: usize, val: u32) {
+		// SAFE: Port registers
+		unsafe { self.regs.write_32(self.base + reg, val) }
+	}
+
+	/// Stop the command engine and FIS receive, returns false on timeout
+	fn stop(&self) -> bool {
+		self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+		if !poll(|| self.read(PX_CMD) & CMD_CR == 0) {
+			return false;
+		}
+		self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+		poll(|| self.read(PX_CMD) & CMD_FR == 0)
+	}
+
+	/// Point the port at its command list and start it, returns false on timeout
+	fn start(&self) -> bool {
+		if !self.stop() {
+			return false;
+		}
+		let list = self.list.phys().get() as u64;
+		let rfis = list + RFIS_OFS as u64;
+		self.write(PX_CLB, list as u32);
+		self.write(PX_CLBU, (list >> 32) as u32);
+		self.write(PX_FB, rfis as u32);
+		self.write(PX_FBU, (rfis >> 32) as u32);
+		self.write(PX_SERR, !0);
+		self.write(PX_IS, !0);
+		self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+		if !poll(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+			return false;
+		}
+		self.write(PX_IE, if self.irq { IE_MASK } else { 0 });
+		self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+		true
+	}
+
+	/// Send IDENTIFY DEVICE and classify the device from the port signature
+	fn identify(&self) -> (AtaClass, Box<AtaIdentifyData>) {
+		let mut ident: Box<AtaIdentifyData> = Box::new(Default::default());
+		match self.read(PX_SIG)
+		{
+		SIG_ATA => {},
+		SIG_ATAPI => return (AtaClass::ATAPI, ident),
+		sig => return (AtaClass::Unknown(sig as u8, (sig >> 8) as u8), ident),
+		}
+		let buf = &mut *ident as *mut AtaIdentifyData as *mut u8;
+		match self.command(buf, 512, false, |_| fis(CMD_IDENTIFY, 0, 0, 0, 0))
+		{
+		Ok(_) => (AtaClass::Native, ident),
+		Err(_) => (AtaClass::Invalid, ident),
+		}
+	}
+
+	/// Handle an interrupt (or poll): record completed commands and recover from errors
+	fn service(&self) {
+		let woke = ::arch::interrupts::without_interrupts(|| {
+			let mut st = self.state.lock();
+			let is = self.read(PX_IS);
+			self.write(PX_IS, is);
+			let outstanding = st.issued & !st.done;
+			if outstanding == 0 {
+				return false;
+			}
+			if is & IS_ERRORS != 0 {
+				println!("warning: AHCI port {}: Error (IS={:#x}, TFD={:#x}, SERR={:#x})", self.index, is,
+					self.read(PX_TFD), self.read(PX_SERR));
+				// - The failing command can't be identified with NCQ, fail everything outstanding
+				st.failed |= outstanding;
+				st.done |= outstanding;
+				st.restart = true;
+				return true;
+			}
+			let active = self.read(PX_CI) | if self.ncq { self.read(PX_SACT) } else { 0 };
+			let finished = outstanding & !active;
+			st.done |= finished;
+			finished != 0
+			});
+		if woke {
+			self.completed.wake_all();
+		}
+	}
+
+	/// Wait until `cond` holds (checked with the state locked)
+	fn wait_for<F: Fn(&PortState)->bool>(&self, cond: F) {
+		let check = || ::arch::interrupts::without_interrupts(|| cond(&self.state.lock()));
+		if self.irq && ::arch::interrupts::enabled() {
+			self.completed.wait_while(|| !check());
+		}
+		else {
+			while !check() {
+				self.service();
+			}
+		}
+	}
+
+	/// Describe `len` bytes at `buf` in the PRDT of `slot`, returns the number of entries
+	fn fill_prdt(&self, slot: usize, buf: *mut u8, len: usize) -> Result<usize,storage::IoError> {
+		// SAFE: Slot is owned by the caller, and only used by the HBA once issued
+		let table = unsafe { &mut self.tables.as_words_mut()[slot * TABLE_SIZE / 4 .. (slot + 1) * TABLE_SIZE / 4] };
+		let prdt = &mut table[PRDT_OFS / 4 ..];
+		// SAFE: Only used to translate addresses
+		let pt = unsafe { ActivePageTable::new() };
+		let mut n = 0;
+		// Open entry, as (physical address, length)
+		let mut cur: Option<(usize, usize)> = None;
+		let mut virt = buf as usize;
+		let end = virt + len;
+		{
+			let mut set = |n: usize, p: usize, l: usize| {
+				prdt[n * 4] = p as u32;
+				prdt[n * 4 + 1] = (p as u64 >> 32) as u32;
+				prdt[n * 4 + 2] = 0;
+				prdt[n * 4 + 3] = (l - 1) as u32;
+				};
+			while virt < end
+			{
+				let chunk = ::core::cmp::min(end - virt, PAGE_SIZE - virt % PAGE_SIZE);
+				let phys = match pt.translate(virt)
+					{
+					Some(v) => v.get(),
+					None => return Err( storage::IoError::Unknown("AHCI: Buffer not mapped") ),
+					};
+				if !self.s64a && phys + chunk > 1 << 32 {
+					return Err( storage::IoError::Unknown("AHCI: Buffer above 4GiB") );
+				}
+				cur = match cur
+					{
+					// Entries can be up to 4MiB
+					Some( (p, l) ) if p + l == phys && l + chunk <= 1 << 22 => Some( (p, l + chunk) ),
+					Some( (p, l) ) => {
+						if n + 1 >= PRDT_ENTRIES {
+							return Err( storage::IoError::InvalidParameter );
+						}
+						set(n, p, l);
+						n += 1;
+						Some( (phys, chunk) )
+						},
+					None => Some( (phys, chunk) ),
+					};
+				virt += chunk;
+			}
+			if let Some( (p, l) ) = cur {
+				set(n, p, l);
+				n += 1;
+			}
+		}
+		Ok(n)
+	}
+
+	/// Issue a command transferring `len` bytes at `buf`, and wait for it to complete
+	///
+	/// `make_fis` builds the command FIS given the slot (the NCQ tag).
+	fn command<F: Fn(u8)->[u8; 20]>(&self, buf: *mut u8, len: usize, write: bool, make_fis: F) -> Result<(),storage::IoError> {
+		assert!(buf as usize % 2 == 0 && len % 2 == 0, "AHCI buffers must be word aligned");
+		// - Claim a free slot
+		let slot = loop
+			{
+			let claimed = ::arch::interrupts::without_interrupts(|| {
+				let mut st = self.state.lock();
+				match (0 .. self.depth).find(|&i| st.busy & (1 << i) == 0)
+				{
+				Some(i) => { st.busy |= 1 << i; Some(i) },
+				None => None,
+				}
+				});
+			match claimed
+			{
+			Some(i) => break i,
+			None => self.wait_for(|st| st.busy.count_ones() < self.depth as u32),
+			}
+			};
+		let bit = 1u32 << slot;
+
+		let entries = match self.fill_prdt(slot, buf, len)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				self.release(bit);
+				return Err(e);
+				},
+			};
+		// SAFE: Slot is owned by this call
+		unsafe {
+			let table = &mut self.tables.as_bytes_mut()[slot * TABLE_SIZE ..];
+			table[.. 20].clone_from_slice(&make_fis(slot as u8));
+			let header = &mut self.list.as_words_mut()[slot * HEADER_SIZE / 4 .. (slot + 1) * HEADER_SIZE / 4];
+			let ctba = self.tables.phys().get() as u64 + (slot * TABLE_SIZE) as u64;
+			// - FIS length (5 dwords), write flag, PRDT length
+			header[0] = 5 | if write { 1 << 6 } else { 0 } | (entries as u32) << 16;
+			header[1] = 0;
+			header[2] = ctba as u32;
+			header[3] = (ctba >> 32) as u32;
+		}
+		{
+			let _lh = self.issue.lock();
+			let restart = ::arch::interrupts::without_interrupts(|| ::core::mem::replace(&mut self.state.lock().restart, false));
+			if restart && !self.start() {
+				println!("warning: AHCI port {}: Unable to restart after error", self.index);
+				::arch::interrupts::without_interrupts(|| self.state.lock().restart = true);
+				self.release(bit);
+				return Err(storage::IoError::Timeout);
+			}
+			::arch::interrupts::without_interrupts(|| {
+				let mut st = self.state.lock();
+				st.issued |= bit;
+				if self.ncq {
+					self.write(PX_SACT, bit);
+				}
+				self.write(PX_CI, bit);
+				});
+		}
+
+		self.wait_for(|st| st.done & bit != 0);
+		let failed = ::arch::interrupts::without_interrupts(|| self.state.lock().failed & bit != 0);
+		self.release(bit);
+		if failed { Err(storage::IoError::BadBlock) } else { Ok( () ) }
+	}
+
+	/// Free a slot (and wake anything waiting for one)
+	fn release(&self, bit: u32) {
+		::arch::interrupts::without_interrupts(|| {
+			let mut st = self.state.lock();
+			st.busy &= !bit;
+			st.issued &= !bit;
+			st.done &= !bit;
+			st.failed &= !bit;
+			});
+		self.completed.wake_all();
+	}
+}
+
+/// Build a host to device register FIS
+fn fis(command: u8, lba: u64, count: u16, features: u16, device: u8) -> [u8; 20]
+{
+	let mut f = [0u8; 20];
+	f[0] = FIS_TYPE_H2D;
+	f[1] = 0x80;	// Command (not control) update
+	f[2] = command;
+	f[3] = features as u8;
+	f[4] = lba as u8;
+	f[5] = (lba >> 8) as u8;
+	f[6] = (lba >> 16) as u8;
+	f[7] = device;
+	f[8] = (lba >> 24) as u8;
+	f[9] = (lba >> 32) as u8;
+	f[10] = (lba >> 40) as u8;
+	f[11] = (features >> 8) as u8;
+	f[12] = count as u8;
+	f[13] = (count >> 8) as u8;
+	f
+}
+
+impl AhciVolume
+{
+	/// Transfer `count` sectors (at most `max_sectors`) between the disk and `buf`
+	fn transfer(&self, lba: u64, count: usize, buf: *mut u8, write: bool) -> Result<(),storage::IoError> {
+		let port = &self.port;
+		let len = count * SECTOR_SIZE;
+		if port.ncq {
+			// - Count is in the features register, the tag in the count register
+			port.command(buf, len, write, |tag| fis(if write { CMD_WRITE_FPDMA } else { CMD_READ_FPDMA },
+				lba, (tag as u16) << 3, count as u16, 0x40))
+		}
+		else if self.lba48 {
+			port.command(buf, len, write, |_| fis(if write { CMD_WRITE_DMA_EXT } else { CMD_READ_DMA_EXT },
+				lba, count as u16, 0, 0x40))
+		}
+		else {
+			if lba + count as u64 > 1 << 28 {
+				return Err(storage::IoError::BadAddr);
+			}
+			// - LBA28: bits 24-27 go in the device register
+			port.command(buf, len, write, |_| fis(if write { CMD_WRITE_DMA } else { CMD_READ_DMA },
+				lba & 0xFF_FFFF, count as u16, 0, 0x40 | ((lba >> 24) & 0xF) as u8))
+		}
+	}
+
+	fn max_sectors(&self) -> usize {
+		if self.lba48 { MAX_SECTORS } else { ::core::cmp::min(MAX_SECTORS, MAX_SECTORS_28) }
+	}
+
+	/// Split a request into commands, bouncing through a word-aligned buffer if `buf` isn't
+	fn transfer_all(&self, blockidx: u64, count: usize, buf: *mut u8, write: bool) -> Result<usize,storage::IoError> {
+		if blockidx + count as u64 > self.size {
+			return Err(storage::IoError::BadAddr);
+		}
+		let mut done = 0;
+		while done < count
+		{
+			let n = ::core::cmp::min(count - done, self.max_sectors());
+			// SAFE: Within the caller's buffer (`count` sectors)
+			let p = unsafe { buf.offset((done * SECTOR_SIZE) as isize) };
+			if p as usize % 2 == 0 {
+				try!(self.transfer(blockidx + done as u64, n, p, write));
+			}
+			else {
+				let mut bounce = vec![0u16; n * SECTOR_SIZE / 2];
+				let bp = bounce.as_mut_ptr() as *mut u8;
+				// SAFE: Both buffers are `n` sectors long
+				unsafe {
+					if write {
+						::core::ptr::copy_nonoverlapping(p as *const u8, bp, n * SECTOR_SIZE);
+					}
+					try!(self.transfer(blockidx + done as u64, n, bp, write));
+					if !write {
+						::core::ptr::copy_nonoverlapping(bp as *const u8, p, n * SECTOR_SIZE);
+					}
+				}
+			}
+			done += n;
+		}
+		Ok(count)
+	}
+}
+
+impl storage::PhysicalVolume for AhciVolume
+{
+	fn name(&self) -> &str { &*self.name }
+	fn blocksize(&self) -> usize { SECTOR_SIZE }
+	fn capacity(&self) -> Option<u64> { Some(self.size) }
+
+	fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!(dst.len(), count * SECTOR_SIZE);
+		self.transfer_all(blockidx, count, dst.as_mut_ptr(), false)
+	}
+	fn write<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, src: &'a [u8]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!(src.len(), count * SECTOR_SIZE);
+		// The buffer is only read from for writes
+		self.transfer_all(blockidx, count, src.as_ptr() as *mut u8, true)
+	}
+	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> Result<(),storage::IoError>
+	{
+		// No TRIM support
+		Ok( () )
+	}
+}
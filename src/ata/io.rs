@@ -6,18 +6,47 @@ use ::prelude::*;
 //use ::memory::helpers::{DMABuffer};
 //use ::async;
 use ::metadevs::storage;
-//use ::device_manager::IOBinding;
+use ::device_manager::IOBinding;
 use x86_64::instructions::port;
-
-pub const SECTOR_SIZE: usize = 128;
+use spin::Mutex;
+use mylib::{VecMap,LazyStatic};
+use mylib::mem::Arc;
+use memory::{ActivePageTable,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use process::WaitQueue;
+use consts::irq::{IRQ_IDE1,IRQ_IDE2};
+use super::{AtaClass,AtaIdentifyData};
+
+/// Size of a sector in bytes
+pub const SECTOR_SIZE: usize = 512;
+/// Size of a sector in 32-bit words (the unit of the data buffers)
+pub const SECTOR_WORDS: usize = SECTOR_SIZE / 4;
 //const MAX_DMA_SECTORS: usize = 0x2_0000 / SECTOR_SIZE;	// Limited by sector count (and PRDT entries)
-const MAX_DMA_SECTORS: usize = 0x1F_F000 / SECTOR_SIZE;	// Limited by sector count (and PRDT entries)
+const MAX_DMA_SECTORS: usize = 0x20_0000 / SECTOR_SIZE;	// Limited by sector count (and PRDT entries)
 // 512 PDRT entries, assume maximum fragmentation = 512 * 4K max = 2^21 = 2MB per transfer
+/// Entries in a PRDT (one page)
+const PRDT_ENTRIES: usize = PAGE_SIZE / 8;
+/// Set on the last PRDT entry
+const PRD_EOT: u32 = 1 << 31;
+/// Size of each channel's bounce buffer (used when a caller's buffer can't be described by the PRDT)
+const BOUNCE_PAGES: usize = 32;
+
+// Bus-master IDE registers (relative to the channel's base, the second channel is at +8)
+const BM_COMMAND: usize = 0;
+const BM_STATUS: usize = 2;
+const BM_PRDT: usize = 4;
+/// Size of a channel's register block
+const BM_CHANNEL_SIZE: usize = 8;
+const BM_CMD_START: u8 = 0x01;
+/// Transfer direction is device to memory
+const BM_CMD_READ: u8 = 0x08;
+const BM_STS_ERR: u8 = 0x02;
+const BM_STS_IRQ: u8 = 0x04;
 
 //const HDD_PIO_W28: u8 = 0x30,
 //const HDD_PIO_R28: u8 = 0x20;
-//const HDD_PIO_W48: u8 = 0x34;
-//const HDD_PIO_R48: u8 = 0x24;
+const HDD_PIO_W48: u8 = 0x34;
+const HDD_PIO_R48: u8 = 0x24;
 const HDD_IDENTIFY: u8 = 0xEC;
 
 const HDD_DMA_R28: u8 = 0xC8;
@@ -59,8 +88,17 @@ const IO_BASE1         : u16 = 0x170;
 const IO_CTRL0         : u16 = 0x3F4;
 const IO_CTRL1         : u16 = 0x374;
 
-const MAX_IDE          : usize = 4;
-const MAX_NSECS        : usize = 128;
+pub const MAX_IDE      : usize = 4;
+/// Maximum number of sectors per LBA28 command (the sector count register is 8 bits)
+pub const MAX_NSECS    : usize = 255;
+/// Maximum number of sectors per LBA48 command (16-bit count)
+pub const MAX_NSECS_48 : usize = 0xFFFF;
+/// First sector that can't be addressed by LBA28 commands (128GiB)
+const LBA28_LIMIT      : u64 = 1 << 28;
+/// Number of status polls before IDENTIFY is considered to have timed out
+const IDENTIFY_POLL_LIMIT: usize = 1_000_000;
+/// Number of status polls before a busy device is considered to have timed out
+const READY_POLL_LIMIT: usize = 1_000_000;
 //const MAX_DISK_NSECS          0x10000000U;
 //const VALID_IDE(ideno)        (((ideno) >= 0) && ((ideno) < MAX_IDE) && (ide_devices[ideno].valid))
 
@@ -78,6 +116,8 @@ const channels:[(u16,u16);2] = [(IO_BASE0, IO_CTRL0),(IO_BASE1, IO_CTRL1)];
 pub struct DmaController
 {
 	pub name: String,
+	/// Devices that support LBA48 commands (from IDENTIFY, indexed by disk)
+	pub lba48: [bool; MAX_IDE],
 	//pub ata_controllers: [AtaController; 2],
 	//pub dma_base: IOBinding,
 }
@@ -85,227 +125,751 @@ pub struct DmaController
 impl DmaController
 {
 	/// Read ATA DMA
-	pub fn do_dma_rd<'a>(&'a self, blockidx: u64, count: usize, dst: &'a mut [u32], disk: u8) -> Result<usize,storage::IoError> {
-		assert_eq!(dst.len(), count * SECTOR_SIZE);
-		let dst = if count > MAX_DMA_SECTORS { &mut dst[.. MAX_DMA_SECTORS * SECTOR_SIZE] } else { dst };
+	pub fn do_dma_rd<'a>(&'a self, prio: u8, blockidx: u64, count: usize, dst: &'a mut [u32], disk: u8) -> Result<usize,storage::IoError> {
+		assert_eq!(dst.len(), count * SECTOR_WORDS);
+		let count = self.max_transfer(disk, count);
+		let dst = &mut dst[.. count * SECTOR_WORDS];
 		//self.do_dma(blockidx, DMABuffer::new_mut(dst, 32), disk, false);
-		self.ide_read_secs(disk,blockidx,dst,count as u8)
+		self.ide_read_secs(prio,disk,blockidx,dst,count)
 		//Ok(233)
 	}
 	/// Write ATA DMA
-	pub fn do_dma_wr<'a>(&'a self, blockidx: u64, count: usize, dst: &'a [u32], disk: u8) -> Result<usize,storage::IoError> {
-		assert_eq!(dst.len(), count * SECTOR_SIZE);
-		let dst = if count > MAX_DMA_SECTORS { &dst[.. MAX_DMA_SECTORS * SECTOR_SIZE] } else { dst };
+	pub fn do_dma_wr<'a>(&'a self, prio: u8, blockidx: u64, count: usize, dst: &'a [u32], disk: u8) -> Result<usize,storage::IoError> {
+		assert_eq!(dst.len(), count * SECTOR_WORDS);
+		let count = self.max_transfer(disk, count);
+		let dst = &dst[.. count * SECTOR_WORDS];
 		//println!("ide_write_secs: disk={},blockidx={},count={}",disk,blockidx,count);
-		self.ide_write_secs(disk,blockidx,dst,count as u8)
+		self.ide_write_secs(prio,disk,blockidx,dst,count)
 		//Ok(233)
 	}
 
-	fn ide_wait_ready(&self, iobase:u16, check_error: usize)->usize {
+	/// Number of sectors (of `count`) that a single command to `disk` can transfer
+	fn max_transfer(&self, disk: u8, count: usize) -> usize {
+		let max = if self.lba48[disk as usize] { MAX_NSECS_48 } else { MAX_NSECS };
+		::core::cmp::min(count, ::core::cmp::min(max, MAX_DMA_SECTORS))
+	}
+
+	/// Poll the status register until BSY clears, returns None on timeout
+	fn ide_poll_status(&self, iobase: u16) -> Option<u8> {
+		for _ in 0 .. IDENTIFY_POLL_LIMIT
+		{
+			// SAFE: Reading the status register has no side effects
+			let r = unsafe { port::inb(iobase + ISA_STATUS) };
+			if r & IDE_BSY == 0 {
+				return Some(r);
+			}
+		}
+		None
+	}
+
+	/// Send IDENTIFY to all devices (master and slave on both channels)
+	pub fn ide_init(&self) -> Vec<(u8, AtaClass, Box<AtaIdentifyData>)> {
+		(0 .. MAX_IDE as u8)
+			.map(|disk| {
+				let (class, ident) = self.ide_identify(disk);
+				(disk, class, ident)
+				})
+			.collect()
+	}
+
+	/// Send IDENTIFY to `disk` (0-3, channel is `disk / 2`) and classify it from the response
+	pub fn ide_identify(&self, disk: u8) -> (AtaClass, Box<AtaIdentifyData>) {
+		let iobase = channels[(disk >> 1) as usize].0;
+		let mut ident: Box<AtaIdentifyData> = Box::new(Default::default());
 		unsafe{
-			let mut r= port::inb(iobase + ISA_STATUS);
-			//println!("iobase:{} ready:{}",iobase,r);
-			while (r & IDE_BSY) > 0{
-				r= port::inb(iobase + ISA_STATUS);
-				//println!("busy");
+			// Floating bus, no devices on this channel
+			if port::inb(iobase + ISA_STATUS) == 0xFF {
+				return (AtaClass::None, ident);
+			}
+			/* step1: select drive */
+			port::outb(iobase + ISA_SDH, 0xA0 | ((disk & 1) << 4));
+			if self.ide_poll_status(iobase).is_none() {
+				return (AtaClass::Invalid, ident);
+			}
+			port::outb(iobase + ISA_SECCNT, 0);
+			port::outb(iobase + ISA_SECTOR, 0);
+			port::outb(iobase + ISA_CYL_LO, 0);
+			port::outb(iobase + ISA_CYL_HI, 0);
+
+			/* step2: send ATA identify command */
+			port::outb(iobase + ISA_COMMAND, IDE_CMD_IDENTIFY);
+			if port::inb(iobase + ISA_STATUS) == 0 {
+				return (AtaClass::None, ident);
+			}
+
+			/* step3: polling, then classify using the signature in the LBA mid/high registers */
+			let status = match self.ide_poll_status(iobase)
+				{
+				Some(v) => v,
+				None => return (AtaClass::Invalid, ident),
+				};
+			let (r4, r5) = (port::inb(iobase + ISA_CYL_LO), port::inb(iobase + ISA_CYL_HI));
+			match (r4, r5)
+			{
+			(0x00, 0x00) => {},
+			// ATAPI (and SATAPI) devices abort IDENTIFY, they need IDENTIFY PACKET DEVICE
+			(0x14, 0xEB) | (0x69, 0x96) => return (AtaClass::ATAPI, ident),
+			_ => return (AtaClass::Unknown(r4, r5), ident),
+			}
+			if status & (IDE_DF | IDE_ERR) != 0 {
+				return (AtaClass::Unknown(r4, r5), ident);
+			}
+
+			/* step4: wait for the data, and read it */
+			let mut ready = false;
+			for _ in 0 .. IDENTIFY_POLL_LIMIT
+			{
+				let r = port::inb(iobase + ISA_STATUS);
+				if r & IDE_ERR != 0 {
+					return (AtaClass::Unknown(r4, r5), ident);
+				}
+				if r & IDE_DRQ != 0 {
+					ready = true;
+					break ;
+				}
+			}
+			if !ready {
+				return (AtaClass::Invalid, ident);
 			}
-			/* nothing */
-			if check_error ==1 && (r & (IDE_DF | IDE_ERR)) != 0 {
-				return 1;
+			let mut buffer: [u32; 128] = [0; 128];
+			for w in buffer.iter_mut() {
+				*w = port::inl(iobase + ISA_DATA);
 			}
+			// SAFE: AtaIdentifyData is plain old data laid out as the 512 byte IDENTIFY response
+			::core::ptr::copy_nonoverlapping(buffer.as_ptr() as *const u8, &mut *ident as *mut AtaIdentifyData as *mut u8,
+				::core::cmp::min(512, ::core::mem::size_of::<AtaIdentifyData>()));
 		}
-		return 0;
-	}
-
-	pub fn ide_init(&self) {
-		//static_assert((SECTSIZE % 4) == 0);
-		for ideno in 0 .. MAX_IDE {
-			//println!("ideno:{}",ideno);
-			/* assume that no device here */
-			//ide_devices[ideno].valid = 0;
-
-			//let iobase = IO_BASE(ideno);
-			let iobase = channels[if ideno >2 {1} else {0}].0;
-
-			/* wait device ready */
-			self.ide_wait_ready(iobase, 0);
-			//println!("ide_wait_ready");
-			unsafe{
-				/* step1: select drive */
-				//println!("outb");
-				port::outb(iobase + ISA_SDH, (0xE0 | ((ideno & 1) << 4)) as u8);
-				self.ide_wait_ready(iobase, 0);
-
-				/* step2: send ATA identify command */
-				//println!("outb");
-				port::outb(iobase + ISA_COMMAND, IDE_CMD_IDENTIFY);
-				self.ide_wait_ready(iobase, 0);
-
-				/* step3: polling */
-				//println!("inb");
-				if port::inb(iobase + ISA_STATUS) == 0 || self.ide_wait_ready(iobase, 1) != 0 {
-					continue ;
+		(AtaClass::Native, ident)
+	}
+
+	fn ide_read_secs<'a>(&'a self, prio: u8, ideno: u8, secno:u64, dst: &'a mut [u32], nsecs:usize) -> Result<usize,storage::IoError> {
+		assert!(nsecs <= MAX_NSECS_48 && (ideno as usize) < MAX_IDE);
+		assert_eq!(dst.len(), nsecs * SECTOR_WORDS);
+		channel(ideno).transfer(Request::new(prio, ideno, secno, nsecs, false, self.lba48[ideno as usize], dst.as_mut_ptr()))
+	}
+
+	fn ide_write_secs<'a>(&'a self, prio: u8, ideno: u8, secno:u64, src: &'a [u32], nsecs:usize) -> Result<usize,storage::IoError> {
+		assert!(nsecs <= MAX_NSECS_48 && (ideno as usize) < MAX_IDE);
+		assert_eq!(src.len(), nsecs * SECTOR_WORDS);
+		// The buffer is only read from for writes
+		channel(ideno).transfer(Request::new(prio, ideno, secno, nsecs, true, self.lba48[ideno as usize], src.as_ptr() as *mut u32))
+	}
+}
+
+/// Wait for BSY to clear
+///
+/// Fails with `Timeout` if the device stays busy, or `BadBlock` if `check_error` is set and the
+/// device reported an error.
+fn wait_ready(iobase: u16, check_error: bool) -> Result<(),storage::IoError> {
+	for _ in 0 .. READY_POLL_LIMIT
+	{
+		// SAFE: Reading the status register has no side effects
+		let r = unsafe { port::inb(iobase + ISA_STATUS) };
+		if r & IDE_BSY == 0 {
+			if check_error && r & (IDE_DF | IDE_ERR) != 0 {
+				return Err(storage::IoError::BadBlock);
+			}
+			return Ok( () );
+		}
+	}
+	Err(storage::IoError::Timeout)
+}
+
+/// Wait ~400ns for the status register to become valid (reading alternate status has no side effects)
+fn delay_400ns(ioctrl: u16) {
+	for _ in 0 .. 4 {
+		// SAFE: Alternate status is read-only
+		unsafe { port::inb(ioctrl + ISA_CTRL); }
+	}
+}
+
+// --------------------------------------------------------------------
+// Per-channel request queue
+//
+// Callers queue a request and sleep. If the controller has bus-master DMA, the request is described
+// by the channel's PRDT (or copied through its bounce buffer) and the IRQ signals completion,
+// otherwise the IRQ handler moves data for the active request one sector at a time (PIO). The next
+// request is started when the active one completes. Requests are started in priority
+// order (0 first, FIFO within a priority), and a queued request that's adjacent to a new one (same
+// disk and direction) is extended instead of queueing another command.
+// --------------------------------------------------------------------
+
+/// A queued transfer (possibly several callers' adjacent requests merged into one command)
+struct Request
+{
+	prio: u8,
+	disk: u8,
+	write: bool,
+	lba48: bool,
+	lba: u64,
+	count: usize,
+	/// Caller buffers in sector order, as (buffer, sector count)
+	/// The callers sleep until the request completes, so the buffers stay valid.
+	segments: Vec<(*mut u32, usize)>,
+	/// Callers waiting on this request, as (ticket, sector count)
+	tickets: Vec<(u64, usize)>,
+	/// Number of sectors transferred so far
+	done: usize,
+	/// How the request is being transferred by DMA (None for PIO)
+	dma: Option<DmaMode>,
+}
+// SAFE: The buffers are only accessed while their owners wait for the request
+unsafe impl Send for Request {}
+
+#[derive(Copy,Clone,PartialEq)]
+enum DmaMode
+{
+	/// The PRDT points at the caller buffers
+	Direct,
+	/// The PRDT points at the bounce buffer
+	Bounce,
+}
+
+/// Bus-master DMA state for a channel
+struct DmaEngine
+{
+	regs: Arc<IOBinding>,
+	/// Offset of the channel's registers in `regs`
+	base: usize,
+	prdt: KernelMapping,
+	bounce: KernelMapping,
+}
+
+struct ChannelQueue
+{
+	pending: Vec<Request>,
+	active: Option<Request>,
+	next_ticket: u64,
+	/// Results of completed requests, by ticket
+	results: VecMap<u64, Result<usize,storage::IoError>>,
+	/// Bus-master DMA, if the controller supports it
+	dma: Option<DmaEngine>,
+}
+struct Channel
+{
+	index: usize,
+	queue: Mutex<ChannelQueue>,
+	/// Woken when a request completes
+	completed: WaitQueue,
+}
+
+static mut S_CHANNELS: LazyStatic<Vec<Channel>> = lazystatic_init!();
+
+/// Get the channel handling `disk` (the queues are created on first use)
+fn channel(disk: u8) -> &'static Channel {
+	// SAFE: Only prepared once, with interrupts disabled
+	unsafe {
+		::arch::interrupts::without_interrupts(|| if !S_CHANNELS.ls_is_valid() {
+			S_CHANNELS.prep(|| (0 .. channels.len()).map(|i| Channel::new(i)).collect());
+			::arch::interrupts::enable_irq(IRQ_IDE1);
+			::arch::interrupts::enable_irq(IRQ_IDE2);
+			});
+		&S_CHANNELS[(disk >> 1) as usize]
+	}
+}
+
+/// Use bus-master DMA on both channels, `regs` is the controller's bus-master register block (BAR4)
+pub fn attach_busmaster(regs: IOBinding) {
+	let regs = Arc::new(regs);
+	for (i, ch) in (0 .. channels.len()).map(|i| (i, channel((i * 2) as u8)))
+	{
+		let engine = match DmaEngine::new(regs.clone(), i * BM_CHANNEL_SIZE)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: ATA: Unable to allocate DMA buffers for channel {}, using PIO", i);
+				continue ;
+				},
+			};
+		::arch::interrupts::without_interrupts(|| ch.queue.lock().dma = Some(engine));
+	}
+	println!("log: ATA: Bus-master DMA enabled ({:?})", regs);
+}
+
+/// IDE IRQ handler (`channel` is 0 for IRQ 14, 1 for IRQ 15), called with interrupts disabled
+pub fn handle_irq(channel: usize) {
+	// Reading the status register acknowledges the interrupt
+	// SAFE: Port IO on the controller's status register
+	let status = unsafe { port::inb(channels[channel].0 + ISA_STATUS) };
+	// SAFE: Only read once prepared
+	if unsafe { S_CHANNELS.ls_is_valid() } {
+		unsafe { S_CHANNELS[channel].service(status) };
+	}
+}
+
+impl Request
+{
+	fn new(prio: u8, disk: u8, lba: u64, count: usize, write: bool, lba48: bool, buf: *mut u32) -> Request {
+		Request {
+			prio: prio,
+			disk: disk,
+			write: write,
+			lba48: lba48,
+			lba: lba,
+			count: count,
+			segments: vec![ (buf, count) ],
+			tickets: Vec::new(),
+			done: 0,
+			dma: None,
+		}
+	}
+	fn max_sectors(&self) -> usize {
+		::core::cmp::min(MAX_DMA_SECTORS, if self.lba48 { MAX_NSECS_48 } else { MAX_NSECS })
+	}
+
+	/// Merge `other` into this request if it's adjacent and compatible, otherwise hand it back
+	fn try_merge(&mut self, other: Request) -> Result<(),Request> {
+		if other.disk != self.disk || other.write != self.write || self.count + other.count > self.max_sectors() {
+			return Err(other);
+		}
+		if self.lba + self.count as u64 == other.lba {
+			self.segments.extend(other.segments);
+		}
+		else if other.lba + other.count as u64 == self.lba {
+			let mut segs = other.segments;
+			segs.extend(self.segments.drain(..));
+			self.segments = segs;
+			self.lba = other.lba;
+		}
+		else {
+			return Err(other);
+		}
+		self.count += other.count;
+		self.tickets.extend(other.tickets);
+		self.prio = ::core::cmp::min(self.prio, other.prio);
+		Ok( () )
+	}
+
+	/// Buffer for sector `idx` of the request
+	fn sector_buf(&self, mut idx: usize) -> *mut u32 {
+		for &(buf, count) in self.segments.iter()
+		{
+			if idx < count {
+				// SAFE: Within the caller's buffer (`count` sectors long)
+				return unsafe { buf.offset((idx * SECTOR_WORDS) as isize) };
+			}
+			idx -= count;
+		}
+		panic!("BUG: ATA request sector {} out of range", idx);
+	}
+
+	/// Move the next sector between the device and the buffers
+	fn transfer_sector(&mut self, iobase: u16) {
+		let buf = self.sector_buf(self.done);
+		let data_port = iobase + ISA_DATA;
+		// SAFE: `buf` is a sector of a live caller buffer
+		unsafe {
+			for i in 0 .. SECTOR_WORDS as isize {
+				if self.write {
+					port::outl(data_port, *buf.offset(i));
 				}
+				else {
+					*buf.offset(i) = port::inl(data_port);
+				}
+			}
+		}
+	}
+}
 
-				//println!("insl");
-				let mut buffer:[u32;128]=[0;128];
-				for i in 0..buffer.len(){
-					buffer[i]=i as u32;
-					if i==1 {
-						//println!("{:#x}",&buffer[i] as *const u32 as usize - ::consts::KERNEL_OFFSET)
-					}
+impl DmaEngine
+{
+	fn new(regs: Arc<IOBinding>, base: usize) -> Option<DmaEngine> {
+		let prdt = match kmap::alloc_mapped(1)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let bounce = match kmap::alloc_mapped(BOUNCE_PAGES)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		// The PRDT and the buffers it points to must be below 4GiB
+		if prdt.phys().get() + prdt.len() > 1 << 32 || bounce.phys().get() + bounce.len() > 1 << 32 {
+			return None;
+		}
+		Some(DmaEngine { regs: regs, base: base, prdt: prdt, bounce: bounce })
+	}
+
+	fn set_prd(&self, idx: usize, phys: usize, len: usize, last: bool) {
+		// SAFE: The PRDT is only used by the device while this channel's active request runs
+		let prdt = unsafe { self.prdt.as_words_mut() };
+		prdt[idx * 2] = phys as u32;
+		// A byte count of zero means 64KiB
+		prdt[idx * 2 + 1] = (len & 0xFFFF) as u32 | if last { PRD_EOT } else { 0 };
+	}
+
+	/// Describe the caller buffers of `req` in the PRDT, returns false if they can't be
+	///
+	/// Physically adjacent pages are merged into one entry, entries can't cross a 64KiB boundary.
+	fn build_direct(&self, req: &Request) -> bool {
+		// SAFE: Only used to translate addresses
+		let table = unsafe { ActivePageTable::new() };
+		let mut n = 0;
+		// Open entry, as (physical address, length)
+		let mut cur: Option<(usize, usize)> = None;
+		for &(buf, count) in req.segments.iter()
+		{
+			let end = buf as usize + count * SECTOR_SIZE;
+			let mut virt = buf as usize;
+			while virt < end
+			{
+				let len = ::core::cmp::min(end - virt, PAGE_SIZE - virt % PAGE_SIZE);
+				let phys = match table.translate(virt)
+					{
+					Some(v) => v.get(),
+					None => return false,
+					};
+				if phys + len > 1 << 32 {
+					return false;
 				}
-				//println!("insl {:#x}",&buffer as *const u32 as usize - ::consts::KERNEL_OFFSET);
-				
-				//println!("insl {:#x}",buffer.as_ptr() as usize - ::consts::KERNEL_OFFSET);
-				//port::insl(iobase + ISA_DATA, &mut buffer);
-				let port=iobase + ISA_DATA;
-				//let buf=&mut buffer;
-				for i in 0..buffer.len(){
-					asm!("insl %dx, (%edi)"
-					:: "{dx}"(port), "{edi}"(&buffer[i])
-					: "edi" : "volatile");
+				cur = match cur
+					{
+					Some( (p, l) ) if p + l == phys && p >> 16 == (phys + len - 1) >> 16 => Some( (p, l + len) ),
+					Some( (p, l) ) => {
+						if n + 1 >= PRDT_ENTRIES {
+							return false;
+						}
+						self.set_prd(n, p, l, false);
+						n += 1;
+						Some( (phys, len) )
+						},
+					None => Some( (phys, len) ),
+					};
+				virt += len;
+			}
+		}
+		match cur
+		{
+		Some( (p, l) ) => { self.set_prd(n, p, l, true); true },
+		None => false,
+		}
+	}
+
+	/// Point the PRDT at the first `bytes` of the bounce buffer
+	fn build_bounce(&self, bytes: usize) {
+		let base = self.bounce.phys().get();
+		let mut ofs = 0;
+		let mut n = 0;
+		while ofs < bytes
+		{
+			let phys = base + ofs;
+			let len = ::core::cmp::min(bytes - ofs, 0x1_0000 - (phys & 0xFFFF));
+			ofs += len;
+			self.set_prd(n, phys, len, ofs == bytes);
+			n += 1;
+		}
+	}
+
+	/// Copy between the caller buffers and the bounce buffer
+	fn copy_bounce(&self, req: &Request, to_bounce: bool) {
+		let mut ofs = 0;
+		for &(buf, count) in req.segments.iter()
+		{
+			let bytes = count * SECTOR_SIZE;
+			let bounce = (self.bounce.base() + ofs) as *mut u8;
+			// SAFE: Both buffers are at least `bytes` long (checked by `prepare`), and distinct
+			unsafe {
+				if to_bounce {
+					::core::ptr::copy_nonoverlapping(buf as *const u8, bounce, bytes);
 				}
-				//println!("insl");
-				for i in 0..4{
-					println!("init:{}",buffer[i]);
+				else {
+					::core::ptr::copy_nonoverlapping(bounce as *const u8, buf as *mut u8, bytes);
 				}
 			}
-			/* device is ok */
-			//ide_devices[ideno].valid = 1;
-
-			/* read identification space of the device */
-			/*let buffer[128];
-			insl(iobase + ISA_DATA, buffer, sizeof(buffer) / sizeof(unsigned int));
-
-			unsigned char *ident = (unsigned char *)buffer;
-			unsigned int sectors;
-			unsigned int cmdsets = *(unsigned int *)(ident + IDE_IDENT_CMDSETS);
-			/* device use 48-bits or 28-bits addressing */
-			if (cmdsets & (1 << 26)) {
-				sectors = *(unsigned int *)(ident + IDE_IDENT_MAX_LBA_EXT);
+			ofs += bytes;
+		}
+	}
+
+	/// Set up the PRDT for `req`, returns None if it can't be transferred by DMA
+	fn prepare(&self, req: &Request) -> Option<DmaMode> {
+		let mode = if self.build_direct(req) {
+				DmaMode::Direct
 			}
-			else {
-				sectors = *(unsigned int *)(ident + IDE_IDENT_MAX_LBA);
+			else if req.count * SECTOR_SIZE <= self.bounce.len() {
+				if req.write {
+					self.copy_bounce(req, true);
+				}
+				self.build_bounce(req.count * SECTOR_SIZE);
+				DmaMode::Bounce
 			}
-			ide_devices[ideno].sets = cmdsets;
-			ide_devices[ideno].size = sectors;
+			else {
+				return None;
+			};
+		// SAFE: Bus-master registers of this channel, the engine is stopped
+		unsafe {
+			self.regs.write_32(self.base + BM_PRDT, self.prdt.phys().get() as u32);
+			// - Clear the error and interrupt bits (write one to clear)
+			self.regs.write_8(self.base + BM_STATUS, BM_STS_ERR | BM_STS_IRQ);
+			self.regs.write_8(self.base + BM_COMMAND, if req.write { 0 } else { BM_CMD_READ });
+		}
+		Some(mode)
+	}
 
-			/* check if supports LBA */
-			assert((*(unsigned short *)(ident + IDE_IDENT_CAPABILITIES) & 0x200) != 0);
+	/// Start the transfer (after the command has been sent to the device)
+	fn start(&self, write: bool) {
+		// SAFE: Bus-master registers of this channel
+		unsafe {
+			self.regs.write_8(self.base + BM_COMMAND, BM_CMD_START | if write { 0 } else { BM_CMD_READ });
+		}
+	}
 
-			unsigned char *model = ide_devices[ideno].model, *data = ident + IDE_IDENT_MODEL;
-			unsigned int i, length = 40;
-			for (i = 0; i < length; i += 2) {
-				model[i] = data[i + 1], model[i + 1] = data[i];
-			}
-			do {
-				model[i] = '\0';
-			} while (i -- > 0 && model[i] == ' ');
+	fn status(&self) -> u8 {
+		// SAFE: Reading the status has no side effects
+		unsafe { self.regs.read_8(self.base + BM_STATUS) }
+	}
 
-			cprintf("ide %d: %10u(sectors), '%s'.\n", ideno, ide_devices[ideno].size, ide_devices[ideno].model);*/
+	/// Stop the engine and acknowledge its status
+	fn stop(&self) {
+		// SAFE: Bus-master registers of this channel
+		unsafe {
+			self.regs.write_8(self.base + BM_COMMAND, 0);
+			self.regs.write_8(self.base + BM_STATUS, BM_STS_ERR | BM_STS_IRQ);
 		}
+	}
+}
 
-		// enable ide interrupt
-		//pic_enable(IRQ_IDE1);
-		//pic_enable(IRQ_IDE2);
+impl Channel
+{
+	fn new(index: usize) -> Channel {
+		Channel {
+			index: index,
+			queue: Mutex::new(ChannelQueue {
+				pending: Vec::new(),
+				active: None,
+				next_ticket: 0,
+				results: VecMap::new(),
+				dma: None,
+				}),
+			completed: WaitQueue::new(),
+		}
 	}
-	fn ide_read_secs<'a>(&'a self, ideno: u8, secno:u64, dst: &'a mut [u32], nsecs:u8) -> Result<usize,storage::IoError> {
-		//assert(nsecs <= MAX_NSECS && VALID_IDE(ideno));
-		//assert(secno < MAX_DISK_NSECS && secno + nsecs <= MAX_DISK_NSECS);
-		let iobase = channels[if ideno >2 {1} else {0}].0;
-		let ioctrl = channels[if ideno >2 {1} else {0}].1;
 
-		//ide_wait_ready(iobase, 0);
+	/// Queue a request and sleep until it completes
+	fn transfer(&self, mut req: Request) -> Result<usize,storage::IoError> {
+		let ticket = ::arch::interrupts::without_interrupts(|| {
+			let mut q = self.queue.lock();
+			let ticket = q.next_ticket;
+			q.next_ticket += 1;
+			req.tickets.push( (ticket, req.count) );
+			let mut req = Some(req);
+			for p in q.pending.iter_mut()
+			{
+				req = match p.try_merge(req.take().unwrap())
+					{
+					Ok(()) => break,
+					Err(r) => Some(r),
+					};
+			}
+			if let Some(req) = req {
+				q.pending.push(req);
+			}
+			ticket
+			});
+		self.kick();
 
-    	self.ide_wait_ready(iobase,0);
+		let is_done = || ::arch::interrupts::without_interrupts(|| self.queue.lock().results.get(&ticket).is_some());
+		if ::arch::interrupts::enabled() {
+			self.completed.wait_while(|| !is_done());
+		}
+		else {
+			// Interrupts are off (e.g. early boot), so poll the controller instead
+			while !is_done() {
+				// SAFE: Port IO on the controller's status register
+				let status = unsafe { port::inb(channels[self.index].0 + ISA_STATUS) };
+				self.service(status);
+				self.kick();
+			}
+		}
+		// - The IRQ handler only finishes requests, so start the next queued one from here
+		self.kick();
+		::arch::interrupts::without_interrupts(|| self.queue.lock().results.remove(&ticket).unwrap())
+	}
 
-		let ret = 0;
-		// generate interrupt
-		unsafe{
-			port::outb(ioctrl + ISA_CTRL, 0);
-			port::outb(iobase + ISA_SECCNT, nsecs);
-			port::outb(iobase + ISA_SECTOR, (secno & 0xFF)as u8);
-			port::outb(iobase + ISA_CYL_LO, ((secno >> 8) & 0xFF)as u8);
-			port::outb(iobase + ISA_CYL_HI, ((secno >> 16) & 0xFF)as u8);
-			port::outb(iobase + ISA_SDH, 0xE0 | ((ideno & 1) << 4) | (((secno >> 24) & 0xF)as u8));
-			//port::outb(iobase + ISA_SDH, (0xE0 | ((ideno & 1) << 4)) as u8);
-			//self.ide_wait_ready(iobase, 0);
-			port::outb(iobase + ISA_COMMAND, IDE_CMD_READ);
-			//self.ide_wait_ready(iobase, 0);
-			// if port::inb(iobase + ISA_STATUS) == 0 || self.ide_wait_ready(iobase, 1) != 0 {
-			// 	println!("error?");
-			// }
-			for i in 0 .. nsecs {
-				//dst = dst + SECTSIZE;
-				let tmp = &mut dst[(i as usize)*SECTOR_SIZE .. ((i+1) as usize)*SECTOR_SIZE];
-				if self.ide_wait_ready(iobase, 1) != 0 {
-					println!("wait ready error");
+	/// Start the next queued request if the channel is idle
+	///
+	/// Called from the submitter/waiter (not the IRQ handler), as starting a command polls the
+	/// controller and PIO writes send their first sector straight away.
+	fn kick(&self) {
+		let failed = ::arch::interrupts::without_interrupts(|| self.start_next(&mut self.queue.lock()));
+		if failed {
+			self.completed.wake_all();
+		}
+	}
+
+	/// Handle a device interrupt (or poll) for the active request
+	fn service(&self, status: u8) {
+		let completed = {
+			let mut q = self.queue.lock();
+			let mut req = match q.active.take()
+				{
+				Some(v) => v,
+				None => return,
+				};
+			let (iobase, ioctrl) = channels[self.index];
+			let result = if let Some(mode) = req.dma {
+					let engine = q.dma.as_ref().expect("BUG: ATA DMA request without an engine");
+					let bm_status = engine.status();
+					if bm_status & BM_STS_ERR != 0 || (status & IDE_BSY == 0 && status & (IDE_ERR | IDE_DF) != 0) {
+						engine.stop();
+						Some( Err(storage::IoError::BadBlock) )
+					}
+					else if status & IDE_BSY != 0 || bm_status & BM_STS_IRQ == 0 {
+						None
+					}
+					else {
+						engine.stop();
+						if mode == DmaMode::Bounce && !req.write {
+							engine.copy_bounce(&req, false);
+						}
+						Some( Ok(req.count) )
+					}
+				}
+				else if status & IDE_BSY != 0 {
+					None
+				}
+				else if status & (IDE_ERR | IDE_DF) != 0 {
+					Some( Err(storage::IoError::BadBlock) )
 				}
-				//self.ide_wait_ready(iobase, 1);
-				//port::insl(iobase, tmp);
-				let port=iobase;
-				//let buf=&mut buffer;
-				for i in 0..tmp.len(){
-					asm!("insl %dx, (%edi)"
-					:: "{dx}"(port), "{edi}"(&tmp[i])
-					: "edi" : "volatile");
+				else if req.write {
+					// The interrupt follows each sector written
+					req.done += 1;
+					if req.done < req.count {
+						req.transfer_sector(iobase);
+						delay_400ns(ioctrl);
+						None
+					}
+					else {
+						Some( Ok(req.count) )
+					}
+				}
+				else if status & IDE_DRQ != 0 {
+					req.transfer_sector(iobase);
+					req.done += 1;
+					delay_400ns(ioctrl);
+					if req.done < req.count { None } else { Some( Ok(req.count) ) }
 				}
-				//println!("read :{}",i);
+				else {
+					None
+				};
+			match result
+			{
+			None => {
+				q.active = Some(req);
+				false
+				},
+			Some(res) => {
+				Self::complete(&mut q, req, res);
+				true
+				},
 			}
+			};
+		if completed {
+			self.completed.wake_all();
 		}
-		Ok(ret)
 	}
 
-	fn ide_write_secs<'a>(&'a self, ideno: u8, secno:u64, src: &'a [u32], nsecs:u8) -> Result<usize,storage::IoError> {
-		//assert(nsecs <= MAX_NSECS && VALID_IDE(ideno));
-		//assert(secno < MAX_DISK_NSECS && secno + nsecs <= MAX_DISK_NSECS);
-		let iobase = channels[if ideno >2 {1} else {0}].0;
-		let ioctrl = channels[if ideno >2 {1} else {0}].1;
+	/// Record the result for all callers of a request
+	fn complete(q: &mut ChannelQueue, req: Request, result: Result<usize,storage::IoError>) {
+		for &(ticket, count) in req.tickets.iter() {
+			q.results.insert(ticket, result.map(|_| count));
+		}
+	}
 
-		//ide_wait_ready(iobase, 0);
+	/// Start the highest priority pending request, if the channel is idle
+	///
+	/// Returns true if any request failed to start (its callers need waking for the error).
+	fn start_next(&self, q: &mut ChannelQueue) -> bool {
+		let mut failed = false;
+		while q.active.is_none()
+		{
+			let i = match (0 .. q.pending.len()).min_by_key(|&i| (q.pending[i].prio, i))
+				{
+				Some(v) => v,
+				None => break,
+				};
+			let mut req = q.pending.remove(i);
+			req.dma = q.dma.as_ref().and_then(|e| e.prepare(&req));
+			match start_command(&req)
+			{
+			Ok(iobase) => {
+				if let Some(_) = req.dma {
+					q.dma.as_ref().unwrap().start(req.write);
+				}
+				else if req.write {
+					// The first sector is sent without waiting for an interrupt
+					if let Err(e) = wait_ready(iobase, true) {
+						Self::complete(q, req, Err(e));
+						failed = true;
+						continue ;
+					}
+					req.transfer_sector(iobase);
+					delay_400ns(channels[self.index].1);
+				}
+				q.active = Some(req);
+				},
+			Err(e) => {
+				Self::complete(q, req, Err(e));
+				failed = true;
+				},
+			}
+		}
+		failed
+	}
+}
 
-    	self.ide_wait_ready(iobase,0);
+/// Select the device and issue a read/write command (DMA or PIO) for a request, returning the IO base
+///
+/// LBA48 commands are used when the transfer needs them (address past 128GiB or more than 255
+/// sectors), LBA28 otherwise as they take fewer port writes.
+fn start_command(req: &Request) -> Result<u16,storage::IoError> {
+	let (ideno, secno, nsecs) = (req.disk, req.lba, req.count);
+	let iobase = channels[(ideno >> 1) as usize].0;
+	let ioctrl = channels[(ideno >> 1) as usize].1;
+	let use_48 = secno + nsecs as u64 > LBA28_LIMIT || nsecs > MAX_NSECS;
+	if use_48 && !req.lba48 {
+		return Err(storage::IoError::BadAddr);
+	}
 
-		let ret = 0;
-		// generate interrupt
-		unsafe{
-			port::outb(ioctrl + ISA_CTRL, 0);
-			port::outb(iobase + ISA_SECCNT, nsecs);
+	try!(wait_ready(iobase, false));
+
+	// generate interrupt
+	unsafe{
+		port::outb(ioctrl + ISA_CTRL, 0);
+		if use_48 {
+			// High bytes first, then the low bytes (the registers are two-deep FIFOs)
+			port::outb(iobase + ISA_SECCNT, (nsecs >> 8) as u8);
+			port::outb(iobase + ISA_SECTOR, (secno >> 24) as u8);
+			port::outb(iobase + ISA_CYL_LO, (secno >> 32) as u8);
+			port::outb(iobase + ISA_CYL_HI, (secno >> 40) as u8);
+			port::outb(iobase + ISA_SECCNT, nsecs as u8);
+			port::outb(iobase + ISA_SECTOR, secno as u8);
+			port::outb(iobase + ISA_CYL_LO, (secno >> 8) as u8);
+			port::outb(iobase + ISA_CYL_HI, (secno >> 16) as u8);
+			port::outb(iobase + ISA_SDH, 0x40 | ((ideno & 1) << 4));
+			port::outb(iobase + ISA_COMMAND, match (req.dma.is_some(), req.write)
+				{
+				(true, true) => HDD_DMA_W48,
+				(true, false) => HDD_DMA_R48,
+				(false, true) => HDD_PIO_W48,
+				(false, false) => HDD_PIO_R48,
+				});
+		}
+		else {
+			port::outb(iobase + ISA_SECCNT, nsecs as u8);
 			port::outb(iobase + ISA_SECTOR, (secno & 0xFF)as u8);
 			port::outb(iobase + ISA_CYL_LO, ((secno >> 8) & 0xFF)as u8);
 			port::outb(iobase + ISA_CYL_HI, ((secno >> 16) & 0xFF)as u8);
 			port::outb(iobase + ISA_SDH, 0xE0 | ((ideno & 1) << 4) | (((secno >> 24) & 0xF)as u8));
-			port::outb(iobase + ISA_COMMAND, IDE_CMD_WRITE);
-			//println!("{}",nsecs);
-			for i in 0 .. nsecs {
-				//dst = dst + SECTSIZE;
-				// if ((ret = ide_wait_ready(iobase, 1)) != 0) {
-				// 	goto out;
-				// }
-				//port::insb(iobase, dst);
-				//println!("i={}",i);
-				let tmp = &src[(i as usize)*SECTOR_SIZE .. ((i+1) as usize)*SECTOR_SIZE];
-				if self.ide_wait_ready(iobase, 1) != 0 {
-					println!("wait ready error");
-				}
-				//println!("write {}:{}",i,src[i as usize]);
-				//println!("outsl");
-				//port::outsl(iobase, tmp);
-				let port=iobase;
-				//let buf=&mut buffer;
-				for i in 0..tmp.len(){
-					asm!("outsl (%esi), %dx"
-        			:: "{dx}"(port), "{esi}"(&tmp[i])
-        			: "edi");
-				}
-				//println!("write :{}",i);
-				// for i in 0..4 {
-				//  	println!("{}",src[i as usize]);
-				// }
-        		//port::outb(iobase, src[i as usize]);
-			}
+			port::outb(iobase + ISA_COMMAND, match (req.dma.is_some(), req.write)
+				{
+				(true, true) => HDD_DMA_W28,
+				(true, false) => HDD_DMA_R28,
+				(false, true) => IDE_CMD_WRITE,
+				(false, false) => IDE_CMD_READ,
+				});
 		}
-		Ok(ret)
 	}
+	delay_400ns(ioctrl);
+	Ok(iobase)
 }
@@ -10,9 +10,9 @@
 //extern crate storage_scsi;
 
 use ::prelude::*;
-use ::mylib::mem::Arc;
+use ::mylib::LazyStatic;
 
-//use ::device_manager;
+use ::device_manager::{self,pci,IOBinding};
 use ::metadevs::storage;
 //use ::async;
 
@@ -20,6 +20,7 @@ use ::metadevs::storage;
 
 //mod drivers;
 pub mod io;
+pub mod ahci;
 
 //pub mod volume;
 
@@ -40,11 +41,11 @@ struct AtapiVolume
 	disk: u8,
 	controller: io::DmaController,
 }
+*/
 
 /// Initial controller handle, owns all volumes and the first controller handle
-struct ControllerRoot
+pub struct ControllerRoot
 {
-	_controller: Arc<io::DmaController>,
 	_volumes: Vec<storage::PhysicalVolumeReg>,
 }
 
@@ -58,6 +59,55 @@ pub enum AtaClass
 }
 impl Default for AtaClass { fn default() -> AtaClass { AtaClass::Invalid } }
 
+static mut S_CONTROLLER: LazyStatic<ControllerRoot> = lazystatic_init!();
+
+/// PCI IDE controller driver, provides the bus-master registers used for DMA
+struct PciIdeDriver;
+struct PciIdeInstance;
+impl device_manager::DriverInstance for PciIdeInstance {}
+
+static S_PCI_DRIVER: PciIdeDriver = PciIdeDriver;
+/// Mass storage, IDE (any programming interface)
+static S_PCI_MATCHES: [pci::Match; 1] = [pci::Match::class(0x01_01_00, 0xFF_FF_00)];
+
+impl device_manager::Driver for PciIdeDriver
+{
+	fn name(&self) -> &str { "ata-pci" }
+	fn matches(&self) -> &[pci::Match] { &S_PCI_MATCHES }
+	fn bind(&self, dev: &pci::Device) -> Option<Box<device_manager::DriverInstance>> {
+		// - This driver only knows the legacy ports, so the channels must be in compatibility mode
+		if dev.class & 0x05 != 0 {
+			println!("log: ATA: Controller {} is in native mode, not using it", dev.addr);
+			return None;
+		}
+		let regs = match dev.map_bar(4)
+			{
+			Some(v) if dev.class & 0x80 != 0 && v.len() >= 16 => v,
+			_ => {
+				println!("log: ATA: Controller {} has no bus-master registers, using PIO", dev.addr);
+				return None;
+				},
+			};
+		let is_io = match regs { IOBinding::IO(..) => true, _ => false };
+		dev.enable(is_io, !is_io, true);
+		io::attach_busmaster(regs);
+		Some(Box::new(PciIdeInstance))
+	}
+}
+
+/// Probe the legacy IDE controller and AHCI HBAs, and register their disks as physical volumes
+///
+/// Bus-master DMA is used if a PCI IDE controller is found, PIO otherwise.
+pub fn init()
+{
+	device_manager::register_driver(&S_PCI_DRIVER);
+	device_manager::register_driver(&ahci::S_DRIVER);
+	// SAFE: Called once during single-threaded startup
+	unsafe {
+		S_CONTROLLER.prep( || ControllerRoot::new() );
+	}
+}
+
 /// ATA "IDENTIFY" packet data
 #[repr(C)]	// All non-u16 values are aligned.
 pub struct AtaIdentifyData
@@ -79,7 +129,15 @@ pub struct AtaIdentifyData
 	pub size_of_rw_multiple: u16,
 	/// LBA 28 sector count (if zero, use 48)
 	pub sector_count_28: u32,
-	_unused6: [u16; 100-62],
+	_unused6: [u16; 75-62],
+	/// Maximum NCQ queue depth - 1 (bits 0:4)
+	pub queue_depth: u16,
+	/// SATA capabilities (bit 8 = NCQ)
+	pub sata_capabilities: u16,
+	_unused6a: [u16; 83-77],
+	/// Command sets supported (bit 10 = LBA48)
+	pub command_sets_2: u16,
+	_unused6b: [u16; 100-84],
 	/// LBA 48 sector count
 	pub sector_count_48: u64,
 	_unused7: [u16; 2],
@@ -116,51 +174,131 @@ impl ::core::fmt::Debug for AtaIdentifyData {
 	}
 }
 
-impl AtaVolume
+impl AtaIdentifyData
 {
-	fn new_boxed(dma_controller: io::DmaController, disk: u8, sectors: u64) -> Box<AtaVolume>
+	/// Model number (trailing padding removed)
+	pub fn model(&self) -> String {
+		ata_string(&self.model_number)
+	}
+	/// Serial number (trailing padding removed)
+	pub fn serial(&self) -> String {
+		ata_string(&self.serial_number)
+	}
+	/// Number of addressable sectors (the LBA48 count if the device supports it)
+	pub fn sector_count(&self) -> u64 {
+		if self.supports_lba48() && self.sector_count_48 != 0 { self.sector_count_48 } else { self.sector_count_28 as u64 }
+	}
+	/// Device supports the LBA48 (EXT) commands
+	pub fn supports_lba48(&self) -> bool {
+		self.command_sets_2 & (1 << 10) != 0
+	}
+	/// Device supports native command queueing (SATA only)
+	pub fn supports_ncq(&self) -> bool {
+		self.sata_capabilities != 0xFFFF && self.sata_capabilities & (1 << 8) != 0
+	}
+	/// Number of NCQ tags the device accepts
+	pub fn ncq_depth(&self) -> usize {
+		(self.queue_depth & 0x1F) as usize + 1
+	}
+	/// Device supports LBA addressing (required by this driver)
+	pub fn supports_lba(&self) -> bool {
+		self.capabilities[0] & 0x200 != 0
+	}
+}
+
+/// Decode an IDENTIFY string (byte pairs are swapped, padded with spaces)
+fn ata_string(raw: &[u8]) -> String
+{
+	let mut rv = String::with_capacity(raw.len());
+	for pair in raw.chunks(2)
 	{
-		Box::new( AtaVolume {
-			name: format!("{}-{}", dma_controller.name, disk),
-			disk: disk,
-			controller: dma_controller,
-			size: sectors,
-			} )
+		for &b in pair.iter().rev() {
+			rv.push( if b == b' ' || b.is_ascii_graphic() { b as char } else { '?' } );
+		}
 	}
+	let len = rv.trim_right().len();
+	rv.truncate(len);
+	rv
 }
-*/
+
 impl AtaVolume
 {
 	pub fn new(sname:String, disk: u8,size:u64) -> Self {
 		AtaVolume {
 			name: sname.clone(),
 			disk: disk,
-			controller: io::DmaController{name: sname.clone()},
+			controller: io::DmaController{name: sname.clone(), ..Default::default()},
 			size:size,
 		}
 	}
+	/// Create a volume for `disk` from its IDENTIFY data
+	pub fn from_identify(sname: String, disk: u8, ident: &AtaIdentifyData) -> Self {
+		let mut rv = AtaVolume::new(sname, disk, ident.sector_count());
+		rv.controller.lba48[disk as usize] = ident.supports_lba48();
+		rv
+	}
+	/// Create a volume for `disk`, with the size reported by IDENTIFY (zero if there's no disk)
+	pub fn open(sname: String, disk: u8) -> Self {
+		match io::DmaController::default().ide_identify(disk)
+		{
+		(AtaClass::Native, ref ident) if ident.supports_lba() => AtaVolume::from_identify(sname, disk, ident),
+		_ => {
+			println!("warning: ATA{}: No usable disk for '{}'", disk, sname);
+			AtaVolume::new(sname, disk, 0)
+			},
+		}
+	}
+	
+	/// Read `num` sectors, split into as many commands as needed
+	pub fn read<'a>(&'a self, prio: u8, idx: u64, num: usize, dst: &'a mut [u32]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!( dst.len(), num * io::SECTOR_WORDS );
+		let mut done = 0;
+		while done < num
+		{
+			let buf = &mut dst[done * io::SECTOR_WORDS ..];
+			done += try!(self.controller.do_dma_rd(prio, idx + done as u64, num - done, buf, self.disk));
+		}
+		Ok(num)
+	}
+	/// Write `num` sectors, split into as many commands as needed
+	pub fn write<'a>(&'a self, prio: u8, idx: u64, num: usize, src: &'a [u32]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!( src.len(), num * io::SECTOR_WORDS );
+		//let ctrlr = &self.controller;
+		let mut done = 0;
+		while done < num
+		{
+			let buf = &src[done * io::SECTOR_WORDS ..];
+			done += try!(self.controller.do_dma_wr(prio, idx + done as u64, num - done, buf, self.disk));
+		}
+		Ok(num)
+	}
+}
+
+impl storage::PhysicalVolume for AtaVolume
+{
 	fn name(&self) -> &str { &*self.name }
 	fn blocksize(&self) -> usize { io::SECTOR_SIZE }
 	fn capacity(&self) -> Option<u64> { Some(self.size) }
-	pub fn init(&self) { self.controller.ide_init(); }
-	pub fn init2(&mut self,sname:String, disk: u8,size:u64) {
-		self.name= sname.clone();
-		self.disk= disk;
-		self.controller= io::DmaController{name: sname.clone()};
-		self.size=size;
-		self.init();
-	}
 	
-	pub fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u32]) -> Result<usize,storage::IoError>
+	fn read<'a>(&'a self, prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,storage::IoError>
 	{
-		assert_eq!( dst.len(), num * io::SECTOR_SIZE );
-		self.controller.do_dma_rd(idx, num, dst, self.disk)
+		let mut words = vec![0u32; count * io::SECTOR_WORDS];
+		try!(AtaVolume::read(self, prio, blockidx, count, &mut words));
+		for (d, w) in dst.chunks_mut(4).zip(words.iter()) {
+			for (i, b) in d.iter_mut().enumerate() {
+				*b = (*w >> (i * 8)) as u8;
+			}
+		}
+		Ok(count)
 	}
-	pub fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u32]) -> Result<usize,storage::IoError>
+	fn write<'a>(&'a self, prio: u8, blockidx: u64, count: usize, src: &'a [u8]) -> Result<usize,storage::IoError>
 	{
-		assert_eq!( src.len(), num * io::SECTOR_SIZE );
-		//let ctrlr = &self.controller;
-		self.controller.do_dma_wr(idx, num, src, self.disk)
+		let words: Vec<u32> = src.chunks(4)
+			.map(|c| c.iter().enumerate().fold(0, |w, (i, &b)| w | (b as u32) << (i * 8)))
+			.collect();
+		AtaVolume::write(self, prio, blockidx, count, &words)
 	}
 	
 	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> Result<(),storage::IoError>
@@ -169,133 +307,48 @@ impl AtaVolume
 		//Box::new(async::NullResultWaiter::new( || Ok( () ) ))
 		Ok(())
 	}
-	
-}
-/*
-impl AtapiVolume
-{
-	fn new(dma_controller: Arc<io::DmaController>, disk: u8) -> Self {
-		AtapiVolume {
-			name: format!("{}-{}", dma_controller.name, disk),
-			controller: dma_controller,
-			disk: disk,
-		}
-	}
 }
-impl storage_scsi::ScsiInterface for AtapiVolume
-{
-	fn name(&self) -> &str {
-		&self.name
-	}
-	fn send<'a>(&'a self, command: &[u8], data: &'a [u8]) -> storage::AsyncIoResult<'a,()> {
-		self.controller.do_atapi_wr(self.disk, command, data)
-	}
-	fn recv<'a>(&'a self, command: &[u8], data: &'a mut [u8]) -> storage::AsyncIoResult<'a,()>  {
-		println!("debug: - command=[{:?}]", command);
-		match command[0] & 0xE0
-		{
-		0x00 => assert_eq!(command.len(), 6),
-		0x20 => assert_eq!(command.len(), 10),
-		0x40 => assert_eq!(command.len(), 10),
-		0xA0 => assert_eq!(command.len(), 12),
-		0x80 => assert_eq!(command.len(), 16),
-		_ => {},
-		}
-		self.controller.do_atapi_rd(self.disk, command, dax86ta)
-	}
-}
-
 impl ControllerRoot
 {
-	fn new(ata_pri: u16, sts_pri: u16, irq_pri: u32,  ata_sec: u16, sts_sec: u16, irq_sec: u32,  bm: device_manager::IOBinding) -> ControllerRoot
+	fn new() -> ControllerRoot
 	{
-		println!("debug: ControllerRoot::new( {:#x}, {:#x}, {},  {:#x}, {:#x}, {},  {:?}",
-			ata_pri, sts_pri, irq_pri,
-			ata_sec, sts_sec, irq_sec,
-			bm
-			);
-		let dma_controller = Arc::new(io::DmaController {
-			name: if ata_pri == 0x1F0 {
-					String::from("ATA")
-				} else {
-					format!("ATA{:x}", ata_pri)
-				},
-			ata_controllers: [
-				io::AtaController::new(ata_pri, sts_pri, irq_pri),
-				io::AtaController::new(ata_sec, sts_sec, irq_sec),
-				],
-			dma_base: bm,
-			});
+		let dma_controller = io::DmaController { name: String::from("ATA"), ..Default::default() };
 		let mut volumes = Vec::new();
 		
 		// Send IDENTIFY to all disks
-		for i in 0 .. 2
+		// (ugly) Handle the relevant disk types, creating devices
+		for (disk, class, ident) in dma_controller.ide_init()
 		{
-			let ctrlr_pri = &dma_controller.ata_controllers[0];
-			let ctrlr_sec = &dma_controller.ata_controllers[1];
-			
-			// Create output data (defaulted, but should be written by the output function)
-			let (mut identify_pri, mut type_pri) = Default::default();
-			let (mut identify_sec, mut type_sec) = Default::default();
-			
-			// Perform IDENTIFY requests, both controllers in pararllel
-			// TODO: Include a timeout to prevent a misbehaving controller from halting the system.
+			match class
 			{
-				use ::async::Waiter;
-				
-				let mut wh_pri = ctrlr_pri.ata_identify(i, &mut identify_pri, &mut type_pri);
-				let mut wh_sec = ctrlr_sec.ata_identify(i, &mut identify_sec, &mut type_sec);
-				//let mut wh_timer = ::async::timer::Waiter::new(2*1000);
-				
-				// Loop until timer fires, or both disks have read
-				while /* !wh_timer.is_complete() &&*/ !(wh_pri.is_complete() && wh_sec.is_complete())
-				{
-					::async::wait_on_list(&mut [&mut wh_pri, &mut wh_sec/*, &mut wh_timer*/], None);
-				}
-			}
-			
-			// (ugly) Handle the relevant disk types, creating devices
-			let devs = [
-				(i, type_pri, identify_pri),
-				(2+i, type_sec, identify_sec)
-				];
-			for &(disk, ref class, ref ident) in devs.iter()
-			{
-				match *class
-				{
-				AtaClass::Invalid => {
-					println!("log: ATA{}: Timeout", disk);
-					},
-				AtaClass::None => {
-					println!("log: ATA{}: No disk", disk);
-					},
-				AtaClass::Native => {
-					let sectors = if ident.sector_count_48 == 0 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
-					println!("log: ATA{}: Hard Disk, {} sectors, {}", disk, sectors, storage::SizePrinter(sectors * io::SECTOR_SIZE as u64));
-					volumes.push( storage::register_pv( AtaVolume::new_boxed(dma_controller.clone(), disk, sectors) ) );
-					},
-				AtaClass::ATAPI => {
-					println!("log: ATA{}: ATAPI", disk);
-					match storage_scsi::Volume::new_boxed( AtapiVolume::new(dma_controller.clone(), disk) )
-					{
-					Ok(scsi_vol) => volumes.push( storage::register_pv( scsi_vol ) ),
-					Err(e) => println!("error: ATA{}: Error while creating SCSI device: {:?}", disk, e),
-					}
-					},
-				AtaClass::Unknown(r4, r5) => {
-					println!("warning: ATA{}: Unknown type response ({:#x}, {:#x})", disk, r4, r5);
-					},
+			AtaClass::Invalid => {
+				println!("log: ATA{}: Timeout", disk);
+				},
+			AtaClass::None => {
+				println!("log: ATA{}: No disk", disk);
+				},
+			AtaClass::Native => {
+				let sectors = ident.sector_count();
+				if ! ident.supports_lba() {
+					println!("warning: ATA{}: '{}' doesn't support LBA, ignoring", disk, ident.model());
+					continue ;
 				}
+				println!("log: ATA{}: Hard Disk '{}' (serial '{}'), {} sectors, {}", disk, ident.model(), ident.serial(),
+					sectors, storage::SizePrinter(sectors * io::SECTOR_SIZE as u64));
+				let vol = AtaVolume::from_identify(format!("ATA{}", disk), disk, &ident);
+				volumes.push( storage::register_pv( Box::new(vol) ) );
+				},
+			AtaClass::ATAPI => {
+				// TODO: Needs a SCSI layer (see AtapiVolume)
+				println!("log: ATA{}: ATAPI, not supported", disk);
+				},
+			AtaClass::Unknown(r4, r5) => {
+				println!("warning: ATA{}: Unknown type response ({:#x}, {:#x})", disk, r4, r5);
+				},
 			}
 		}
 		
 		// Return a controller handle, holding on to all handles
-		ControllerRoot { _controller: dma_controller, _volumes: volumes, }
+		ControllerRoot { _volumes: volumes, }
 	}
 }
-
-impl device_manager::DriverInstance for ControllerRoot
-{
-	// Just a marker trait
-}
-*/
\ No newline at end of file
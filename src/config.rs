@@ -88,6 +88,10 @@ def_config_set! {
 		Loader @ "LOADER" = "/sysroot/bin/loader",
 //		/// Startup - Init executable (first userland process)
 		Init @ "INIT" = "/sysroot/bin/init",
+//		/// Storage - Multi-volume sets to assemble (see metadevs::raid)
+		Raid @ "RAID" = "",
+//		/// Storage - Sizes (KiB, comma separated) of RAM disks to create
+		RamDisk @ "RAMDISK" = "",
 	}
 }
 
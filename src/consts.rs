@@ -27,6 +27,12 @@ pub const MAX_CPU_NUM: usize = 8;
     /// Size of kernel heap
     pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
+    /// Offset to the kernel frame mapping window (see `memory::kmap`)
+    /// Lives in the heap PML4 so every page table created from the kernel one shares it
+    pub const KERNEL_KMAP_OFFSET: usize = KERNEL_HEAP_OFFSET + PML4_SIZE / 2;
+    /// Size of the kernel frame mapping window
+    pub const KERNEL_KMAP_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+
     /// Offset to kernel percpu variables
     //TODO: Use 64-bit fs offset to enable this pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
     pub const KERNEL_PERCPU_OFFSET: usize = 0xC000_0000;
@@ -120,6 +126,7 @@ pub mod irq {
     pub const T_SWITCH_TOU : u8 = 120;  // user/kernel switch
     pub const T_SWITCH_TOK : u8 = 121;  // user/kernel switch
     pub const T_FORK       : u8 = 122;  // user/kernel switch
+    pub const T_YIELD      : u8 = 123;  // give up the CPU (kernel threads)
 
 	pub const IRQ_TIMER    : u8 =  0;
 	pub const IRQ_KBD      : u8 =  1;
@@ -0,0 +1,218 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/device_manager/mod.rs
+//! Device to driver mapping manager
+//!
+//! Buses (currently only PCI) report devices here, drivers register the devices they handle, and
+//! the manager binds each device to the best matching driver. Binding works in either order, so
+//! drivers can register before or after enumeration.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use mylib::LazyStatic;
+use memory::kmap::KernelMapping;
+use x86_64::instructions::port;
+
+pub mod pci;
+
+/// A bound driver instance (dropping it unbinds the driver)
+pub trait DriverInstance: Send
+{
+}
+
+/// A device driver
+pub trait Driver: Send + Sync
+{
+	/// Driver name (for logging)
+	fn name(&self) -> &str;
+	/// Devices this driver may handle
+	fn matches(&self) -> &[pci::Match];
+	/// Bind to a matching device, returning None if the device can't be used after all
+	fn bind(&self, dev: &pci::Device) -> Option<Box<DriverInstance>>;
+}
+
+/// IO or memory region used by a device
+pub enum IOBinding
+{
+	/// IO ports (base, count)
+	IO(u16, u16),
+	/// Memory-mapped registers (mapping, offset of the region within it, size)
+	Memory(KernelMapping, usize, usize),
+}
+
+/// A device and the driver bound to it
+struct DeviceSlot
+{
+	dev: pci::Device,
+	instance: Option<(&'static Driver, Box<DriverInstance>)>,
+}
+struct Manager
+{
+	drivers: Vec<&'static Driver>,
+	devices: Vec<DeviceSlot>,
+}
+
+static mut S_MANAGER: LazyStatic<Mutex<Manager>> = lazystatic_init!();
+
+fn manager() -> &'static Mutex<Manager>
+{
+	// SAFE: Prepared by `init` before any other use
+	unsafe { &S_MANAGER }
+}
+
+/// Enumerate the PCI bus and record the devices found
+pub fn init()
+{
+	// SAFE: Called once during startup
+	unsafe {
+		S_MANAGER.prep(|| Mutex::new(Manager { drivers: Vec::new(), devices: Vec::new() }));
+	}
+	pci::init();
+	let devs = pci::enumerate();
+	println!("log: PCI: {} devices", devs.len());
+	for dev in devs
+	{
+		println!("log: PCI {}: {:04x}:{:04x} class {:06x}", dev.addr, dev.vendor, dev.device, dev.class);
+		manager().lock().devices.push(DeviceSlot { dev: dev, instance: None });
+	}
+	bind_unbound();
+}
+
+/// Register a driver, binding it to any matching devices without a driver
+pub fn register_driver(driver: &'static Driver)
+{
+	manager().lock().drivers.push(driver);
+	bind_unbound();
+}
+
+/// Returns the devices matching `m` (e.g. for a driver that needs to find a companion device)
+pub fn find_devices(m: &pci::Match) -> Vec<pci::Device>
+{
+	manager().lock().devices.iter().filter(|s| m.matches(&s.dev)).map(|s| s.dev.clone()).collect()
+}
+
+/// Try to bind every device without a driver
+///
+/// The lock is released while calling `Driver::bind` (drivers may call back into the manager).
+/// If several drivers match a device, the one with the most specific match wins.
+fn bind_unbound()
+{
+	let mut idx = 0;
+	loop
+	{
+		let (dev, driver) = {
+			let lh = manager().lock();
+			if idx >= lh.devices.len() {
+				break ;
+			}
+			let slot = &lh.devices[idx];
+			idx += 1;
+			if slot.instance.is_some() {
+				continue ;
+			}
+			let best = lh.drivers.iter()
+				.filter_map(|&d| d.matches().iter().filter(|m| m.matches(&slot.dev)).map(|m| m.specificity()).max().map(|s| (s, d)))
+				.max_by_key(|&(s, _)| s);
+			match best
+			{
+			Some( (_, d) ) => (slot.dev.clone(), d),
+			None => continue,
+			}
+			};
+		match driver.bind(&dev)
+		{
+		Some(inst) => {
+			println!("log: PCI {}: Bound to '{}'", dev.addr, driver.name());
+			manager().lock().devices[idx - 1].instance = Some( (driver, inst) );
+			},
+		None => println!("log: PCI {}: '{}' declined the device", dev.addr, driver.name()),
+		}
+	}
+}
+
+impl IOBinding
+{
+	/// Size of the region in bytes
+	pub fn len(&self) -> usize {
+		match *self
+		{
+		IOBinding::IO(_, n) => n as usize,
+		IOBinding::Memory(_, _, n) => n,
+		}
+	}
+	fn mem_ptr(&self, ofs: usize, size: usize) -> usize {
+		match *self
+		{
+		IOBinding::Memory(ref m, base, len) => {
+			assert!(ofs + size <= len, "IOBinding access {:#x}+{} out of range ({:#x})", ofs, size, len);
+			m.base() + base + ofs
+			},
+		_ => unreachable!(),
+		}
+	}
+	fn io_port(&self, ofs: usize, size: usize) -> u16 {
+		match *self
+		{
+		IOBinding::IO(base, len) => {
+			assert!(ofs + size <= len as usize, "IOBinding access {:#x}+{} out of range ({:#x})", ofs, size, len);
+			base + ofs as u16
+			},
+		_ => unreachable!(),
+		}
+	}
+
+	pub unsafe fn read_8(&self, ofs: usize) -> u8 {
+		match *self
+		{
+		IOBinding::IO(..) => port::inb(self.io_port(ofs, 1)),
+		IOBinding::Memory(..) => ::core::ptr::read_volatile(self.mem_ptr(ofs, 1) as *const u8),
+		}
+	}
+	pub unsafe fn read_16(&self, ofs: usize) -> u16 {
+		match *self
+		{
+		IOBinding::IO(..) => port::inw(self.io_port(ofs, 2)),
+		IOBinding::Memory(..) => ::core::ptr::read_volatile(self.mem_ptr(ofs, 2) as *const u16),
+		}
+	}
+	pub unsafe fn read_32(&self, ofs: usize) -> u32 {
+		match *self
+		{
+		IOBinding::IO(..) => port::inl(self.io_port(ofs, 4)),
+		IOBinding::Memory(..) => ::core::ptr::read_volatile(self.mem_ptr(ofs, 4) as *const u32),
+		}
+	}
+	pub unsafe fn write_8(&self, ofs: usize, val: u8) {
+		match *self
+		{
+		IOBinding::IO(..) => port::outb(self.io_port(ofs, 1), val),
+		IOBinding::Memory(..) => ::core::ptr::write_volatile(self.mem_ptr(ofs, 1) as *mut u8, val),
+		}
+	}
+	pub unsafe fn write_16(&self, ofs: usize, val: u16) {
+		match *self
+		{
+		IOBinding::IO(..) => port::outw(self.io_port(ofs, 2), val),
+		IOBinding::Memory(..) => ::core::ptr::write_volatile(self.mem_ptr(ofs, 2) as *mut u16, val),
+		}
+	}
+	pub unsafe fn write_32(&self, ofs: usize, val: u32) {
+		match *self
+		{
+		IOBinding::IO(..) => port::outl(self.io_port(ofs, 4), val),
+		IOBinding::Memory(..) => ::core::ptr::write_volatile(self.mem_ptr(ofs, 4) as *mut u32, val),
+		}
+	}
+}
+
+impl ::core::fmt::Debug for IOBinding
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		match *self
+		{
+		IOBinding::IO(base, len) => write!(f, "IO({:#x}+{:#x})", base, len),
+		IOBinding::Memory(ref m, ofs, len) => write!(f, "Memory({:#x}+{:#x})", m.phys() as usize + ofs, len),
+		}
+	}
+}
@@ -0,0 +1,514 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/device_manager/pci.rs
+//! PCI bus enumeration and configuration space access
+//!
+//! Configuration space is accessed through the ECAM window described by the ACPI MCFG table when
+//! there is one (segment 0 only), otherwise through the legacy 0xCF8/0xCFC ports.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use x86_64::instructions::port;
+use arch::driver::acpi;
+use memory::{Frame,EntryFlags,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use super::IOBinding;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const MAX_DEV: u8 = 32;
+const MAX_FUNC: u8 = 8;
+
+// Configuration space registers
+const REG_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER: u16 = 0x0C;
+const REG_BAR0: u16 = 0x10;
+const REG_SECONDARY_BUS: u16 = 0x19;
+const REG_CAP_PTR: u16 = 0x34;
+const REG_INTERRUPT: u16 = 0x3C;
+
+const CMD_IO: u16 = 1 << 0;
+const CMD_MEMORY: u16 = 1 << 1;
+const CMD_BUS_MASTER: u16 = 1 << 2;
+const CMD_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAP_LIST: u16 = 1 << 4;
+
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const CLASS_BRIDGE_PCI: u32 = 0x0604;
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// MSI message address targeting the boot processor's local APIC
+const MSI_ADDRESS: u32 = 0xFEE0_0000;
+
+/// ECAM window for segment 0 (physical base, first bus, last bus)
+static S_ECAM: Mutex<Option<(usize, u8, u8)>> = Mutex::new(None);
+
+/// Location of a function on the bus
+#[derive(Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Address
+{
+	pub bus: u8,
+	pub dev: u8,
+	pub func: u8,
+}
+impl_fmt! {
+	Display(self, f) for Address {
+		write!(f, "{:02x}:{:02x}.{}", self.bus, self.dev, self.func)
+	}
+}
+
+/// A decoded base address register
+#[derive(Copy,Clone,Debug)]
+pub enum Bar
+{
+	None,
+	Io { base: u16, size: u16 },
+	Memory { base: u64, size: u64, prefetchable: bool, is_64: bool },
+}
+
+/// A PCI function and its configuration (as read during enumeration)
+#[derive(Clone,Debug)]
+pub struct Device
+{
+	pub addr: Address,
+	pub vendor: u16,
+	pub device: u16,
+	/// Class code, subclass and programming interface (`0xCCSSPP`)
+	pub class: u32,
+	pub revision: u8,
+	pub header_type: u8,
+	/// Legacy interrupt line (as set up by the firmware) and pin (0 = none)
+	pub irq_line: u8,
+	pub irq_pin: u8,
+	pub bars: [Bar; 6],
+}
+
+/// A driver's device match
+#[derive(Copy,Clone,Debug)]
+pub struct Match
+{
+	/// Vendor and device ID, `ANY_ID` matches all
+	pub vendor: u16,
+	pub device: u16,
+	/// Class code compared after masking with `class_mask`
+	pub class: u32,
+	pub class_mask: u32,
+}
+pub const ANY_ID: u16 = 0xFFFF;
+
+impl Match
+{
+	/// Match a specific vendor/device pair
+	pub const fn device(vendor: u16, device: u16) -> Match {
+		Match { vendor: vendor, device: device, class: 0, class_mask: 0 }
+	}
+	/// Match a class code (`mask` selects which of class/subclass/interface to compare)
+	pub const fn class(class: u32, mask: u32) -> Match {
+		Match { vendor: ANY_ID, device: ANY_ID, class: class, class_mask: mask }
+	}
+
+	pub fn matches(&self, dev: &Device) -> bool {
+		(self.vendor == ANY_ID || self.vendor == dev.vendor)
+			&& (self.device == ANY_ID || self.device == dev.device)
+			&& dev.class & self.class_mask == self.class & self.class_mask
+	}
+	/// Ranking used when several drivers match (exact IDs beat class matches)
+	pub fn specificity(&self) -> u32 {
+		let mut rv = self.class_mask.count_ones();
+		if self.vendor != ANY_ID {
+			rv += 32;
+		}
+		if self.device != ANY_ID {
+			rv += 32;
+		}
+		rv
+	}
+}
+
+/// Locate the ECAM window from the MCFG table
+pub fn init()
+{
+	let sdts = acpi::find_sdt("MCFG");
+	let data = match sdts.first()
+		{
+		Some(sdt) => sdt.data(),
+		None => {
+			println!("log: PCI: No MCFG table, using port IO");
+			return ;
+			},
+		};
+	// - 8 reserved bytes, then 16-byte allocation entries
+	for ent in data[::core::cmp::min(8, data.len())..].chunks(16).filter(|e| e.len() == 16)
+	{
+		let base = (0 .. 8).fold(0u64, |acc, i| acc | (ent[i] as u64) << (i * 8)) as usize;
+		let segment = ent[8] as u16 | (ent[9] as u16) << 8;
+		let (start_bus, end_bus) = (ent[10], ent[11]);
+		if segment != 0 {
+			println!("log: PCI: Ignoring ECAM for segment {}", segment);
+			continue ;
+		}
+		println!("log: PCI: ECAM at {:#x} (buses {}-{})", base, start_bus, end_bus);
+		*S_ECAM.lock() = Some( (base, start_bus, end_bus) );
+		break ;
+	}
+}
+
+/// Map the ECAM page for `addr`, if it's covered by the window
+fn ecam_page(addr: Address) -> Option<KernelMapping>
+{
+	let (base, start, end) = match *S_ECAM.lock()
+		{
+		Some(v) => v,
+		None => return None,
+		};
+	if addr.bus < start || addr.bus > end {
+		return None;
+	}
+	let ofs = ((addr.bus - start) as usize) << 20 | (addr.dev as usize) << 15 | (addr.func as usize) << 12;
+	// Config space accesses are rare (enumeration and driver setup), so the page isn't kept mapped
+	kmap::map_frames(Frame::containing_address(base + ofs), 1, EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE)
+}
+
+fn port_address(addr: Address, ofs: u16) -> u32
+{
+	0x8000_0000 | (addr.bus as u32) << 16 | (addr.dev as u32) << 11 | (addr.func as u32) << 8 | (ofs as u32 & 0xFC)
+}
+
+/// Read a 32-bit configuration register (`ofs` is rounded down to a multiple of 4)
+pub fn read_config(addr: Address, ofs: u16) -> u32
+{
+	let ofs = ofs & !3;
+	match ecam_page(addr)
+	{
+	// SAFE: Aligned read within the mapped page
+	Some(m) => unsafe { ::core::ptr::read_volatile( (m.base() + ofs as usize) as *const u32 ) },
+	None if ofs < 0x100 => ::arch::interrupts::without_interrupts(|| unsafe {
+		// SAFE: The address/data pair is only used with interrupts disabled
+		port::outl(CONFIG_ADDRESS, port_address(addr, ofs));
+		port::inl(CONFIG_DATA)
+		}),
+	None => !0,
+	}
+}
+/// Write a 32-bit configuration register
+pub fn write_config(addr: Address, ofs: u16, val: u32)
+{
+	let ofs = ofs & !3;
+	match ecam_page(addr)
+	{
+	// SAFE: Aligned write within the mapped page
+	Some(m) => unsafe { ::core::ptr::write_volatile( (m.base() + ofs as usize) as *mut u32, val ) },
+	None if ofs < 0x100 => ::arch::interrupts::without_interrupts(|| unsafe {
+		// SAFE: The address/data pair is only used with interrupts disabled
+		port::outl(CONFIG_ADDRESS, port_address(addr, ofs));
+		port::outl(CONFIG_DATA, val);
+		}),
+	None => {},
+	}
+}
+fn read_config_16(addr: Address, ofs: u16) -> u16
+{
+	(read_config(addr, ofs) >> ((ofs & 2) * 8)) as u16
+}
+fn read_config_8(addr: Address, ofs: u16) -> u8
+{
+	(read_config(addr, ofs) >> ((ofs & 3) * 8)) as u8
+}
+
+/// Enumerate all functions reachable from bus 0
+pub fn enumerate() -> Vec<Device>
+{
+	let mut rv = Vec::new();
+	let host = Address { bus: 0, dev: 0, func: 0 };
+	if read_config_8(host, REG_HEADER + 2) & HEADER_MULTIFUNCTION != 0 {
+		// - Multiple host controllers, function N handles bus N
+		for func in 0 .. MAX_FUNC
+		{
+			if read_config(Address { func: func, ..host }, REG_ID) as u16 != 0xFFFF {
+				scan_bus(func, &mut rv);
+			}
+		}
+	}
+	else {
+		scan_bus(0, &mut rv);
+	}
+	rv
+}
+
+fn scan_bus(bus: u8, out: &mut Vec<Device>)
+{
+	for dev in 0 .. MAX_DEV
+	{
+		let addr = Address { bus: bus, dev: dev, func: 0 };
+		if read_config(addr, REG_ID) as u16 == 0xFFFF {
+			continue ;
+		}
+		let funcs = if read_config_8(addr, REG_HEADER + 2) & HEADER_MULTIFUNCTION != 0 { MAX_FUNC } else { 1 };
+		for func in 0 .. funcs
+		{
+			let addr = Address { func: func, ..addr };
+			if let Some(d) = Device::probe(addr)
+			{
+				// - Follow PCI-PCI bridges to their secondary bus
+				if d.header_type & 0x7F == 1 && d.class >> 8 == CLASS_BRIDGE_PCI {
+					let secondary = read_config_8(addr, REG_SECONDARY_BUS);
+					out.push(d);
+					if secondary > bus {
+						scan_bus(secondary, out);
+					}
+					else {
+						println!("warning: PCI {}: Bridge has bad secondary bus {}", addr, secondary);
+					}
+				}
+				else {
+					out.push(d);
+				}
+			}
+		}
+	}
+}
+
+impl Device
+{
+	/// Read the configuration of the function at `addr` (None if there's nothing there)
+	fn probe(addr: Address) -> Option<Device>
+	{
+		let id = read_config(addr, REG_ID);
+		if id as u16 == 0xFFFF {
+			return None;
+		}
+		let class = read_config(addr, REG_CLASS);
+		let header_type = read_config_8(addr, REG_HEADER + 2);
+		let int = read_config(addr, REG_INTERRUPT);
+		let mut rv = Device {
+			addr: addr,
+			vendor: id as u16,
+			device: (id >> 16) as u16,
+			class: class >> 8,
+			revision: class as u8,
+			header_type: header_type,
+			irq_line: int as u8,
+			irq_pin: (int >> 8) as u8,
+			bars: [Bar::None; 6],
+			};
+		// - Only normal devices have six BARs (bridges have two)
+		let nbars = match header_type & 0x7F
+			{
+			0 => 6,
+			1 => 2,
+			_ => 0,
+			};
+		let mut idx = 0;
+		while idx < nbars
+		{
+			let (bar, slots) = rv.decode_bar(idx, nbars);
+			rv.bars[idx] = bar;
+			idx += slots;
+		}
+		Some(rv)
+	}
+
+	/// Decode BAR `idx`, returning it and the number of slots it uses
+	fn decode_bar(&self, idx: usize, nbars: usize) -> (Bar, usize)
+	{
+		let reg = REG_BAR0 + idx as u16 * 4;
+		// - Sizing: write all-ones with decoding disabled so the device doesn't respond at a bogus address
+		let cmd = read_config_16(self.addr, REG_COMMAND);
+		// (the status half of the dword is RW1C, so zeroes are written to it)
+		write_config(self.addr, REG_COMMAND, (cmd & !(CMD_IO | CMD_MEMORY)) as u32);
+		let size_of = |reg: u16| {
+			let orig = read_config(self.addr, reg);
+			write_config(self.addr, reg, !0);
+			let mask = read_config(self.addr, reg);
+			write_config(self.addr, reg, orig);
+			(orig, mask)
+			};
+		let (orig, mask) = size_of(reg);
+		let rv = if orig & 1 != 0 {
+				let mask = mask & !3 & 0xFFFF;
+				if mask == 0 {
+					(Bar::None, 1)
+				}
+				else {
+					(Bar::Io { base: (orig & !3) as u16, size: (!mask + 1) as u16 }, 1)
+				}
+			}
+			else {
+				let is_64 = (orig >> 1) & 3 == 2 && idx + 1 < nbars;
+				let (orig_hi, mask_hi) = if is_64 { size_of(reg + 4) } else { (0, if mask == 0 { 0 } else { !0 }) };
+				let base = (orig_hi as u64) << 32 | (orig & !0xF) as u64;
+				let mask = (mask_hi as u64) << 32 | (mask & !0xF) as u64;
+				let bar = if mask == 0 {
+						Bar::None
+					}
+					else {
+						Bar::Memory { base: base, size: (!mask).wrapping_add(1), prefetchable: orig & 8 != 0, is_64: is_64 }
+					};
+				(bar, if is_64 { 2 } else { 1 })
+			};
+		write_config(self.addr, REG_COMMAND, cmd as u32);
+		rv
+	}
+
+	/// Read a configuration register of this device
+	pub fn read_config(&self, ofs: u16) -> u32 {
+		read_config(self.addr, ofs)
+	}
+	/// Write a configuration register of this device
+	pub fn write_config(&self, ofs: u16, val: u32) {
+		write_config(self.addr, ofs, val)
+	}
+
+	/// Map BAR `idx` (IO BARs are returned as-is, memory BARs are mapped uncached)
+	pub fn map_bar(&self, idx: usize) -> Option<IOBinding> {
+		match self.bars.get(idx).cloned().unwrap_or(Bar::None)
+		{
+		Bar::None => None,
+		Bar::Io { base, size } => Some(IOBinding::IO(base, size)),
+		Bar::Memory { base, size, .. } => {
+			let base = base as usize;
+			let size = size as usize;
+			let ofs = base % PAGE_SIZE;
+			let pages = (ofs + size + PAGE_SIZE - 1) / PAGE_SIZE;
+			let mapping = match kmap::map_frames(Frame::containing_address(base), pages, EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE)
+				{
+				Some(v) => v,
+				None => {
+					println!("warning: PCI {}: No space to map BAR{} ({} bytes)", self.addr, idx, size);
+					return None;
+					},
+				};
+			Some(IOBinding::Memory(mapping, ofs, size))
+			},
+		}
+	}
+
+	/// Enable IO/memory decoding and bus mastering
+	pub fn enable(&self, io: bool, mem: bool, bus_master: bool) {
+		let mut cmd = read_config_16(self.addr, REG_COMMAND);
+		if io { cmd |= CMD_IO; }
+		if mem { cmd |= CMD_MEMORY; }
+		if bus_master { cmd |= CMD_BUS_MASTER; }
+		// - The status half is RW1C, write zeroes to it
+		write_config(self.addr, REG_COMMAND, cmd as u32);
+	}
+
+	/// List the capabilities as (id, offset)
+	pub fn capabilities(&self) -> Vec<(u8, u16)> {
+		let mut rv = Vec::new();
+		if read_config_16(self.addr, REG_STATUS) & STATUS_CAP_LIST == 0 {
+			return rv;
+		}
+		let mut ptr = read_config_8(self.addr, REG_CAP_PTR) & !3;
+		// - Bounded walk in case of a looping list
+		for _ in 0 .. 48
+		{
+			if ptr == 0 {
+				break ;
+			}
+			let hdr = read_config(self.addr, ptr as u16);
+			rv.push( (hdr as u8, ptr as u16) );
+			ptr = (hdr >> 8) as u8 & !3;
+		}
+		rv
+	}
+
+	/// Locate capability `id` in the capability list, returning its offset
+	pub fn find_capability(&self, id: u8) -> Option<u16> {
+		self.capabilities().into_iter().find(|&(i, _)| i == id).map(|(_, ofs)| ofs)
+	}
+
+	/// Route the device's interrupt through MSI to `handler`
+	///
+	/// Returns the IRQ number used, or None if the device doesn't support MSI (or no IRQ is free).
+	/// Legacy INTx is disabled when MSI is enabled.
+	pub fn enable_msi(&self, handler: fn()) -> Option<u8> {
+		let cap = match self.find_capability(CAP_MSI)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let irq = match ::arch::interrupts::irq::alloc_handler(handler)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: PCI {}: No free IRQ for MSI", self.addr);
+				return None;
+				},
+			};
+		let vector = ::consts::irq::T_IRQ0 + irq;
+		let control = read_config_16(self.addr, cap + 2);
+		let is_64 = control & (1 << 7) != 0;
+		write_config(self.addr, cap + 4, MSI_ADDRESS);
+		let data_ofs = if is_64 {
+				write_config(self.addr, cap + 8, 0);
+				cap + 12
+			}
+			else {
+				cap + 8
+			};
+		// - Data is a 16-bit field, only write the low half of the dword
+		let old = read_config(self.addr, data_ofs) & 0xFFFF_0000;
+		write_config(self.addr, data_ofs, old | vector as u32);
+		// - Single message (multiple message enable = 0), enabled
+		let control = (control & !(7 << 4)) | 1;
+		write_config(self.addr, cap, (read_config(self.addr, cap) & 0xFFFF) | (control as u32) << 16);
+		let cmd = read_config_16(self.addr, REG_COMMAND);
+		write_config(self.addr, REG_COMMAND, (cmd | CMD_INTX_DISABLE) as u32);
+		println!("log: PCI {}: MSI enabled on IRQ {}", self.addr, irq);
+		Some(irq)
+	}
+
+	/// Route MSI-X table entry 0 to `handler` (the other entries stay masked)
+	///
+	/// Returns the IRQ number used, or None if the device doesn't support MSI-X.
+	pub fn enable_msix(&self, handler: fn()) -> Option<u8> {
+		let cap = match self.find_capability(CAP_MSIX)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let table = read_config(self.addr, cap + 4);
+		let regs = match self.map_bar((table & 7) as usize)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: PCI {}: MSI-X table BAR{} isn't usable", self.addr, table & 7);
+				return None;
+				},
+			};
+		let irq = match ::arch::interrupts::irq::alloc_handler(handler)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: PCI {}: No free IRQ for MSI-X", self.addr);
+				return None;
+				},
+			};
+		let control = read_config_16(self.addr, cap + 2);
+		let set_control = |control: u16| write_config(self.addr, cap, (read_config(self.addr, cap) & 0xFFFF) | (control as u32) << 16);
+		// - Enabled with every vector masked while the entry is written
+		set_control(control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+		let entry = (table & !7) as usize;
+		// SAFE: Entry 0 of the device's MSI-X table
+		unsafe {
+			regs.write_32(entry, MSI_ADDRESS);
+			regs.write_32(entry + 4, 0);
+			regs.write_32(entry + 8, (::consts::irq::T_IRQ0 + irq) as u32);
+			regs.write_32(entry + 12, 0);
+		}
+		set_control((control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+		let cmd = read_config_16(self.addr, REG_COMMAND);
+		write_config(self.addr, REG_COMMAND, (cmd | CMD_INTX_DISABLE) as u32);
+		println!("log: PCI {}: MSI-X enabled on IRQ {}", self.addr, irq);
+		Some(irq)
+	}
+}
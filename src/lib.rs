@@ -108,7 +108,7 @@ pub mod metadevs;
 /// Device to driver mapping manager
 ///
 /// Starts driver instances for the devices it sees
-//pub mod device_manager;
+pub mod device_manager;
 
 #[macro_use]
 pub mod vfs;
@@ -118,6 +118,7 @@ pub mod config;
 
 //pub mod irqs;
 pub mod ata;
+pub mod virtio;
 
 //#[macro_use]
 //pub mod process;
@@ -152,6 +153,16 @@ pub extern fn rust_main(multiboot_information_address: usize) {
     unsafe{
         allocator::init(&mut active_table);
     }
+    // remember boot modules (the initramfs is unpacked by vfs::init, RAM disk images are
+    // registered by metadevs::ramdisk::init)
+    for module in boot_info.module_tags() {
+        if module.name().starts_with("ramdisk") {
+            metadevs::ramdisk::add_module(module.start_address() as usize, module.end_address() as usize, module.name());
+        }
+        else {
+            vfs::initramfs::add_module(module.start_address() as usize, module.end_address() as usize, module.name());
+        }
+    }
 
     // initialize our IDT and GDT
     arch::gdt::init();
@@ -171,6 +182,7 @@ pub extern fn rust_main(multiboot_information_address: usize) {
         keyboard::init();
     }
     modules::ps2::init();
+    device_manager::init();
 
     test!(global_allocator);
     test!(alloc_sth);
@@ -260,9 +272,8 @@ fn ata_test(){
 	println!("ata_test");
 	use alloc::string::String;
 	let mut dst: [u32;512]=[0;512];
-	let block_size = 128;
-	let sata: ata::AtaVolume=ata::AtaVolume::new(String::from("test"),0,2048);
-	sata.init();
+	let block_size = ata::io::SECTOR_WORDS;
+	let sata: ata::AtaVolume=ata::AtaVolume::open(String::from("test"),0);
 	// Read up to 'block_step' blocks in each read call
 	// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
 	{
@@ -282,7 +293,10 @@ fn ata_test(){
 			match sata.write(prio, blk_id, blocks, &dst)//.wait()
 			{
 				Ok(v) => v,
-				Err(e) => todo!("Error when PV fails to read: {:?}", e),
+				Err(e) => {
+					println!("ata_test: write failed: {:?}", e);
+					return ;
+					},
 			};
 
 			//read test
@@ -294,7 +308,10 @@ fn ata_test(){
 			let real_count = match sata.read(prio, blk_id, blocks, &mut dst)//.wait()
 				{
 				Ok(v) => v,
-				Err(e) => todo!("Error when PV fails to read: {:?}", e),
+				Err(e) => {
+					println!("ata_test: read failed: {:?}", e);
+					return ;
+					},
 				};
 			println!("real_count:{} blocks:{}",real_count,blocks);
 			for i in 1..9{
@@ -314,7 +331,14 @@ fn fsinit()
 	use vfs::Path;
 
     metadevs::storage::init();
+    metadevs::partitions::init();
+    metadevs::raid::init();
+    metadevs::ramdisk::init();
+    ata::init();
+    virtio::init();
+    metadevs::raid::assemble_pending();
     vfs::init();
+    vfs::start_flush_daemon();
 	// TODO: Should I automount at startup, then use chroot magic?
 	//automount();
 	
@@ -390,7 +414,7 @@ fn vfs_test()
 		println!("\nls3:");
 		ls(Path::new("/system"));
 		println!("");
-		match handle::File::open( Path::new("/system/1.TXT"), handle::FileOpenMode::SharedRO )
+		match handle::File::open( Path::new("/system/1.TXT"), handle::FileOpenMode::ExclRW )
 		{
 		Err(e) => println!("waring: VFS test file can't be opened: {:?}", e),//log_warning!("VFS test file can't be opened: {:?}", e),
 		Ok(mut h) => {
@@ -422,7 +446,7 @@ fn vfs_test()
 		//ls(Path::new("/"));
 		
 		handle::Dir::open(Path::new("/system")).unwrap().mkfile("2.TXT", handle::FileOpenMode::SharedRO).unwrap();
-		match handle::File::open( Path::new("/system/2.TXT"), handle::FileOpenMode::SharedRO )
+		match handle::File::open( Path::new("/system/2.TXT"), handle::FileOpenMode::ExclRW )
 		{
 			Err(e) => println!("waring: VFS test file can't be opened: {:?}", e),//log_warning!("VFS test file can't be opened: {:?}", e),
 			Ok(mut h) => {
@@ -439,7 +463,7 @@ fn vfs_test()
 				let sz = h.read(0, &mut buf).unwrap();
 			},
 		}
-		match handle::File::open( Path::new("/system/1.TXT"), handle::FileOpenMode::SharedRO )
+		match handle::File::open( Path::new("/system/1.TXT"), handle::FileOpenMode::ExclRW )
 		{
 			Err(e) => println!("waring: VFS test file can't be opened: {:?}", e),//log_warning!("VFS test file can't be opened: {:?}", e),
 			Ok(mut h) => {
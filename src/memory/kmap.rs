@@ -0,0 +1,154 @@
+//! Kernel window for mapping physical frames
+//!
+//! Physical memory is not linearly mapped into the kernel, so anything that needs to touch the
+//! contents of a frame it did not get from the heap (page cache pages, DMA buffers, ...) maps it
+//! into this window first.
+
+use alloc::vec::Vec;
+use core::slice;
+use spin::Mutex;
+use consts::{KERNEL_KMAP_OFFSET, KERNEL_KMAP_SIZE};
+use super::*;
+
+const KMAP_PAGES: usize = KERNEL_KMAP_SIZE / PAGE_SIZE;
+
+/// Allocation bitmap for the window, one bit per page
+static KMAP_USED: Mutex<Option<Vec<u64>>> = Mutex::new(None);
+
+/// A run of physically contiguous frames mapped into the kernel window
+///
+/// The window slot (and the frames, if they were allocated by `alloc_mapped`) are released on drop.
+#[derive(Debug)]
+pub struct KernelMapping {
+    base: VirtualAddress,
+    frame: Frame,
+    count: usize,
+    owns_frames: bool,
+}
+
+/// Map `count` frames starting at `frame` into the window
+///
+/// The frames are not owned by the mapping (e.g. MMIO regions or frames owned elsewhere).
+pub fn map_frames(frame: Frame, count: usize, flags: EntryFlags) -> Option<KernelMapping> {
+    let slot = reserve(count)?;
+    map_slot(slot, &frame, count, flags);
+    Some(KernelMapping { base: slot_address(slot), frame, count, owns_frames: false })
+}
+
+/// Allocate `count` physically contiguous, zeroed frames and map them into the window
+pub fn alloc_mapped(count: usize) -> Option<KernelMapping> {
+    let frame = allocate_frames(count)?;
+    let slot = match reserve(count) {
+        Some(v) => v,
+        None => {
+            deallocate_frames(frame, count);
+            return None;
+        }
+    };
+    map_slot(slot, &frame, count, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    let rv = KernelMapping { base: slot_address(slot), frame, count, owns_frames: true };
+    unsafe { ::rlibc::memset(rv.base as *mut u8, 0, count * PAGE_SIZE); }
+    Some(rv)
+}
+
+impl KernelMapping {
+    /// Virtual address of the first mapped byte
+    pub fn base(&self) -> VirtualAddress {
+        self.base
+    }
+    /// Physical address of the first mapped byte
+    pub fn phys(&self) -> PAddr {
+        self.frame.start_address()
+    }
+    /// First frame of the mapping
+    pub fn frame(&self) -> Frame {
+        self.frame.clone()
+    }
+    /// Number of mapped pages
+    pub fn page_count(&self) -> usize {
+        self.count
+    }
+    pub fn len(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+
+    /// The mapped memory as bytes
+    ///
+    /// Unsafe because the caller must ensure nothing else (e.g. a device) is writing the memory
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+        slice::from_raw_parts(self.base as *const u8, self.len())
+    }
+    pub unsafe fn as_bytes_mut(&self) -> &mut [u8] {
+        slice::from_raw_parts_mut(self.base as *mut u8, self.len())
+    }
+    /// The mapped memory as 32-bit words (the unit used by the VFS file API)
+    pub unsafe fn as_words(&self) -> &[u32] {
+        slice::from_raw_parts(self.base as *const u32, self.len() / 4)
+    }
+    pub unsafe fn as_words_mut(&self) -> &mut [u32] {
+        slice::from_raw_parts_mut(self.base as *mut u32, self.len() / 4)
+    }
+}
+
+impl Drop for KernelMapping {
+    fn drop(&mut self) {
+        let mut active_table = unsafe { ActivePageTable::new() };
+        for i in 0..self.count {
+            let page = Page::containing_address(self.base + i * PAGE_SIZE);
+            let (result, _frame) = active_table.unmap_return(page, true);
+            result.flush(&mut active_table);
+        }
+        if self.owns_frames {
+            deallocate_frames(self.frame.clone(), self.count);
+        }
+        release((self.base - KERNEL_KMAP_OFFSET) / PAGE_SIZE, self.count);
+    }
+}
+
+fn slot_address(slot: usize) -> VirtualAddress {
+    KERNEL_KMAP_OFFSET + slot * PAGE_SIZE
+}
+
+fn map_slot(slot: usize, frame: &Frame, count: usize, flags: EntryFlags) {
+    let mut active_table = unsafe { ActivePageTable::new() };
+    for i in 0..count {
+        let page = Page::containing_address(slot_address(slot + i));
+        let frame = Frame { number: frame.number + i };
+        let result = active_table.map_to(page, frame, flags);
+        result.flush(&mut active_table);
+    }
+}
+
+/// Find and mark `count` contiguous free pages in the window
+fn reserve(count: usize) -> Option<usize> {
+    assert!(count > 0);
+    let mut lh = KMAP_USED.lock();
+    let bitmap = lh.get_or_insert_with(|| vec![0u64; KMAP_PAGES / 64]);
+    let is_used = |bm: &Vec<u64>, i: usize| bm[i / 64] & (1 << (i % 64)) != 0;
+
+    let mut run_start = 0;
+    let mut run_len = 0;
+    for i in 0..KMAP_PAGES {
+        if is_used(bitmap, i) {
+            run_len = 0;
+            run_start = i + 1;
+            continue;
+        }
+        run_len += 1;
+        if run_len == count {
+            for j in run_start..run_start + count {
+                bitmap[j / 64] |= 1 << (j % 64);
+            }
+            return Some(run_start);
+        }
+    }
+    None
+}
+
+fn release(slot: usize, count: usize) {
+    let mut lh = KMAP_USED.lock();
+    let bitmap = lh.as_mut().expect("kmap release before reserve");
+    for j in slot..slot + count {
+        bitmap[j / 64] &= !(1 << (j % 64));
+    }
+}
@@ -20,6 +20,7 @@ mod stack_allocator;
 pub mod address;
 mod frame;
 pub mod memory_set;
+pub mod kmap;
 
 pub static FRAME_ALLOCATOR: Mutex<Option<RecycleAllocator<BumpAllocator>>> = Mutex::new(None);
 pub static STACK_ALLOCATOR: Mutex<Option<StackAllocator>> = Mutex::new(None);
@@ -52,12 +53,15 @@ pub fn init(boot_info: &BootInformation) -> ActivePageTable {
     println!("multiboot start: {:#x}, multiboot end: {:#x}",
              boot_info_start,
              boot_info_end);
+    // Boot modules (e.g. the initramfs) are loaded after the kernel and read once the VFS is up,
+    // so keep the frame allocator off them too
+    let modules_end = boot_info.module_tags().map(|m| m.end_address() as usize).max().unwrap_or(0);
     println!("memory area:");
     for area in memory_map_tag.memory_areas() {
         println!("{:?}", area);
     }    
 
-    *FRAME_ALLOCATOR.lock() = Some(RecycleAllocator::new(BumpAllocator::new(kernel_start.0 as usize, kernel_end.0 as usize, memory_map_tag.memory_areas())));
+    *FRAME_ALLOCATOR.lock() = Some(RecycleAllocator::new(BumpAllocator::new(kernel_start.0 as usize, ::core::cmp::max(kernel_end.0 as usize, modules_end), memory_map_tag.memory_areas())));
 
     unsafe{ init_pat(); }
     let mut active_table = remap_the_kernel(boot_info);
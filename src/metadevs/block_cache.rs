@@ -0,0 +1,188 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/block_cache.rs
+//! Logical volume block cache
+//!
+//! Each logical volume owns a `BlockCache` holding up to `capacity` blocks. Reads are filled from
+//! the cache, writes are held in it (write-back) until the block is evicted or the volume is synced.
+//! Eviction picks the least recently used block.
+#[allow(unused_imports)]
+use prelude::*;
+use mylib::VecMap;
+use super::storage::IoError;
+
+/// Default number of blocks cached for each logical volume
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Callback used to write a dirty block back to the volume
+pub type WritebackFn<'a> = FnMut(u64, &[u8]) -> Result<(),IoError> + 'a;
+
+/// Block cache statistics
+#[derive(Debug,Default,Copy,Clone)]
+pub struct CacheStats
+{
+	/// Reads served from the cache
+	pub hits: u64,
+	/// Blocks read from the volume
+	pub misses: u64,
+	/// Dirty blocks written back to the volume
+	pub writebacks: u64,
+	/// Blocks dropped to make room for others
+	pub evictions: u64,
+}
+
+struct CachedBlock
+{
+	data: Box<[u8]>,
+	/// Block has been written since it was last written back
+	dirty: bool,
+	/// Value of `BlockCache::tick` on the last access (for LRU eviction)
+	last_use: u64,
+}
+
+pub struct BlockCache
+{
+	/// Maximum number of cached blocks, 0 disables caching
+	capacity: usize,
+	/// Access counter, used as the LRU timestamp
+	tick: u64,
+	blocks: VecMap<u64,CachedBlock>,
+	stats: CacheStats,
+}
+
+impl Default for BlockCache
+{
+	fn default() -> BlockCache {
+		BlockCache::new(DEFAULT_CAPACITY)
+	}
+}
+
+impl BlockCache
+{
+	pub fn new(capacity: usize) -> BlockCache {
+		BlockCache {
+			capacity: capacity,
+			tick: 0,
+			blocks: VecMap::new(),
+			stats: Default::default(),
+		}
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+	pub fn stats(&self) -> CacheStats {
+		self.stats
+	}
+	pub fn contains(&self, idx: u64) -> bool {
+		self.blocks.get(&idx).is_some()
+	}
+
+	/// Change the capacity, evicting (and writing back) blocks if the cache shrinks
+	pub fn set_capacity(&mut self, capacity: usize, writeback: &mut WritebackFn) -> Result<(),IoError> {
+		self.capacity = capacity;
+		while self.blocks.len() > self.capacity {
+			try!(self.evict_one(writeback));
+		}
+		Ok( () )
+	}
+
+	/// Look up a cached block, counting a hit if present
+	pub fn get(&mut self, idx: u64) -> Option<&[u8]> {
+		self.tick += 1;
+		let tick = self.tick;
+		match self.blocks.get_mut(&idx)
+		{
+		Some(b) => {
+			b.last_use = tick;
+			self.stats.hits += 1;
+			Some(&b.data)
+			},
+		None => None,
+		}
+	}
+
+	/// Insert a block that was just read from the volume (counts a miss)
+	pub fn fill(&mut self, idx: u64, data: &[u8], writeback: &mut WritebackFn) -> Result<(),IoError> {
+		self.stats.misses += 1;
+		if self.contains(idx) {
+			// Already present (and possibly newer than the volume), keep the cached copy
+			return Ok( () );
+		}
+		self.insert(idx, data, false, writeback)
+	}
+
+	/// Store a written block, to be written back later
+	pub fn write(&mut self, idx: u64, data: &[u8], writeback: &mut WritebackFn) -> Result<(),IoError> {
+		self.insert(idx, data, true, writeback)
+	}
+
+	/// Drop a block without writing it back (e.g. when the volume copy was overwritten directly)
+	pub fn discard(&mut self, idx: u64) {
+		self.blocks.remove(&idx);
+	}
+
+	/// Write back all dirty blocks (in block order)
+	pub fn flush(&mut self, writeback: &mut WritebackFn) -> Result<(),IoError> {
+		for (&idx, b) in self.blocks.iter_mut()
+		{
+			if b.dirty {
+				try!( writeback(idx, &b.data) );
+				b.dirty = false;
+				self.stats.writebacks += 1;
+			}
+		}
+		Ok( () )
+	}
+
+	/// Returns true if any block is waiting to be written back
+	pub fn is_dirty(&self) -> bool {
+		self.blocks.iter().any(|(_,b)| b.dirty)
+	}
+
+	fn insert(&mut self, idx: u64, data: &[u8], dirty: bool, writeback: &mut WritebackFn) -> Result<(),IoError> {
+		if self.capacity == 0 {
+			return Ok( () );
+		}
+		self.tick += 1;
+		let tick = self.tick;
+		if let Some(b) = self.blocks.get_mut(&idx)
+		{
+			b.data.copy_from_slice(data);
+			b.dirty |= dirty;
+			b.last_use = tick;
+			return Ok( () );
+		}
+		while self.blocks.len() >= self.capacity {
+			try!(self.evict_one(writeback));
+		}
+		self.blocks.insert(idx, CachedBlock {
+			data: data.to_vec().into_boxed_slice(),
+			dirty: dirty,
+			last_use: tick,
+			});
+		Ok( () )
+	}
+
+	/// Evict the least recently used block, writing it back if dirty
+	fn evict_one(&mut self, writeback: &mut WritebackFn) -> Result<(),IoError> {
+		let idx = match self.blocks.iter().min_by_key(|&(_,b)| b.last_use)
+			{
+			Some((&idx,_)) => idx,
+			None => return Ok( () ),
+			};
+		{
+			let b = self.blocks.get_mut(&idx).unwrap();
+			if b.dirty {
+				// Leave the block cached if the write fails, so the data isn't lost
+				try!( writeback(idx, &b.data) );
+				b.dirty = false;
+				self.stats.writebacks += 1;
+			}
+		}
+		self.blocks.remove(&idx);
+		self.stats.evictions += 1;
+		Ok( () )
+	}
+}
@@ -0,0 +1,435 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/journal.rs
+//! Write-ahead block journal for filesystem drivers
+//!
+//! A filesystem reserves a region of its volume for the journal, groups related block writes into
+//! a `Transaction`, and passes it to `Journal::commit`. Commit writes the blocks to the journal
+//! region followed by a commit record, then writes them to their real locations (checkpoint). After
+//! a crash, `Journal::open` replays a transaction whose commit record made it to disk, and discards
+//! one that didn't, so either all or none of a transaction's writes are seen.
+//!
+//! Journal region layout (in volume blocks, relative to the start of the region):
+//! - 0: Superblock (magic, version, region length, block size, next sequence number, CRC)
+//! - 1: Descriptor (magic, sequence number, block count, target block numbers)
+//! - 2 .. 2+count: Block data
+//! - 2+count: Commit record (magic, sequence number, CRC of the descriptor and data)
+//!
+//! Only one transaction is held in the journal at a time, the sequence number in the superblock is
+//! advanced once a transaction has been checkpointed so it isn't replayed again.
+#[allow(unused_imports)]
+use prelude::*;
+use mylib::VecMap;
+use mylib::crc32::crc32;
+use spin::Mutex;
+use super::storage::{IoError,VolumeHandle};
+
+const SUPERBLOCK_MAGIC: u32 = 0x4C4E524A;	// "JRNL"
+const DESCRIPTOR_MAGIC: u32 = 0x4353444A;	// "JDSC"
+const COMMIT_MAGIC: u32 = 0x544D434A;	// "JCMT"
+const VERSION: u32 = 1;
+/// Size of the descriptor header, target block numbers follow
+const DESCRIPTOR_HEADER: usize = 16;
+/// Superblock, descriptor and commit record
+const OVERHEAD_BLOCKS: u64 = 3;
+
+/// Block storage used by the journal (implemented for `VolumeHandle`)
+pub trait BlockDevice
+{
+	fn block_size(&self) -> usize;
+	fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError>;
+	fn write_blocks(&self, idx: u64, src: &[u8]) -> Result<(),IoError>;
+	/// Make all previous writes durable (a write barrier)
+	fn sync(&self) -> Result<(),IoError>;
+}
+impl BlockDevice for VolumeHandle
+{
+	fn block_size(&self) -> usize {
+		VolumeHandle::block_size(self)
+	}
+	fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		VolumeHandle::read_blocks(self, idx, dst)
+	}
+	fn write_blocks(&self, idx: u64, src: &[u8]) -> Result<(),IoError> {
+		VolumeHandle::write_blocks(self, idx, src)
+	}
+	fn sync(&self) -> Result<(),IoError> {
+		VolumeHandle::sync(self)
+	}
+}
+
+/// A journal region on a volume
+pub struct Journal
+{
+	/// First block of the region (the superblock)
+	start: u64,
+	/// Length of the region in blocks
+	length: u64,
+	block_size: usize,
+	/// Sequence number of the next transaction (also serialises commits)
+	sequence: Mutex<u64>,
+}
+
+/// A group of block writes applied atomically by `Journal::commit`
+pub struct Transaction
+{
+	block_size: usize,
+	blocks: VecMap<u64,Vec<u8>>,
+}
+
+impl Journal
+{
+	/// Initialise an empty journal in blocks `start .. start+length`
+	pub fn format<D: ?Sized + BlockDevice>(dev: &D, start: u64, length: u64) -> Result<Journal,IoError> {
+		let bs = dev.block_size();
+		if length < OVERHEAD_BLOCKS + 1 || bs < DESCRIPTOR_HEADER + 8 {
+			return Err( IoError::InvalidParameter );
+		}
+		let rv = Journal {
+			start: start,
+			length: length,
+			block_size: bs,
+			sequence: Mutex::new(1),
+			};
+		// Clear the descriptor, so a stale one can't match a later sequence number
+		try!( dev.write_blocks(start + 1, &vec![0u8; bs]) );
+		try!( rv.write_superblock(dev, 1) );
+		try!( dev.sync() );
+		Ok(rv)
+	}
+
+	/// Open the journal starting at block `start`, replaying a committed transaction
+	pub fn open<D: ?Sized + BlockDevice>(dev: &D, start: u64) -> Result<Journal,IoError> {
+		let bs = dev.block_size();
+		let mut sb = vec![0u8; bs];
+		try!( dev.read_blocks(start, &mut sb) );
+		if get_u32(&sb, 0) != SUPERBLOCK_MAGIC || get_u32(&sb, 4) != VERSION {
+			return Err( IoError::Unknown("Journal superblock not found") );
+		}
+		if get_u32(&sb, 28) != crc32(0, &sb[..28]) {
+			return Err( IoError::Unknown("Journal superblock corrupt") );
+		}
+		if get_u32(&sb, 24) as usize != bs {
+			return Err( IoError::Unknown("Journal block size mismatch") );
+		}
+		let rv = Journal {
+			start: start,
+			length: get_u64(&sb, 8),
+			block_size: bs,
+			sequence: Mutex::new(get_u64(&sb, 16)),
+			};
+		if try!(rv.replay(dev)) {
+			println!("journal: Replayed transaction at block {}", start);
+		}
+		Ok(rv)
+	}
+
+	/// Length of the journal region in blocks
+	pub fn length(&self) -> u64 {
+		self.length
+	}
+	/// Maximum number of blocks in a single transaction
+	pub fn max_transaction_blocks(&self) -> usize {
+		let by_descriptor = (self.block_size - DESCRIPTOR_HEADER) / 8;
+		::core::cmp::min(by_descriptor as u64, self.length - OVERHEAD_BLOCKS) as usize
+	}
+
+	/// Start a new (empty) transaction
+	pub fn begin(&self) -> Transaction {
+		Transaction {
+			block_size: self.block_size,
+			blocks: VecMap::new(),
+		}
+	}
+
+	/// Atomically apply a transaction's writes
+	///
+	/// Returns once the blocks are in their final locations. If this fails part way through, the
+	/// transaction is either replayed or discarded when the journal is next opened.
+	pub fn commit<D: ?Sized + BlockDevice>(&self, dev: &D, tx: Transaction) -> Result<(),IoError> {
+		let count = tx.blocks.len();
+		if count == 0 {
+			return Ok( () );
+		}
+		if count > self.max_transaction_blocks() {
+			return Err( IoError::InvalidParameter );
+		}
+		let mut seq = self.sequence.lock();
+		let bs = self.block_size;
+
+		// 1. Descriptor and block data
+		let mut desc = vec![0u8; bs];
+		put_u32(&mut desc, 0, DESCRIPTOR_MAGIC);
+		put_u64(&mut desc, 4, *seq);
+		put_u32(&mut desc, 12, count as u32);
+		for (i, (&idx, _)) in tx.blocks.iter().enumerate() {
+			put_u64(&mut desc, DESCRIPTOR_HEADER + i * 8, idx);
+		}
+		let mut crc = crc32(0, &desc);
+		try!( dev.write_blocks(self.start + 1, &desc) );
+		for (i, (_, data)) in tx.blocks.iter().enumerate()
+		{
+			crc = crc32(crc, data);
+			try!( dev.write_blocks(self.start + 2 + i as u64, data) );
+		}
+		try!( dev.sync() );
+
+		// 2. Commit record, the transaction survives a crash once this is on disk
+		try!( dev.write_blocks(self.start + 2 + count as u64, &self.commit_record(*seq, crc)) );
+		try!( dev.sync() );
+
+		// 3. Checkpoint, then retire the transaction
+		for (&idx, data) in tx.blocks.iter() {
+			try!( dev.write_blocks(idx, data) );
+		}
+		try!( dev.sync() );
+		*seq += 1;
+		try!( self.write_superblock(dev, *seq) );
+		dev.sync()
+	}
+
+	/// Replay the transaction in the journal if it was fully committed
+	///
+	/// Returns `true` if a transaction was replayed.
+	fn replay<D: ?Sized + BlockDevice>(&self, dev: &D) -> Result<bool,IoError> {
+		let mut seq = self.sequence.lock();
+		let bs = self.block_size;
+		let mut desc = vec![0u8; bs];
+		try!( dev.read_blocks(self.start + 1, &mut desc) );
+		let count = get_u32(&desc, 12) as usize;
+		if get_u32(&desc, 0) != DESCRIPTOR_MAGIC || get_u64(&desc, 4) != *seq || count == 0 || count > self.max_transaction_blocks() {
+			// Nothing (new) was written
+			return Ok(false);
+		}
+		let mut data = vec![0u8; count * bs];
+		try!( dev.read_blocks(self.start + 2, &mut data) );
+		let crc = crc32(crc32(0, &desc), &data);
+		let mut commit = vec![0u8; bs];
+		try!( dev.read_blocks(self.start + 2 + count as u64, &mut commit) );
+		if commit != self.commit_record(*seq, crc) {
+			// Crashed before the commit record was written, the transaction never happened
+			return Ok(false);
+		}
+
+		for (i, blk) in data.chunks(bs).enumerate() {
+			try!( dev.write_blocks(get_u64(&desc, DESCRIPTOR_HEADER + i * 8), blk) );
+		}
+		try!( dev.sync() );
+		*seq += 1;
+		try!( self.write_superblock(dev, *seq) );
+		try!( dev.sync() );
+		Ok(true)
+	}
+
+	fn commit_record(&self, seq: u64, crc: u32) -> Vec<u8> {
+		let mut rv = vec![0u8; self.block_size];
+		put_u32(&mut rv, 0, COMMIT_MAGIC);
+		put_u64(&mut rv, 4, seq);
+		put_u32(&mut rv, 12, crc);
+		rv
+	}
+	fn write_superblock<D: ?Sized + BlockDevice>(&self, dev: &D, seq: u64) -> Result<(),IoError> {
+		let mut sb = vec![0u8; self.block_size];
+		put_u32(&mut sb, 0, SUPERBLOCK_MAGIC);
+		put_u32(&mut sb, 4, VERSION);
+		put_u64(&mut sb, 8, self.length);
+		put_u64(&mut sb, 16, seq);
+		put_u32(&mut sb, 24, self.block_size as u32);
+		let crc = crc32(0, &sb[..28]);
+		put_u32(&mut sb, 28, crc);
+		dev.write_blocks(self.start, &sb)
+	}
+}
+
+impl Transaction
+{
+	/// Add a block write (replacing an earlier write of the same block in this transaction)
+	pub fn write_block(&mut self, idx: u64, data: &[u8]) {
+		assert_eq!(data.len(), self.block_size, "Transaction::write_block - Data must be one block");
+		self.blocks.insert(idx, data.to_vec());
+	}
+	/// Read a block, as it will be once this transaction is committed
+	pub fn read_block<D: ?Sized + BlockDevice>(&self, dev: &D, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		match self.blocks.get(&idx)
+		{
+		Some(data) => {
+			dst.copy_from_slice(data);
+			Ok( () )
+			},
+		None => dev.read_blocks(idx, dst),
+		}
+	}
+	/// Number of blocks written by this transaction
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.blocks.len() == 0
+	}
+}
+
+fn get_u32(buf: &[u8], ofs: usize) -> u32
+{
+	(0 .. 4).fold(0, |v, i| v | (buf[ofs + i] as u32) << (i * 8))
+}
+fn get_u64(buf: &[u8], ofs: usize) -> u64
+{
+	get_u32(buf, ofs) as u64 | (get_u32(buf, ofs + 4) as u64) << 32
+}
+fn put_u32(buf: &mut [u8], ofs: usize, v: u32)
+{
+	for i in 0 .. 4 {
+		buf[ofs + i] = (v >> (i * 8)) as u8;
+	}
+}
+fn put_u64(buf: &mut [u8], ofs: usize, v: u64)
+{
+	put_u32(buf, ofs, v as u32);
+	put_u32(buf, ofs + 4, (v >> 32) as u32);
+}
+
+#[cfg(test)]
+mod test
+{
+	use prelude::*;
+	use core::cell::RefCell;
+	use super::*;
+
+	const BS: usize = 64;
+	const BLOCKS: usize = 32;
+	const JOURNAL_START: u64 = 16;
+	const JOURNAL_LEN: u64 = 16;
+
+	enum Op
+	{
+		Write(u64, Vec<u8>),
+		Sync,
+	}
+
+	/// In-memory disk image that records every write (to replay a prefix as a "crash")
+	struct Image
+	{
+		data: RefCell<Vec<u8>>,
+		log: RefCell<Vec<Op>>,
+	}
+	impl Image
+	{
+		fn new(data: Vec<u8>) -> Image {
+			Image { data: RefCell::new(data), log: RefCell::new(Vec::new()) }
+		}
+		fn block(&self, idx: u64) -> Vec<u8> {
+			self.data.borrow()[idx as usize * BS .. (idx as usize + 1) * BS].to_vec()
+		}
+	}
+	impl BlockDevice for Image
+	{
+		fn block_size(&self) -> usize {
+			BS
+		}
+		fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+			let ofs = idx as usize * BS;
+			dst.copy_from_slice(&self.data.borrow()[ofs .. ofs + dst.len()]);
+			Ok( () )
+		}
+		fn write_blocks(&self, idx: u64, src: &[u8]) -> Result<(),IoError> {
+			let ofs = idx as usize * BS;
+			self.data.borrow_mut()[ofs .. ofs + src.len()].copy_from_slice(src);
+			self.log.borrow_mut().push(Op::Write(idx, src.to_vec()));
+			Ok( () )
+		}
+		fn sync(&self) -> Result<(),IoError> {
+			self.log.borrow_mut().push(Op::Sync);
+			Ok( () )
+		}
+	}
+
+	/// Small LCG, good enough to pick crash points
+	struct Rng(u64);
+	impl Rng
+	{
+		fn next(&mut self, max: usize) -> usize {
+			self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+			((self.0 >> 33) as usize) % max
+		}
+	}
+
+	#[test]
+	fn commit_and_reopen() {
+		let img = Image::new(vec![0u8; BS * BLOCKS]);
+		let j = Journal::format(&img, JOURNAL_START, JOURNAL_LEN).unwrap();
+		let mut tx = j.begin();
+		tx.write_block(3, &[0xAA; BS]);
+		tx.write_block(5, &[0x55; BS]);
+		let mut buf = [0u8; BS];
+		tx.read_block(&img, 3, &mut buf).unwrap();
+		assert_eq!(&buf[..], &[0xAA; BS][..]);
+		j.commit(&img, tx).unwrap();
+		assert_eq!(img.block(3), vec![0xAA; BS]);
+		assert_eq!(img.block(5), vec![0x55; BS]);
+		// Nothing left to replay
+		let j = Journal::open(&img, JOURNAL_START).unwrap();
+		assert_eq!(j.replay(&img).unwrap(), false);
+	}
+
+	#[test]
+	fn oversized_transaction() {
+		let img = Image::new(vec![0u8; BS * BLOCKS]);
+		let j = Journal::format(&img, JOURNAL_START, 5).unwrap();
+		let mut tx = j.begin();
+		for i in 0 .. 3 {
+			tx.write_block(i, &[1; BS]);
+		}
+		assert!(j.commit(&img, tx).is_err());
+	}
+
+	/// Crash at random points during a commit: after reopening, either all or none of the
+	/// transaction's blocks must have been written
+	#[test]
+	fn crash_during_commit() {
+		let base = Image::new(vec![0u8; BS * BLOCKS]);
+		Journal::format(&base, JOURNAL_START, JOURNAL_LEN).unwrap();
+		let base_data = base.data.borrow().clone();
+
+		// Record the writes made by a commit
+		let img = Image::new(base_data.clone());
+		let j = Journal::open(&img, JOURNAL_START).unwrap();
+		let targets = [1u64, 4, 7, 9];
+		let mut tx = j.begin();
+		for &t in targets.iter() {
+			tx.write_block(t, &[t as u8 + 0x10; BS]);
+		}
+		j.commit(&img, tx).unwrap();
+		let log = img.log.into_inner();
+
+		let mut rng = Rng(0x1234);
+		for _ in 0 .. 200
+		{
+			// Writes before the crash point are applied, except that writes after the last sync
+			// can be lost (the disk can reorder them)
+			let crash = rng.next(log.len() + 1);
+			let last_sync = log[..crash].iter().rposition(|op| match *op { Op::Sync => true, _ => false }).unwrap_or(0);
+			let crashed = Image::new(base_data.clone());
+			for (i, op) in log[..crash].iter().enumerate()
+			{
+				if let Op::Write(idx, ref data) = *op {
+					if i < last_sync || rng.next(2) == 0 {
+						crashed.write_blocks(idx, data).unwrap();
+					}
+				}
+			}
+
+			let j = Journal::open(&crashed, JOURNAL_START).unwrap();
+			let new_count = targets.iter().filter(|&&t| crashed.block(t) == vec![t as u8 + 0x10; BS]).count();
+			let old_count = targets.iter().filter(|&&t| crashed.block(t) == vec![0u8; BS]).count();
+			assert!(new_count == targets.len() || old_count == targets.len(),
+				"Crash at {}/{} left a partial transaction ({} new, {} old)", crash, log.len(), new_count, old_count);
+
+			// The journal is usable after recovery
+			let mut tx = j.begin();
+			tx.write_block(2, &[0xEE; BS]);
+			j.commit(&crashed, tx).unwrap();
+			assert_eq!(crashed.block(2), vec![0xEE; BS]);
+		}
+	}
+}
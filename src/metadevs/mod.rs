@@ -1 +1,6 @@
 pub mod storage;
+pub mod journal;
+pub mod partitions;
+pub mod raid;
+pub mod ramdisk;
+mod block_cache;
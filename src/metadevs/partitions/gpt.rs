@@ -0,0 +1,245 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/partitions/gpt.rs
+//! GUID Partition Table mapper
+//!
+//! Partitions are numbered by their index in the entry array. The primary header (block 1) is
+//! used if its header and entry array CRCs are valid, otherwise the backup at the end of the disk.
+#[allow(unused_imports)]
+use prelude::*;
+use mylib::crc32::crc32;
+use metadevs::storage::{self,PhysicalVolume,IoError};
+use super::{read_block,partition_name,get_u32,get_u64};
+
+const SIGNATURE: &'static [u8] = b"EFI PART";
+/// Size of the header fields covered by this code (revision 1.0)
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+/// Largest entry array accepted (the spec minimum is 16KiB, this allows for generous tables)
+const MAX_ENTRIES_BYTES: usize = 1 << 20;
+
+pub struct Mapper;
+
+pub static S_MAPPER: Mapper = Mapper;
+
+/// A validated header and its entry array
+struct Table
+{
+	first_usable: u64,
+	last_usable: u64,
+	entry_size: usize,
+	entries: Vec<u8>,
+}
+
+/// Load the table using the header at `lba`, returning None if the header or entries are invalid
+fn load_table(pv: &PhysicalVolume, lba: u64) -> Result<Option<Table>,IoError>
+{
+	let block = try!(read_block(pv, lba));
+	if &block[..8] != SIGNATURE {
+		return Ok(None);
+	}
+	let header_size = get_u32(&block, 12) as usize;
+	if header_size < MIN_HEADER_SIZE || header_size > block.len() {
+		return Ok(None);
+	}
+	// - The header CRC is calculated with the CRC field zeroed
+	let crc = crc32( crc32( crc32(0, &block[..16]), &[0; 4] ), &block[20 .. header_size] );
+	if crc != get_u32(&block, 16) || get_u64(&block, 24) != lba {
+		return Ok(None);
+	}
+
+	let first_usable = get_u64(&block, 40);
+	let last_usable = get_u64(&block, 48);
+	let entries_lba = get_u64(&block, 72);
+	let num_entries = get_u32(&block, 80) as usize;
+	let entry_size = get_u32(&block, 84) as usize;
+	if entry_size < MIN_ENTRY_SIZE || entry_size % 8 != 0 || num_entries > MAX_ENTRIES_BYTES / entry_size {
+		return Ok(None);
+	}
+	let capacity = pv.capacity().unwrap_or(0);
+	let bytes = num_entries * entry_size;
+	let blocks = ((bytes + pv.blocksize() - 1) / pv.blocksize()) as u64;
+	if first_usable > last_usable || last_usable >= capacity || entries_lba + blocks > capacity {
+		return Ok(None);
+	}
+
+	let mut entries = Vec::with_capacity(blocks as usize * pv.blocksize());
+	for i in 0 .. blocks {
+		entries.extend_from_slice( &try!(read_block(pv, entries_lba + i)) );
+	}
+	entries.truncate(bytes);
+	if crc32(0, &entries) != get_u32(&block, 88) {
+		return Ok(None);
+	}
+
+	Ok(Some(Table {
+		first_usable: first_usable,
+		last_usable: last_usable,
+		entry_size: entry_size,
+		entries: entries,
+		}))
+}
+
+/// Load the primary table, falling back to the backup
+fn find_table(pv: &PhysicalVolume) -> Result<Option<Table>,IoError>
+{
+	if pv.blocksize() < 512 {
+		return Ok(None);
+	}
+	if let Some(t) = try!(load_table(pv, 1)) {
+		return Ok(Some(t));
+	}
+	match pv.capacity()
+	{
+	Some(cap) if cap > 2 => match try!(load_table(pv, cap - 1))
+		{
+		Some(t) => {
+			println!("warning: {}: Primary GPT is corrupt, using the backup", pv.name());
+			Ok(Some(t))
+			},
+		None => Ok(None),
+		},
+	_ => Ok(None),
+	}
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "gpt" }
+	fn handles_pv(&self, pv: &PhysicalVolume) -> Result<usize,IoError> {
+		match try!(find_table(pv))
+		{
+		Some(_) => Ok(2),
+		None => Ok(0),
+		}
+	}
+
+	fn enum_volumes(&self, pv: &PhysicalVolume, new_volume_cb: &mut FnMut(String, u64, u64)) -> Result<(),IoError> {
+		let table = match try!(find_table(pv))
+			{
+			Some(v) => v,
+			None => return Ok( () ),
+			};
+		for (i, e) in table.entries.chunks(table.entry_size).enumerate()
+		{
+			// - An all-zero type GUID marks an unused entry
+			if e[..16].iter().all(|&b| b == 0) {
+				continue ;
+			}
+			let first = get_u64(e, 32);
+			let last = get_u64(e, 40);
+			if first < table.first_usable || last > table.last_usable || last < first {
+				println!("warning: {}: Partition {} ({}-{}) is outside the usable area", pv.name(), i, first, last);
+				continue ;
+			}
+			new_volume_cb(partition_name(pv, i), first, last - first + 1);
+		}
+		Ok( () )
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use prelude::*;
+	use mylib::crc32::crc32;
+	use metadevs::storage::Mapper as StorageMapper;
+	use super::super::test_volume::{MemVolume,BLOCK_SIZE,put_u32,put_u64,volumes};
+	use super::S_MAPPER;
+
+	const BLOCKS: u64 = 64;
+	const NUM_ENTRIES: usize = 8;
+	const ENTRY_SIZE: usize = 128;
+	/// Blocks used by the entry array
+	const ENTRY_BLOCKS: u64 = (NUM_ENTRIES * ENTRY_SIZE / BLOCK_SIZE) as u64;
+
+	/// Write a header (and its entry array) with the header at `lba` and entries at `entries_lba`
+	fn write_table(pv: &mut MemVolume, lba: u64, alt_lba: u64, entries_lba: u64, entries: &[u8]) {
+		for (i, b) in entries.chunks(BLOCK_SIZE).enumerate() {
+			pv.block_mut(entries_lba + i as u64).clone_from_slice(b);
+		}
+		let h = pv.block_mut(lba);
+		h[..8].clone_from_slice(b"EFI PART");
+		put_u32(h, 8, 0x10000);
+		put_u32(h, 12, 92);
+		put_u64(h, 24, lba);
+		put_u64(h, 32, alt_lba);
+		put_u64(h, 40, 2 + ENTRY_BLOCKS);
+		put_u64(h, 48, BLOCKS - 2 - ENTRY_BLOCKS);
+		put_u64(h, 72, entries_lba);
+		put_u32(h, 80, NUM_ENTRIES as u32);
+		put_u32(h, 84, ENTRY_SIZE as u32);
+		put_u32(h, 88, crc32(0, entries));
+		put_u32(h, 16, 0);
+		let crc = crc32(0, &h[..92]);
+		put_u32(h, 16, crc);
+	}
+
+	/// Build a disk with a protective MBR and primary/backup GPTs holding `parts` (first, last)
+	fn make_disk(parts: &[(usize,u64,u64)]) -> MemVolume {
+		let mut pv = MemVolume::new(BLOCKS as usize);
+		{
+			let mbr = pv.block_mut(0);
+			mbr[446 + 4] = 0xEE;
+			put_u32(mbr, 446 + 8, 1);
+			put_u32(mbr, 446 + 12, (BLOCKS - 1) as u32);
+			mbr[510] = 0x55;
+			mbr[511] = 0xAA;
+		}
+		let mut entries = vec![0u8; NUM_ENTRIES * ENTRY_SIZE];
+		for &(idx, first, last) in parts
+		{
+			let e = &mut entries[idx * ENTRY_SIZE .. (idx+1) * ENTRY_SIZE];
+			// Linux filesystem data type GUID (only needs to be non-zero here)
+			e[..16].clone_from_slice(&[0xAF,0x3D,0xC6,0x0F, 0x83,0x84, 0x72,0x47, 0x8E,0x79, 0x3D,0x69,0xD8,0x47,0x7D,0xE4]);
+			e[16] = idx as u8 + 1;
+			put_u64(e, 32, first);
+			put_u64(e, 40, last);
+		}
+		write_table(&mut pv, 1, BLOCKS - 1, 2, &entries);
+		write_table(&mut pv, BLOCKS - 1, 1, BLOCKS - 1 - ENTRY_BLOCKS, &entries);
+		pv
+	}
+
+	#[test]
+	fn valid_table() {
+		let pv = make_disk(&[ (0, 10, 19), (3, 20, 49) ]);
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 2);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![
+			(String::from("ATA0p0"), 10, 10),
+			(String::from("ATA0p3"), 20, 30),
+			]);
+		// The protective MBR is left to this mapper
+		assert_eq!(super::super::mbr::S_MAPPER.handles_pv(&pv).unwrap(), 0);
+	}
+
+	#[test]
+	fn corrupt_primary_uses_backup() {
+		let mut pv = make_disk(&[ (1, 10, 19) ]);
+		// Header CRC mismatch
+		pv.block_mut(1)[50] ^= 1;
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 2);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![ (String::from("ATA0p1"), 10, 10) ]);
+
+		// Entry array CRC mismatch
+		let mut pv = make_disk(&[ (1, 10, 19) ]);
+		pv.block_mut(2)[ENTRY_SIZE + 33] ^= 1;
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![ (String::from("ATA0p1"), 10, 10) ]);
+	}
+
+	#[test]
+	fn corrupt_both() {
+		let mut pv = make_disk(&[ (0, 10, 19) ]);
+		pv.block_mut(1)[50] ^= 1;
+		pv.block_mut(BLOCKS - 1)[50] ^= 1;
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 0);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![]);
+	}
+
+	#[test]
+	fn entry_outside_usable_area() {
+		let pv = make_disk(&[ (0, 1, 19), (1, 20, 29), (2, 30, BLOCKS) ]);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![ (String::from("ATA0p1"), 20, 10) ]);
+	}
+}
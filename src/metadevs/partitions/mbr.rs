@@ -0,0 +1,234 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/partitions/mbr.rs
+//! MBR (DOS) partition table mapper
+//!
+//! Primary partitions are numbered by their slot (0-3), logical partitions within an extended
+//! partition are numbered from 4 in chain order.
+#[allow(unused_imports)]
+use prelude::*;
+use metadevs::storage::{self,PhysicalVolume,IoError};
+use super::{read_block,partition_name,get_u16,get_u32};
+
+const TABLE_OFS: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const SIGNATURE_OFS: usize = 510;
+const SIGNATURE: u16 = 0xAA55;
+/// Partition type of a GPT protective MBR
+const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+/// Maximum number of logical partitions followed (bounds the work done on a corrupt chain)
+const MAX_LOGICAL: usize = 128;
+
+pub struct Mapper;
+
+pub static S_MAPPER: Mapper = Mapper;
+
+/// A partition table entry
+struct Entry
+{
+	status: u8,
+	ty: u8,
+	/// First block (relative to the table's base, which depends on the table)
+	start: u64,
+	count: u64,
+}
+impl Entry
+{
+	fn is_extended(&self) -> bool {
+		self.ty == 0x05 || self.ty == 0x0F || self.ty == 0x85
+	}
+}
+
+/// Parse a table block, returning None if it doesn't have the boot signature
+fn parse_table(block: &[u8]) -> Option<[Entry; 4]>
+{
+	if block.len() < 512 || get_u16(block, SIGNATURE_OFS) != SIGNATURE {
+		return None;
+	}
+	let ent = |i: usize| {
+		let e = &block[TABLE_OFS + i * ENTRY_SIZE .. TABLE_OFS + (i+1) * ENTRY_SIZE];
+		Entry {
+			status: e[0],
+			ty: e[4],
+			start: get_u32(e, 8) as u64,
+			count: get_u32(e, 12) as u64,
+			}
+		};
+	Some([ ent(0), ent(1), ent(2), ent(3) ])
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "mbr" }
+	fn handles_pv(&self, pv: &PhysicalVolume) -> Result<usize,IoError> {
+		if pv.blocksize() < 512 {
+			return Ok(0);
+		}
+		let block = try!(read_block(pv, 0));
+		let table = match parse_table(&block)
+			{
+			Some(v) => v,
+			None => return Ok(0),
+			};
+		// - A boot sector without a partition table (e.g. a FAT VBR) also has the signature, so
+		//   check that the entries look sane
+		if table.iter().any(|e| e.status & 0x7F != 0) || table.iter().all(|e| e.ty == 0) {
+			return Ok(0);
+		}
+		// - Leave GPT disks to the GPT mapper (this MBR only covers the disk)
+		if table.iter().any(|e| e.ty == TYPE_GPT_PROTECTIVE) {
+			return Ok(0);
+		}
+		Ok(1)
+	}
+
+	fn enum_volumes(&self, pv: &PhysicalVolume, new_volume_cb: &mut FnMut(String, u64, u64)) -> Result<(),IoError> {
+		let capacity = pv.capacity().unwrap_or(0);
+		let table = match parse_table(&try!(read_block(pv, 0)))
+			{
+			Some(v) => v,
+			None => return Ok( () ),
+			};
+		let mut add_volume = |idx: usize, base: u64, count: u64| {
+			if count == 0 || base >= capacity {
+				println!("warning: {}: Partition {} ({}+{}) is outside the disk", pv.name(), idx, base, count);
+				return ;
+			}
+			// Truncated disk images are common, expose what's there
+			let count = ::core::cmp::min(count, capacity - base);
+			new_volume_cb(partition_name(pv, idx), base, count);
+			};
+
+		let mut extended = None;
+		for (i, e) in table.iter().enumerate()
+		{
+			if e.ty == 0 {
+				continue ;
+			}
+			if e.is_extended() {
+				if extended.is_none() {
+					extended = Some(e.start);
+				}
+				continue ;
+			}
+			add_volume(i, e.start, e.count);
+		}
+
+		// Logical partitions: a chain of EBRs, each with the partition (relative to the EBR) and
+		// the next EBR (relative to the start of the extended partition)
+		if let Some(ext_base) = extended
+		{
+			let mut ebr = ext_base;
+			let mut visited = Vec::new();
+			for idx in 4 .. 4 + MAX_LOGICAL
+			{
+				if ebr >= capacity {
+					break ;
+				}
+				let table = match parse_table(&try!(read_block(pv, ebr)))
+					{
+					Some(v) => v,
+					None => {
+						println!("warning: {}: Bad EBR at block {}", pv.name(), ebr);
+						break ;
+						},
+					};
+				if table[0].ty != 0 {
+					add_volume(idx, ebr + table[0].start, table[0].count);
+				}
+				if ! table[1].is_extended() {
+					break ;
+				}
+				visited.push(ebr);
+				ebr = ext_base + table[1].start;
+				if visited.contains(&ebr) {
+					println!("warning: {}: EBR chain loops at block {}", pv.name(), ebr);
+					break ;
+				}
+			}
+		}
+		Ok( () )
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use prelude::*;
+	use metadevs::storage::Mapper as StorageMapper;
+	use super::super::test_volume::{MemVolume,put_u32,volumes};
+	use super::S_MAPPER;
+
+	fn set_entry(block: &mut [u8], slot: usize, ty: u8, start: u32, count: u32) {
+		let ofs = super::TABLE_OFS + slot * super::ENTRY_SIZE;
+		block[ofs + 4] = ty;
+		put_u32(block, ofs + 8, start);
+		put_u32(block, ofs + 12, count);
+		block[510] = 0x55;
+		block[511] = 0xAA;
+	}
+
+	#[test]
+	fn primary_and_logical() {
+		let mut pv = MemVolume::new(1000);
+		{
+			let mbr = pv.block_mut(0);
+			set_entry(mbr, 0, 0x83, 2, 100);
+			set_entry(mbr, 2, 0x0C, 102, 50);
+			set_entry(mbr, 3, 0x05, 200, 800);
+		}
+		// First EBR: logical at +1, next EBR at ext+300
+		set_entry(pv.block_mut(200), 0, 0x83, 1, 99);
+		set_entry(pv.block_mut(200), 1, 0x05, 300, 200);
+		// Second EBR: logical at +2, end of chain
+		set_entry(pv.block_mut(500), 0, 0x83, 2, 100);
+
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 1);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![
+			(String::from("ATA0p0"), 2, 100),
+			(String::from("ATA0p2"), 102, 50),
+			(String::from("ATA0p4"), 201, 99),
+			(String::from("ATA0p5"), 502, 100),
+			]);
+	}
+
+	#[test]
+	fn looping_chain() {
+		let mut pv = MemVolume::new(100);
+		set_entry(pv.block_mut(0), 0, 0x05, 10, 90);
+		// Two EBRs that point at each other
+		set_entry(pv.block_mut(10), 0, 0x83, 1, 5);
+		set_entry(pv.block_mut(10), 1, 0x05, 10, 80);
+		set_entry(pv.block_mut(20), 0, 0x83, 1, 5);
+		set_entry(pv.block_mut(20), 1, 0x05, 0, 90);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![
+			(String::from("ATA0p4"), 11, 5),
+			(String::from("ATA0p5"), 21, 5),
+			]);
+	}
+
+	#[test]
+	fn rejects_non_tables() {
+		// No signature
+		let pv = MemVolume::new(10);
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 0);
+		// Signature but garbage status bytes (e.g. a boot sector)
+		let mut pv = MemVolume::new(10);
+		set_entry(pv.block_mut(0), 0, 0x83, 1, 5);
+		pv.block_mut(0)[super::TABLE_OFS] = 0x12;
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 0);
+		// Protective MBR
+		let mut pv = MemVolume::new(10);
+		set_entry(pv.block_mut(0), 0, 0xEE, 1, 9);
+		assert_eq!(S_MAPPER.handles_pv(&pv).unwrap(), 0);
+	}
+
+	#[test]
+	fn clipped_to_disk() {
+		let mut pv = MemVolume::new(50);
+		set_entry(pv.block_mut(0), 0, 0x83, 10, 100);
+		set_entry(pv.block_mut(0), 1, 0x83, 60, 10);
+		assert_eq!(volumes(&S_MAPPER, &pv), vec![ (String::from("ATA0p0"), 10, 40) ]);
+	}
+}
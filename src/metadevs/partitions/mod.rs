@@ -0,0 +1,112 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/partitions/mod.rs
+//! Partition table mappers (MBR and GPT)
+//!
+//! Each partition becomes a logical volume named after the physical volume and the partition
+//! number, e.g. `ATA0p0` for the first partition on `ATA0`.
+#[allow(unused_imports)]
+use prelude::*;
+use super::storage::{self,PhysicalVolume,IoError};
+
+mod mbr;
+mod gpt;
+
+/// Register the partition table mappers with the storage subsystem
+pub fn init()
+{
+	storage::register_mapper(&mbr::S_MAPPER);
+	storage::register_mapper(&gpt::S_MAPPER);
+}
+
+/// Logical volume name for partition `idx` of `pv`
+fn partition_name(pv: &PhysicalVolume, idx: usize) -> String
+{
+	format!("{}p{}", pv.name(), idx)
+}
+
+/// Read a single block of `pv` (partition tables are never larger than that at a time)
+fn read_block(pv: &PhysicalVolume, idx: u64) -> Result<Vec<u8>,IoError>
+{
+	let mut buf = vec![0u8; pv.blocksize()];
+	if try!(pv.read(0, idx, 1, &mut buf)) != 1 {
+		return Err(IoError::Unknown("Short read of partition table"));
+	}
+	Ok(buf)
+}
+
+fn get_u16(buf: &[u8], ofs: usize) -> u16
+{
+	buf[ofs] as u16 | (buf[ofs+1] as u16) << 8
+}
+fn get_u32(buf: &[u8], ofs: usize) -> u32
+{
+	get_u16(buf, ofs) as u32 | (get_u16(buf, ofs+2) as u32) << 16
+}
+fn get_u64(buf: &[u8], ofs: usize) -> u64
+{
+	get_u32(buf, ofs) as u64 | (get_u32(buf, ofs+4) as u64) << 32
+}
+
+/// In-memory physical volume for mapper tests
+#[cfg(test)]
+mod test_volume
+{
+	use prelude::*;
+	use super::super::storage::{PhysicalVolume,IoError};
+
+	pub const BLOCK_SIZE: usize = 512;
+
+	pub struct MemVolume
+	{
+		pub data: Vec<u8>,
+	}
+	impl MemVolume
+	{
+		pub fn new(blocks: usize) -> MemVolume {
+			MemVolume { data: vec![0u8; blocks * BLOCK_SIZE] }
+		}
+		pub fn block_mut(&mut self, idx: u64) -> &mut [u8] {
+			let ofs = idx as usize * BLOCK_SIZE;
+			&mut self.data[ofs .. ofs + BLOCK_SIZE]
+		}
+	}
+	impl PhysicalVolume for MemVolume
+	{
+		fn name(&self) -> &str { "ATA0" }
+		fn blocksize(&self) -> usize { BLOCK_SIZE }
+		fn capacity(&self) -> Option<u64> { Some( (self.data.len() / BLOCK_SIZE) as u64 ) }
+		fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,IoError> {
+			let ofs = blockidx as usize * BLOCK_SIZE;
+			if ofs + count * BLOCK_SIZE > self.data.len() {
+				return Err(IoError::BadAddr);
+			}
+			dst.clone_from_slice(&self.data[ofs .. ofs + count * BLOCK_SIZE]);
+			Ok(count)
+		}
+		fn write<'a>(&'a self, _prio: u8, _blockidx: u64, _count: usize, _src: &'a [u8]) -> Result<usize,IoError> {
+			Err(IoError::ReadOnly)
+		}
+		fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> Result<(),IoError> {
+			Ok( () )
+		}
+	}
+
+	pub fn put_u32(buf: &mut [u8], ofs: usize, v: u32) {
+		for i in 0 .. 4 {
+			buf[ofs + i] = (v >> (i * 8)) as u8;
+		}
+	}
+	pub fn put_u64(buf: &mut [u8], ofs: usize, v: u64) {
+		put_u32(buf, ofs, v as u32);
+		put_u32(buf, ofs + 4, (v >> 32) as u32);
+	}
+
+	/// Collect the volumes reported by a mapper as (name, first block, block count)
+	pub fn volumes(mapper: &::metadevs::storage::Mapper, pv: &MemVolume) -> Vec<(String,u64,u64)> {
+		let mut rv = Vec::new();
+		mapper.enum_volumes(pv, &mut |name, base, len| rv.push( (name, base, len) )).unwrap();
+		rv
+	}
+}
@@ -0,0 +1,341 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/raid.rs
+//! Multi-volume sets (concatenated, striped and mirrored logical volumes)
+//!
+//! Sets are found from a label in the last block of each member physical volume, or listed in the
+//! `RAID` boot option as `name:layout:member,member,...` (several sets separated by `;`). There
+//! `layout` is `concat`, `mirror` or `stripeN` (N = chunk size in blocks), and the members are
+//! existing single-region logical volumes such as partitions.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use mylib::LazyStatic;
+use config;
+use super::storage::{self,PhysicalVolume,IoError,Layout};
+
+// Label (little endian, last block of each member):
+//  0: Magic
+//  8: Set ID (shared by all members)
+// 16: Level (LEVEL_*)
+// 20: Member count
+// 24: Index of this member
+// 28: Chunk size in blocks (striped sets)
+// 32: Number of data blocks (starting at block 0 of the member)
+// 40: Set name (NUL padded)
+const MAGIC: &'static [u8; 8] = b"TIFRAID1";
+const LEVEL_STRIPE: u32 = 0;
+const LEVEL_MIRROR: u32 = 1;
+const LEVEL_CONCAT: u32 = 0xFFFF_FFFF;
+const NAME_OFS: usize = 40;
+const NAME_LEN: usize = 32;
+
+pub struct Mapper;
+
+pub static S_MAPPER: Mapper = Mapper;
+
+/// Set membership, as read from a member's label
+#[derive(Debug,PartialEq)]
+struct Label
+{
+	set_id: u64,
+	name: String,
+	layout: Layout,
+	members: usize,
+	index: usize,
+	data_blocks: u64,
+}
+
+/// A labelled set, waiting for its members
+struct Set
+{
+	set_id: u64,
+	name: String,
+	layout: Layout,
+	/// (physical volume, first block, block count) of each member found so far
+	members: Vec<Option<(usize,u64,u64)>>,
+	assembled: bool,
+}
+
+static mut S_SETS: LazyStatic<Mutex<Vec<Set>>> = lazystatic_init!();
+
+fn sets() -> &'static Mutex<Vec<Set>>
+{
+	// SAFE: Prepared by `init` before the mapper is registered
+	unsafe { &S_SETS }
+}
+
+/// Register the RAID label mapper
+pub fn init()
+{
+	// SAFE: Called once during startup
+	unsafe {
+		S_SETS.prep(|| Mutex::new(Vec::new()));
+	}
+	storage::register_mapper(&S_MAPPER);
+}
+
+/// Assemble what's left once the storage drivers have probed their devices
+///
+/// Labelled mirrors with missing members start degraded, striped and concatenated sets can't run
+/// without every member. Sets from the boot configuration are created here too.
+pub fn assemble_pending()
+{
+	for set in sets().lock().iter_mut().filter(|s| !s.assembled)
+	{
+		let present = set.members.iter().filter(|m| m.is_some()).count();
+		let missing = set.members.len() - present;
+		if set.layout == Layout::Mirror && present > 0 {
+			println!("warning: RAID '{}': {} of {} members missing, running degraded", set.name, missing, set.members.len());
+			assemble(set);
+		}
+		else {
+			println!("warning: RAID '{}': {} of {} members missing, not assembled", set.name, missing, set.members.len());
+		}
+	}
+
+	for ent in config::get_string(config::Value::Raid).split(';').filter(|e| !e.is_empty())
+	{
+		if let Err(e) = assemble_config(ent) {
+			println!("warning: RAID option '{}': {}", ent, e);
+		}
+	}
+}
+
+/// Create a set from one entry of the `RAID` boot option
+fn assemble_config(ent: &str) -> Result<(),&'static str>
+{
+	let (name, layout, member_names) = try!(parse_config(ent));
+	let mut members = Vec::new();
+	for m in member_names
+	{
+		match storage::lv_region(m)
+		{
+		Some(r) => members.push(r),
+		None if layout == Layout::Mirror => println!("warning: RAID '{}': Member '{}' not found, running degraded", name, m),
+		None => return Err("Member volume not found"),
+		}
+	}
+	if members.is_empty() {
+		return Err("No members found");
+	}
+	match storage::new_composite_lv(String::from(name), layout, &members)
+	{
+	Ok(_) => Ok( () ),
+	Err(IoError::InvalidParameter) => Err("Members have differing block sizes, or the name is in use"),
+	Err(IoError::Unknown(msg)) => Err(msg),
+	Err(_) => Err("Unable to create the volume"),
+	}
+}
+
+/// Split a `RAID` option entry into the name, layout and member names
+fn parse_config(ent: &str) -> Result<(&str, Layout, Vec<&str>),&'static str>
+{
+	let mut it = ent.splitn(3, ':');
+	let name = it.next().unwrap();
+	let layout = match it.next()
+		{
+		Some("concat") => Layout::Concat,
+		Some("mirror") => Layout::Mirror,
+		Some(l) if l.starts_with("stripe") => match l["stripe".len() ..].parse()
+			{
+			Ok(0) | Err(_) => return Err("Bad stripe chunk size"),
+			Ok(v) => Layout::Stripe(v),
+			},
+		_ => return Err("Expected concat, mirror or stripeN"),
+		};
+	let members: Vec<&str> = match it.next()
+		{
+		Some(m) => m.split(',').filter(|m| !m.is_empty()).collect(),
+		None => Vec::new(),
+		};
+	if name.is_empty() || members.is_empty() {
+		return Err("Expected name:layout:member,...");
+	}
+	Ok( (name, layout, members) )
+}
+
+/// Create the volume for a set from the members present
+fn assemble(set: &mut Set)
+{
+	let members: Vec<_> = set.members.iter().filter_map(|m| *m).collect();
+	match storage::new_composite_lv(set.name.clone(), set.layout, &members)
+	{
+	Ok(_) => set.assembled = true,
+	Err(e) => println!("warning: RAID '{}': Unable to assemble: {:?}", set.name, e),
+	}
+}
+
+/// Record a labelled member, assembling the set once all members are present
+fn add_member(label: Label, pv_id: usize, pv_name: &str)
+{
+	let mut sets = sets().lock();
+	let idx = match sets.iter().position(|s| s.set_id == label.set_id)
+		{
+		Some(i) => i,
+		None => {
+			sets.push(Set {
+				set_id: label.set_id,
+				name: label.name.clone(),
+				layout: label.layout,
+				members: vec![None; label.members],
+				assembled: false,
+				});
+			sets.len() - 1
+			},
+		};
+	let set = &mut sets[idx];
+	if set.layout != label.layout || set.members.len() != label.members {
+		println!("warning: RAID '{}': Label on {} doesn't match the other members", set.name, pv_name);
+		return ;
+	}
+	if set.assembled || set.members[label.index].is_some() {
+		println!("warning: RAID '{}': {} is a duplicate of member {}", set.name, pv_name, label.index);
+		return ;
+	}
+	println!("log: RAID '{}': {} is member {} of {}", set.name, pv_name, label.index, label.members);
+	set.members[label.index] = Some( (pv_id, 0, label.data_blocks) );
+	if set.members.iter().all(|m| m.is_some()) {
+		assemble(set);
+	}
+}
+
+/// Read the label from the last block of `pv`
+fn read_label(pv: &PhysicalVolume) -> Result<Option<Label>,IoError>
+{
+	let cap = match pv.capacity()
+		{
+		Some(v) if v > 1 => v,
+		_ => return Ok(None),
+		};
+	let mut buf = vec![0u8; pv.blocksize()];
+	if try!(pv.read(0, cap - 1, 1, &mut buf)) != 1 {
+		return Err(IoError::Unknown("Short read of RAID label"));
+	}
+	Ok( match parse_label(&buf)
+		{
+		Some(ref l) if l.data_blocks >= cap => None,
+		l => l,
+		} )
+}
+
+fn parse_label(buf: &[u8]) -> Option<Label>
+{
+	if &buf[0 .. 8] != &MAGIC[..] {
+		return None;
+	}
+	let members = get_u32(buf, 20) as usize;
+	let index = get_u32(buf, 24) as usize;
+	let chunk = get_u32(buf, 28) as usize;
+	let layout = match get_u32(buf, 16)
+		{
+		LEVEL_STRIPE if chunk > 0 => Layout::Stripe(chunk),
+		LEVEL_MIRROR => Layout::Mirror,
+		LEVEL_CONCAT => Layout::Concat,
+		_ => return None,
+		};
+	let name = &buf[NAME_OFS .. NAME_OFS + NAME_LEN];
+	let name = match ::core::str::from_utf8( name.split(|&b| b == 0).next().unwrap() )
+		{
+		Ok(v) if v.len() > 0 => String::from(v),
+		_ => return None,
+		};
+	let data_blocks = get_u64(buf, 32);
+	if members == 0 || index >= members || data_blocks == 0 {
+		return None;
+	}
+	Some(Label {
+		set_id: get_u64(buf, 8),
+		name: name,
+		layout: layout,
+		members: members,
+		index: index,
+		data_blocks: data_blocks,
+		})
+}
+
+fn get_u32(buf: &[u8], ofs: usize) -> u32
+{
+	(0 .. 4).fold(0, |v, i| v | (buf[ofs+i] as u32) << (i * 8))
+}
+fn get_u64(buf: &[u8], ofs: usize) -> u64
+{
+	get_u32(buf, ofs) as u64 | (get_u32(buf, ofs+4) as u64) << 32
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "raid" }
+	fn handles_pv(&self, pv: &PhysicalVolume) -> Result<usize,IoError> {
+		Ok( if try!(read_label(pv)).is_some() { 3 } else { 0 } )
+	}
+	fn enum_volumes(&self, _pv: &PhysicalVolume, _new_volume_cb: &mut FnMut(String, u64, u64)) -> Result<(),IoError> {
+		// Members aren't usable on their own, the set's volume is created by `bind_pv`
+		Ok( () )
+	}
+	fn bind_pv(&self, pv_id: usize, pv: &PhysicalVolume) -> Result<(),IoError> {
+		if let Some(label) = try!(read_label(pv)) {
+			add_member(label, pv_id, pv.name());
+		}
+		Ok( () )
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use prelude::*;
+	use super::{parse_label,parse_config,Label,MAGIC,LEVEL_STRIPE,LEVEL_MIRROR,LEVEL_CONCAT};
+	use metadevs::storage::Layout;
+
+	fn put_u32(buf: &mut [u8], ofs: usize, v: u32) {
+		for i in 0 .. 4 {
+			buf[ofs + i] = (v >> (i * 8)) as u8;
+		}
+	}
+	fn label(level: u32, members: u32, index: u32, chunk: u32) -> Vec<u8> {
+		let mut buf = vec![0u8; 512];
+		buf[0 .. 8].copy_from_slice(MAGIC);
+		put_u32(&mut buf, 8, 0x1234);
+		put_u32(&mut buf, 16, level);
+		put_u32(&mut buf, 20, members);
+		put_u32(&mut buf, 24, index);
+		put_u32(&mut buf, 28, chunk);
+		put_u32(&mut buf, 32, 1000);
+		buf[40 .. 44].copy_from_slice(b"md0\0");
+		buf
+	}
+
+	#[test]
+	fn labels() {
+		assert_eq!(parse_label(&label(LEVEL_MIRROR, 2, 1, 0)), Some(Label {
+			set_id: 0x1234,
+			name: String::from("md0"),
+			layout: Layout::Mirror,
+			members: 2,
+			index: 1,
+			data_blocks: 1000,
+			}));
+		assert_eq!(parse_label(&label(LEVEL_STRIPE, 3, 0, 16)).unwrap().layout, Layout::Stripe(16));
+		assert_eq!(parse_label(&label(LEVEL_CONCAT, 2, 0, 0)).unwrap().layout, Layout::Concat);
+		// Bad chunk size, bad member index, unknown level, no magic
+		assert!(parse_label(&label(LEVEL_STRIPE, 3, 0, 0)).is_none());
+		assert!(parse_label(&label(LEVEL_MIRROR, 2, 2, 0)).is_none());
+		assert!(parse_label(&label(5, 2, 0, 0)).is_none());
+		assert!(parse_label(&vec![0u8; 512]).is_none());
+	}
+
+	#[test]
+	fn config() {
+		assert_eq!(parse_config("md0:mirror:ATA0p1,ATA1p1"), Ok( ("md0", Layout::Mirror, vec!["ATA0p1", "ATA1p1"]) ));
+		assert_eq!(parse_config("big:stripe32:ATA0w,ATA1w,ATA2w"), Ok( ("big", Layout::Stripe(32), vec!["ATA0w", "ATA1w", "ATA2w"]) ));
+		assert_eq!(parse_config("cat:concat:ATA0p1"), Ok( ("cat", Layout::Concat, vec!["ATA0p1"]) ));
+		assert!(parse_config("md0:stripe0:ATA0w").is_err());
+		assert!(parse_config("md0:raid5:ATA0w").is_err());
+		assert!(parse_config("md0:mirror").is_err());
+		assert!(parse_config(":mirror:ATA0w").is_err());
+	}
+}
+
+// vim: ft=rust
@@ -0,0 +1,170 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/ramdisk.rs
+//! RAM-backed physical volumes
+//!
+//! Disks are created zeroed with a fixed size (`create`, or the `RAMDISK` boot option listing
+//! sizes in KiB separated by commas), or from a multiboot module whose command line starts with
+//! `ramdisk`. Module disks use the module's memory in place instead of copying it.
+#[allow(unused_imports)]
+use prelude::*;
+use core::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+use spin::Mutex;
+use memory::{Frame,EntryFlags,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use config;
+use super::storage::{self,PhysicalVolume,PhysicalVolumeReg,IoError};
+
+const BLOCK_SIZE: usize = 512;
+
+/// A RAM disk
+struct RamDisk
+{
+	name: String,
+	mem: KernelMapping,
+	/// Offset of the first block within `mem` (module disks need not start on a page)
+	ofs: usize,
+	blocks: u64,
+	/// Serialises access to the memory
+	lock: Mutex<()>,
+}
+
+/// A module passed by the bootloader (physical address range)
+struct BootModule
+{
+	start: usize,
+	end: usize,
+	name: String,
+}
+static S_MODULES: Mutex<Option<Vec<BootModule>>> = Mutex::new(None);
+/// Registrations of the created disks (RAM disks are never removed)
+static S_DISKS: Mutex<Option<Vec<PhysicalVolumeReg>>> = Mutex::new(None);
+static S_NEXT_INDEX: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Record a boot module to use as a RAM disk (called from `rust_main` before storage is up)
+pub fn add_module(start: usize, end: usize, name: &str)
+{
+	S_MODULES.lock().get_or_insert_with(Vec::new).push(BootModule {
+		start: start,
+		end: end,
+		name: String::from(name),
+		});
+}
+
+/// Create the RAM disks requested by boot modules and the boot configuration
+pub fn init()
+{
+	let modules = S_MODULES.lock().take().unwrap_or_default();
+	for m in modules
+	{
+		if let Err(e) = create_from_module(&m) {
+			println!("warning: ramdisk: Module '{}' not used: {}", m.name, e);
+		}
+	}
+
+	for size in config::get_string(config::Value::RamDisk).split(',').filter(|s| !s.is_empty())
+	{
+		match size.parse::<u64>()
+		{
+		Ok(kib) if kib > 0 => if create(kib * 1024 / BLOCK_SIZE as u64).is_none() {
+			println!("warning: ramdisk: Unable to allocate {} KiB", kib);
+			},
+		_ => println!("warning: ramdisk: Bad size '{}' (expected KiB)", size),
+		}
+	}
+}
+
+/// Create a zeroed RAM disk of `blocks` blocks, returning its name
+pub fn create(blocks: u64) -> Option<String>
+{
+	if blocks == 0 {
+		return None;
+	}
+	let bytes = blocks as usize * BLOCK_SIZE;
+	let mem = match kmap::alloc_mapped( (bytes + PAGE_SIZE - 1) / PAGE_SIZE )
+		{
+		Some(v) => v,
+		None => return None,
+		};
+	Some( register(mem, 0, blocks) )
+}
+
+fn create_from_module(m: &BootModule) -> Result<String,&'static str>
+{
+	let page_ofs = m.start % PAGE_SIZE;
+	let len = m.end - m.start;
+	if len < BLOCK_SIZE {
+		return Err("Smaller than a block");
+	}
+	if len % BLOCK_SIZE != 0 {
+		println!("warning: ramdisk: Module '{}' is not a whole number of blocks, the tail is ignored", m.name);
+	}
+	let pages = (page_ofs + len + PAGE_SIZE - 1) / PAGE_SIZE;
+	let mem = match kmap::map_frames(Frame::containing_address(m.start), pages, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)
+		{
+		Some(v) => v,
+		None => return Err("Unable to map"),
+		};
+	Ok( register(mem, page_ofs, (len / BLOCK_SIZE) as u64) )
+}
+
+fn register(mem: KernelMapping, ofs: usize, blocks: u64) -> String
+{
+	let name = format!("RAM{}", S_NEXT_INDEX.fetch_add(1, Ordering::Relaxed));
+	println!("log: ramdisk: {} {}", name, storage::SizePrinter(blocks * BLOCK_SIZE as u64));
+	let reg = storage::register_pv(Box::new(RamDisk {
+		name: name.clone(),
+		mem: mem,
+		ofs: ofs,
+		blocks: blocks,
+		lock: Mutex::new(()),
+		}));
+	S_DISKS.lock().get_or_insert_with(Vec::new).push(reg);
+	name
+}
+
+impl RamDisk
+{
+	/// Byte range of `count` blocks starting at `idx`
+	fn range(&self, idx: u64, count: usize) -> Result<(usize,usize),IoError> {
+		if idx > self.blocks || count as u64 > self.blocks - idx {
+			return Err( IoError::BadAddr );
+		}
+		let start = self.ofs + idx as usize * BLOCK_SIZE;
+		Ok( (start, start + count * BLOCK_SIZE) )
+	}
+}
+
+impl PhysicalVolume for RamDisk
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> usize { BLOCK_SIZE }
+	fn capacity(&self) -> Option<u64> { Some(self.blocks) }
+
+	fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,IoError> {
+		let (start, end) = try!(self.range(blockidx, count));
+		let _lh = self.lock.lock();
+		// SAFE: The memory belongs to this disk, and access is serialised by `lock`
+		dst[.. end - start].copy_from_slice( unsafe { &self.mem.as_bytes()[start .. end] } );
+		Ok(count)
+	}
+	fn write<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, src: &'a [u8]) -> Result<usize,IoError> {
+		let (start, end) = try!(self.range(blockidx, count));
+		let _lh = self.lock.lock();
+		// SAFE: The memory belongs to this disk, and access is serialised by `lock`
+		unsafe { self.mem.as_bytes_mut()[start .. end].copy_from_slice( &src[.. end - start] ); }
+		Ok(count)
+	}
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> Result<(),IoError> {
+		let (start, end) = try!(self.range(blockidx, count));
+		let _lh = self.lock.lock();
+		// SAFE: The memory belongs to this disk, and access is serialised by `lock`
+		for b in unsafe { &mut self.mem.as_bytes_mut()[start .. end] } {
+			*b = 0;
+		}
+		Ok( () )
+	}
+}
+
+// vim: ft=rust
@@ -4,12 +4,15 @@
 // Core/metadevs/storage.rs
 // - Storage (block device) subsystem
 use prelude::*;
-use core::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT};
+use core::sync::atomic::{AtomicUsize,AtomicBool,ATOMIC_USIZE_INIT,Ordering};
 //use sync::mutex::LazyMutex;
 use mylib::{VecMap,LazyStatic};
 use mylib::mem::Arc;
 use ata;
 use spin::Mutex;
+use super::block_cache::BlockCache;
+
+pub use super::block_cache::CacheStats;
 
 
 //module_define!{Storage, [], init}
@@ -20,13 +23,12 @@ use spin::Mutex;
 pub struct VolumeHandle
 {
 	handle: ::mylib::mem::Arc<LogicalVolume>,
-	// TODO: Store within this a single block cache? Or store on the LV?
 }
 
 /// Physical volume registration (PV will be deregistered when this handle is dropped)
-/// 
-// TODO: What is the behavior when this PV still has LVs (open LVs too?). Just waiting will not
-// be the correct behavior.
+///
+/// Dropping it removes the LVs using the PV (open handles to them get `NoMedium`) and waits for
+/// transfers already running on the device to finish.
 pub struct PhysicalVolumeReg
 {
 	idx: usize,
@@ -111,6 +113,12 @@ pub trait PhysicalVolume: Send + 'static
 	/// Erases (requests the underlying storage forget about) `count` blocks starting at `blockidx`.
 	/// This is functionally equivalent to the SSD "TRIM" command.
 	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> Result<(),IoError>;
+	/// Commits data written to the volume to stable storage
+	///
+	/// Only needed for devices with a volatile write cache, the default does nothing.
+	fn flush(&self) -> Result<(),IoError> {
+		Ok( () )
+	}
 }
 
 /// Registration for a physical volume handling driver
@@ -126,6 +134,30 @@ pub trait Mapper: Send + Sync
 	
 	/// Enumerate volumes
 	fn enum_volumes(&self, pv: &PhysicalVolume, f: &mut FnMut(String, u64, u64)) -> Result<(),IoError>;
+	/// Called with the volume's index once it has been registered and its volumes enumerated
+	///
+	/// Mappers for sets spanning several physical volumes (e.g. RAID) use this to collect the
+	/// members, see `new_composite_lv`. The default does nothing.
+	fn bind_pv(&self, _pv_id: usize, _pv: &PhysicalVolume) -> Result<(),IoError> {
+		Ok( () )
+	}
+}
+
+/// Arrangement of the regions of a logical volume
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Layout
+{
+	/// Regions follow each other (JBOD)
+	Concat,
+	/// Striped (RAID0) with the given chunk size in blocks
+	Stripe(usize),
+	/// Every region holds a copy of the data (RAID1)
+	Mirror,
+}
+impl Default for Layout {
+	fn default() -> Layout {
+		Layout::Concat
+	}
 }
 
 
@@ -134,6 +166,12 @@ struct PhysicalVolumeInfo
 {
 	dev: Box<PhysicalVolume>,
 	mapper: Option<(usize,&'static Mapper)>,
+	/// Blocks the device has reported as bad
+	bad_blocks: Mutex<Vec<u64>>,
+	/// Set by `remove_pv`, no new transfers are started after this
+	removed: AtomicBool,
+	/// Number of `PvRef`s outstanding (transfers running on the device)
+	in_flight: AtomicUsize,
 }
 unsafe impl Send for PhysicalVolumeInfo {}
 unsafe impl Sync for PhysicalVolumeInfo {}
@@ -147,12 +185,18 @@ struct LogicalVolume
 	name: String,
 	/// If true, a VolumeHandle exists for this volume
 	is_opened: bool,
+	/// Used by a composite volume, so it can't be opened (writes would bypass the composite layout)
+	claimed: AtomicBool,
 	/// Logical block size (max physical block size)
 	block_size: usize,
-	/// Stripe size (number of blocks), None = JBOD
-	chunk_size: Option<usize>,
+	/// How the regions are combined
+	layout: Layout,
 	/// Physical regions that compose this logical volume
 	regions: Vec<PhysicalRegion>,
+	/// Mirror leg to try first for the next read (rotated to spread reads over the legs)
+	next_leg: AtomicUsize,
+	/// Cached blocks (write-back, shared by all users of the volume)
+	cache: Mutex<BlockCache>,
 }
 /// Physical region used by a logical volume
 struct PhysicalRegion
@@ -160,10 +204,39 @@ struct PhysicalRegion
 	volume: usize,
 	block_count: usize,	// usize to save space in average case
 	first_block: u64,
+	/// Set when a mirror leg returns an error, the leg is no longer used after that
+	failed: AtomicBool,
+}
+impl PhysicalRegion
+{
+	fn new(volume: usize, first_block: u64, block_count: u64) -> PhysicalRegion {
+		assert!(block_count <= !0usize as u64);
+		PhysicalRegion { volume: volume, block_count: block_count as usize, first_block: first_block, failed: AtomicBool::new(false) }
+	}
+}
+
+/// Access to the physical volumes under a logical volume (replaced by in-memory volumes in tests)
+trait PvAccess
+{
+	fn read(&self, pv: usize, first: u64, dst: &mut [u8]) -> Result<(),IoError>;
+	fn write(&self, pv: usize, first: u64, src: &[u8]) -> Result<(),IoError>;
+	fn flush(&self, pv: usize) -> Result<(),IoError>;
 }
+/// The physical volumes registered with the storage subsystem
+struct RegisteredPvs;
+/// A physical volume in use by a transfer (keeps `remove_pv` from freeing it)
+struct PvRef(&'static PhysicalVolumeInfo);
+
+/// Number of times a transfer that timed out is retried
+const TIMEOUT_RETRIES: usize = 3;
+/// Timer ticks to wait before the first retry of a timed out transfer (doubled for each later retry)
+const RETRY_BACKOFF: usize = 5;
 
 static S_NEXT_PV_IDX: AtomicUsize = ATOMIC_USIZE_INIT;
-static mut S_PHYSICAL_VOLUMES: LazyStatic<VecMap<usize,PhysicalVolumeInfo>> = lazystatic_init!();
+// Boxed so entries don't move when the map is changed under a running transfer
+static mut S_PHYSICAL_VOLUMES: LazyStatic<VecMap<usize,Box<PhysicalVolumeInfo>>> = lazystatic_init!();
+/// Held while looking up a PV for a transfer, or while removing one
+static S_PV_LOOKUP_LOCK: Mutex<()> = Mutex::new( () );
 static S_NEXT_LV_IDX: AtomicUsize = ATOMIC_USIZE_INIT;
 static mut S_LOGICAL_VOLUMES: LazyStatic<VecMap<usize,Arc<LogicalVolume>>> = lazystatic_init!();
 static mut S_MAPPERS: LazyStatic<Vec<&'static Mapper>> = lazystatic_init!();
@@ -222,10 +295,13 @@ pub fn register_pv(dev: Box<PhysicalVolume>) -> PhysicalVolumeReg
 	}
 	
 	// Wait until after checking for a handler before we add the PV to the list
-	unsafe{S_PHYSICAL_VOLUMES.ls_unsafe_mut().insert(pv_id, PhysicalVolumeInfo {
+	unsafe{S_PHYSICAL_VOLUMES.ls_unsafe_mut().insert(pv_id, Box::new(PhysicalVolumeInfo {
 		dev: dev,
 		mapper: None,
-		});}
+		bad_blocks: Mutex::new(Vec::new()),
+		removed: AtomicBool::new(false),
+		in_flight: AtomicUsize::new(0),
+		}));}
 	
 	if let Some(mapper) = best_mapper {
 		apply_mapper_to_pv(mapper, best_mapper_level, pv_id, unsafe{S_PHYSICAL_VOLUMES.ls_unsafe_mut().get_mut(&pv_id).unwrap()})
@@ -328,19 +404,24 @@ fn apply_mapper_to_pv(mapper: &'static Mapper, level: usize, pv_id: usize, pvi:
 	Err(e) => {},//log_error!("IO Error while enumerating {}: {:?}", pvi.dev.name(), e),
 	Ok(_) => {},
 	}
+	if let Err(e) = mapper.bind_pv(pv_id, &*pvi.dev) {
+		println!("warning: Mapper '{}' failed to bind {}: {:?}", mapper.name(), pvi.dev.name(), e);
+	}
 }
 fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size: u64)
 {
 	let lvidx = S_NEXT_LV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
 	
-	assert!(size <= !0usize as u64);
 	let lv = Arc::new( LogicalVolume {
 		index: lvidx,
 		name: name,
 		is_opened: false,
+		claimed: AtomicBool::new(false),
 		block_size: block_size,
-		chunk_size: None,
-		regions: vec![ PhysicalRegion{ volume: pv_id, block_count: size as usize, first_block: base } ],
+		layout: Layout::Concat,
+		regions: vec![ PhysicalRegion::new(pv_id, base, size) ],
+		next_leg: AtomicUsize::new(0),
+		cache: Default::default(),
 		} );
 	
 	//log_log!("Logical Volume: {} {}", lv.name, SizePrinter(size*block_size as u64));
@@ -353,6 +434,158 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 	// TODO: Inform something of the new LV
 }
 
+/// Create a logical volume from regions of several physical volumes
+///
+/// `members` lists the regions as (physical volume, first block, block count) in set order, they
+/// must all have the same block size.
+pub fn new_composite_lv(name: String, layout: Layout, members: &[(usize,u64,u64)]) -> Result<(),IoError>
+{
+	if members.is_empty() || layout == Layout::Stripe(0) {
+		return Err( IoError::InvalidParameter );
+	}
+	if unsafe{S_LOGICAL_VOLUMES.iter()}.any(|(_,lv)| lv.name == name) {
+		return Err( IoError::InvalidParameter );
+	}
+	let mut block_size = 0;
+	for &(pv, first, count) in members
+	{
+		let dev = match unsafe{S_PHYSICAL_VOLUMES.get(&pv)}
+			{
+			Some(v) => &v.dev,
+			None => return Err( IoError::BadAddr ),
+			};
+		if block_size != 0 && dev.blocksize() != block_size {
+			return Err( IoError::InvalidParameter );
+		}
+		block_size = dev.blocksize();
+		if first + count > dev.capacity().unwrap_or(0) {
+			return Err( IoError::BadAddr );
+		}
+	}
+	// Claim the volumes covering the members (e.g. partitions), they can't be used directly any more
+	let claimed: Vec<&Arc<LogicalVolume>> = unsafe{S_LOGICAL_VOLUMES.iter()}
+		.map(|(_,lv)| lv)
+		.filter(|lv| members.iter().any(|&(pv, first, count)| lv.uses_blocks(pv, first, count)))
+		.collect();
+	// - The registry holds one reference, any other is an open handle
+	if claimed.iter().any(|lv| Arc::strong_count(lv) > 1) {
+		return Err( IoError::Unknown("Member volume is open") );
+	}
+	for lv in claimed {
+		lv.claimed.store(true, Ordering::SeqCst);
+	}
+	
+	let lvidx = S_NEXT_LV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
+	let lv = Arc::new( LogicalVolume {
+		index: lvidx,
+		name: name,
+		is_opened: false,
+		claimed: AtomicBool::new(false),
+		block_size: block_size,
+		layout: layout,
+		regions: members.iter().map(|&(pv, first, count)| PhysicalRegion::new(pv, first, count)).collect(),
+		next_leg: AtomicUsize::new(0),
+		cache: Default::default(),
+		} );
+	println!("log: Logical Volume: {} {} ({:?}, {} regions)", lv.name, SizePrinter(lv.block_count() * block_size as u64), layout, members.len());
+	unsafe{S_LOGICAL_VOLUMES.ls_unsafe_mut().insert(lvidx, lv);}
+	Ok( () )
+}
+
+/// Returns the physical region (volume, first block, block count) behind a single-region logical volume
+pub fn lv_region(name: &str) -> Option<(usize,u64,u64)>
+{
+	match unsafe{S_LOGICAL_VOLUMES.iter()}.find(|&(_,lv)| lv.name == name)
+	{
+	Some((_,lv)) if lv.regions.len() == 1 => {
+		let r = &lv.regions[0];
+		Some( (r.volume, r.first_block, r.block_count as u64) )
+		},
+	_ => None,
+	}
+}
+
+/// Write back the cached blocks of every logical volume
+pub fn sync_all()
+{
+	for (_,lv) in unsafe{S_LOGICAL_VOLUMES.iter()}
+	{
+		if let Err(e) = lv.sync() {
+			println!("warning: Sync of LV '{}' failed: {:?}", lv.name, e);
+		}
+	}
+}
+
+/// Write back the cached blocks of the logical volume `name`
+///
+/// Closing a `VolumeHandle` leaves dirty blocks in the cache (for the flush daemon), so callers
+/// releasing a volume for good (e.g. unmount) sync it first. Unregistered volumes (e.g. the
+/// storage-less ones from `VolumeHandle::new_ramdisk(0)`) have nothing to write.
+pub fn sync_named(name: &str) -> Result<(),IoError>
+{
+	match unsafe{S_LOGICAL_VOLUMES.iter()}.find(|&(_, ref v)| v.name == name)
+	{
+	Some((_,lv)) => lv.sync(),
+	None => Ok( () ),
+	}
+}
+
+/// Remove a physical volume (called when its registration is dropped, e.g. on hot removal)
+///
+/// Logical volumes using it are removed, handles still open to them get `NoMedium` errors from
+/// then on. Mirrors with other working legs stay, running degraded. Returns once transfers that
+/// were already running on the device have finished, as the driver frees it after this.
+fn remove_pv(pv_id: usize)
+{
+	let pvi = {
+		let _lh = S_PV_LOOKUP_LOCK.lock();
+		match unsafe{S_PHYSICAL_VOLUMES.ls_unsafe_mut().remove(&pv_id)}
+		{
+		Some(v) => { v.removed.store(true, Ordering::SeqCst); v },
+		None => return,
+		}
+		};
+	println!("log: Removing physical volume {}", pvi.dev.name());
+	let keys: Vec<usize> = unsafe{S_LOGICAL_VOLUMES.iter()}
+		.filter( |&(_,lv)| lv.regions.iter().any(|r| r.volume == pv_id) )
+		.map(|(&i,_)| i)
+		.collect();
+	for k in keys
+	{
+		let keep = {
+			let lv = unsafe{S_LOGICAL_VOLUMES.get(&k).unwrap()};
+			let other_legs = lv.regions.iter().any(|r| r.volume != pv_id && !r.failed.load(Ordering::Relaxed));
+			if lv.layout == Layout::Mirror && other_legs {
+				for r in lv.regions.iter().filter(|r| r.volume == pv_id) {
+					lv.fail_leg(r, IoError::NoMedium);
+				}
+				true
+			}
+			else {
+				false
+			}
+			};
+		if !keep {
+			if let Some(lv) = unsafe{S_LOGICAL_VOLUMES.ls_unsafe_mut().remove(&k)} {
+				println!("log: Logical Volume {} removed", lv.name);
+			}
+		}
+	}
+	while pvi.in_flight.load(Ordering::SeqCst) > 0 {
+		::process::sleep(1);
+	}
+}
+
+/// Blocks of a physical volume that the device has reported as bad
+pub fn bad_blocks(pv_id: usize) -> Vec<u64>
+{
+	match unsafe{S_PHYSICAL_VOLUMES.get(&pv_id)}
+	{
+	Some(v) => v.bad_blocks.lock().clone(),
+	None => Vec::new(),
+	}
+}
+
 /// Enumerate present physical volumes (returning both the identifier and name)
 pub fn enum_pvs() -> Vec<(usize,String)>
 {
@@ -385,9 +618,26 @@ impl_fmt!{
 
 impl VolumeHandle
 {
-	pub fn new_ramdisk(_count: usize) -> VolumeHandle {
-		VolumeHandle {
-			handle: Arc::new(LogicalVolume::default())
+	/// Create a RAM disk of `count` blocks and open the volume covering it
+	///
+	/// A count of zero gives a volume with no storage, for filesystems that don't use their
+	/// volume (e.g. devfs). Panics if the memory can't be allocated.
+	pub fn new_ramdisk(count: usize) -> VolumeHandle {
+		if count == 0 {
+			return VolumeHandle {
+				handle: Arc::new(LogicalVolume::default())
+			};
+		}
+		let name = match super::ramdisk::create(count as u64)
+			{
+			Some(v) => v,
+			None => panic!("Unable to allocate a {} block RAM disk", count),
+			};
+		// A new (zeroed) disk is always exposed whole by the fallback mapper
+		match VolumeHandle::open_named(&format!("{}w", name))
+		{
+		Ok(v) => v,
+		Err(e) => panic!("BUG - Opening new RAM disk {} failed: {}", name, e),
 		}
 	}
 	/// Acquire an unique handle to a logical volume
@@ -404,7 +654,10 @@ impl VolumeHandle
 		match unsafe{S_LOGICAL_VOLUMES.ls_unsafe_mut().iter_mut().find(|&(_, ref v)| v.name == name)}
 		{
 		Some((_,v)) => {
-			if Arc::get_mut(v).is_some() {
+			if v.claimed.load(Ordering::SeqCst) {
+				Err( VolOpenError::Locked )
+			}
+			else if Arc::get_mut(v).is_some() {
 				Ok( VolumeHandle { handle: v.clone() } )
 			}
 			else {
@@ -418,6 +671,10 @@ impl VolumeHandle
 	pub fn block_size(&self) -> usize {
 		self.handle.block_size
 	}
+	/// Number of blocks in the volume
+	pub fn block_count(&self) -> u64 {
+		self.handle.block_count()
+	}
 
 	pub fn idx(&self) -> usize {
 		self.handle.index
@@ -426,93 +683,335 @@ impl VolumeHandle
 		&self.handle.name
 	}
 	
-	// TODO: Return a more complex type that can be incremented
-	// Returns: VolIdx, Block, Count
-	fn get_phys_block(&self, idx: u64, count: usize) -> Option<(usize,u64,usize)> {
-		if let Some(size) = self.handle.chunk_size
+	/// Read a series of blocks from the volume into the provided buffer.
+	/// 
+	/// The buffer must be a multiple of the logical block size
+	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		//log_trace!("VolumeHandle::read_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		let lv = &*self.handle;
+		let bs = self.block_size();
+		if dst.len() % bs != 0 {
+			//log_warning!("Read size {} not a multiple of {} bytes", dst.len(), bs);
+			return Err( IoError::InvalidParameter );
+		}
+		
+		let mut cache = lv.cache.lock();
+		if cache.capacity() == 0 {
+			return lv.read_uncached(idx, dst);
+		}
+		let count = dst.len() / bs;
+		let mut i = 0;
+		while i < count
+		{
+			if let Some(data) = cache.get(idx + i as u64) {
+				dst[i*bs .. (i+1)*bs].copy_from_slice(data);
+				i += 1;
+				continue ;
+			}
+			// Read the whole run of uncached blocks in one request
+			let start = i;
+			i += 1;
+			while i < count && !cache.contains(idx + i as u64) {
+				i += 1;
+			}
+			let run = &mut dst[start*bs .. i*bs];
+			try!( lv.read_uncached(idx + start as u64, run) );
+			for (j,blk) in run.chunks(bs).enumerate() {
+				try!( cache.fill(idx + (start + j) as u64, blk, &mut |b, d| lv.write_uncached(b, d)) );
+			}
+		}
+		Ok( () )
+	}
+
+	/// Write a series of blocks to the volume
+	///
+	/// Writes are held in the volume's block cache until evicted or synced. Writes larger than the
+	/// cache go straight to the volume.
+	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		//log_trace!("VolumeHandle::write_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		let lv = &*self.handle;
+		let bs = self.block_size();
+		if dst.len() % bs != 0 {
+			//log_warning!("Write size {} not a multiple of {} bytes", dst.len(), bs);
+			return Err( IoError::InvalidParameter );
+		}
+		
+		let mut cache = lv.cache.lock();
+		let count = dst.len() / bs;
+		if count > cache.capacity() {
+			for i in 0 .. count {
+				cache.discard(idx + i as u64);
+			}
+			return lv.write_uncached(idx, dst);
+		}
+		for (i,blk) in dst.chunks(bs).enumerate() {
+			try!( cache.write(idx + i as u64, blk, &mut |b, d| lv.write_uncached(b, d)) );
+		}
+		Ok( () )
+	}
+
+	/// Write back all cached blocks for this volume
+	pub fn sync(&self) -> Result<(),IoError> {
+		self.handle.sync()
+	}
+	/// Set the number of blocks cached for this volume (0 disables the cache)
+	pub fn set_cache_capacity(&self, blocks: usize) -> Result<(),IoError> {
+		let lv = &*self.handle;
+		lv.cache.lock().set_capacity(blocks, &mut |b, d| lv.write_uncached(b, d))
+	}
+	/// Block cache statistics for this volume
+	pub fn cache_stats(&self) -> CacheStats {
+		self.handle.cache.lock().stats()
+	}
+}
+
+impl LogicalVolume
+{
+	/// Returns true if any of `count` blocks from `first` on physical volume `pv` are in this volume
+	fn uses_blocks(&self, pv: usize, first: u64, count: u64) -> bool {
+		self.regions.iter().any(|r| r.volume == pv && r.first_block < first + count && first < r.first_block + r.block_count as u64)
+	}
+	/// Number of usable blocks (striping ignores any partial stripe at the end of the regions)
+	fn block_count(&self) -> u64 {
+		let min = self.regions.iter().map(|r| r.block_count as u64).min().unwrap_or(0);
+		match self.layout
 		{
-			todo!("Non JBOD logocal volumes ({} block stripe)", size);
+		Layout::Concat => self.regions.iter().map(|r| r.block_count as u64).sum(),
+		Layout::Stripe(chunk) => min / chunk as u64 * chunk as u64 * self.regions.len() as u64,
+		Layout::Mirror => min,
 		}
-		else
+	}
+
+	// TODO: Return a more complex type that can be incremented
+	// Returns: Region, Block within the region, Count
+	//
+	// Mirrors always return region 0, the caller picks the leg(s).
+	fn get_phys_block(&self, idx: u64, count: usize) -> Option<(usize,u64,usize)> {
+		match self.layout
 		{
+		Layout::Concat => {
 			let mut idx_rem = idx;
-			for v in self.handle.regions.iter()
+			for (i,v) in self.regions.iter().enumerate()
 			{
 				if idx_rem < v.block_count as u64 {
 					let ret_count = ::core::cmp::min(
 						v.block_count as u64 - idx_rem,
 						count as u64
 						) as usize;
-					return Some( (v.volume, v.first_block + idx_rem, ret_count) );
+					return Some( (i, idx_rem, ret_count) );
 				}
 				else {
 					idx_rem -= v.block_count as u64;
 				}
 			}
+			None
+			},
+		Layout::Stripe(chunk) => {
+			if idx >= self.block_count() {
+				return None;
+			}
+			let chunk = chunk as u64;
+			let n = self.regions.len() as u64;
+			let stripe = idx / chunk;
+			let ofs = idx % chunk;
+			let ret_count = ::core::cmp::min(chunk - ofs, count as u64) as usize;
+			Some( ((stripe % n) as usize, stripe / n * chunk + ofs, ret_count) )
+			},
+		Layout::Mirror => {
+			let size = self.block_count();
+			if idx >= size {
+				return None;
+			}
+			Some( (0, idx, ::core::cmp::min(size - idx, count as u64) as usize) )
+			},
 		}
-		None
 	}
 	
-	/// Read a series of blocks from the volume into the provided buffer.
-	/// 
-	/// The buffer must be a multiple of the logical block size
-	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
-		//log_trace!("VolumeHandle::read_blocks(idx={}, dst={{len={}}})", idx, dst.len());
-		if dst.len() % self.block_size() != 0 {
-			//log_warning!("Read size {} not a multiple of {} bytes", dst.len(), self.block_size());
-			return Err( IoError::InvalidParameter );
+	/// Write back all dirty cached blocks, then flush the physical volumes' write caches
+	fn sync(&self) -> Result<(),IoError> {
+		try!(self.cache.lock().flush(&mut |b, d| self.write_uncached(b, d)));
+		self.flush_via(&RegisteredPvs)
+	}
+	fn flush_via(&self, pvs: &PvAccess) -> Result<(),IoError> {
+		let mut flushed = Vec::new();
+		for r in self.regions.iter()
+		{
+			if flushed.contains(&r.volume) || r.failed.load(Ordering::Relaxed) {
+				continue ;
+			}
+			match pvs.flush(r.volume)
+			{
+			Ok(()) => {},
+			Err(e) if self.layout == Layout::Mirror => self.fail_leg(r, e),
+			Err(e) => return Err(e),
+			}
+			flushed.push(r.volume);
 		}
-		
-		let mut rem = dst.len() / self.block_size();
+		if self.layout == Layout::Mirror && self.regions.iter().all(|r| r.failed.load(Ordering::Relaxed)) {
+			return Err( IoError::NoMedium );
+		}
+		Ok( () )
+	}
+	
+	/// Read blocks directly from the physical volumes, bypassing the cache
+	fn read_uncached(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		self.read_via(&RegisteredPvs, idx, dst)
+	}
+	fn read_via(&self, pvs: &PvAccess, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		let mut rem = dst.len() / self.block_size;
 		let mut blk = 0;
 		while rem > 0
 		{
-			let (pv, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
+			let (r, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
 				Some(v) => v,
 				None => {
-					//log_warning!("VolumeHandle::read_blocks - Block id {} is invalid", idx + blk as u64);
+					//log_warning!("LogicalVolume::read_uncached - Block id {} is invalid", idx + blk as u64);
 					return Err( IoError::BadAddr )
 					},
 				};
-			//log_trace!("- PV{} {} + {}", pv, ofs, count);
+			//log_trace!("- R{} {} + {}", r, ofs, count);
 			assert!(count <= rem);
-			let bofs = blk as usize * self.block_size();
-			let dst = &mut dst[bofs .. bofs + count * self.block_size()];
-			try!( unsafe{S_PHYSICAL_VOLUMES.get(&pv).expect("Volume missing").read(ofs, dst)} );
+			let bofs = blk as usize * self.block_size;
+			let dst = &mut dst[bofs .. bofs + count * self.block_size];
+			if self.layout == Layout::Mirror {
+				try!( self.read_mirror(pvs, ofs, dst) );
+			}
+			else {
+				let region = &self.regions[r];
+				try!( pvs.read(region.volume, region.first_block + ofs, dst) );
+			}
 			blk += count;
 			rem -= count;
 		}
 		Ok( () )
 	}
 
-	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
-		//log_trace!("VolumeHandle::write_blocks(idx={}, dst={{len={}}})", idx, dst.len());
-		if dst.len() % self.block_size() != 0 {
-			//log_warning!("Write size {} not a multiple of {} bytes", dst.len(), self.block_size());
-			return Err( IoError::InvalidParameter );
-		}
-		
-		let mut rem = dst.len() / self.block_size();
+	/// Write blocks directly to the physical volumes, bypassing the cache
+	fn write_uncached(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		self.write_via(&RegisteredPvs, idx, dst)
+	}
+	fn write_via(&self, pvs: &PvAccess, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		let mut rem = dst.len() / self.block_size;
 		let mut blk = 0;
 		while rem > 0
 		{
-			let (pv, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
+			let (r, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
 				Some(v) => v,
 				None => {
-					//log_warning!("VolumeHandle::write_blocks - Block id {} is invalid", idx + blk as u64);
+					//log_warning!("LogicalVolume::write_uncached - Block id {} is invalid", idx + blk as u64);
 					return Err( IoError::BadAddr )
 					},
 				};
-			//log_trace!("- PV{} {} + {}", pv, ofs, count);
+			//log_trace!("- R{} {} + {}", r, ofs, count);
 			assert!(count <= rem);
-			let bofs = blk as usize * self.block_size();
-			let dst = &dst[bofs .. bofs + count * self.block_size()];
-			try!( unsafe{S_PHYSICAL_VOLUMES.get(&pv).unwrap().write(ofs, dst)} );
+			let bofs = blk as usize * self.block_size;
+			let dst = &dst[bofs .. bofs + count * self.block_size];
+			if self.layout == Layout::Mirror {
+				try!( self.write_mirror(pvs, ofs, dst) );
+			}
+			else {
+				let region = &self.regions[r];
+				try!( pvs.write(region.volume, region.first_block + ofs, dst) );
+			}
 			blk += count;
 			rem -= count;
 		}
 		Ok( () )
 	}
+
+	/// Read from one working mirror leg, starting at a different leg each time to spread the load
+	/// and moving on to the next leg if one fails
+	fn read_mirror(&self, pvs: &PvAccess, ofs: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		let n = self.regions.len();
+		let first = self.next_leg.fetch_add(1, Ordering::Relaxed);
+		let mut err = IoError::NoMedium;
+		for i in 0 .. n
+		{
+			let leg = &self.regions[(first + i) % n];
+			if leg.failed.load(Ordering::Relaxed) {
+				continue ;
+			}
+			match pvs.read(leg.volume, leg.first_block + ofs, dst)
+			{
+			Ok(()) => return Ok( () ),
+			Err(e) => {
+				self.fail_leg(leg, e);
+				err = e;
+				},
+			}
+		}
+		Err(err)
+	}
+	/// Write to every working mirror leg, succeeding if at least one leg took the data
+	fn write_mirror(&self, pvs: &PvAccess, ofs: u64, src: &[u8]) -> Result<(),IoError> {
+		let mut written = false;
+		let mut err = IoError::NoMedium;
+		for leg in self.regions.iter()
+		{
+			if leg.failed.load(Ordering::Relaxed) {
+				continue ;
+			}
+			match pvs.write(leg.volume, leg.first_block + ofs, src)
+			{
+			Ok(()) => written = true,
+			Err(e) => {
+				self.fail_leg(leg, e);
+				err = e;
+				},
+			}
+		}
+		if written { Ok( () ) } else { Err(err) }
+	}
+	/// Drop a mirror leg after an error (there is no resync, the leg stays out until the next boot)
+	fn fail_leg(&self, leg: &PhysicalRegion, e: IoError) {
+		if !leg.failed.swap(true, Ordering::Relaxed) {
+			println!("warning: LV '{}': Mirror leg on PV{} failed ({:?}), running degraded", self.name, leg.volume, e);
+		}
+	}
+}
+
+impl RegisteredPvs
+{
+	/// Look up a physical volume (it may have been removed while an LV using it was open)
+	fn get(&self, pv: usize) -> Result<PvRef,IoError> {
+		let _lh = S_PV_LOOKUP_LOCK.lock();
+		match unsafe{S_PHYSICAL_VOLUMES.get(&pv)}
+		{
+		Some(v) if !v.removed.load(Ordering::SeqCst) => {
+			v.in_flight.fetch_add(1, Ordering::SeqCst);
+			// SAFE: `remove_pv` doesn't free the PV until this reference is dropped
+			Ok( PvRef(unsafe{ &*(&**v as *const PhysicalVolumeInfo) }) )
+			},
+		_ => Err( IoError::NoMedium ),
+		}
+	}
+}
+impl ::core::ops::Deref for PvRef
+{
+	type Target = PhysicalVolumeInfo;
+	fn deref(&self) -> &PhysicalVolumeInfo {
+		self.0
+	}
+}
+impl Drop for PvRef
+{
+	fn drop(&mut self) {
+		self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+impl PvAccess for RegisteredPvs
+{
+	fn read(&self, pv: usize, first: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		try!( try!(self.get(pv)).read(first, dst) );
+		Ok( () )
+	}
+	fn write(&self, pv: usize, first: u64, src: &[u8]) -> Result<(),IoError> {
+		try!( try!(self.get(pv)).write(first, src) );
+		Ok( () )
+	}
+	fn flush(&self, pv: usize) -> Result<(),IoError> {
+		try!(self.get(pv)).dev.flush()
+	}
 }
 
 impl PhysicalVolumeInfo
@@ -529,67 +1028,100 @@ impl PhysicalVolumeInfo
 		//log_trace!("PhysicalVolumeInfo::read(first={},{} bytes)", first, dst.len());
 		let block_size = self.dev.blocksize();
 		let total_blocks = dst.len() / block_size;
-		// Read up to 'block_step' blocks in each read call
 		// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
+		let mut blk = 0;
+		while blk < total_blocks
 		{
-			let mut buf = dst;
-			let mut blk_id = first;
-			while buf.len() > 0
-			{
-				assert!(buf.len() % block_size == 0);
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				let real_count = match self.dev.read(prio, blk_id, blocks, buf)//.wait()
-					{
-					Ok(v) => v,
-					Err(e) => todo!("Error when PV fails to read: {:?}", e),
-					};
-				assert!(real_count <= blocks);
-				blk_id += real_count as u64;
-
-				// SAFE: Evil stuff to advance the buffer
-				buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
-//				split_at_mut_inplace(&mut buf, real_count * block_size);
-			}
+			let base = first + blk as u64;
+			let buf = &mut dst[blk * block_size ..];
+			// TODO: Async! (maybe return a composite read handle?)
+			blk += try!(self.transfer(base, total_blocks - blk, &mut |b, count| {
+				let ofs = (b - base) as usize * block_size;
+				self.dev.read(0, b, count, &mut buf[ofs .. ofs + count * block_size])
+				}));
 		}
-
 		Ok(total_blocks)
 	}
 	
 	/// Write blocks from the device
-	pub fn write(&self, first: u64, dst: &[u8]) -> Result<usize,IoError>
+	pub fn write(&self, first: u64, src: &[u8]) -> Result<usize,IoError>
 	{
-		//log_trace!("PhysicalVolumeInfo::write(first={},{} bytes)", first, dst.len());
+		//log_trace!("PhysicalVolumeInfo::write(first={},{} bytes)", first, src.len());
 		let block_step = self.max_blocks_per_read();
 		let block_size = self.dev.blocksize();
-		// Read up to 'block_step' blocks in each read call
+		let total_blocks = src.len() / block_size;
+		// Write up to 'block_step' blocks in each write call
+		let mut blk = 0;
+		while blk < total_blocks
 		{
-			let iter_ids  = (first .. ).step_by(block_step);
-			let iter_bufs = dst.chunks( block_step * block_size );
-			for (blk_id,buf) in iter_ids.zip( iter_bufs )
+			let base = first + blk as u64;
+			let buf = &src[blk * block_size ..];
+			let count = ::core::cmp::min(total_blocks - blk, block_step);
+			// TODO: Async! (maybe return a composite read handle?)
+			blk += try!(self.transfer(base, count, &mut |b, count| {
+				let ofs = (b - base) as usize * block_size;
+				self.dev.write(0, b, count, &buf[ofs .. ofs + count * block_size])
+				}));
+		}
+		Ok(total_blocks)
+	}
+
+	/// Run one device transfer of `count` blocks starting at `first`, returning the number of
+	/// blocks done
+	///
+	/// Timeouts are retried (waiting longer each time). When the device reports a bad block, the
+	/// range is retried a block at a time to find out which blocks are bad.
+	fn transfer(&self, first: u64, count: usize, op: &mut FnMut(u64, usize) -> Result<usize,IoError>) -> Result<usize,IoError>
+	{
+		let mut attempt = 0;
+		loop
+		{
+			match op(first, count)
 			{
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				match self.dev.write(prio, blk_id, blocks, buf)//.wait()
-				{
-				Ok(real_count) => { assert!(real_count == blocks, "TODO: Handle incomplete writes"); },
-				Err(e) => todo!("Error when PV fails to write: {:?}", e),
+			Ok(0) => return Err( IoError::Unknown("Device transferred no blocks") ),
+			Ok(n) => {
+				assert!(n <= count);
+				return Ok(n);
+				},
+			Err(IoError::Timeout) if attempt < TIMEOUT_RETRIES => {
+				attempt += 1;
+				println!("warning: PV {}: Timeout at block {}, retry {} of {}", self.dev.name(), first, attempt, TIMEOUT_RETRIES);
+				::process::sleep(RETRY_BACKOFF << (attempt - 1));
+				},
+			Err(IoError::BadBlock) => {
+				if count == 1 {
+					self.record_bad_block(first);
 				}
+				else {
+					for b in first .. first + count as u64 {
+						if let Err(IoError::BadBlock) = op(b, 1) {
+							self.record_bad_block(b);
+						}
+					}
+				}
+				return Err( IoError::BadBlock );
+				},
+			Err(e) => return Err(e),
 			}
 		}
-		Ok(dst.len()/block_size)
+	}
+
+	fn record_bad_block(&self, block: u64)
+	{
+		let mut lh = self.bad_blocks.lock();
+		if !lh.contains(&block) {
+			println!("warning: PV {}: Bad block {}", self.dev.name(), block);
+			lh.push(block);
+		}
 	}
 }
 
 impl ::core::ops::Drop for PhysicalVolumeReg
 {
+	/// Hot removal of the volume, see `remove_pv`
 	fn drop(&mut self)
 	{
-		todo!("PhysicalVolumeReg::drop idx={}", self.idx);
+		remove_pv(self.idx);
 	}
 }
 
@@ -644,4 +1176,235 @@ mod default_mapper
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use prelude::*;
+	use spin::Mutex;
+	use core::sync::atomic::{AtomicUsize,AtomicBool,Ordering};
+	use super::{LogicalVolume,PhysicalRegion,PvAccess,Layout,IoError};
+	use super::{PhysicalVolume,PhysicalVolumeInfo};
+
+	const BS: usize = 512;
+
+	/// In-memory physical volumes, indexed by PV number
+	struct MemPvs
+	{
+		disks: Vec<Mutex<Vec<u8>>>,
+		reads: Vec<AtomicUsize>,
+		broken: Vec<AtomicBool>,
+	}
+	impl MemPvs
+	{
+		fn new(count: usize, blocks: usize) -> MemPvs {
+			MemPvs {
+				disks: (0 .. count).map(|_| Mutex::new(vec![0u8; blocks * BS])).collect(),
+				reads: (0 .. count).map(|_| AtomicUsize::new(0)).collect(),
+				broken: (0 .. count).map(|_| AtomicBool::new(false)).collect(),
+				}
+		}
+		fn block(&self, pv: usize, idx: u64) -> Vec<u8> {
+			let ofs = idx as usize * BS;
+			self.disks[pv].lock()[ofs .. ofs + BS].to_vec()
+		}
+		fn check(&self, pv: usize, first: u64, len: usize) -> Result<usize,IoError> {
+			if self.broken[pv].load(Ordering::Relaxed) {
+				return Err(IoError::BadBlock);
+			}
+			let ofs = first as usize * BS;
+			if ofs + len > self.disks[pv].lock().len() {
+				return Err(IoError::BadAddr);
+			}
+			Ok(ofs)
+		}
+	}
+	impl PvAccess for MemPvs
+	{
+		fn read(&self, pv: usize, first: u64, dst: &mut [u8]) -> Result<(),IoError> {
+			let ofs = try!(self.check(pv, first, dst.len()));
+			self.reads[pv].fetch_add(1, Ordering::Relaxed);
+			dst.copy_from_slice(&self.disks[pv].lock()[ofs .. ofs + dst.len()]);
+			Ok( () )
+		}
+		fn write(&self, pv: usize, first: u64, src: &[u8]) -> Result<(),IoError> {
+			let ofs = try!(self.check(pv, first, src.len()));
+			self.disks[pv].lock()[ofs .. ofs + src.len()].copy_from_slice(src);
+			Ok( () )
+		}
+		fn flush(&self, pv: usize) -> Result<(),IoError> {
+			try!(self.check(pv, 0, 0));
+			Ok( () )
+		}
+	}
+
+	fn lv(layout: Layout, regions: &[(usize,u64,u64)]) -> LogicalVolume {
+		LogicalVolume {
+			block_size: BS,
+			layout: layout,
+			regions: regions.iter().map(|&(pv, first, count)| PhysicalRegion::new(pv, first, count)).collect(),
+			..Default::default()
+			}
+	}
+	/// Blocks filled with their logical index
+	fn pattern(first: u64, count: usize) -> Vec<u8> {
+		(0 .. count).flat_map(|i| vec![(first as usize + i) as u8; BS]).collect()
+	}
+
+	#[test]
+	fn uses_blocks_overlap() {
+		let v = lv(Layout::Concat, &[(0, 8, 8), (1, 0, 4)]);
+		assert!( v.uses_blocks(0, 0, 9) );
+		assert!( v.uses_blocks(0, 15, 10) );
+		assert!( v.uses_blocks(1, 3, 1) );
+		assert!( !v.uses_blocks(0, 0, 8) );
+		assert!( !v.uses_blocks(0, 16, 4) );
+		assert!( !v.uses_blocks(1, 4, 4) );
+		assert!( !v.uses_blocks(2, 0, 100) );
+	}
+
+	#[test]
+	fn concat_spans_regions() {
+		let pvs = MemPvs::new(2, 16);
+		let lv = lv(Layout::Concat, &[(0, 4, 3), (1, 0, 5)]);
+		assert_eq!(lv.block_count(), 8);
+		lv.write_via(&pvs, 0, &pattern(0, 8)).unwrap();
+		assert_eq!(pvs.block(0, 6), vec![2u8; BS]);
+		assert_eq!(pvs.block(1, 0), vec![3u8; BS]);
+		let mut buf = vec![0u8; 4 * BS];
+		lv.read_via(&pvs, 1, &mut buf).unwrap();
+		assert_eq!(buf, pattern(1, 4));
+		assert!(lv.read_via(&pvs, 7, &mut buf).is_err());
+	}
+
+	#[test]
+	fn stripe_mapping() {
+		let pvs = MemPvs::new(3, 16);
+		// Chunk of 2 blocks, 9 blocks on the smallest member = 4 whole chunks each
+		let lv = lv(Layout::Stripe(2), &[(0, 0, 9), (1, 1, 10), (2, 0, 12)]);
+		assert_eq!(lv.block_count(), 24);
+		lv.write_via(&pvs, 0, &pattern(0, 24)).unwrap();
+		// Chunk 0 = PV0 0-1, chunk 1 = PV1 1-2, chunk 2 = PV2 0-1, chunk 3 = PV0 2-3
+		assert_eq!(pvs.block(0, 1), vec![1u8; BS]);
+		assert_eq!(pvs.block(1, 1), vec![2u8; BS]);
+		assert_eq!(pvs.block(2, 1), vec![5u8; BS]);
+		assert_eq!(pvs.block(0, 2), vec![6u8; BS]);
+		assert_eq!(pvs.block(2, 7), vec![23u8; BS]);
+		let mut buf = vec![0u8; 7 * BS];
+		lv.read_via(&pvs, 3, &mut buf).unwrap();
+		assert_eq!(buf, pattern(3, 7));
+		assert!(lv.read_via(&pvs, 20, &mut buf).is_err());
+	}
+
+	#[test]
+	fn mirror_balances_reads() {
+		let pvs = MemPvs::new(2, 8);
+		let lv = lv(Layout::Mirror, &[(0, 0, 8), (1, 2, 6)]);
+		assert_eq!(lv.block_count(), 6);
+		lv.write_via(&pvs, 0, &pattern(0, 6)).unwrap();
+		assert_eq!(pvs.block(0, 5), vec![5u8; BS]);
+		assert_eq!(pvs.block(1, 7), vec![5u8; BS]);
+		let mut buf = vec![0u8; BS];
+		for i in 0 .. 4 {
+			lv.read_via(&pvs, i, &mut buf).unwrap();
+			assert_eq!(buf, pattern(i, 1));
+		}
+		assert_eq!(pvs.reads[0].load(Ordering::Relaxed), 2);
+		assert_eq!(pvs.reads[1].load(Ordering::Relaxed), 2);
+	}
+
+	#[test]
+	fn mirror_degraded() {
+		let pvs = MemPvs::new(2, 4);
+		let lv = lv(Layout::Mirror, &[(0, 0, 4), (1, 0, 4)]);
+		lv.write_via(&pvs, 0, &pattern(0, 4)).unwrap();
+		pvs.broken[0].store(true, Ordering::Relaxed);
+		// Reads fall back to the remaining leg, and the failed leg is dropped
+		let mut buf = vec![0u8; 4 * BS];
+		for _ in 0 .. 2 {
+			lv.read_via(&pvs, 0, &mut buf).unwrap();
+			assert_eq!(buf, pattern(0, 4));
+		}
+		assert!(lv.regions[0].failed.load(Ordering::Relaxed));
+		lv.write_via(&pvs, 1, &pattern(7, 1)).unwrap();
+		assert_eq!(pvs.block(1, 1), vec![7u8; BS]);
+		lv.flush_via(&pvs).unwrap();
+		// Losing the last leg fails the volume
+		pvs.broken[1].store(true, Ordering::Relaxed);
+		assert!(lv.write_via(&pvs, 0, &pattern(0, 1)).is_err());
+		assert!(lv.read_via(&pvs, 0, &mut buf).is_err());
+		assert!(lv.flush_via(&pvs).is_err());
+	}
+
+	/// Device that times out a number of times, and has one unreadable block
+	struct FlakyPv
+	{
+		timeouts: AtomicUsize,
+		bad: u64,
+	}
+	impl PhysicalVolume for FlakyPv
+	{
+		fn name(&self) -> &str { "FLAKY" }
+		fn blocksize(&self) -> usize { BS }
+		fn capacity(&self) -> Option<u64> { Some(16) }
+		fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,IoError> {
+			if self.timeouts.load(Ordering::Relaxed) > 0 {
+				self.timeouts.fetch_sub(1, Ordering::Relaxed);
+				return Err(IoError::Timeout);
+			}
+			if blockidx <= self.bad && self.bad < blockidx + count as u64 {
+				return Err(IoError::BadBlock);
+			}
+			for (i,b) in dst.chunks_mut(BS).enumerate() {
+				for v in b.iter_mut() {
+					*v = (blockidx as usize + i) as u8;
+				}
+			}
+			Ok(count)
+		}
+		fn write<'a>(&'a self, _prio: u8, _blockidx: u64, count: usize, _src: &'a [u8]) -> Result<usize,IoError> {
+			Ok(count)
+		}
+		fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> Result<(),IoError> {
+			Ok( () )
+		}
+	}
+	fn flaky(timeouts: usize, bad: u64) -> PhysicalVolumeInfo {
+		PhysicalVolumeInfo {
+			dev: Box::new(FlakyPv { timeouts: AtomicUsize::new(timeouts), bad: bad }),
+			mapper: None,
+			bad_blocks: Mutex::new(Vec::new()),
+			removed: AtomicBool::new(false),
+			in_flight: AtomicUsize::new(0),
+			}
+	}
+
+	#[test]
+	fn timeouts_retried() {
+		let mut buf = vec![0u8; 4 * BS];
+		assert_eq!(flaky(3, 99).read(2, &mut buf).unwrap(), 4);
+		assert_eq!(buf, pattern(2, 4));
+		match flaky(4, 99).read(2, &mut buf)
+		{
+		Err(IoError::Timeout) => {},
+		r => panic!("Expected a timeout, got {:?}", r),
+		}
+	}
+
+	#[test]
+	fn bad_blocks_isolated() {
+		let pvi = flaky(0, 5);
+		let mut buf = vec![0u8; 4 * BS];
+		match pvi.read(3, &mut buf)
+		{
+		Err(IoError::BadBlock) => {},
+		r => panic!("Expected a bad block, got {:?}", r),
+		}
+		// The readable blocks around the bad one are still filled in
+		assert_eq!(&buf[3*BS ..], &pattern(6, 1)[..]);
+		assert_eq!(*pvi.bad_blocks.lock(), vec![5]);
+		assert!(pvi.read(5, &mut buf[.. BS]).is_err());
+		assert_eq!(pvi.bad_blocks.lock().len(), 1);
+	}
+}
+
 // vim: ft=rust
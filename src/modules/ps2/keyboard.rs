@@ -173,10 +173,12 @@ impl Dev
 					if release {
 						// self.guidev.release_key(key);
 						println!("keyboard: release key {:?}", key);
+						super::push_keyboard_event(key, false);
 					}
 					else {
 						// self.guidev.press_key(key);
 						println!("keyboard: press key {:?}", key);
+						super::push_keyboard_event(key, true);
 					}
 				}
 				self.state = State::Idle(Layer::Base,false);
@@ -6,6 +6,7 @@ mod keycodes;
 
 use x86_64::instructions::port::{inb, outb};
 use spin::Mutex;
+use mylib::collections::VecDeque;
 use self::i8042::Port;
 
 #[derive(Debug, Copy, Clone)]
@@ -41,12 +42,29 @@ static KBD_DEV: Mutex<Option<PS2Dev>> = Mutex::new(None);
 static port1: Mutex<Option<Port>> = Mutex::new(None);
 static port2: Mutex<Option<Port>> = Mutex::new(None);
 
+/// Size (in bytes) of the keyboard and mouse event buffers
+const EVENT_BUFFER_SIZE: usize = 256;
+
+/// Queue of input events waiting to be read (through `/dev/kbd` and `/dev/mouse`)
+///
+/// Events that don't fit are dropped, so a reader never sees a partial event. The queue is
+/// allocated by `init` (so the IRQ handlers never allocate), events before that are dropped.
+struct EventBuffer(Option<VecDeque<u8>>);
+
+/// Keyboard events, 2 bytes each: HID key code, 1=press/0=release
+static KBD_EVENTS: Mutex<EventBuffer> = Mutex::new(EventBuffer::new());
+/// Mouse events, 5 bytes each: button mask, dx (i16 LE), dy (i16 LE)
+static MOUSE_EVENTS: Mutex<EventBuffer> = Mutex::new(EventBuffer::new());
+
 #[cfg(any(target_arch="x86_64", target_arch="x86"))]
 pub fn init() {
 	i8042::init();
 	*KBD_DEV.lock() = PS2Dev::new_keyboard(&kbd_MF2).1;
 	*MOUSE_DEV.lock() = PS2Dev::new_mouse(&mouse_std).1;
 
+	KBD_EVENTS.lock().0 = Some(VecDeque::with_capacity(EVENT_BUFFER_SIZE));
+	MOUSE_EVENTS.lock().0 = Some(VecDeque::with_capacity(EVENT_BUFFER_SIZE));
+
 	*port1.lock() = Some(Port::new(false));
 	*port2.lock() = Some(Port::new(true));
 
@@ -103,6 +121,44 @@ pub fn handle_irq_mouse()
 	}
 }
 
+impl EventBuffer
+{
+	const fn new() -> EventBuffer {
+		EventBuffer(None)
+	}
+	fn push(&mut self, event: &[u8]) {
+		if let Some(ref mut q) = self.0 {
+			if q.len() + event.len() <= EVENT_BUFFER_SIZE {
+				q.extend_from_slice(event);
+			}
+		}
+	}
+	fn pop(&mut self, dst: &mut [u8]) -> usize {
+		match self.0
+		{
+		Some(ref mut q) => q.pop_front_into(dst),
+		None => 0,
+		}
+	}
+}
+
+/// Read queued keyboard events (non-blocking), returns the number of bytes read
+pub fn read_keyboard(dst: &mut [u8]) -> usize {
+	KBD_EVENTS.lock().pop(dst)
+}
+/// Read queued mouse events (non-blocking), returns the number of bytes read
+pub fn read_mouse(dst: &mut [u8]) -> usize {
+	MOUSE_EVENTS.lock().pop(dst)
+}
+
+fn push_keyboard_event(key: keycodes::KeyCode, pressed: bool) {
+	KBD_EVENTS.lock().push(&[key as u8, pressed as u8]);
+}
+fn push_mouse_event(btns: u8, dx: i16, dy: i16) {
+	let (dx, dy) = (dx as u16, dy as u16);
+	MOUSE_EVENTS.lock().push(&[btns, dx as u8, (dx >> 8) as u8, dy as u8, (dy >> 8) as u8]);
+}
+
 impl PS2Dev {
 	fn new_mouse(ty: &'static mouse::Type) -> (Option<u8>, Option<PS2Dev>) {
 		let (byte, dev) = mouse::Dev::new(*ty);
@@ -86,6 +86,9 @@ impl Dev
 					}
 				}
 				self.btns = newbtns;
+				if dx != 0 || dy != 0 || changed != 0 {
+					super::push_mouse_event(newbtns, dx, dy);
+				}
 				(None, State::Idle)
 				},
 			};
@@ -7,9 +7,9 @@
 use prelude::*;
 use core::{cmp,ops};
 
-#[derive(PartialOrd,Ord,PartialEq,Eq)]
+#[derive(PartialOrd,Ord,PartialEq,Eq,Hash)]
 pub struct ByteStr([u8]);
-#[derive(PartialOrd,Ord,PartialEq,Eq,Default,Clone)]
+#[derive(PartialOrd,Ord,PartialEq,Eq,Hash,Default,Clone)]
 pub struct ByteString(Vec<u8>);
 
 impl ByteStr
@@ -90,6 +90,14 @@ impl ByteString
 	pub fn new() -> ByteString {
 		ByteString(Vec::new())
 	}
+	/// Append raw bytes to the end of the string
+	pub fn extend_from_slice(&mut self, v: &[u8]) {
+		self.0.extend_from_slice(v)
+	}
+	/// Shorten the string to `len` bytes
+	pub fn truncate(&mut self, len: usize) {
+		self.0.truncate(len)
+	}
 }
 impl ::core::iter::FromIterator<u8> for ByteString {
 	fn from_iter<T>(iterator: T) -> ByteString
@@ -0,0 +1,188 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/lib/collections/hash_map.rs
+//! Hashed Key-Value map (separate chaining)
+use prelude::*;
+use mylib::borrow::Borrow;
+use core::hash::{Hash,Hasher};
+
+/// Initial number of buckets
+const INITIAL_BUCKETS: usize = 16;
+
+/// Key-value map using a FNV-1a hash and a bucket per hash slot
+///
+/// The table doubles when the average chain length goes over 2, so lookups are O(1) on average.
+pub struct HashMap<K: Hash + Eq,V>
+{
+	buckets: Vec<Vec<(K,V)>>,
+	count: usize,
+}
+
+/// FNV-1a hasher (fast and good enough for small keys, not DoS resistant)
+pub struct FnvHasher(u64);
+impl Default for FnvHasher
+{
+	fn default() -> FnvHasher {
+		FnvHasher(0xcbf29ce484222325)
+	}
+}
+impl Hasher for FnvHasher
+{
+	fn finish(&self) -> u64 {
+		self.0
+	}
+	fn write(&mut self, bytes: &[u8]) {
+		for &b in bytes {
+			self.0 ^= b as u64;
+			self.0 = self.0.wrapping_mul(0x100000001b3);
+		}
+	}
+}
+
+/// Immutable iterator for HashMap
+pub struct Iter<'a, K: 'a, V: 'a>
+{
+	buckets: &'a [Vec<(K,V)>],
+	bucket: usize,
+	pos: usize,
+}
+
+impl<K: Hash + Eq, V> Default for HashMap<K,V>
+{
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<K: Hash + Eq, V> HashMap<K,V>
+{
+	pub fn new() -> HashMap<K,V> {
+		HashMap {
+			buckets: Vec::new(),
+			count: 0,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.count
+	}
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	fn bucket_for<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+		let mut h = FnvHasher::default();
+		key.hash(&mut h);
+		(h.finish() % self.buckets.len() as u64) as usize
+	}
+
+	/// Resize the table if it's empty or the chains are getting long
+	fn maybe_grow(&mut self) {
+		if self.buckets.len() == 0 || self.count > self.buckets.len() * 2
+		{
+			let new_count = ::core::cmp::max(INITIAL_BUCKETS, self.buckets.len() * 2);
+			let old = ::core::mem::replace(&mut self.buckets, Vec::with_capacity(new_count));
+			for _ in 0 .. new_count {
+				self.buckets.push(Vec::new());
+			}
+			for (k, v) in old.into_iter().flat_map(|b| b.into_iter())
+			{
+				let b = self.bucket_for(&k);
+				self.buckets[b].push( (k, v) );
+			}
+		}
+	}
+
+	/// Returns the previous item (replaced), if any
+	pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+		self.maybe_grow();
+		let b = self.bucket_for(&key);
+		for ent in self.buckets[b].iter_mut()
+		{
+			if ent.0 == key {
+				return Some( ::core::mem::replace(&mut ent.1, value) );
+			}
+		}
+		self.buckets[b].push( (key, value) );
+		self.count += 1;
+		None
+	}
+
+	pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V>
+	where
+		K: Borrow<Q>
+	{
+		if self.buckets.len() == 0 {
+			return None;
+		}
+		let b = self.bucket_for(key);
+		match self.buckets[b].iter().position(|e| e.0.borrow() == key)
+		{
+		Some(i) => {
+			self.count -= 1;
+			Some( self.buckets[b].swap_remove(i).1 )
+			},
+		None => None,
+		}
+	}
+
+	pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&V>
+	where
+		K: Borrow<Q>
+	{
+		if self.buckets.len() == 0 {
+			return None;
+		}
+		let b = self.bucket_for(key);
+		self.buckets[b].iter().find(|e| e.0.borrow() == key).map(|e| &e.1)
+	}
+	pub fn get_mut<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<&mut V>
+	where
+		K: Borrow<Q>
+	{
+		if self.buckets.len() == 0 {
+			return None;
+		}
+		let b = self.bucket_for(key);
+		self.buckets[b].iter_mut().find(|e| e.0.borrow() == key).map(|e| &mut e.1)
+	}
+
+	/// Remove all items for which `f` returns false
+	pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+		let mut removed = 0;
+		for b in self.buckets.iter_mut()
+		{
+			let before = b.len();
+			b.retain(|e| f(&e.0, &e.1));
+			removed += before - b.len();
+		}
+		self.count -= removed;
+	}
+
+	pub fn iter(&self) -> Iter<K,V> {
+		Iter {
+			buckets: &self.buckets,
+			bucket: 0,
+			pos: 0,
+		}
+	}
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V>
+{
+	type Item = (&'a K, &'a V);
+	fn next(&mut self) -> Option<(&'a K, &'a V)> {
+		while self.bucket < self.buckets.len()
+		{
+			if self.pos < self.buckets[self.bucket].len() {
+				let e = &self.buckets[self.bucket][self.pos];
+				self.pos += 1;
+				return Some( (&e.0, &e.1) );
+			}
+			self.bucket += 1;
+			self.pos = 0;
+		}
+		None
+	}
+}
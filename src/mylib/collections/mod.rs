@@ -1,9 +1,11 @@
 //! Collection traits
 
-//pub mod vec_deque;
+pub mod hash_map;
+pub mod vec_deque;
 pub mod vec_map;
 
-//pub use self::vec_deque::VecDeque;
+pub use self::hash_map::HashMap;
+pub use self::vec_deque::VecDeque;
 pub use self::vec_map::VecMap;
 	
 /// A mutable sequence
@@ -1,16 +1,18 @@
 // "Tifflin" Kernel
 // - By John Hodge (thePowersGang)
 //
-// Core/lib/vec_map.rs
-//! Sorted vector backed Key-Value map.
-//use prelude::*;
-//use core::{ops,fmt};
-use memory::heap::ArrayAlloc;
+// Core/lib/collections/vec_deque.rs
+//! Growable ring buffer
+use prelude::*;
 
+/// Double-ended queue backed by a growable ring buffer
 pub struct VecDeque<T>
 {
-	data: ArrayAlloc<T>,
+	/// Slots, `None` when unused
+	data: Vec<Option<T>>,
+	/// Index of the first item
 	start: usize,
+	/// Number of items
 	size: usize,
 }
 impl<T> Default for VecDeque<T>
@@ -22,59 +24,74 @@ impl<T> Default for VecDeque<T>
 
 impl<T> VecDeque<T>
 {
-	pub const fn new() -> VecDeque<T>
+	pub fn new() -> VecDeque<T>
 	{
 		VecDeque {
-			data: ArrayAlloc::empty(),
+			data: Vec::new(),
 			start: 0,
 			size: 0,
 			}
 	}
+	/// Create a queue that can hold `capacity` items without reallocating
+	pub fn with_capacity(capacity: usize) -> VecDeque<T>
+	{
+		let mut rv = VecDeque::new();
+		rv.grow(capacity);
+		rv
+	}
 
-	fn ensure_free_slot(&mut self) {
-		if self.size == self.data.count() {
-			self.data.expand(self.size + 1);
-			if self.start > 0 {
-				// Move from `start` .. `size - start` forward by `new_size - size`
-				let delta = self.data.count() - self.size;
-				for src_idx in (self.start .. self.size - self.start).rev()
-				{
-					let dst_idx = src_idx + delta;
-					// SAFE: In-bounds access, write to unused region from soon-to-be invalid region
-					unsafe {
-						::core::ptr::write( self.data.get_ptr_mut(dst_idx), ::core::ptr::read(self.data.get_ptr(src_idx)) );
-					}
-				}
+	/// Resize the buffer to `new_count` slots (must be at least `size`), moving items to the start
+	fn grow(&mut self, new_count: usize) {
+		assert!(new_count >= self.size);
+		let old_count = self.data.len();
+		let mut data = Vec::with_capacity(new_count);
+		for i in 0 .. self.size {
+			data.push( self.data[(self.start + i) % old_count].take() );
+		}
+		while data.len() < new_count {
+			data.push(None);
+		}
+		self.data = data;
+		self.start = 0;
+	}
 
-				self.start += delta;
-			}
+	fn ensure_free_slot(&mut self) {
+		if self.size == self.data.len() {
+			let new_count = ::core::cmp::max(4, self.data.len() * 2);
+			self.grow(new_count);
 		}
+		assert!(self.size < self.data.len());
+	}
 
-		assert!(self.size < self.data.count());
+	/// Index into `data` of the `idx`th item
+	fn slot(&self, idx: usize) -> usize {
+		(self.start + idx) % self.data.len()
 	}
 
 	pub fn is_empty(&self) -> bool {
 		self.size == 0
 	}
+	/// Number of items in the queue
+	pub fn len(&self) -> usize {
+		self.size
+	}
+	/// Number of items the queue can hold without reallocating
+	pub fn capacity(&self) -> usize {
+		self.data.len()
+	}
 
 	pub fn push_back(&mut self, v: T) {
 		self.ensure_free_slot();
-		assert!(self.size < self.data.count());
-		let idx = (self.start + self.size) % self.data.count();
-		// SAFE: Write is to valid (and unused) memory
-		unsafe { ::core::ptr::write(self.data.get_ptr_mut(idx), v); }
-
+		let idx = self.slot(self.size);
+		self.data[idx] = Some(v);
 		self.size += 1;
-		assert!(self.size <= self.data.count());
 	}
 
 	pub fn pop_back(&mut self) -> Option<T> {
 		if self.size > 0 {
-			let idx = (self.start + self.size) % self.data.count();
-			// SAFE: Reads from valid and soon forgotten memory
-			let rv = unsafe { ::core::ptr::read( self.data.get_ptr(idx) ) };
+			let idx = self.slot(self.size - 1);
 			self.size -= 1;
-			Some(rv)
+			self.data[idx].take()
 		}
 		else {
 			None
@@ -83,36 +100,69 @@ impl<T> VecDeque<T>
 
 	pub fn push_front(&mut self, v: T) {
 		self.ensure_free_slot();
-		assert!(self.size < self.data.count());
-
-		// SAFE: Writes to valid and unused memory
-		unsafe { ::core::ptr::write(self.data.get_ptr_mut(self.start), v); }
-
-		if self.start == 0 {
-			self.start = self.data.count() - 1;
-		}
-		else {
-			self.start -= 1;
-		}
+		self.start = if self.start == 0 { self.data.len() - 1 } else { self.start - 1 };
+		let idx = self.start;
+		self.data[idx] = Some(v);
 		self.size += 1;
-		assert!(self.start < self.data.count());
-		assert!(self.size <= self.data.count());
 	}
 
 	pub fn pop_front(&mut self) -> Option<T> {
 		if self.size > 0 {
-			// SAFE: Read from valid & initialised memory, pointer advanced after read
-			let rv = unsafe { ::core::ptr::read( self.data.get_ptr(self.start) ) };
-			self.start += 1;
-			if self.start == self.data.count() {
-				self.start = 0;
-			}
+			let idx = self.start;
+			self.start = self.slot(1);
 			self.size -= 1;
-			Some(rv)
+			self.data[idx].take()
 		}
 		else {
 			None
 		}
 	}
-}
 
+	pub fn front(&self) -> Option<&T> {
+		self.get(0)
+	}
+	pub fn back(&self) -> Option<&T> {
+		if self.size > 0 { self.get(self.size - 1) } else { None }
+	}
+	/// Get the `idx`th item from the front
+	pub fn get(&self, idx: usize) -> Option<&T> {
+		if idx < self.size {
+			self.data[self.slot(idx)].as_ref()
+		}
+		else {
+			None
+		}
+	}
+
+	/// Remove all items (keeping the allocation)
+	pub fn clear(&mut self) {
+		while let Some(_) = self.pop_front() {
+		}
+		self.start = 0;
+	}
+
+	/// Move up to `dst.len()` items from the front into `dst`, returning the count
+	pub fn pop_front_into(&mut self, dst: &mut [T]) -> usize {
+		let count = ::core::cmp::min(dst.len(), self.size);
+		for d in dst[..count].iter_mut() {
+			*d = self.pop_front().unwrap();
+		}
+		count
+	}
+}
+impl<T: Clone> VecDeque<T>
+{
+	/// Push clones of the items in `src` to the back (growing the buffer at most once)
+	pub fn extend_from_slice(&mut self, src: &[T]) {
+		let needed = self.size + src.len();
+		if needed > self.data.len() {
+			let new_count = ::core::cmp::max(needed, self.data.len() * 2);
+			self.grow(new_count);
+		}
+		for v in src {
+			let idx = self.slot(self.size);
+			self.data[idx] = Some(v.clone());
+			self.size += 1;
+		}
+	}
+}
@@ -66,6 +66,10 @@ impl<K: Ord, V> VecMap<K,V>
 			},
 		}
 	}
+	/// Number of items in the map
+	pub fn len(&self) -> usize {
+		self.ents.len()
+	}
 	/// Remove an item from the map
 	pub fn remove(&mut self, k: &K) -> Option<V> {
 		match self.ents.binary_search_by(|e| e.0.cmp(k))
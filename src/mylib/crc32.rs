@@ -0,0 +1,24 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/lib/crc32.rs
+//! CRC-32 (IEEE 802.3 polynomial, as used by zlib, GPT and the volume journal)
+
+const POLY: u32 = 0xEDB88320;
+
+/// Update a running CRC-32 with `data`
+///
+/// Start with `0`, and pass the previous result to continue over more data (so
+/// `crc32(crc32(0, a), b) == crc32(0, a ++ b)`).
+pub fn crc32(crc: u32, data: &[u8]) -> u32
+{
+	let mut crc = !crc;
+	for &b in data
+	{
+		crc ^= b as u32;
+		for _ in 0 .. 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+		}
+	}
+	!crc
+}
@@ -55,6 +55,10 @@ impl<T: ?Sized> Aref<T>
 	pub fn borrow(&self) -> ArefBorrow<T> {
 		self.__inner.borrow()
 	}
+	/// Number of outstanding borrows (the `Aref` can only be dropped when this is zero)
+	pub fn borrow_count(&self) -> usize {
+		self.__inner.borrow_count()
+	}
 }
 impl<T: ?Sized> ops::Deref for Aref<T>
 {
@@ -99,6 +103,10 @@ impl<T: ?Sized> ArefInner<T>
 			__ptr: unsafe { NonNull::new_unchecked(self as *const _ as *mut _) },
 			}
 	}
+	/// Number of outstanding borrows
+	pub fn borrow_count(&self) -> usize {
+		self.count.load(Ordering::SeqCst)
+	}
 }
 impl<T: ?Sized> ops::Deref for ArefInner<T>
 {
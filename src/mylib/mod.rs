@@ -8,6 +8,7 @@
 
 //pub use self::queue::Queue;
 pub use self::collections::vec_map::VecMap;
+pub use self::collections::hash_map::HashMap;
 //pub use self::btree_map::BTreeMap;
 //pub use self::vec::Vec;
 pub use self::sparse_vec::SparseVec;
@@ -39,6 +40,7 @@ pub mod sparse_vec;
 //#[macro_use]
 //pub mod string;
 pub mod byte_str;
+pub mod crc32;
 
 //pub mod btree_map;
 
@@ -4,10 +4,16 @@ use self::process::*;
 use self::processor::*;
 use arch::paging::{ActivePageTable,InactivePageTable};
 use vfs;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
 mod process;
 mod processor;
 mod stack;
+mod wait_queue;
+
+pub use self::wait_queue::WaitQueue;
 
 /// 平台相关依赖：struct TrapFrame
 ///
@@ -49,6 +55,169 @@ pub fn schedule(rsp: &mut usize) {
     PROCESSOR.try().unwrap().lock().schedule(rsp);
 }
 
+/// Start a new kernel thread running `entry`
+pub fn spawn(name: &'static str, entry: extern fn()) {
+    let process = Process::new(name, entry);
+    // The timer IRQ also takes the processor lock, so keep it out while we hold it
+    ::arch::interrupts::without_interrupts(|| PROCESSOR.try().unwrap().lock().add(process));
+}
+
+/// Let the next runnable process run, returning when this one is scheduled again
+///
+/// Does nothing before `init` or with interrupts disabled (the caller may hold a lock that the
+/// other processes, or the timer, need).
+pub fn yield_now() {
+    if PROCESSOR.try().is_some() && ::arch::interrupts::enabled() {
+        ::arch::syscall::yield_cpu();
+    }
+}
+
+/// Wait for at least `ticks` timer ticks, letting other processes run meanwhile
+///
+/// The tick count only advances with interrupts enabled, so with them disabled this returns
+/// immediately.
+pub fn sleep(ticks: usize) {
+    use arch::interrupts::irq::irq_count;
+    use consts::irq::IRQ_TIMER;
+    if !::arch::interrupts::enabled() {
+        return;
+    }
+    let start = irq_count(IRQ_TIMER);
+    while irq_count(IRQ_TIMER).wrapping_sub(start) < ticks {
+        yield_now();
+    }
+}
+
+/// Snapshot of a process (see `get_info`)
+pub struct ProcessInfo {
+    pub pid: usize,
+    pub name: &'static str,
+    pub status: String,
+    pub is_user: bool,
+    pub uid: u32,
+    pub gid: u32,
+    pub root: vfs::PathBuf,
+    pub cwd: vfs::PathBuf,
+    /// Command line
+    pub args: Vec<String>,
+    /// Open handles as (handle ID, description)
+    pub handles: Vec<(usize, String)>,
+    /// `MemorySet` debug output (empty for kernel threads)
+    pub memory_map: String,
+}
+
+/// IDs of all processes
+pub fn enum_pids() -> Vec<usize> {
+    with_processor(|p| p.map(|p| p.pids()).unwrap_or_default())
+}
+
+/// Information about process `pid`
+pub fn get_info(pid: usize) -> Option<ProcessInfo> {
+    with_processor(|p| p.and_then(|p| p.get(pid)).map(|p| p.info()))
+}
+
+/// ID of the running process (0 before `init`)
+pub fn current_pid() -> usize {
+    with_processor(|p| p.map(|p| p.current_pid()).unwrap_or(0))
+}
+
+/// Mark `pid` as sleeping (not scheduled) for `reason`, or wake it with `None`
+fn set_sleeping(pid: usize, reason: Option<usize>) {
+    with_processor(|p| if let Some(p) = p { p.set_sleeping(pid, reason) })
+}
+
+fn is_sleeping(pid: usize) -> bool {
+    with_processor(|p| p.map(|p| p.is_sleeping(pid)).unwrap_or(false))
+}
+
+/// Run `f` on the processor, `None` before `init`
+fn with_processor<R, F: FnOnce(Option<&mut Processor>) -> R>(f: F) -> R {
+    match PROCESSOR.try() {
+        Some(p) => {
+            // The timer IRQ also takes the processor lock (and this can be called from IRQs, so
+            // leave interrupts as they were)
+            ::arch::interrupts::without_interrupts(|| f(Some(&mut *p.lock())))
+        }
+        None => f(None),
+    }
+}
+
+/// Run `f` on the current process
+///
+/// Before `init` there is no process, and `f` is passed `None`.
+fn with_current<R, F: FnOnce(Option<&mut Process>) -> R>(f: F) -> R {
+    match PROCESSOR.try() {
+        Some(p) => {
+            // The timer IRQ also takes the processor lock
+            ::arch::interrupts::without_interrupts(|| f(Some(p.lock().current_mut())))
+        }
+        None => f(None),
+    }
+}
+
+/// Working directory of the current process
+pub fn get_cwd() -> vfs::PathBuf {
+    with_current(|p| p.map(|p| p.cwd.clone()).unwrap_or_else(|| vfs::PathBuf::from("/")))
+}
+/// Root directory of the current process (in the global namespace)
+pub fn get_root() -> vfs::PathBuf {
+    with_current(|p| p.map(|p| p.root.clone()).unwrap_or_else(|| vfs::PathBuf::from("/")))
+}
+/// Set the working directory (absolute and normalised, checked by `vfs::chdir`)
+pub fn set_cwd(path: vfs::PathBuf) {
+    with_current(|p| p.expect("set_cwd before process init").cwd = path)
+}
+/// Set the root directory (absolute and normalised, checked by `vfs::chroot`)
+pub fn set_root(path: vfs::PathBuf) {
+    with_current(|p| p.expect("set_root before process init").root = path)
+}
+
+/// User ID of the current process
+pub fn get_uid() -> u32 {
+    with_current(|p| p.map(|p| p.uid).unwrap_or(0))
+}
+/// Group ID of the current process
+pub fn get_gid() -> u32 {
+    with_current(|p| p.map(|p| p.gid).unwrap_or(0))
+}
+/// Set the user and group IDs of the current process
+pub fn set_ids(uid: u32, gid: u32) {
+    with_current(|p| {
+        let p = p.expect("set_ids before process init");
+        p.uid = uid;
+        p.gid = gid;
+    })
+}
+
+/// Set the command line of the current process
+pub fn set_args(args: Vec<String>) {
+    with_current(|p| p.expect("set_args before process init").args = args)
+}
+
+/// Source of handle IDs, unique over all processes so removing a stale one is harmless
+static NEXT_HANDLE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Record a newly opened handle (shown as `desc`) in the current process's handle table
+///
+/// Returns the (process, handle) IDs to pass to `remove_handle` when the handle is closed.
+pub fn add_handle(desc: String) -> (usize, usize) {
+    let id = NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed);
+    let pid = with_current(|p| match p {
+        Some(p) => {
+            p.handles.push((id, desc));
+            p.pid
+        }
+        None => 0,
+    });
+    (pid, id)
+}
+/// Remove a handle recorded by `add_handle` (the handle may be closed by another process)
+pub fn remove_handle(pid: usize, id: usize) {
+    with_processor(|p| if let Some(p) = p.and_then(|p| p.get_mut(pid)) {
+        p.handles.retain(|h| h.0 != id);
+    })
+}
+
 /// Fork the current process
 pub fn fork(tf: &TrapFrame) {
     let curr_rsp: usize;
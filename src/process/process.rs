@@ -12,6 +12,7 @@ use memory::Stack;
 use memory::memory_set::{MemoryArea,MemorySet};
 use memory::PAddr;
 use memory::address::FromToVirtualAddress;
+use vfs::PathBuf;
 
 #[derive(Debug)]
 pub struct Process {
@@ -25,6 +26,17 @@ pub struct Process {
     pub(in process) status: Status,
     pub(in process) rsp: usize,
     pub(in process) is_user: bool,
+    /// Root directory (absolute path in the global namespace, see `chroot`)
+    pub(in process) root: PathBuf,
+    /// Working directory (absolute path within `root`)
+    pub(in process) cwd: PathBuf,
+    /// Credentials used for filesystem permission checks (0 = superuser)
+    pub(in process) uid: u32,
+    pub(in process) gid: u32,
+    /// Command line (just the name unless set with `set_args`)
+    pub(in process) args: Vec<String>,
+    /// Open handles as (handle ID, description), see `add_handle`
+    pub(in process) handles: Vec<(usize, String)>,
 }
 
 pub type Pid = usize;
@@ -65,6 +77,12 @@ impl Process {
             status: Status::Ready,
             rsp,
             is_user: false,
+            root: PathBuf::from("/"),
+            cwd: PathBuf::from("/"),
+            uid: 0,
+            gid: 0,
+            args: vec![String::from(name)],
+            handles: Vec::new(),
         }
     }
     /// Make the first kernel thread `initproc`
@@ -91,6 +109,12 @@ impl Process {
             status: Status::Running,
             rsp: 0, // will be set at first schedule
             is_user: false,
+            root: PathBuf::from("/"),
+            cwd: PathBuf::from("/"),
+            uid: 0,
+            gid: 0,
+            args: vec![String::from("init")],
+            handles: Vec::new(),
         }
     }
 
@@ -149,6 +173,12 @@ impl Process {
             status: Status::Ready,
             rsp,
             is_user: true,
+            root: PathBuf::from("/"),
+            cwd: PathBuf::from("/"),
+            uid: 0,
+            gid: 0,
+            args: vec![String::from("user")],
+            handles: Vec::new(),
         }
     }
 
@@ -200,6 +230,33 @@ impl Process {
             status: Status::Ready,
             rsp,
             is_user: true,
+            root: self.root.clone(),
+            cwd: self.cwd.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            // Handles belong to the parent (closing them updates its table)
+            args: self.args.clone(),
+            handles: Vec::new(),
+        }
+    }
+
+    /// Snapshot of this process for `/proc`
+    pub fn info(&self) -> ProcessInfo {
+        ProcessInfo {
+            pid: self.pid,
+            name: self.name,
+            status: format!("{:?}", self.status),
+            is_user: self.is_user,
+            uid: self.uid,
+            gid: self.gid,
+            root: self.root.clone(),
+            cwd: self.cwd.clone(),
+            args: self.args.clone(),
+            handles: self.handles.clone(),
+            memory_map: match self.memory_set {
+                Some(ref ms) => format!("{:#?}", ms),
+                None => String::new(),
+            },
         }
     }
 }
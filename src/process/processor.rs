@@ -41,15 +41,60 @@ impl Processor {
         ////deug!("finish add");
     }
 
+    /// IDs of all processes
+    pub fn pids(&self) -> Vec<Pid> {
+        self.procs.keys().cloned().collect()
+    }
+
+    pub fn get(&self, pid: Pid) -> Option<&Process> {
+        self.procs.get(&pid)
+    }
+
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut Process> {
+        self.procs.get_mut(&pid)
+    }
+
+    /// The running process
+    pub fn current_mut(&mut self) -> &mut Process {
+        self.procs.get_mut(&self.current_pid).unwrap()
+    }
+
     pub fn schedule(&mut self, rsp: &mut usize) {
         let pid = self.find_next();
         self.switch_to(pid, rsp);
     }
 
     fn find_next(&self) -> Pid {
-        *self.procs.keys()
-            .find(|&&i| i > self.current_pid)
-            .unwrap_or(self.procs.keys().nth(0).unwrap())
+        // Sleeping processes are skipped until woken (there is always the idle thread)
+        let runnable = |p: &Process| match p.status { Status::Sleeping(_) => false, _ => true };
+        self.procs.iter()
+            .find(|&(&i, p)| i > self.current_pid && runnable(p))
+            .or_else(|| self.procs.iter().find(|&(_, p)| runnable(p)))
+            .map(|(&i, _)| i)
+            .unwrap_or(self.current_pid)
+    }
+
+    pub fn current_pid(&self) -> Pid {
+        self.current_pid
+    }
+
+    /// Put `pid` to sleep (`reason` is recorded in the status) or wake it (`reason` = None)
+    pub fn set_sleeping(&mut self, pid: Pid, reason: Option<usize>) {
+        let current_pid = self.current_pid;
+        if let Some(p) = self.procs.get_mut(&pid) {
+            p.status = match reason {
+                Some(r) => Status::Sleeping(r),
+                None if pid == current_pid => Status::Running,
+                None => Status::Ready,
+            };
+        }
+    }
+
+    pub fn is_sleeping(&self, pid: Pid) -> bool {
+        match self.procs.get(&pid).map(|p| &p.status) {
+            Some(&Status::Sleeping(_)) => true,
+            _ => false,
+        }
     }
 
     fn switch_to(&mut self, pid: Pid, rsp: &mut usize) {
@@ -69,7 +114,10 @@ impl Processor {
         }
         {
             let current = self.procs.get_mut(&self.current_pid).unwrap();
-            current.status = Status::Ready;
+            // Leave sleeping processes asleep
+            if let Status::Running = current.status {
+                current.status = Status::Ready;
+            }
             current.rsp = *rsp;
         }
         {
@@ -0,0 +1,51 @@
+use spin::Mutex;
+use alloc::vec::Vec;
+use arch::interrupts::without_interrupts;
+
+/// Processes blocked until another process signals a change (e.g. data written to a pipe)
+///
+/// Waiting processes are marked as sleeping, so the scheduler skips them until `wake_all`.
+/// `wake_all` can be called from interrupt handlers (e.g. disk completion).
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: Mutex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Block the current process while `cond` returns true
+    ///
+    /// Must be called with interrupts enabled (and no spinlocks held), so the scheduler can switch
+    /// to the process that will call `wake_all`.
+    pub fn wait_while<F: FnMut() -> bool>(&self, mut cond: F) {
+        let pid = super::current_pid();
+        while cond() {
+            // Register before checking again, so a wake between the check and sleeping isn't lost
+            without_interrupts(|| self.waiters.lock().push(pid));
+            super::set_sleeping(pid, Some(self as *const _ as usize));
+            if cond() {
+                // The scheduler skips us until `wake_all` (yield_now returns at once if it can't switch)
+                while super::is_sleeping(pid) {
+                    super::yield_now();
+                }
+            }
+            else {
+                super::set_sleeping(pid, None);
+            }
+            without_interrupts(|| self.waiters.lock().retain(|&p| p != pid));
+        }
+    }
+
+    /// Wake every waiting process
+    pub fn wake_all(&self) {
+        let waiters = without_interrupts(|| ::core::mem::replace(&mut *self.waiters.lock(), Vec::new()));
+        for pid in waiters {
+            super::set_sleeping(pid, None);
+        }
+    }
+}
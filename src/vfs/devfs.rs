@@ -0,0 +1,409 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/devfs.rs
+//! Device filesystem (mounted at `/dev`)
+//!
+//! Exposes devices as special files: `null`, `zero`, `random`, the serial ports (`com1`, `com2`),
+//! PS/2 input (`kbd`, `mouse`) and every logical volume (by name). The directory is built on each
+//! lookup, so volumes registered after the mount still appear.
+#[allow(unused_imports)]
+use prelude::*;
+use super::{mount, node};
+use metadevs::storage::{self, VolumeHandle, VolOpenError};
+use mylib::byte_str::ByteStr;
+use mylib::mem::Arc;
+use arch::interrupts::without_interrupts;
+use spin::Mutex;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+
+/// `ioctl`: Block size of a block device
+pub const IOCTL_BLOCK_SIZE: u32 = 1;
+/// `ioctl`: Number of blocks in a block device
+pub const IOCTL_BLOCK_COUNT: u32 = 2;
+/// `ioctl`: Write back cached blocks of a block device
+pub const IOCTL_SYNC: u32 = 3;
+
+/// Block devices use inode `BLOCK_INODE_BASE + LV index`
+const BLOCK_INODE_BASE: node::InodeId = 0x100;
+
+#[derive(Debug,Copy,Clone)]
+enum Device
+{
+	Null,
+	Zero,
+	Random,
+	Com1,
+	Com2,
+	Keyboard,
+	Mouse,
+	/// Logical volume (index)
+	Volume(usize),
+}
+
+/// Fixed devices, inode is the index plus one
+static S_DEVICES: [(&'static str, Device); 7] = [
+	("null", Device::Null),
+	("zero", Device::Zero),
+	("random", Device::Random),
+	("com1", Device::Com1),
+	("com2", Device::Com2),
+	("kbd", Device::Keyboard),
+	("mouse", Device::Mouse),
+	];
+
+/// xorshift64* state for `/dev/random`, seeded on first use
+// NOTE: Not cryptographically secure
+static S_RANDOM_STATE: Mutex<u64> = Mutex::new(0);
+
+struct DevFS
+{
+	_vh: VolumeHandle,
+	/// Mount time, used as the timestamps of all nodes
+	time: node::Timestamp,
+}
+struct RootDir
+{
+	time: node::Timestamp,
+}
+struct DevNode
+{
+	inode: node::InodeId,
+	dev: Device,
+	time: node::Timestamp,
+	/// Volume handle (and number of open handles using it) while a volume device is open
+	///
+	/// Shared so I/O runs without this lock held.
+	volume: Mutex<Option<(Arc<VolumeHandle>, usize)>>,
+}
+
+pub fn init()
+{
+	let h = mount::DriverRegistration::new("devfs", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, _vol: &VolumeHandle) -> super::Result<usize> {
+		// Not backed by a volume, only mounted by name
+		Ok(0)
+	}
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<mount::Filesystem>> {
+		Ok(Box::new(DevFS {
+			_vh: vol,
+			time: ::time::realtime(),
+			}))
+	}
+}
+
+impl mount::Filesystem for DevFS
+{
+	fn root_inode(&self) -> node::InodeId {
+		0
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		if id == 0 {
+			return Some(node::Node::Dir(Box::new(RootDir { time: self.time })));
+		}
+		let dev = if id >= BLOCK_INODE_BASE {
+				let idx = (id - BLOCK_INODE_BASE) as usize;
+				if ! storage::enum_lvs().iter().any(|&(i,_)| i == idx) {
+					return None;
+				}
+				Device::Volume(idx)
+			}
+			else {
+				match S_DEVICES.get(id as usize - 1)
+				{
+				Some(&(_, dev)) => dev,
+				None => return None,
+				}
+			};
+		Some(node::Node::Special(Box::new(DevNode { inode: id, dev: dev, time: self.time, volume: Mutex::new(None) })))
+	}
+}
+
+/// All directory entries (fixed devices, then volumes)
+fn entries() -> Vec<(node::InodeId, String)>
+{
+	let mut rv: Vec<_> = S_DEVICES.iter().enumerate()
+		.map(|(i, &(name, _))| (i as node::InodeId + 1, String::from(name)))
+		.collect();
+	rv.extend( storage::enum_lvs().into_iter().map(|(idx, name)| (BLOCK_INODE_BASE + idx as node::InodeId, name)) );
+	rv
+}
+
+fn fill_random(buf: &mut [u8])
+{
+	let mut state = S_RANDOM_STATE.lock();
+	if *state == 0 {
+		let (s, ns) = ::time::realtime();
+		*state = (s << 30) ^ ns ^ 0x9E37_79B9_7F4A_7C15;
+	}
+	for chunk in buf.chunks_mut(8)
+	{
+		*state ^= *state >> 12;
+		*state ^= *state << 25;
+		*state ^= *state >> 27;
+		let v = state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+		for (i, b) in chunk.iter_mut().enumerate() {
+			*b = (v >> (i * 8)) as u8;
+		}
+	}
+}
+
+/// Mix written data into the random state
+fn stir_random(buf: &[u8])
+{
+	let mut state = S_RANDOM_STATE.lock();
+	for (i, &b) in buf.iter().enumerate() {
+		*state ^= (b as u64) << ((i % 8) * 8);
+	}
+	if *state == 0 {
+		// Zero is the "unseeded" value (and a fixed point of xorshift)
+		*state = 1;
+	}
+}
+
+fn open_volume(idx: usize) -> super::Result<VolumeHandle>
+{
+	let name = match storage::enum_lvs().into_iter().find(|&(i,_)| i == idx)
+		{
+		Some((_, name)) => name,
+		None => return Err(super::Error::NotFound),
+		};
+	match VolumeHandle::open_named(&name)
+	{
+	Ok(v) => Ok(v),
+	Err(VolOpenError::NotFound) => Err(super::Error::NotFound),
+	// Mounted or opened elsewhere
+	Err(VolOpenError::Locked) => Err(super::Error::Locked),
+	}
+}
+
+/// Returns the block index and the number of bytes of `len` at `ofs` that are within the volume
+fn block_range(vh: &VolumeHandle, ofs: u64, len: usize) -> super::Result<(u64, usize)>
+{
+	let bs = vh.block_size();
+	if ofs % bs as u64 != 0 || len % bs != 0 {
+		return Err(super::Error::InvalidParameter);
+	}
+	let idx = ofs / bs as u64;
+	let count = vh.block_count();
+	if idx >= count {
+		return Ok( (idx, 0) );
+	}
+	let blocks = ::core::cmp::min( (len / bs) as u64, count - idx ) as usize;
+	Ok( (idx, blocks * bs) )
+}
+
+impl node::NodeBase for RootDir {
+	fn get_id(&self) -> node::InodeId {
+		0
+	}
+	fn get_any(&self) -> &::core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Metadata {
+		node::Metadata {
+			class: node::NodeClass::Dir,
+			size: entries().len() as u64,
+			mode: node::Mode::from_bits_truncate(0o755),
+			uid: 0,
+			gid: 0,
+			atime: self.time,
+			mtime: self.time,
+			ctime: self.time,
+			nlink: 2,
+		}
+	}
+}
+impl node::Dir for RootDir {
+	fn lookup(&self, name: &ByteStr) -> super::Result<node::InodeId> {
+		match entries().into_iter().find(|e| name == &e.1[..])
+		{
+		Some((inode, _)) => Ok(inode),
+		None => Err(super::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> super::Result<usize> {
+		let mut count = 0;
+		for (inode, name) in entries().into_iter().skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(inode, &mut name.bytes()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> super::Result<node::InodeId> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn link(&self, _name: &ByteStr, _node: &node::NodeBase) -> super::Result<()> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn unlink(&self, _name: &ByteStr) -> super::Result<()> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn cache_lookups(&self) -> bool {
+		// Volumes can be added after mount
+		false
+	}
+}
+
+impl node::NodeBase for DevNode {
+	fn get_id(&self) -> node::InodeId {
+		self.inode
+	}
+	fn get_any(&self) -> &::core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Metadata {
+		let (mode, size) = match self.dev
+			{
+			Device::Null | Device::Zero | Device::Random => (0o666, 0),
+			Device::Com1 | Device::Com2 => (0o660, 0),
+			Device::Keyboard | Device::Mouse => (0o640, 0),
+			Device::Volume(idx) => (0o600, self.with_volume(idx, |vh| Ok(vh.block_size() as u64 * vh.block_count())).unwrap_or(0)),
+			};
+		node::Metadata {
+			class: node::NodeClass::Special,
+			size: size,
+			mode: node::Mode::from_bits_truncate(mode),
+			uid: 0,
+			gid: 0,
+			atime: self.time,
+			mtime: self.time,
+			ctime: self.time,
+			nlink: 1,
+		}
+	}
+}
+
+impl DevNode {
+	/// Run `f` on the volume opened by `open`
+	///
+	/// Without an open handle (e.g. `stat`) the volume is opened just for the call.
+	fn with_volume<R, F: FnOnce(&VolumeHandle) -> super::Result<R>>(&self, idx: usize, f: F) -> super::Result<R> {
+		let opened = self.volume.lock().as_ref().map(|&(ref vh, _)| vh.clone());
+		match opened
+		{
+		Some(vh) => f(&vh),
+		None => f(&try!(open_volume(idx))),
+		}
+	}
+}
+
+impl node::Special for DevNode {
+	fn typename(&self) -> &str {
+		match self.dev
+		{
+		Device::Volume(_) => "block",
+		_ => "char",
+		}
+	}
+
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> super::Result<usize> {
+		use arch::driver::serial::{COM1, COM2};
+		match self.dev
+		{
+		Device::Null => Ok(0),
+		Device::Zero => {
+			for b in buf.iter_mut() {
+				*b = 0;
+			}
+			Ok(buf.len())
+			},
+		Device::Random => {
+			fill_random(buf);
+			Ok(buf.len())
+			},
+		Device::Com1 => Ok( without_interrupts(|| COM1.lock().read(buf)) ),
+		Device::Com2 => Ok( without_interrupts(|| COM2.lock().read(buf)) ),
+		Device::Keyboard => Ok( without_interrupts(|| ::modules::ps2::read_keyboard(buf)) ),
+		Device::Mouse => Ok( without_interrupts(|| ::modules::ps2::read_mouse(buf)) ),
+		Device::Volume(idx) => self.with_volume(idx, |vh| {
+			let (blk, len) = try!(block_range(vh, ofs, buf.len()));
+			if len > 0 {
+				try!(vh.read_blocks(blk, &mut buf[..len]));
+			}
+			Ok(len)
+			}),
+		}
+	}
+
+	fn write(&self, ofs: u64, buf: &[u8]) -> super::Result<usize> {
+		use arch::driver::serial::{COM1, COM2};
+		match self.dev
+		{
+		Device::Null | Device::Zero => Ok(buf.len()),
+		Device::Random => {
+			stir_random(buf);
+			Ok(buf.len())
+			},
+		Device::Com1 | Device::Com2 => {
+			let port = if let Device::Com1 = self.dev { &COM1 } else { &COM2 };
+			without_interrupts(|| {
+				let mut lh = port.lock();
+				for &b in buf {
+					lh.send(b);
+				}
+				});
+			Ok(buf.len())
+			},
+		Device::Keyboard | Device::Mouse => Err(super::Error::PermissionDenied),
+		Device::Volume(idx) => self.with_volume(idx, |vh| {
+			let (blk, len) = try!(block_range(vh, ofs, buf.len()));
+			if len == 0 && buf.len() > 0 {
+				return Err(super::Error::OutOfSpace);
+			}
+			try!(vh.write_blocks(blk, &buf[..len]));
+			Ok(len)
+			}),
+		}
+	}
+
+	/// Volume devices keep the volume open (locked against other users) while any handle is open
+	fn open(&self, _read: bool, _write: bool) -> super::Result<()> {
+		if let Device::Volume(idx) = self.dev {
+			let mut lh = self.volume.lock();
+			match *lh
+			{
+			Some((_, ref mut count)) => *count += 1,
+			None => *lh = Some( (Arc::new(try!(open_volume(idx))), 1) ),
+			}
+		}
+		Ok( () )
+	}
+	fn close(&self, _read: bool, _write: bool) {
+		let mut lh = self.volume.lock();
+		let last = match *lh
+			{
+			Some((_, ref mut count)) => { *count -= 1; *count == 0 },
+			None => false,
+			};
+		if last {
+			let (vh, _) = lh.take().unwrap();
+			if let Err(e) = vh.sync() {
+				println!("warning: devfs: Sync of '{}' on close failed: {:?}", vh.name(), e);
+			}
+		}
+	}
+
+	fn ioctl(&self, cmd: u32, _arg: usize) -> super::Result<usize> {
+		match (self.dev, cmd)
+		{
+		(Device::Volume(idx), IOCTL_BLOCK_SIZE) => self.with_volume(idx, |vh| Ok(vh.block_size())),
+		(Device::Volume(idx), IOCTL_BLOCK_COUNT) => self.with_volume(idx, |vh| Ok(vh.block_count() as usize)),
+		(Device::Volume(idx), IOCTL_SYNC) => self.with_volume(idx, |vh| {
+			try!(vh.sync());
+			Ok(0)
+			}),
+		_ => Err(super::Error::InvalidParameter),
+		}
+	}
+}
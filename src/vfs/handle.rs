@@ -5,7 +5,9 @@
 //! Opened file interface
 #[allow(unused_imports)]
 use prelude::*;
-use super::node::{CacheHandle,NodeType};
+use super::node::{CacheHandle,NodeType,Metadata,Mode,LockKind};
+use core::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+use spin::Mutex;
 use mylib::byte_str::{ByteStr,ByteString};
 use super::Path;
 use super::super::memory::*;
@@ -15,12 +17,21 @@ use super::super::memory::*;
 pub struct Any {
 	node: CacheHandle,
 }
-#[derive(Debug,Clone)]
+#[derive(Debug)]
 /// Normal file
 pub struct File {
 	node: CacheHandle,
 	mode: FileOpenMode,
+	/// Identifies this handle as the owner of its byte-range locks
+	lock_owner: usize,
+	/// `UniqueRW` private copy of the file contents, made on the first write
+	private: Mutex<Option<Vec<u32>>>,
+	/// Entry in the opening process's handle table
+	proc_handle: (usize, usize),
 }
+
+/// Source of `File::lock_owner` values
+static S_NEXT_LOCK_OWNER: AtomicUsize = ATOMIC_USIZE_INIT;
 #[derive(Debug,Clone)]
 /// Directory (for enumeration)
 pub struct Dir {
@@ -31,10 +42,30 @@ pub struct Dir {
 pub struct Symlink {
 	node: CacheHandle,
 }
-#[derive(Debug,Clone)]
-/// Special file (?API exposed)
+#[derive(Debug)]
+/// Special file (device, named pipe, ...)
 pub struct Special {
 	node: CacheHandle,
+	mode: SpecialOpenMode,
+	/// Entry in the opening process's handle table
+	proc_handle: (usize, usize),
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum SpecialOpenMode
+{
+	ReadOnly,
+	WriteOnly,
+	ReadWrite,
+}
+impl SpecialOpenMode
+{
+	fn can_read(&self) -> bool {
+		*self != SpecialOpenMode::WriteOnly
+	}
+	fn can_write(&self) -> bool {
+		*self != SpecialOpenMode::ReadOnly
+	}
 }
 
 #[derive(Debug,Clone)]
@@ -81,16 +112,40 @@ pub enum MemoryMapMode
 
 impl Any
 {
-	/// Open the specified path (not caring what the actual type is)
+	/// Open the specified path (not caring what the actual type is), following a final symbolic link
 	pub fn open(path: &Path) -> super::Result<Any> {
 		//log_trace!("Any::open({:?})", path);
 		let node = try!(CacheHandle::from_path(path));
 		Ok(Any { node: node })
 	}
+	/// Open the specified path without following a final symbolic link (`O_NOFOLLOW`)
+	///
+	/// If the path names a symbolic link, the handle is to the link (so `to_file` etc fail with
+	/// `TypeMismatch`, and `metadata` is the link's `lstat`).
+	pub fn open_nofollow(path: &Path) -> super::Result<Any> {
+		let node = try!(CacheHandle::from_path_nofollow(path));
+		Ok(Any { node: node })
+	}
 
 	pub fn get_class(&self) -> super::node::NodeClass {
 		self.node.get_class()
 	}
+	/// Return the node's metadata (`stat`)
+	pub fn metadata(&self) -> Metadata {
+		self.node.get_metadata()
+	}
+	/// Change the permission bits
+	pub fn chmod(&self, mode: Mode) -> super::Result<()> {
+		self.node.chmod(mode)
+	}
+	/// Change the owner and group
+	pub fn chown(&self, uid: u32, gid: u32) -> super::Result<()> {
+		self.node.chown(uid, gid)
+	}
+	/// Set the access and modification times
+	pub fn utimes(&self, atime: super::node::Timestamp, mtime: super::node::Timestamp) -> super::Result<()> {
+		self.node.utimes(atime, mtime)
+	}
 	
 	/// Upgrade the handle to a directory handle
 	pub fn to_dir(self) -> super::Result<Dir> {
@@ -119,6 +174,17 @@ impl Any
 			Err(super::Error::TypeMismatch)
 		}
 	}
+	
+	pub fn to_special(self, mode: SpecialOpenMode) -> super::Result<Special> {
+		if self.node.is_special() {
+			try!(self.node.special_open(mode.can_read(), mode.can_write()));
+			let proc_handle = ::process::add_handle(describe(&self.node, &mode));
+			Ok(Special { node: self.node, mode: mode, proc_handle: proc_handle })
+		}
+		else {
+			Err(super::Error::TypeMismatch)
+		}
+	}
 }
 
 pub struct MemoryMapHandle<'a>
@@ -126,6 +192,10 @@ pub struct MemoryMapHandle<'a>
 	handle: &'a File,
 	base: *mut (),
 	len: usize,
+	/// Index of the first mapped file page
+	first_page: u64,
+	/// Pages are mapped writable (`MemoryMapMode::WriteBack`)
+	writable: bool,
 }
 
 impl File
@@ -141,21 +211,46 @@ impl File
 			println!("Err:TypeMismatch");
 			return Err(super::Error::TypeMismatch);
 		}
+		let access = match mode
+			{
+			FileOpenMode::SharedRO => Mode::USER_READ,
+			FileOpenMode::Execute => Mode::USER_READ | Mode::USER_EXEC,
+			FileOpenMode::ExclRW => Mode::USER_READ | Mode::USER_WRITE,
+			// Writes only go to the private copy
+			FileOpenMode::UniqueRW => Mode::USER_READ,
+			FileOpenMode::Append => Mode::USER_WRITE,
+			FileOpenMode::Unsynch => Mode::USER_READ | Mode::USER_WRITE,
+			};
+		try!(node.check_access(access));
 		match mode
 		{
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be readable in current context)
-		FileOpenMode::SharedRO => {},
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be executable in current context)
-		FileOpenMode::Execute => {},
-		_ => todo!("Acquire lock depending on mode({:?})", mode),
+		FileOpenMode::ExclRW | FileOpenMode::Append | FileOpenMode::Unsynch => try!(node.check_writable()),
+		FileOpenMode::Execute if node.mount_options().noexec => return Err(super::Error::PermissionDenied),
+		_ => {},
 		}
-		Ok(File { node: node, mode: mode })
+		try!(node.open_lock(&mode));
+		// Create the handle now so the lock is released by `drop` if the below fails
+		let rv = File {
+			proc_handle: ::process::add_handle(describe(&node, &mode)),
+			node: node,
+			mode: mode,
+			lock_owner: S_NEXT_LOCK_OWNER.fetch_add(1, Ordering::Relaxed),
+			private: Mutex::new(None),
+			};
+		try!(rv.node.touch(true, false));
+		Ok(rv)
 	}
 	
 	pub fn size(&self) -> u64 {
-		self.node.get_valid_size()
+		match *self.private.lock()
+		{
+		Some(ref data) => data.len() as u64 * 4,
+		None => self.node.get_valid_size(),
+		}
+	}
+	/// Return the file's metadata (`fstat`)
+	pub fn metadata(&self) -> Metadata {
+		self.node.get_metadata()
 	}
 
 	/// Read data from the file at the specified offset
@@ -164,21 +259,93 @@ impl File
 	/// slice).
 	pub fn read(&self, ofs: u64, dst: &mut [u32]) -> super::Result<usize> {
 		assert!(self.node.is_file());
+		if let FileOpenMode::Append = self.mode {
+			return Err(super::Error::PermissionDenied);
+		}
+		if let Some(ref data) = *self.private.lock() {
+			if ofs % 4 != 0 {
+				return Err(super::Error::InvalidParameter);
+			}
+			let start = ::core::cmp::min(ofs / 4, data.len() as u64) as usize;
+			let len = ::core::cmp::min(dst.len(), data.len() - start);
+			dst[..len].copy_from_slice(&data[start .. start + len]);
+			return Ok(len);
+		}
 		self.node.read(ofs, dst)
 	}
+	/// Write data to the file at the specified offset
+	///
+	/// `Append` handles ignore `ofs` and always write to the end of the file, `UniqueRW` handles
+	/// write to a private copy.
 	pub fn write(&self, ofs: u64, src: &[u32]) -> super::Result<usize> {
 		assert!(self.node.is_file());
 		//todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
-		self.node.write(ofs, src)
+		match self.mode
+		{
+		FileOpenMode::SharedRO | FileOpenMode::Execute => Err(super::Error::PermissionDenied),
+		FileOpenMode::Append => {
+			try!(self.node.append(src));
+			Ok(src.len())
+			},
+		FileOpenMode::UniqueRW => self.write_private(ofs, src),
+		FileOpenMode::ExclRW | FileOpenMode::Unsynch => self.node.write(ofs, src),
+		}
+	}
+	fn write_private(&self, ofs: u64, src: &[u32]) -> super::Result<usize> {
+		let mut lh = self.private.lock();
+		if lh.is_none() {
+			let mut data = vec![0; ((self.node.get_valid_size() + 3) / 4) as usize];
+			let len = try!(self.node.read(0, &mut data));
+			data.truncate(len);
+			*lh = Some(data);
+		}
+		let data = lh.as_mut().unwrap();
+		// Same rule as `node::File::write` - can only grow from the end
+		if ofs % 4 != 0 || ofs / 4 > data.len() as u64 {
+			return Err(super::Error::InvalidParameter);
+		}
+		let start = (ofs / 4) as usize;
+		let overlap = ::core::cmp::min(src.len(), data.len() - start);
+		data[start .. start + overlap].copy_from_slice(&src[..overlap]);
+		data.extend_from_slice(&src[overlap..]);
+		Ok(src.len())
 	}
+	/// Replace the contents of the file (`UniqueRW` handles replace their private copy)
 	pub fn mut_write(&mut self, src: &[u32]) -> super::Result<usize> {
 		assert!(self.node.is_file());
 		//todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
-		self.node.mut_write(src)
+		match self.mode
+		{
+		FileOpenMode::SharedRO | FileOpenMode::Execute => Err(super::Error::PermissionDenied),
+		FileOpenMode::UniqueRW => {
+			*self.private.lock() = Some(src.to_vec());
+			Ok(src.len())
+			},
+		_ => self.node.mut_write(src),
+		}
+	}
+
+	/// Write back any cached changes to this file (`fsync`)
+	pub fn sync(&self) -> super::Result<()> {
+		self.node.sync()
+	}
+
+	/// Acquire an advisory lock on a byte range of the file
+	///
+	/// Locks are per-handle, never block (`Locked` is returned on conflict), and are released
+	/// when the handle is dropped.
+	pub fn lock(&self, ofs: u64, len: u64, kind: LockKind) -> super::Result<()> {
+		self.node.lock_range(self.lock_owner, ofs, len, kind)
+	}
+	/// Release this handle's advisory locks within a byte range
+	pub fn unlock(&self, ofs: u64, len: u64) {
+		self.node.unlock_range(self.lock_owner, ofs, len)
 	}
 
-	/*
 	/// Map a file into the address space
+	///
+	/// The mapped frames are the file's page cache pages, so the mapping sees (and for `WriteBack`,
+	/// makes) the same changes as `read`/`write` on any handle to the file.
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle> {
 		//log_debug!("memory_map(self={{mode:{:?}}}, address={:#x}, ofs={:#x}, size={:#x}, mode={:?})",
 		//	self.mode, address, ofs, size, mode);
@@ -211,7 +378,9 @@ impl File
 		// Writeback - Requires exclusive access to the file (or a copy)
 		MemoryMapMode::WriteBack => match self.mode
 			{
-			//FileOpenMode::ExclRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
+			// No other handles can observe the changes (besides Append, which can't read)
+			FileOpenMode::ExclRW => {},
+			FileOpenMode::Unsynch => {},
 			//FileOpenMode::UniqueRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
 			_ => return Err(super::Error::PermissionDenied),
 			},
@@ -226,63 +395,55 @@ impl File
 		if address % PAGE_SIZE != (ofs % PAGE_SIZE as u64) as usize {
 			return Err( super::Error::Unknown("memory_map alignment mismatch") );
 		}
-		// - Limit checking (ofs + size must be within size of the file)
-		// TODO: Limit checking
-		// - Reserve the region to be mapped (reserve sticks a zero page in)
+		// - Limit checking (ofs + size must be within size of the file, rounded up to a page)
+		let file_pages = (self.size() + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
 		let page_count = size / PAGE_SIZE;
-		/*let mut resv = match ::memory::virt::reserve(address as *mut (), page_count)
-			{
-			Ok(v) => v,
-			Err(e) => {
-				//log_notice!("mmap reserve error {:?}", e);
-				return Err( super::Error::Locked );
-				},
-			};*/
-		// - Obtain handles to each cached page, and map into the reservation
-		for i in 0 .. page_count {
-			let page = ofs / PAGE_SIZE as u64 + i as u64;
-			// 1. Search the node for this particular page
-			//let lh = self.page_cache.read();
-			//  - If found, map over region
-			// 2. Drop lock, read data from file, and try again
-			//drop(lh)
-			//try!( self.node.read(page * PAGE_SIZE as u64, resv.get_mut_page(i)) );
-			// 3. Acquire write on lock, and attempt to insert a handle to this page
-			//let lh = self.page_cache.write();
-			//match lh.try_insert(pag, self.get_page_handle(i))
-			//{
-			//Ok(_) => {},	// Inserted correctly
-			//Err(h) => {	// Another handle made a page for this first
-			//	resv.map_at(i, h);	// - Map over our original attempt
-			//	},
-			//}
+		let first_page = ofs / PAGE_SIZE as u64;
+		if first_page + page_count as u64 > file_pages {
+			return Err( super::Error::InvalidParameter );
 		}
-		resv.finalise( match mode
+
+		let writable = match mode { MemoryMapMode::WriteBack => true, _ => false };
+		let flags = match mode
 			{
-			MemoryMapMode::ReadOnly  => ::memory::ProtectionMode::UserRO,
-			MemoryMapMode::Execute   => ::memory::ProtectionMode::UserRX,
-			MemoryMapMode::COW       => ::memory::ProtectionMode::UserCOW,
-			MemoryMapMode::WriteBack => ::memory::ProtectionMode::UserRW,
-			})
-			.unwrap();
-		//log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
-		Ok(MemoryMapHandle {
+			MemoryMapMode::ReadOnly  => EntryFlags::USER_ACCESSIBLE | EntryFlags::NO_EXECUTE,
+			MemoryMapMode::Execute   => EntryFlags::USER_ACCESSIBLE,
+			// TODO: Copy on write faults, until then the page is just read-only
+			MemoryMapMode::COW       => EntryFlags::USER_ACCESSIBLE,
+			MemoryMapMode::WriteBack => EntryFlags::USER_ACCESSIBLE | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+			};
+		let mut rv = MemoryMapHandle {
 			handle: self,
 			base: address as *mut (),
-			len: page_count * PAGE_SIZE,
-			})
-	}*/
+			len: 0,
+			first_page: first_page,
+			writable: writable,
+			};
+		// - Obtain handles to each cached page, and map into the address space
+		// SAFE: Mapping into unused user memory, checked below
+		let mut active_table = unsafe { ActivePageTable::new() };
+		for i in 0 .. page_count
+		{
+			let page = Page::containing_address(address + i * PAGE_SIZE);
+			if active_table.translate_page(page).is_some() {
+				//log_notice!("memory_map - {:#x} already mapped", page.start_address());
+				// - Dropping `rv` unmaps the pages mapped so far
+				return Err( super::Error::Locked );
+			}
+			let frame = try!(self.node.map_page(first_page + i as u64, writable));
+			let result = active_table.map_to(page, frame, flags);
+			result.flush(&mut active_table);
+			rv.len += PAGE_SIZE;
+		}
+		//log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
+		Ok(rv)
+	}
 }
 impl ::core::ops::Drop for File
 {
 	fn drop(&mut self) {
-		match self.mode
-		{
-		FileOpenMode::SharedRO => {},
-		FileOpenMode::Execute => {},
-		_ => todo!("File::drop() - mode={:?}", self.mode),
-		}
-		// TODO: For files, we need to release the lock
+		self.node.open_unlock(&self.mode, self.lock_owner);
+		::process::remove_handle(self.proc_handle.0, self.proc_handle.1);
 	}
 }
 
@@ -294,9 +455,15 @@ impl<'a> Drop for MemoryMapHandle<'a>
 		assert_eq!(self.base as usize % PAGE_SIZE, 0, "TODO: Handle unaligned addresses in MemoryMapHandle::drop");
 		let npages = self.len / PAGE_SIZE;
 		// SAFE: This is a uniquely owned handle
-		// unsafe {
-		// 	::memory::virt::unmap(self.base, npages);
-		// }
+		let mut active_table = unsafe { ActivePageTable::new() };
+		for i in 0 .. npages
+		{
+			let page = Page::containing_address(self.base as usize + i * PAGE_SIZE);
+			// - The frame belongs to the page cache, so don't free it
+			let (result, _frame) = active_table.unmap_return(page, false);
+			result.flush(&mut active_table);
+			self.handle.node.unmap_page(self.first_page + i as u64, self.writable);
+		}
 	}
 }
 
@@ -307,6 +474,11 @@ impl Dir
 		try!(Any::open(path)).to_dir()
 	}
 	
+	/// Return the directory's metadata
+	pub fn metadata(&self) -> Metadata {
+		self.node.get_metadata()
+	}
+	
 	pub fn iter(&self) -> DirIter {
 		DirIter {
 			handle: self,
@@ -331,11 +503,31 @@ impl Dir
 		try!(self.node.create(name.as_ref(), NodeType::Symlink(target)));
 		Ok( () )
 	}
+	/// Create a new named pipe (open it with `Special::open`)
+	pub fn mkfifo(&self, name: &str) -> super::Result<()> {
+		try!(self.node.create(name.as_ref(), NodeType::Fifo));
+		Ok( () )
+	}
 	/// Create a new file
 	pub fn mkfile(&self, name: &str, mode: FileOpenMode) -> super::Result<File> {
 		let node = try!(self.node.create(name.as_ref(), NodeType::File));
 		assert!(node.is_file());
-		Ok( File { node: node, mode: mode } )
+		File::from_node(node, mode)
+	}
+
+	/// Remove a (non-directory) entry
+	pub fn unlink(&self, name: &str) -> super::Result<()> {
+		self.node.unlink(name.as_ref())
+	}
+	/// Remove an empty directory
+	pub fn rmdir(&self, name: &str) -> super::Result<()> {
+		self.node.rmdir(name.as_ref())
+	}
+	/// Atomically move `old_name` to `new_name` in `new_dir` (which can be this directory)
+	///
+	/// Fails with `CrossDevice` if the directories are on different mounts.
+	pub fn rename(&self, old_name: &str, new_dir: &Dir, new_name: &str) -> super::Result<()> {
+		self.node.rename(old_name.as_ref(), &new_dir.node, new_name.as_ref())
 	}
 
 	/// Open a child of this node
@@ -399,10 +591,63 @@ impl<'a> ::core::iter::Iterator for DirIter<'a> {
 
 impl Symlink
 {
+	/// Open a symbolic link (the final component isn't followed)
 	pub fn open(path: &Path) -> super::Result<Symlink> {
-		try!(Any::open(path)).to_symlink()
+		try!(Any::open_nofollow(path)).to_symlink()
+	}
+	/// Return the link's metadata (`lstat`)
+	pub fn metadata(&self) -> Metadata {
+		self.node.get_metadata()
 	}
+	/// Return the raw target (`readlink`), relative targets are relative to the link's directory
 	pub fn get_target(&self) -> super::Result<ByteString> {
 		self.node.get_target()
 	}
 }
+
+impl Special
+{
+	/// Open a special node, this can block (e.g. a named pipe waiting for the other end)
+	pub fn open(path: &Path, mode: SpecialOpenMode) -> super::Result<Special> {
+		try!(Any::open(path)).to_special(mode)
+	}
+	/// Returns the type of special node (e.g. "char", "block")
+	pub fn typename(&self) -> &str {
+		self.node.get_typename().expect("Special handle to non-special node")
+	}
+	/// Return the node's metadata
+	pub fn metadata(&self) -> Metadata {
+		self.node.get_metadata()
+	}
+	/// Read bytes from the node (`ofs` is ignored by stream devices)
+	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
+		if !self.mode.can_read() {
+			return Err(super::Error::PermissionDenied);
+		}
+		self.node.special_read(ofs, dst)
+	}
+	/// Write bytes to the node (`ofs` is ignored by stream devices)
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		if !self.mode.can_write() {
+			return Err(super::Error::PermissionDenied);
+		}
+		self.node.special_write(ofs, src)
+	}
+	/// Send a node-specific control request
+	pub fn ioctl(&self, cmd: u32, arg: usize) -> super::Result<usize> {
+		self.node.special_ioctl(cmd, arg)
+	}
+}
+impl ::core::ops::Drop for Special
+{
+	fn drop(&mut self) {
+		self.node.special_close(self.mode.can_read(), self.mode.can_write());
+		::process::remove_handle(self.proc_handle.0, self.proc_handle.1);
+	}
+}
+
+/// Description of an open handle for the process handle table (`/proc/<pid>/files`)
+fn describe<M: ::core::fmt::Debug>(node: &CacheHandle, mode: &M) -> String
+{
+	format!("{}:{:#x} {:?}", node.mount_id(), node.inode(), mode)
+}
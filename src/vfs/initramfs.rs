@@ -0,0 +1,367 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/initramfs.rs
+//! Initial RAM filesystem, unpacked from a multiboot module
+//!
+//! The bootloader loads the archive (newc cpio or ustar) as a module, `rust_main` records it with
+//! `add_module` (its frames are kept out of the frame allocator by `memory::init`), and
+//! `vfs::init` unpacks it into the root ramfs with `load`.
+//!
+//! Directories, regular files, symbolic links and named pipes are created with the archive's
+//! permissions, owner and modification time. Device nodes and hard links are skipped.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use memory::{Frame,EntryFlags,PAGE_SIZE};
+use memory::kmap;
+use super::node::{self,CacheHandle,NodeType};
+use super::{Path,PathBuf};
+
+const CPIO_HEADER_LEN: usize = 110;
+const TAR_BLOCK: usize = 512;
+
+/// A module passed by the bootloader (physical address range)
+struct BootModule
+{
+	start: usize,
+	end: usize,
+	name: String,
+}
+static S_MODULES: Mutex<Option<Vec<BootModule>>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ArchiveError
+{
+	/// Neither a newc cpio nor a ustar archive
+	UnknownFormat,
+	/// Archive ends part way through the entry at this offset
+	Truncated(usize),
+	/// Malformed header at this offset
+	BadHeader(usize),
+}
+
+enum Kind<'a>
+{
+	File(&'a [u8]),
+	Dir,
+	Symlink(&'a [u8]),
+	Fifo,
+	/// Archive metadata (e.g. pax headers), silently skipped
+	Meta,
+	/// Unsupported (devices, hard links)
+	Other,
+}
+struct Entry<'a>
+{
+	name: Vec<u8>,
+	kind: Kind<'a>,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	mtime: u64,
+}
+
+/// Record a boot module (called from `rust_main` before the VFS is initialised)
+pub fn add_module(start: usize, end: usize, name: &str)
+{
+	S_MODULES.lock().get_or_insert_with(Vec::new).push(BootModule {
+		start: start,
+		end: end,
+		name: String::from(name),
+		});
+}
+
+/// Unpack all recorded boot modules into the root filesystem
+pub fn load()
+{
+	let modules = S_MODULES.lock().take().unwrap_or_default();
+	for m in modules
+	{
+		let page_ofs = m.start % PAGE_SIZE;
+		let len = m.end - m.start;
+		if len == 0 {
+			println!("warning: initramfs: Module '{}' is empty", m.name);
+			continue ;
+		}
+		let pages = (page_ofs + len + PAGE_SIZE - 1) / PAGE_SIZE;
+		let mapping = match kmap::map_frames(Frame::containing_address(m.start), pages, EntryFlags::NO_EXECUTE)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: initramfs: Unable to map module '{}' ({} bytes)", m.name, len);
+				continue ;
+				},
+			};
+		// SAFE: The module's frames are reserved at boot, and nothing writes to them
+		let data = unsafe { &mapping.as_bytes()[page_ofs .. page_ofs + len] };
+		match unpack(data)
+		{
+		Ok(count) => println!("initramfs: Unpacked {} entries from '{}'", count, m.name),
+		Err(e) => println!("warning: initramfs: Module '{}' not unpacked: {:?}", m.name, e),
+		}
+	}
+}
+
+/// Unpack an archive into the root filesystem, returning the number of entries created
+fn unpack(data: &[u8]) -> Result<usize,ArchiveError>
+{
+	let mut count = 0;
+	{
+		let mut cb = |e: Entry| {
+			match create_entry(&e)
+			{
+			Ok(true) => count += 1,
+			Ok(false) => {},
+			Err(err) => println!("warning: initramfs: '{}' not created: {:?}", Path::new(&e.name[..]), err),
+			}
+			};
+		if data.starts_with(b"070701") || data.starts_with(b"070702") {
+			try!(parse_cpio(data, &mut cb));
+		}
+		else if data.len() >= TAR_BLOCK && &data[257..262] == b"ustar" {
+			try!(parse_tar(data, &mut cb));
+		}
+		else {
+			return Err(ArchiveError::UnknownFormat);
+		}
+	}
+	Ok(count)
+}
+
+/// Create a node for an archive entry, returns false if it was skipped
+fn create_entry(e: &Entry) -> super::Result<bool>
+{
+	// Archive paths are relative to the root (usually with a leading `./`)
+	let mut path = PathBuf::from("/");
+	path.push( Path::new(&e.name[..]) );
+	let path = path.normalise();
+	let (parent, name) = match (path.parent(), path.file_name())
+		{
+		(Some(p), Some(n)) => (p, n),
+		// The root itself
+		_ => return Ok(false),
+		};
+	let parent = try!(make_dirs(parent));
+	let mut node = match e.kind
+		{
+		Kind::Dir => match parent.open_child(name)
+			{
+			Ok(ref n) if !n.is_dir() => return Err(super::Error::AlreadyExists),
+			Ok(n) => n,
+			Err(super::Error::NotFound) => try!(parent.create(name, NodeType::Dir)),
+			Err(err) => return Err(err),
+			},
+		Kind::File(_) => try!(parent.create(name, NodeType::File)),
+		Kind::Symlink(target) => try!(parent.create(name, NodeType::Symlink(Path::new(target)))),
+		Kind::Fifo => try!(parent.create(name, NodeType::Fifo)),
+		Kind::Meta => return Ok(false),
+		Kind::Other => return Err(super::Error::TypeMismatch),
+		};
+	if let Kind::File(data) = e.kind {
+		try!(write_data(&mut node, data));
+	}
+	try!(node.chmod( node::Mode::from_bits_truncate(e.mode as u16) ));
+	try!(node.chown(e.uid, e.gid));
+	try!(node.utimes( (e.mtime, 0), (e.mtime, 0) ));
+	Ok(true)
+}
+
+/// Open the directory at (absolute, normalised) `path`, creating missing components
+fn make_dirs(path: &Path) -> super::Result<CacheHandle>
+{
+	let mut cur = try!(CacheHandle::from_path(Path::new("/")));
+	for seg in path
+	{
+		if seg.len() == 0 {
+			continue ;
+		}
+		cur = match cur.open_child(seg)
+			{
+			Ok(n) => n,
+			Err(super::Error::NotFound) => try!(cur.create(seg, NodeType::Dir)),
+			Err(e) => return Err(e),
+			};
+		if ! cur.is_dir() {
+			return Err(super::Error::NonDirComponent);
+		}
+	}
+	Ok(cur)
+}
+
+/// Store file contents
+///
+/// NOTE: `mut_write` is used as it allocates the file's storage in one go. It works in words, so
+/// the file is then truncated to the exact size.
+fn write_data(node: &mut CacheHandle, data: &[u8]) -> super::Result<()>
+{
+	if data.len() == 0 {
+		return Ok( () );
+	}
+	let mut buf = vec![0u32; (data.len() + 3) / 4];
+	for (i, &b) in data.iter().enumerate() {
+		buf[i / 4] |= (b as u32) << ((i % 4) * 8);
+	}
+	try!(node.mut_write(&buf));
+	try!(node.truncate(data.len() as u64));
+	Ok( () )
+}
+
+/// Parse a newc ("070701", or "070702" with checksums) cpio archive
+fn parse_cpio<F: FnMut(Entry)>(data: &[u8], cb: &mut F) -> Result<(),ArchiveError>
+{
+	let mut ofs = 0;
+	loop
+	{
+		if ofs + CPIO_HEADER_LEN > data.len() {
+			return Err(ArchiveError::Truncated(ofs));
+		}
+		let hdr = &data[ofs .. ofs + CPIO_HEADER_LEN];
+		if &hdr[..6] != b"070701" && &hdr[..6] != b"070702" {
+			return Err(ArchiveError::BadHeader(ofs));
+		}
+		// Fields after the magic: ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+		// rdevmajor, rdevminor, namesize, check
+		let mut fields = [0u32; 13];
+		for (i, f) in fields.iter_mut().enumerate() {
+			*f = try!(parse_number(&hdr[6 + i * 8 .. 14 + i * 8], 16).ok_or(ArchiveError::BadHeader(ofs)));
+		}
+		let (mode, filesize, namesize) = (fields[1], fields[6] as usize, fields[11] as usize);
+
+		let name_start = ofs + CPIO_HEADER_LEN;
+		let name_end = name_start + namesize;
+		if namesize == 0 || name_end > data.len() {
+			return Err(ArchiveError::Truncated(ofs));
+		}
+		// `namesize` includes the NUL terminator
+		let name = &data[name_start .. name_end - 1];
+		let data_start = align(name_end, 4);
+		let data_end = data_start + filesize;
+		if data_end > data.len() {
+			return Err(ArchiveError::Truncated(ofs));
+		}
+		if name == b"TRAILER!!!" {
+			return Ok( () );
+		}
+		let body = &data[data_start .. data_end];
+		let kind = match mode & 0o170000
+			{
+			0o040000 => Kind::Dir,
+			0o100000 => Kind::File(body),
+			0o120000 => Kind::Symlink(body),
+			0o010000 => Kind::Fifo,
+			_ => Kind::Other,
+			};
+		cb(Entry {
+			name: name.to_vec(),
+			kind: kind,
+			mode: mode & 0o7777,
+			uid: fields[2],
+			gid: fields[3],
+			mtime: fields[5] as u64,
+			});
+		ofs = align(data_end, 4);
+	}
+}
+
+/// Parse a ustar archive
+fn parse_tar<F: FnMut(Entry)>(data: &[u8], cb: &mut F) -> Result<(),ArchiveError>
+{
+	let mut ofs = 0;
+	while ofs + TAR_BLOCK <= data.len()
+	{
+		let hdr = &data[ofs .. ofs + TAR_BLOCK];
+		// End of archive is marked by zero blocks
+		if hdr.iter().all(|&b| b == 0) {
+			return Ok( () );
+		}
+		// Checksum is the sum of the header bytes with the checksum field taken as spaces
+		let sum = hdr.iter().enumerate()
+			.map(|(i, &b)| if 148 <= i && i < 156 { b' ' as u32 } else { b as u32 })
+			.fold(0, |a, b| a + b);
+		if parse_number(&hdr[148 .. 156], 8) != Some(sum) {
+			return Err(ArchiveError::BadHeader(ofs));
+		}
+		let field = |start: usize, end: usize| parse_number(&hdr[start .. end], 8).ok_or(ArchiveError::BadHeader(ofs));
+		let mode = try!(field(100, 108));
+		let uid = try!(field(108, 116));
+		let gid = try!(field(116, 124));
+		let size = try!(field(124, 136)) as usize;
+		let mtime = try!(field(136, 148)) as u64;
+
+		let mut name = Vec::new();
+		let prefix = c_str(&hdr[345 .. 500]);
+		if prefix.len() > 0 {
+			name.extend_from_slice(prefix);
+			name.push(b'/');
+		}
+		name.extend_from_slice(c_str(&hdr[0 .. 100]));
+
+		let body_start = ofs + TAR_BLOCK;
+		let body_end = body_start + size;
+		if body_end > data.len() {
+			return Err(ArchiveError::Truncated(ofs));
+		}
+		let body = &data[body_start .. body_end];
+		let kind = match hdr[156]
+			{
+			b'0' | b'\0' | b'7' => Kind::File(body),
+			b'5' => Kind::Dir,
+			b'2' => Kind::Symlink(c_str(&hdr[157 .. 257])),
+			b'6' => Kind::Fifo,
+			// pax extended headers and GNU long names
+			b'x' | b'g' | b'L' | b'K' => Kind::Meta,
+			_ => Kind::Other,
+			};
+		cb(Entry {
+			name: name,
+			kind: kind,
+			mode: mode & 0o7777,
+			uid: uid,
+			gid: gid,
+			mtime: mtime,
+			});
+		ofs = body_start + align(size, TAR_BLOCK);
+	}
+	// Tolerate a missing end-of-archive marker
+	Ok( () )
+}
+
+/// Parse a fixed-width ASCII number (leading/trailing spaces and NULs are ignored)
+fn parse_number(field: &[u8], radix: u32) -> Option<u32>
+{
+	let mut rv: u32 = 0;
+	let mut seen_digit = false;
+	for &b in field
+	{
+		match (b as char).to_digit(radix)
+		{
+		Some(d) => {
+			rv = match rv.checked_mul(radix).and_then(|v| v.checked_add(d))
+				{
+				Some(v) => v,
+				None => return None,
+				};
+			seen_digit = true;
+			},
+		None if b == b' ' || b == 0 => if seen_digit { break ; },
+		None => return None,
+		}
+	}
+	Some(rv)
+}
+
+/// Bytes up to the first NUL
+fn c_str(field: &[u8]) -> &[u8]
+{
+	match field.iter().position(|&b| b == 0)
+	{
+	Some(len) => &field[..len],
+	None => field,
+	}
+}
+
+fn align(v: usize, to: usize) -> usize
+{
+	(v + to - 1) / to * to
+}
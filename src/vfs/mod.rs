@@ -34,6 +34,14 @@ pub enum Error
 	NonDirComponent,
 	/// Symbolic link recursion limit reached
 	RecursionDepthExceeded,
+	/// Write to a pipe with no readers
+	BrokenPipe,
+	/// Operation would cross a filesystem boundary (e.g. rename between mounts)
+	CrossDevice,
+	/// Directory to be removed (or replaced) is not empty
+	NotEmpty,
+	/// Node is in use and can't be removed (e.g. a mountpoint)
+	Busy,
 
 
 	/// Block-level IO Error
@@ -76,6 +84,18 @@ pub mod mount;
 pub mod handle;
 mod path;
 mod ramfs;
+pub mod devfs;
+pub mod pipe;
+pub mod initramfs;
+mod procfs;
+mod page_cache;
+
+/// Timer ticks between runs of the flush daemon (5s with the 100Hz PIT)
+const FLUSH_INTERVAL_TICKS: usize = 500;
+/// Size (in 512 byte blocks) of the RAM disk holding the root filesystem (8 MiB)
+const ROOT_RAMDISK_BLOCKS: usize = 16 * 1024;
+/// Size (in words) of the buffer used by `copy_recursive`
+const COPY_CHUNK_WORDS: usize = 1024;
 
 pub fn init()
 {
@@ -88,18 +108,259 @@ pub fn init()
 	//let h = mount::DriverRegistration::new("ramfs", &ramfs::S_DRIVER);
 	mount::DriverRegistration::new("ramfs",&ramfs::S_DRIVER);
 	//mount::test();
-	let sv=VolumeHandle::new_ramdisk(0);
+	let sv=VolumeHandle::new_ramdisk(ROOT_RAMDISK_BLOCKS);
 	ramfs::init();
+	devfs::init();
+	procfs::init();
 	mount::mount("/".as_ref(), sv, "ramfs", &[]).expect("Unable to mount /");
-	// 3. Initialise root filesystem layout
+	// 3. Unpack the initramfs (if one was passed by the bootloader)
+	initramfs::load();
+	// 4. Initialise root filesystem layout (the initramfs may already provide these)
 	let root = match handle::Dir::open( Path::new("/") )
 		{
 		Ok(v) => v,
 		Err(e) => panic!("BUG - Opening '/' failed: {:?}", e),
 		};
-	root.mkdir("system").unwrap();
-	root.mkdir("volumes").unwrap();
-	root.mkdir("temp").unwrap();
+	mkdir_existing(&root, "system");
+	mkdir_existing(&root, "volumes");
+	mkdir_existing(&root, "temp");
+	mkdir_existing(&root, "dev");
+	mount::mount("/dev".as_ref(), VolumeHandle::new_ramdisk(0), "devfs", &[]).expect("Unable to mount /dev");
+	mkdir_existing(&root, "proc");
+	mount::mount("/proc".as_ref(), VolumeHandle::new_ramdisk(0), "procfs", &["ro"]).expect("Unable to mount /proc");
+}
+
+/// Create a directory under `/` at boot, leaving an existing one in place
+fn mkdir_existing(root: &handle::Dir, name: &str)
+{
+	match root.mkdir(name)
+	{
+	Ok(_) => {},
+	Err(Error::AlreadyExists) => {},
+	Err(e) => panic!("Unable to create '/{}': {:?}", name, e),
+	}
+}
+
+/// Change the current process's working directory
+pub fn chdir(path: &Path) -> Result<()>
+{
+	let node = try!(node::CacheHandle::from_path(path));
+	if ! node.is_dir() {
+		return Err(Error::NonDirComponent);
+	}
+	// NOTE: The stored path is the lexical one (like `cd -L`), so `..` after a symlink returns to
+	// where the link was.
+	::process::set_cwd( ::process::get_cwd().join(path).normalise() );
+	Ok( () )
+}
+
+/// Get the current process's working directory (relative to the process root)
+pub fn getcwd() -> PathBuf
+{
+	::process::get_cwd()
+}
+
+/// Change the current process's root directory, the working directory is reset to the new root
+pub fn chroot(path: &Path) -> Result<()>
+{
+	let node = try!(node::CacheHandle::from_path(path));
+	if ! node.is_dir() {
+		return Err(Error::NonDirComponent);
+	}
+	let new_root = ::process::get_cwd().join(path).normalise();
+	// `new_root` is within the current root, which is a global path
+	let mut global = ::process::get_root();
+	global.push( try!(new_root.split_off_first().ok_or(Error::MalformedPath)).1 );
+	::process::set_root( global.normalise() );
+	::process::set_cwd( PathBuf::from("/") );
+	Ok( () )
+}
+
+/// Open the directory containing `path`, returning it and the final component
+fn open_parent(path: &Path) -> Result<(node::CacheHandle, &ByteStr)>
+{
+	let name = try!(path.file_name().ok_or(Error::InvalidParameter));
+	let parent = try!(node::CacheHandle::from_path( path.parent().unwrap_or(Path::new("")) ));
+	if ! parent.is_dir() {
+		return Err(Error::NonDirComponent);
+	}
+	Ok( (parent, name) )
+}
+
+/// Get the metadata of the node at `path`, following a final symbolic link
+pub fn stat(path: &Path) -> Result<node::Metadata>
+{
+	Ok( try!(node::CacheHandle::from_path(path)).get_metadata() )
+}
+
+/// Get the metadata of the node at `path`, or of the link itself if it's a symbolic link
+pub fn lstat(path: &Path) -> Result<node::Metadata>
+{
+	Ok( try!(node::CacheHandle::from_path_nofollow(path)).get_metadata() )
+}
+
+/// Read the target of the symbolic link at `path` (`InvalidParameter` if it's not a link)
+pub fn readlink(path: &Path) -> Result<ByteString>
+{
+	let node = try!(node::CacheHandle::from_path_nofollow(path));
+	if ! node.is_symlink() {
+		return Err(Error::InvalidParameter);
+	}
+	node.get_target()
+}
+
+/// Move `old` to `new` (replacing a compatible existing node), both must be on the same mount
+pub fn rename(old: &Path, new: &Path) -> Result<()>
+{
+	let (old_dir, old_name) = try!(open_parent(old));
+	let (new_dir, new_name) = try!(open_parent(new));
+	old_dir.rename(old_name, &new_dir, new_name)
+}
+
+/// Remove a non-directory node
+pub fn unlink(path: &Path) -> Result<()>
+{
+	let (dir, name) = try!(open_parent(path));
+	dir.unlink(name)
+}
+
+/// Remove an empty directory
+pub fn rmdir(path: &Path) -> Result<()>
+{
+	let (dir, name) = try!(open_parent(path));
+	dir.rmdir(name)
+}
+
+/// Recursively copy `src` to `dst` (which must not exist)
+///
+/// Symbolic links are copied (not followed), named pipes are recreated empty and other special
+/// nodes fail with `TypeMismatch`. Volumes mounted below `src` are skipped.
+pub fn copy_recursive(src: &Path, dst: &Path) -> Result<()>
+{
+	let src = try!(node::CacheHandle::from_path_nofollow(src));
+	let (dst_dir, name) = try!(open_parent(dst));
+	if src.is_dir() && try!(src.contains(&dst_dir)) {
+		return Err(Error::InvalidParameter);
+	}
+	copy_node(&src, &dst_dir, name)
+}
+
+/// Recursively remove `path` (symbolic links are removed, not followed)
+///
+/// Fails with `Busy` if there's a mountpoint within `path`, leaving the rest partially removed.
+pub fn remove_recursive(path: &Path) -> Result<()>
+{
+	let (dir, name) = try!(open_parent(path));
+	remove_node(&dir, name)
+}
+
+fn copy_node(src: &node::CacheHandle, dst_dir: &node::CacheHandle, name: &ByteStr) -> Result<()>
+{
+	let meta = src.get_metadata();
+	let new = match meta.class
+		{
+		node::NodeClass::Dir => {
+			let new = try!(dst_dir.create(name, node::NodeType::Dir));
+			for child_name in try!(dir_entries(src))
+			{
+				let child = try!(src.open_child(&child_name));
+				if child.mount_id() != src.mount_id() {
+					continue ;
+				}
+				try!(copy_node(&child, &new, &child_name));
+			}
+			new
+			},
+		node::NodeClass::File => {
+			let new = try!(dst_dir.create(name, node::NodeType::File));
+			let mut buf = vec![0u32; COPY_CHUNK_WORDS];
+			let mut ofs = 0;
+			loop
+			{
+				let len = try!(src.read(ofs, &mut buf));
+				if len == 0 {
+					break ;
+				}
+				try!(new.append(&buf[..len]));
+				ofs += len as u64 * 4;
+			}
+			// - Files are copied in words, drop the padding this added to the last one
+			try!(new.truncate(meta.size));
+			new
+			},
+		node::NodeClass::Symlink => {
+			let target = try!(src.get_target());
+			try!(dst_dir.create(name, node::NodeType::Symlink(Path::new(&target))))
+			},
+		node::NodeClass::Special => {
+			if try!(src.get_typename()) != "fifo" {
+				return Err(Error::TypeMismatch);
+			}
+			try!(dst_dir.create(name, node::NodeType::Fifo))
+			},
+		};
+	// Keep the permission bits, the copy is owned by the caller (like `cp -r`)
+	match new.chmod(meta.mode)
+	{
+	Ok(_) | Err(Error::ReadOnlyFilesystem) => Ok( () ),
+	Err(e) => Err(e),
+	}
+}
+
+fn remove_node(dir: &node::CacheHandle, name: &ByteStr) -> Result<()>
+{
+	let node = try!(dir.open_child(name));
+	if node.is_dir() {
+		if node.mount_id() != dir.mount_id() {
+			return Err(Error::Busy);
+		}
+		for child_name in try!(dir_entries(&node))
+		{
+			try!(remove_node(&node, &child_name));
+		}
+		dir.rmdir(name)
+	}
+	else {
+		dir.unlink(name)
+	}
+}
+
+/// Collect the names in a directory (so it isn't being read while entries are changed)
+fn dir_entries(dir: &node::CacheHandle) -> Result<Vec<ByteString>>
+{
+	let mut rv = Vec::new();
+	let mut ofs = 0;
+	loop
+	{
+		let count = rv.len();
+		ofs = try!(dir.read_dir(ofs, &mut |_, name| { rv.push(name.collect()); true }));
+		if rv.len() == count {
+			break ;
+		}
+	}
+	Ok(rv)
+}
+
+/// Write all dirty cached data back to the filesystem drivers, then to the volumes
+pub fn sync()
+{
+	node::sync_all();
+	::metadevs::storage::sync_all();
+}
+
+/// Start the kernel thread that periodically writes back dirty file pages and volume blocks
+pub fn start_flush_daemon()
+{
+	::process::spawn("vfs_flush", flush_daemon);
+}
+
+extern fn flush_daemon()
+{
+	loop
+	{
+		sync();
+		::process::sleep(FLUSH_INTERVAL_TICKS);
+	}
 }
 
 pub fn readFile(path: &str, dst: &mut [u32]){
@@ -120,7 +381,7 @@ pub fn readFile(path: &str, dst: &mut [u32]){
 }
 
 pub fn writeFile(path: &str, src: &[u32]){
-	match handle::File::open( Path::new(path), handle::FileOpenMode::SharedRO )
+	match handle::File::open( Path::new(path), handle::FileOpenMode::ExclRW )
 	{
 		Err(e) => println!("waring: VFS test file can't be opened: {:?}", e),
 		Ok(mut h) => {
@@ -4,7 +4,7 @@
 // Core/vfs/mount.rs
 //! Mountpoint managment
 use prelude::*;
-use super::path::Path;
+use super::path::{Path,PathBuf};
 use super::node::{InodeId,Node,CacheHandle};
 //use sync::RwLock;
 use mylib::{LazyStatic,SparseVec,VecMap};
@@ -26,6 +26,44 @@ struct MountedVolume
 {
 	mountpoint_node: CacheHandle,
 	fs: Box<Filesystem>,
+	info: MountInfo,
+	/// Unmounted with `force` while nodes were still in use, released when the last one closes
+	detached: bool,
+}
+
+/// Mount options (parsed from the strings passed to `mount`/`remount`)
+#[derive(Debug,Default,Clone)]
+pub struct MountOptions
+{
+	/// `ro` - Refuse all modifications
+	pub read_only: bool,
+	/// `noexec` - Files can't be opened for execution
+	pub noexec: bool,
+	/// `sync` - Writes go straight to the filesystem driver
+	pub sync: bool,
+}
+
+/// Mount table entry (see `enum_mounts`)
+#[derive(Debug,Clone)]
+pub struct MountInfo
+{
+	/// Mount ID, 0 is the root
+	pub id: usize,
+	/// Location in the global namespace
+	pub path: PathBuf,
+	/// Filesystem driver name
+	pub filesystem: &'static str,
+	/// Name of the mounted volume
+	pub volume: String,
+	pub options: MountOptions,
+}
+
+/// Flags for `unmount`
+#[derive(Debug,Default,Clone)]
+pub struct UnmountFlags
+{
+	/// Detach the volume from the tree even if nodes are in use, it's released when they close
+	pub force: bool,
 }
 
 
@@ -67,6 +105,8 @@ static mut S_DRIVERS: LazyStatic< VecMap<&'static str, &'static Driver> > = lazy
 static mut S_VOLUMES: LazyStatic< SparseVec<MountedVolume> > = lazystatic_init!();
 /// Root mount 根
 static mut S_ROOT_VOLUME: Option<Box<Filesystem>> = None;
+/// Root mount table entry
+static mut S_ROOT_INFO: Option<MountInfo> = None;
 
 pub fn init()
 {
@@ -79,7 +119,6 @@ pub fn init()
 }
 
 /// Mount a volume at the provided location 在提供位置安装volume
-// TODO: Parse options
 pub fn test(){
 	//println!("test:");
 	DriverRegistration::new("ramfs",&super::ramfs::S_DRIVER);
@@ -90,7 +129,7 @@ pub fn mount2(location: &Path, fs: &str, _options: &[&str]) -> Result<(),MountEr
 	DriverRegistration::new("ramfs",&super::ramfs::S_DRIVER);
 	Ok(())
 }
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
 	//println!("mount: ");
 	//DriverRegistration::new("ramfs",&super::ramfs::S_DRIVER);
@@ -102,22 +141,24 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 			None => println!("insert failed"),
 		}
 	}
+	let mut opts = MountOptions::default();
+	try!(opts.apply(options));
 	let drivers = unsafe{S_DRIVERS.ls_unsafe_mut()};
 	// 1. (maybe) detect filesystem 检测文件系统
-	let driver = if fs == "" {
+	let (fs_name, driver) = if fs == "" {
 			match drivers.iter()
 				.filter_map(|(n,fs)| fs.detect(&vol).ok().map(|r| (r, n, fs)))
 				.max_by_key(|&(l,_,_)| l)
 			{
 			Some((0,_,_)) => return Err(MountError::NoHandler),
-			Some((_,_name,fs)) => fs,
+			Some((_,&name,fs)) => (name, fs),
 			None => return Err(MountError::NoHandler),
 			}
 		}
 		else {
-			match drivers.get(fs)
+			match drivers.iter().find(|&(&n,_)| n == fs)
 			{
-			Some(d) => d,
+			Some((&name,d)) => (name, d),
 			None => {
 				//log_notice!("Filesystem '{}' not registered", fs);
 				println!("notice: Filesystem '{}' not registered", fs);
@@ -125,20 +166,30 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 				},
 			}
 		};
+	let mut info = MountInfo {
+		id: 0,
+		path: PathBuf::from(location),
+		filesystem: fs_name,
+		volume: String::from(vol.name()),
+		options: opts,
+		};
 	
 	if location == Path::new("/")
 	{
+		let lh = unsafe{&S_ROOT_VOLUME};
+		if lh.is_some() {
+			// NOTE: Use `remount` to change the options of /
+			return Err(MountError::MountpointUsed);
+		}
 		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
 			{
 			Ok(v) => v,
 			Err(_) => return Err(MountError::CallFailed),
 			};
-		let lh = unsafe{&S_ROOT_VOLUME};
-		if lh.is_some() {
-			//log_warning!("TODO: Support remounting /");
-			return Err(MountError::MountpointUsed);
+		unsafe{
+			S_ROOT_VOLUME = Some(fs);
+			S_ROOT_INFO = Some(info);
 		}
-		unsafe{S_ROOT_VOLUME = Some(fs);}
 	}
 	else
 	{
@@ -154,24 +205,35 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 		if nh.is_mountpoint() {
 			return Err(MountError::MountpointUsed);
 		}
+		// - Record the location in the global namespace (the passed path may be relative/chrooted)
+		info.path = global_path(&::process::get_root(), &::process::get_cwd(), location);
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		unsafe{S_VOLUMES.ls_unsafe_mut().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) })};
-		let vidx = unsafe{S_VOLUMES.len()-1};
+		let vidx = unsafe{S_VOLUMES.ls_unsafe_mut().insert(MountedVolume {
+			mountpoint_node: nh,
+			fs: Box::new(NullFs),
+			info: info,
+			detached: false,
+			})};
+		let id = vidx + 1;
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		let fs = match driver.mount(vol, SelfHandle(id))
 			{
 			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
+			Err(_) => {
+				unsafe{S_VOLUMES.ls_unsafe_mut().remove(vidx)};
+				return Err(MountError::CallFailed)
+				},
 			};
 
 		// 5. Store and bind to mountpoint
 		{
 			let lh = unsafe{S_VOLUMES.ls_unsafe_mut()};
 			lh[vidx].fs = fs;
-			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
+			lh[vidx].info.id = id;
+			if lh[vidx].mountpoint_node.mount(id) == false {
 				lh.remove(vidx);
 				return Err(MountError::MountpointUsed);
 			}
@@ -180,6 +242,151 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 
 	Ok( () )
 }
+
+/// Change the options of the volume mounted at `location`
+///
+/// Options not listed keep their current value (e.g. `&["ro"]` only makes the volume read-only)
+pub fn remount(location: &Path, options: &[&str]) -> Result<(),MountError>
+{
+	let id = try!(mount_id_at(location));
+	let mut opts = Handle::from_id(id).options();
+	try!(opts.apply(options));
+	if opts.read_only {
+		// Push out cached writes before they become impossible
+		super::node::sync_mount(id);
+	}
+	unsafe {
+		if id == 0 {
+			S_ROOT_INFO.as_mut().unwrap().options = opts;
+		}
+		else {
+			S_VOLUMES.ls_unsafe_mut()[id - 1].info.options = opts;
+		}
+	}
+	Ok( () )
+}
+
+/// Unmount the volume mounted at `location`
+///
+/// Fails with `Busy` if any of its nodes are still open, unless `flags.force` is set (in which
+/// case the volume is removed from the tree now, and released once the last node is closed).
+/// The root volume can't be unmounted.
+pub fn unmount(location: &Path, flags: UnmountFlags) -> Result<(),MountError>
+{
+	let id = try!(mount_id_at(location));
+	if id == 0 {
+		return Err(MountError::Busy);
+	}
+	let in_use = super::node::mount_in_use(id);
+	if in_use && !flags.force {
+		return Err(MountError::Busy);
+	}
+	
+	// Unbind from the mountpoint, so new lookups see the underlying directory
+	{
+		let mv = unsafe{ &mut S_VOLUMES.ls_unsafe_mut()[id - 1] };
+		mv.mountpoint_node.unmount();
+		mv.detached = true;
+	}
+	if !in_use {
+		release_detached(id);
+	}
+	Ok( () )
+}
+
+/// Enumerate the mount table
+pub fn enum_mounts() -> Vec<MountInfo>
+{
+	let mut rv: Vec<MountInfo> = unsafe{S_ROOT_INFO.iter().cloned().collect()};
+	rv.extend( unsafe{S_VOLUMES.iter()}.filter(|v| !v.detached).map(|v| v.info.clone()) );
+	rv
+}
+
+/// Called when the last handle to a node of a detached volume is dropped
+pub fn release_detached(id: usize)
+{
+	if id == 0 || ! unsafe{S_VOLUMES.get(id - 1)}.map(|v| v.detached).unwrap_or(false) {
+		return ;
+	}
+	if super::node::mount_in_use(id) {
+		return ;
+	}
+	// Flush and drop cached nodes first, as they reference the filesystem
+	super::node::purge_mount(id);
+	// Dropping the volume releases the filesystem (and the mountpoint node)
+	let name = unsafe{S_VOLUMES.ls_unsafe_mut()[id - 1].info.volume.clone()};
+	unsafe{S_VOLUMES.ls_unsafe_mut().remove(id - 1)};
+	// - Closing the volume doesn't write back its block cache, so do that now
+	if let Err(e) = ::metadevs::storage::sync_named(&name) {
+		println!("warning: Sync of LV '{}' on unmount failed: {:?}", name, e);
+	}
+}
+
+/// Location of `path` in the global namespace, for a process with root `root` and working
+/// directory `cwd` (itself relative to `root`)
+///
+/// Resolved like a lookup: `path` is taken relative to `cwd` and `..` stops at the process root.
+/// NOTE: This is lexical, symbolic links are not followed.
+fn global_path(root: &Path, cwd: &Path, path: &Path) -> PathBuf
+{
+	// Normalising an absolute path drops `..` at its root, so it can't escape `root`
+	let local = PathBuf::from("/").join(cwd).join(path).normalise();
+	let mut rv = PathBuf::from(root);
+	for seg in &*local {
+		rv.push(seg);
+	}
+	rv.normalise()
+}
+
+/// Mount ID of the volume whose root is at `location`
+fn mount_id_at(location: &Path) -> Result<usize,MountError>
+{
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let id = nh.mount_id();
+	if nh.inode() != Handle::from_id(id).root_inode() {
+		return Err(MountError::NotMounted);
+	}
+	Ok(id)
+}
+
+impl MountOptions
+{
+	/// Update the options from a list of option strings
+	pub fn apply(&mut self, options: &[&str]) -> Result<(),MountError> {
+		for &o in options
+		{
+			match o
+			{
+			"ro" => self.read_only = true,
+			"rw" => self.read_only = false,
+			"noexec" => self.noexec = true,
+			"exec" => self.noexec = false,
+			"sync" => self.sync = true,
+			"async" => self.sync = false,
+			"" => {},
+			_ => {
+				println!("notice: Unknown mount option '{}'", o);
+				return Err(MountError::InvalidOption);
+				},
+			}
+		}
+		Ok( () )
+	}
+}
+impl_fmt! {
+	Display(self,f) for MountOptions {
+		write!(f, "{},{},{}",
+			if self.read_only { "ro" } else { "rw" },
+			if self.noexec { "noexec" } else { "exec" },
+			if self.sync { "sync" } else { "async" }
+			)
+	}
+}
+
 #[derive(Debug)]
 pub enum MountError
 {
@@ -188,6 +395,9 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	InvalidOption,
+	NotMounted,
+	Busy,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -198,6 +408,9 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::InvalidOption => "Unknown mount option",
+			&MountError::NotMounted => "No volume is mounted at the specified location",
+			&MountError::Busy => "The volume is in use",
 			})
 	}
 }
@@ -259,6 +472,14 @@ impl Handle
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
 		self.with_fs(|fs| fs.get_node_by_inode(id))
 	}
+	pub fn options(&self) -> MountOptions {
+		if self.0 == 0 {
+			unsafe{S_ROOT_INFO.as_ref().map(|i| i.options.clone()).unwrap_or_default()}
+		}
+		else {
+			unsafe{S_VOLUMES.get(self.0 - 1).unwrap().info.options.clone()}
+		}
+	}
 
 	fn with_fs<R, F: FnOnce(&Filesystem)->R>(&self, f: F) -> R {
 		if self.0 == 0 {
@@ -278,3 +499,31 @@ impl SelfHandle
 	}
 }
 
+#[cfg(test)]
+mod test
+{
+	use super::global_path;
+	use vfs::{Path,PathBuf};
+
+	fn check(root: &str, cwd: &str, path: &str, expect: &str) {
+		assert_eq!(global_path(Path::new(root), Path::new(cwd), Path::new(path)), PathBuf::from(expect));
+	}
+
+	#[test]
+	fn global_path_plain_root() {
+		check("/", "/", "/mnt", "/mnt");
+		check("/", "/home", "mnt", "/home/mnt");
+		check("/", "/home", "../mnt", "/mnt");
+	}
+
+	#[test]
+	fn global_path_chrooted() {
+		check("/jail", "/", "/mnt", "/jail/mnt");
+		// The working directory is within the root, not the global namespace
+		check("/jail", "/home", "/mnt", "/jail/mnt");
+		check("/jail", "/home", "mnt", "/jail/home/mnt");
+		// `..` can't leave the process root
+		check("/jail", "/home", "../../../etc", "/jail/etc");
+		check("/jail", "/", "/../etc", "/jail/etc");
+	}
+}
@@ -5,11 +5,12 @@
 //! VFS vode management
 use prelude::*;
 use super::Path;
+use super::handle::FileOpenMode;
 //use sync::mutex::LazyMutex;
 use mylib::byte_str::{ByteStr,ByteString};
-use mylib::{VecMap,LazyStatic};
-use spin::Mutex;
-use core::sync::atomic::{self,AtomicUsize};
+use mylib::{HashMap,LazyStatic};
+use spin::{Mutex,MutexGuard};
+use core::sync::atomic::{self,AtomicUsize,ATOMIC_USIZE_INIT};
 
 pub type InodeId = u64;
 pub type Result<T> = ::core::result::Result<T,super::Error>;
@@ -20,8 +21,10 @@ pub enum NodeType<'a> {
 	File,
 	Dir,
 	Symlink(&'a super::Path),
+	/// Named pipe (a `Special` node)
+	Fifo,
 }
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Copy,Clone)]
 pub enum NodeClass {
 	File,
 	Dir,
@@ -29,12 +32,55 @@ pub enum NodeClass {
 	Special,
 }
 
+/// Time in (seconds, nanoseconds) since the Unix epoch, as returned by `time::realtime`
+pub type Timestamp = (u64,u64);
+
+bitflags! {
+	/// Permission bits (unix layout)
+	pub struct Mode: u16 {
+		const USER_READ   = 0o400;
+		const USER_WRITE  = 0o200;
+		const USER_EXEC   = 0o100;
+		const GROUP_READ  = 0o040;
+		const GROUP_WRITE = 0o020;
+		const GROUP_EXEC  = 0o010;
+		const OTHER_READ  = 0o004;
+		const OTHER_WRITE = 0o002;
+		const OTHER_EXEC  = 0o001;
+	}
+}
+
+/// Node metadata (as returned by `stat`)
+#[derive(Debug,Clone)]
+pub struct Metadata {
+	pub class: NodeClass,
+	/// Size in bytes (files), number of entries (directories) or target length (symlinks)
+	pub size: u64,
+	pub mode: Mode,
+	pub uid: u32,
+	pub gid: u32,
+	/// Last access time
+	pub atime: Timestamp,
+	/// Last modification of the contents
+	pub mtime: Timestamp,
+	/// Last change of the contents or metadata
+	pub ctime: Timestamp,
+	/// Number of names referring to this node
+	pub nlink: u32,
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not nessesarily same as `self`, up to the driver)
 	fn get_any(&self) -> &Any;
+	/// Return the node's metadata
+	fn get_metadata(&self) -> Metadata;
+	/// Update the mode, owner and times from `meta` (other fields are ignored)
+	fn set_metadata(&self, _meta: &Metadata) -> Result<()> {
+		Err( super::Error::ReadOnlyFilesystem )
+	}
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
@@ -77,6 +123,19 @@ pub trait Dir: NodeBase {
 	fn link(&self, name: &ByteStr, inode: &NodeBase) -> Result<()>;
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> Result<()>;
+	/// Atomically move the entry `old_name` to `new_name` in `new_dir` (a directory on the same
+	/// filesystem, possibly `self`)
+	///
+	/// An existing `new_name` is replaced if both are non-directories, or if both are directories
+	/// and the target is empty.
+	fn rename(&self, _old_name: &ByteStr, _new_dir: &Dir, _new_name: &ByteStr) -> Result<()> {
+		Err( super::Error::ReadOnlyFilesystem )
+	}
+	/// Returns false if entries can change without going through the VFS (e.g. generated
+	/// listings), so the results of `lookup` mustn't be cached
+	fn cache_lookups(&self) -> bool {
+		true
+	}
 }
 /// Trait for symbolic link nodes.
 pub trait Symlink: NodeBase {
@@ -90,7 +149,29 @@ pub trait Special: NodeBase {
 	/// Returns a string indicating the type of special node
 	fn typename(&self) -> &str;
 	
-	// TODO: Include an API (similar to the syscall API) to communicate with the node
+	/// Read data from the node, returning the number of bytes read
+	///
+	/// `ofs` is a byte offset for seekable nodes (e.g. block devices), stream nodes ignore it.
+	fn read(&self, _ofs: u64, _buf: &mut [u8]) -> Result<usize> {
+		Err( super::Error::PermissionDenied )
+	}
+	/// Write data to the node, returning the number of bytes written
+	fn write(&self, _ofs: u64, _buf: &[u8]) -> Result<usize> {
+		Err( super::Error::PermissionDenied )
+	}
+	/// Called when a handle is opened on the node, `read`/`write` give the handle's access
+	///
+	/// Can block (e.g. a named pipe waits for the other end).
+	fn open(&self, _read: bool, _write: bool) -> Result<()> {
+		Ok( () )
+	}
+	/// Called when a handle opened with `open` is closed
+	fn close(&self, _read: bool, _write: bool) {
+	}
+	/// Node-specific control request (meaning of `cmd` and `arg` is up to the node)
+	fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<usize> {
+		Err( super::Error::InvalidParameter )
+	}
 }
 
 /// VFS Node
@@ -105,10 +186,9 @@ pub enum Node
 enum CacheNodeInt
 {
 	File {
-		fsnode: Box<File>
-		
-		// File memory map data
-		//mapped_pages: HashMap<u64,FrameHandle>
+		fsnode: Box<File>,
+		/// Cached file pages (also backs memory mappings)
+		page_cache: super::page_cache::PageCache,
 		},
 	Dir {
 		mountpoint: AtomicUsize,	// 0 is invalid (that's root), so means "no mount"
@@ -126,7 +206,7 @@ impl_from!{
 	From<Node>(v) for CacheNodeInt {
 		match v
 		{
-		Node::File(f) => CacheNodeInt::File { fsnode: f },
+		Node::File(f) => CacheNodeInt::File { fsnode: f, page_cache: Default::default() },
 		Node::Dir(f) => CacheNodeInt::Dir { fsnode: f, mountpoint: AtomicUsize::new(0) },
 		Node::Symlink(f) => CacheNodeInt::Symlink { target: f.read(), fsnode: f },
 		Node::Special(f) => CacheNodeInt::Special { fsnode: f },
@@ -137,7 +217,49 @@ impl_from!{
 struct CachedNode
 {
 	refcount: AtomicUsize,
+	/// Value of `S_CACHE_CLOCK` when a handle was last released (for LRU eviction)
+	last_used: AtomicUsize,
 	node: CacheNodeInt,
+	/// Open handles and advisory locks (see `handle::FileOpenMode`)
+	locks: Mutex<LockState>,
+}
+
+/// Per-node open mode and byte-range lock state
+#[derive(Default)]
+struct LockState
+{
+	/// `SharedRO` and `Execute` handles
+	readers: usize,
+	/// An `ExclRW` handle is open
+	exclusive: bool,
+	/// `UniqueRW` handles (reading a private copy)
+	unique: usize,
+	/// `Append` handles
+	appenders: usize,
+	/// `Unsynch` handles
+	unsynch: usize,
+	/// Advisory byte-range locks
+	ranges: Vec<RangeLock>,
+}
+
+/// Advisory byte-range lock type
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum LockKind
+{
+	/// Multiple holders allowed (read lock)
+	Shared,
+	/// Single holder (write lock)
+	Exclusive,
+}
+
+struct RangeLock
+{
+	/// Lock owner (a `handle::File`)
+	owner: usize,
+	kind: LockKind,
+	start: u64,
+	/// Exclusive end offset
+	end: u64,
 }
 unsafe impl Send for CachedNode {}
 unsafe impl Sync for CachedNode {}
@@ -151,7 +273,33 @@ pub struct CacheHandle
 unsafe impl Sync for CacheHandle {}
 unsafe impl Send for CacheHandle {}
 
-static mut S_NODE_CACHE: LazyStatic<VecMap<(usize,InodeId),Box<CachedNode>>> = lazystatic_init!();
+/// Unreferenced nodes are evicted (least recently used first) when more than this many are cached
+pub const NODE_CACHE_LIMIT: usize = 256;
+/// Maximum number of cached name lookups (the table is emptied when it fills)
+pub const DENTRY_CACHE_LIMIT: usize = 1024;
+/// Free frame count below which all unreferenced nodes (and their cached pages) are evicted
+const LOW_MEMORY_FRAMES: usize = 64;
+
+/// Cached nodes, and name lookups in cached directories
+#[derive(Default)]
+struct NodeCache
+{
+	nodes: HashMap<(usize,InodeId),Box<CachedNode>>,
+	/// (mount, directory inode, name) to inode
+	dentries: HashMap<(usize,InodeId,ByteString),InodeId>,
+	/// Incremented when a dentry is invalidated, so a lookup that raced with it isn't cached
+	dentry_gen: usize,
+}
+
+static mut S_NODE_CACHE: LazyStatic<Mutex<NodeCache>> = lazystatic_init!();
+/// Advanced each time a handle is released
+static S_CACHE_CLOCK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn node_cache() -> MutexGuard<'static, NodeCache>
+{
+	// SAFE: Initialised by `init`, and only accessed through the lock
+	unsafe { S_NODE_CACHE.lock() }
+}
 
 pub fn init()
 {
@@ -181,31 +329,59 @@ impl Clone for CacheHandle
 	}
 }
 
+impl ::core::ops::Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		// SAFE: self.ptr is valid until this reference is released
+		let prev = unsafe {
+			(*self.ptr).last_used.store(S_CACHE_CLOCK.fetch_add(1, atomic::Ordering::Relaxed), atomic::Ordering::Relaxed);
+			(*self.ptr).refcount.fetch_sub(1, atomic::Ordering::Relaxed)
+			};
+		if prev == 1 {
+			// Last user of a force-unmounted volume?
+			super::mount::release_detached(self.mountpt);
+		}
+	}
+}
+
 impl CacheHandle
 {
 	/// Obtain a node handle using a mountpoint ID and inode number
 	pub fn from_ids(mountpoint: usize, inode: InodeId) -> super::Result<CacheHandle>
-	{/*
-		let sv : VecMap<(usize, u64), Box<CachedNode>>=VecMap::new();
-		let nd=unsafe{match &S_NODE_CACHE{
-			Some(snd) => snd,
-			None => &sv,
-		}};*/
-		use mylib::vec_map::Entry;
-		// TODO: Use a hashmap of some form and use a fixed-range allocation (same as VMM code)
-		let ptr: *const _ = &**match unsafe{S_NODE_CACHE.ls_unsafe_mut().entry( (mountpoint, inode) )}
-			{
-			Entry::Occupied(mut e) =>
-				{
-				e.get_mut().refcount.fetch_add(1, atomic::Ordering::Relaxed);
-				e.into_mut()
-				},
-			Entry::Vacant(e) =>
-				match super::mount::Handle::from_id(mountpoint).get_node(inode)
+	{
+		let ptr: *const CachedNode = {
+			let mut cache = node_cache();
+			let existing = match cache.nodes.get( &(mountpoint, inode) )
 				{
-				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
-				None => return Err( super::Error::NotFound ),
+				Some(cn) => {
+					cn.refcount.fetch_add(1, atomic::Ordering::Relaxed);
+					Some(&**cn as *const CachedNode)
+					},
+				None => None,
+				};
+			match existing
+			{
+			Some(p) => p,
+			None => {
+				let node = match super::mount::Handle::from_id(mountpoint).get_node(inode)
+					{
+					Some(node) => node,
+					None => return Err( super::Error::NotFound ),
+					};
+				if cache.nodes.len() >= NODE_CACHE_LIMIT || ::memory::free_frames() < LOW_MEMORY_FRAMES {
+					cache.evict(NODE_CACHE_LIMIT * 3 / 4);
+				}
+				let cn = Box::new(CachedNode {
+					node: node.into(),
+					refcount: AtomicUsize::new(1),
+					last_used: AtomicUsize::new(0),
+					locks: Default::default(),
+					});
+				let p = &*cn as *const CachedNode;
+				cache.nodes.insert( (mountpoint, inode), cn );
+				p
 				},
+			}
 			};
 
 		// SAFE: Reference count has been incremented by this function, and will be valid until return
@@ -236,88 +412,78 @@ impl CacheHandle
 	}
 	
 	
-	pub fn from_path_at_node(mut node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
+	/// Obtain a node handle using a path relative to `node_h`
+	///
+	/// `..` never leaves `node_h`, absolute paths (and symbolic links) are resolved from the
+	/// process root. A symbolic link in the final component is followed.
+	pub fn from_path_at_node(node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
 	{
-		//println!("from_path_at_node");
 		//log_function!("CacheHandle::from_path_at_node(node_h={:?}, {:?})", node_h, path);
-		let path = if path.is_absolute() {
-				try!(path.split_off_first().ok_or(super::Error::MalformedPath)).1
-			}
-			else {
-				path
-			};
-		// Iterate path components
-		for seg in path
-		{
-			//log_trace!("seg = {:?}", seg);
-			// Loop resolving symbolic links
-			loop
-			{
-				// TODO: Should symlinks be handled in this function? Or should the passed path be without symlinks?
-				node_h = if let CacheNodeInt::Symlink { ref target, .. } = *node_h.as_ref() {
-					//log_debug!("- seg={:?} : SYMLINK {:?}", seg, name);
-					let linkpath = Path::new(&target);
-					if linkpath.is_absolute() {
-						try!(CacheHandle::from_path(linkpath))
-					}
-					else {
-						//TODO: To make this work (or any path-relative symlink), the current position in
-						//      `path` needs to be known.
-						// It can be hacked up though...
-						let parent_segs_len = seg.as_ref().as_ptr() as usize - AsRef::<[u8]>::as_ref(path).as_ptr() as usize;
-						let parent = ByteStr::new( &AsRef::<[u8]>::as_ref(path)[..parent_segs_len] );
-
-						//let segs = [ &path[..pos], &link ];
-						//let p = PathChain::new(&segs);
-						//try!( CacheHandle::from_path(p) )
-						// Recurse with a special chained path type
-						// (that iterates but can't be sliced).
-						todo!("Relative symbolic links {:?} relative to {:?}", target, parent)
-					}
-				}
-				else {
-					break;
-				};
-			}
-
-			// Look up this component in the current node
-			node_h = match *node_h.as_ref()
-				{
-				CacheNodeInt::Dir { fsnode: ref dir, .. } => {
-					//log_debug!("- seg={:?} : DIR", seg);
-					let next_id = match dir.lookup(seg)
-						{
-						Ok(v) => v,
-						Err(_) => return Err(super::Error::NotFound),
-						};
-					try!(CacheHandle::from_ids( node_h.mountpt, next_id ))
-					},
-				_ => return Err(super::Error::NonDirComponent),
-				};
-		}
-		//log_trace!("CacheHandle::from_path_at_node() {:?}", node_h);
-		Ok( node_h )
+		let mut stack = vec![node_h];
+		let mut floor = 1;
+		let mut links = 0;
+		try!(walk_path(&mut stack, &mut floor, &mut links, path));
+		try!(follow_links(&mut stack, &mut floor, &mut links));
+		Ok( stack.pop().unwrap() )
 	}
 
-	/// Obtain a node handle using a path
+	/// Obtain a node handle using a path, following a symbolic link in the final component
+	///
+	/// Absolute paths are resolved from the current process's root, relative paths from its
+	/// working directory.
 	pub fn from_path(path: &Path) -> super::Result<CacheHandle>
 	{
-		//println!("from_path");
+		CacheHandle::from_path_int(path, true)
+	}
+	/// Obtain a node handle using a path, returning the symbolic link itself if the final
+	/// component is one (`O_NOFOLLOW`/`lstat`)
+	pub fn from_path_nofollow(path: &Path) -> super::Result<CacheHandle>
+	{
+		CacheHandle::from_path_int(path, false)
+	}
+	fn from_path_int(path: &Path, follow: bool) -> super::Result<CacheHandle>
+	{
 		//log_function!("CacheHandle::from_path({:?})", path);
 		// TODO: Support path caching?
-		
-		// - Remove the leading / from the absolute path
-		//  > Also checks that it's actually abolsute
-		let (first_comp, path) = try!( path.split_off_first().ok_or(super::Error::MalformedPath) );
-		if first_comp.len() != 0 {
-			return Err(super::Error::MalformedPath);
+		let mut links = 0;
+		let (mut stack, mut floor) = try!(root_stack(&mut links));
+		if ! path.is_absolute() {
+			try!(walk_path(&mut stack, &mut floor, &mut links, &::process::get_cwd()));
 		}
-
-		// Acquire the root vnode
+		try!(walk_path(&mut stack, &mut floor, &mut links, path));
+		if follow {
+			try!(follow_links(&mut stack, &mut floor, &mut links));
+		}
+		//log_trace!("CacheHandle::from_path() {:?}", stack.last());
+		Ok( stack.pop().unwrap() )
+	}
+	
+	/// Obtain a handle to the root of the global namespace (ignores the process root)
+	fn global_root() -> super::Result<CacheHandle>
+	{
 		let mph = super::mount::Handle::from_id(0);
-		let node_h = try!(CacheHandle::from_ids( mph.id(), mph.root_inode() ));
-
-		CacheHandle::from_path_at_node(node_h, path)
+		CacheHandle::from_ids( mph.id(), mph.root_inode() )
+	}
+	
+	/// ID of the mounted volume this node is on
+	pub fn mount_id(&self) -> usize {
+		self.mountpt
+	}
+	pub fn inode(&self) -> InodeId {
+		self.inode
+	}
+	/// Options of the volume this node is on
+	pub fn mount_options(&self) -> super::mount::MountOptions {
+		super::mount::Handle::from_id(self.mountpt).options()
+	}
+	/// Fail with `ReadOnlyFilesystem` if the node's volume is mounted read-only
+	pub fn check_writable(&self) -> super::Result<()> {
+		if self.mount_options().read_only {
+			Err( super::Error::ReadOnlyFilesystem )
+		}
+		else {
+			Ok( () )
+		}
 	}
 	
 	pub fn get_class(&self) -> NodeClass {
@@ -338,6 +504,9 @@ impl CacheHandle
 	pub fn is_symlink(&self) -> bool {
 		self.get_class() == NodeClass::Symlink
 	}
+	pub fn is_special(&self) -> bool {
+		self.get_class() == NodeClass::Special
+	}
 
 	pub fn get_any(&self) -> &Any {
 		match self.as_ref()
@@ -348,6 +517,107 @@ impl CacheHandle
 		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
+	
+	// NOTE: `&File` etc can't be upcast to `&NodeBase`, so the NodeBase methods are matched out
+	fn node_get_metadata(&self) -> Metadata {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_metadata(),
+		}
+	}
+	fn node_set_metadata(&self, meta: &Metadata) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.set_metadata(meta),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.set_metadata(meta),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.set_metadata(meta),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.set_metadata(meta),
+		}
+	}
+}
+/// Metadata methods
+impl CacheHandle
+{
+	pub fn get_metadata(&self) -> Metadata {
+		let mut rv = self.node_get_metadata();
+		// Cached (unflushed) writes can change the size
+		if let &CacheNodeInt::File { ref fsnode, ref page_cache } = self.as_ref() {
+			rv.size = page_cache.size(&**fsnode);
+		}
+		rv
+	}
+	
+	/// Check that the current process has all of the `want` permissions (using the `USER_*` bits)
+	///
+	/// uid 0 bypasses read/write checks, but still needs an execute bit set for execute.
+	pub fn check_access(&self, want: Mode) -> super::Result<()> {
+		let meta = self.node_get_metadata();
+		let (uid, gid) = (::process::get_uid(), ::process::get_gid());
+		let ok = if uid == 0 {
+				!want.contains(Mode::USER_EXEC) || meta.mode.intersects(Mode::USER_EXEC | Mode::GROUP_EXEC | Mode::OTHER_EXEC)
+			}
+			else {
+				// Shift the requested bits down to the class that applies
+				let shift = if uid == meta.uid { 0 } else if gid == meta.gid { 3 } else { 6 };
+				let want_bits = want.bits() >> shift;
+				meta.mode.bits() & want_bits == want_bits
+			};
+		if ok {
+			Ok( () )
+		}
+		else {
+			Err( super::Error::PermissionDenied )
+		}
+	}
+	
+	/// Change the permission bits (owner or uid 0 only)
+	pub fn chmod(&self, mode: Mode) -> super::Result<()> {
+		self.update_metadata(false, |m| m.mode = mode)
+	}
+	/// Change the owner and group (uid 0 only)
+	pub fn chown(&self, uid: u32, gid: u32) -> super::Result<()> {
+		self.update_metadata(true, |m| { m.uid = uid; m.gid = gid; })
+	}
+	/// Set the access and modification times (owner or uid 0 only)
+	pub fn utimes(&self, atime: Timestamp, mtime: Timestamp) -> super::Result<()> {
+		self.update_metadata(false, |m| { m.atime = atime; m.mtime = mtime; })
+	}
+	/// Mark the node as accessed (`atime`) and/or modified (`mtime`), skipping permission checks
+	pub fn touch(&self, accessed: bool, modified: bool) -> super::Result<()> {
+		if self.mount_options().read_only {
+			return Ok( () );
+		}
+		let mut meta = self.node_get_metadata();
+		let now = ::time::realtime();
+		if accessed {
+			meta.atime = now;
+		}
+		if modified {
+			meta.mtime = now;
+			meta.ctime = now;
+		}
+		match self.node_set_metadata(&meta)
+		{
+		// Filesystems without settable metadata just don't track times
+		Err(super::Error::ReadOnlyFilesystem) => Ok( () ),
+		v => v,
+		}
+	}
+	
+	fn update_metadata<F: FnOnce(&mut Metadata)>(&self, root_only: bool, f: F) -> super::Result<()> {
+		let mut meta = self.node_get_metadata();
+		try!(self.check_writable());
+		let uid = ::process::get_uid();
+		if uid != 0 && (root_only || uid != meta.uid) {
+			return Err( super::Error::PermissionDenied );
+		}
+		f(&mut meta);
+		meta.ctime = ::time::realtime();
+		self.node_set_metadata(&meta)
+	}
 }
 /// Directory methods
 impl CacheHandle
@@ -356,7 +626,10 @@ impl CacheHandle
 		match self.as_ref()
 		{
 		&CacheNodeInt::Dir { ref fsnode, .. } => {
+			try!(self.check_writable());
+			try!(self.check_access(Mode::USER_WRITE | Mode::USER_EXEC));
 			let inode = try!(fsnode.create(name, ty));
+			try!(self.touch(false, true));
 			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
 			},
 		_ => Err( super::Error::Unknown("Calling create on non-directory") ),
@@ -372,13 +645,160 @@ impl CacheHandle
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<CacheHandle> {
 		match self.as_ref()
 		{
-		&CacheNodeInt::Dir { ref fsnode, .. } => {
-			let inode = try!(fsnode.lookup(name));
+		&CacheNodeInt::Dir { .. } => {
+			let inode = try!(self.lookup(name));
 			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
 			},
 		_ => Err( super::Error::Unknown("Calling open_child on non-directory") ),
 		}
 	}
+	/// Look up `name` in this directory, using (and filling) the dentry cache
+	fn lookup(&self, name: &ByteStr) -> super::Result<InodeId> {
+		let fsnode = match self.as_ref()
+			{
+			&CacheNodeInt::Dir { ref fsnode, .. } => fsnode,
+			_ => return Err( super::Error::NonDirComponent ),
+			};
+		if ! fsnode.cache_lookups() {
+			return fsnode.lookup(name);
+		}
+		let key = (self.mountpt, self.inode, ByteString::from(name));
+		let gen = {
+			let cache = node_cache();
+			if let Some(&inode) = cache.dentries.get(&key) {
+				return Ok(inode);
+			}
+			cache.dentry_gen
+			};
+		let inode = try!(fsnode.lookup(name));
+		let mut cache = node_cache();
+		if cache.dentry_gen == gen {
+			if cache.dentries.len() >= DENTRY_CACHE_LIMIT {
+				cache.dentries = HashMap::new();
+			}
+			cache.dentries.insert(key, inode);
+		}
+		Ok(inode)
+	}
+	/// Drop the cached lookup of `name` in this directory (after it's removed or replaced)
+	fn forget_dentry(&self, name: &ByteStr) {
+		let mut cache = node_cache();
+		cache.dentry_gen += 1;
+		cache.dentries.remove( &(self.mountpt, self.inode, ByteString::from(name)) );
+	}
+	/// Remove the non-directory entry `name`
+	pub fn unlink(&self, name: &ByteStr) -> super::Result<()> {
+		self.remove_entry(name, false)
+	}
+	/// Remove the empty directory `name`
+	pub fn rmdir(&self, name: &ByteStr) -> super::Result<()> {
+		self.remove_entry(name, true)
+	}
+	/// Move the entry `old_name` to `new_name` in `new_dir`
+	///
+	/// Fails with `CrossDevice` if `new_dir` is on a different mount, and `Busy` if either name
+	/// is a mountpoint.
+	pub fn rename(&self, old_name: &ByteStr, new_dir: &CacheHandle, new_name: &ByteStr) -> super::Result<()> {
+		let (src, dst) = match (self.as_ref(), new_dir.as_ref())
+			{
+			(&CacheNodeInt::Dir { fsnode: ref src, .. }, &CacheNodeInt::Dir { fsnode: ref dst, .. }) => (src, dst),
+			_ => return Err( super::Error::NonDirComponent ),
+			};
+		if self.mountpt != new_dir.mountpt {
+			return Err( super::Error::CrossDevice );
+		}
+		try!(check_entry_name(old_name));
+		try!(check_entry_name(new_name));
+		try!(self.check_writable());
+		try!(self.check_access(Mode::USER_WRITE | Mode::USER_EXEC));
+		try!(new_dir.check_access(Mode::USER_WRITE | Mode::USER_EXEC));
+		
+		let child = try!(self.open_child(old_name));
+		// `open_child` enters mounted volumes, so a different mount ID means a mountpoint
+		if child.mountpt != self.mountpt {
+			return Err( super::Error::Busy );
+		}
+		match new_dir.open_child(new_name)
+		{
+		Ok(ref target) if target.mountpt != self.mountpt => return Err( super::Error::Busy ),
+		Ok(_) | Err(super::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+		// A directory can't be moved inside itself
+		if child.is_dir() && try!(child.contains(new_dir)) {
+			return Err( super::Error::InvalidParameter );
+		}
+		
+		let rv = src.rename(old_name, &**dst, new_name);
+		self.forget_dentry(old_name);
+		new_dir.forget_dentry(new_name);
+		try!(rv);
+		try!(self.touch(false, true));
+		new_dir.touch(false, true)
+	}
+	
+	/// Returns true if this directory has no entries
+	pub fn is_empty_dir(&self) -> super::Result<bool> {
+		let mut empty = true;
+		try!(self.read_dir(0, &mut |_, _| { empty = false; false }));
+		Ok(empty)
+	}
+	/// Returns true if `other` is this directory or below it (on the same mount)
+	pub fn contains(&self, other: &CacheHandle) -> super::Result<bool> {
+		if self.mountpt == other.mountpt && self.inode == other.inode {
+			return Ok(true);
+		}
+		let mut children = Vec::new();
+		try!(self.read_dir(0, &mut |inode, _| { children.push(inode); true }));
+		for inode in children
+		{
+			// NOTE: Mountpoints are followed by `from_ids`, so skip anything that left this mount
+			let child = try!(CacheHandle::from_ids(self.mountpt, inode));
+			if child.is_dir() && child.mountpt == self.mountpt && try!(child.contains(other)) {
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+	
+	fn remove_entry(&self, name: &ByteStr, want_dir: bool) -> super::Result<()> {
+		let fsnode = match self.as_ref()
+			{
+			&CacheNodeInt::Dir { ref fsnode, .. } => fsnode,
+			_ => return Err( super::Error::NonDirComponent ),
+			};
+		try!(check_entry_name(name));
+		try!(self.check_writable());
+		try!(self.check_access(Mode::USER_WRITE | Mode::USER_EXEC));
+		let child = try!(self.open_child(name));
+		if child.mountpt != self.mountpt {
+			return Err( super::Error::Busy );
+		}
+		match (want_dir, child.is_dir())
+		{
+		(true, false) => return Err( super::Error::NonDirComponent ),
+		(false, true) => return Err( super::Error::TypeMismatch ),
+		(true, true) => if ! try!(child.is_empty_dir()) {
+			return Err( super::Error::NotEmpty );
+			},
+		(false, false) => {},
+		}
+		let rv = fsnode.unlink(name);
+		self.forget_dentry(name);
+		try!(rv);
+		self.touch(false, true)
+	}
+}
+
+/// Reject names that can't be removed or replaced (empty, `.` and `..`)
+fn check_entry_name(name: &ByteStr) -> super::Result<()>
+{
+	if name.len() == 0 || name == "." || name == ".." {
+		Err( super::Error::InvalidParameter )
+	}
+	else {
+		Ok( () )
+	}
 }
 /// Directory methods (mountpoint)
 impl CacheHandle
@@ -402,7 +822,108 @@ impl CacheHandle
 		_ => false,
 		}
 	}
+	/// Remove the mount binding from this node
+	pub fn unmount(&self) {
+		if let &CacheNodeInt::Dir { ref mountpoint, .. } = self.as_ref() {
+			mountpoint.store(0, atomic::Ordering::Relaxed);
+		}
+	}
 }
+/// Open mode and advisory lock methods
+impl CacheHandle
+{
+	/// Register an open handle with the given mode, failing with `Locked` if it conflicts
+	///
+	/// - `SharedRO`/`Execute`/`UniqueRW` conflict with `ExclRW` and `Unsynch`
+	/// - `ExclRW` conflicts with everything except `Append`
+	/// - `Append` only conflicts with `Unsynch`
+	/// - `Unsynch` conflicts with every other mode
+	pub fn open_lock(&self, mode: &FileOpenMode) -> super::Result<()> {
+		let mut lh = self.cached().locks.lock();
+		let ok = match *mode
+			{
+			FileOpenMode::SharedRO | FileOpenMode::Execute | FileOpenMode::UniqueRW => !lh.exclusive && lh.unsynch == 0,
+			FileOpenMode::ExclRW => !lh.exclusive && lh.readers == 0 && lh.unique == 0 && lh.unsynch == 0,
+			FileOpenMode::Append => lh.unsynch == 0,
+			FileOpenMode::Unsynch => !lh.exclusive && lh.readers == 0 && lh.unique == 0 && lh.appenders == 0,
+			};
+		if !ok {
+			return Err( super::Error::Locked );
+		}
+		match *mode
+		{
+		FileOpenMode::SharedRO | FileOpenMode::Execute => lh.readers += 1,
+		FileOpenMode::ExclRW => lh.exclusive = true,
+		FileOpenMode::UniqueRW => lh.unique += 1,
+		FileOpenMode::Append => lh.appenders += 1,
+		FileOpenMode::Unsynch => lh.unsynch += 1,
+		}
+		Ok( () )
+	}
+	/// Release an open registered by `open_lock`, along with any range locks held by `owner`
+	pub fn open_unlock(&self, mode: &FileOpenMode, owner: usize) {
+		let mut lh = self.cached().locks.lock();
+		match *mode
+		{
+		FileOpenMode::SharedRO | FileOpenMode::Execute => lh.readers -= 1,
+		FileOpenMode::ExclRW => lh.exclusive = false,
+		FileOpenMode::UniqueRW => lh.unique -= 1,
+		FileOpenMode::Append => lh.appenders -= 1,
+		FileOpenMode::Unsynch => lh.unsynch -= 1,
+		}
+		lh.ranges.retain(|r| r.owner != owner);
+	}
+	
+	/// Acquire an advisory lock on `len` bytes at `ofs` (non-blocking, `Locked` on conflict)
+	///
+	/// Locks held by the same owner never conflict, and re-locking a range changes its kind.
+	pub fn lock_range(&self, owner: usize, ofs: u64, len: u64, kind: LockKind) -> super::Result<()> {
+		let end = try!(ofs.checked_add(len).ok_or(super::Error::InvalidParameter));
+		if len == 0 {
+			return Err( super::Error::InvalidParameter );
+		}
+		let mut lh = self.cached().locks.lock();
+		let conflict = lh.ranges.iter().any(|r|
+			r.owner != owner && r.start < end && ofs < r.end && (kind == LockKind::Exclusive || r.kind == LockKind::Exclusive)
+			);
+		if conflict {
+			return Err( super::Error::Locked );
+		}
+		remove_range(&mut lh.ranges, owner, ofs, end);
+		lh.ranges.push(RangeLock { owner: owner, kind: kind, start: ofs, end: end });
+		Ok( () )
+	}
+	/// Release any locks held by `owner` in the given range
+	pub fn unlock_range(&self, owner: usize, ofs: u64, len: u64) {
+		let end = ofs.saturating_add(len);
+		remove_range(&mut self.cached().locks.lock().ranges, owner, ofs, end);
+	}
+	
+	fn cached(&self) -> &CachedNode {
+		// SAFE: The cached node isn't freed while a handle (this one) holds a reference
+		unsafe { &*self.ptr }
+	}
+}
+
+/// Remove `owner`'s locks from [start, end), splitting partially covered locks
+fn remove_range(ranges: &mut Vec<RangeLock>, owner: usize, start: u64, end: u64)
+{
+	let mut new = Vec::new();
+	ranges.retain(|r| {
+		if r.owner != owner || r.end <= start || end <= r.start {
+			return true;
+		}
+		if r.start < start {
+			new.push(RangeLock { owner: owner, kind: r.kind, start: r.start, end: start });
+		}
+		if end < r.end {
+			new.push(RangeLock { owner: owner, kind: r.kind, start: end, end: r.end });
+		}
+		false
+		});
+	ranges.extend(new);
+}
+
 /// Normal file methods
 impl CacheHandle
 {
@@ -410,21 +931,29 @@ impl CacheHandle
 	pub fn get_valid_size(&self) -> u64 {
 		match self.as_ref()
 		{
-		&CacheNodeInt::File { ref fsnode, .. } => fsnode.size(),
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => page_cache.size(&**fsnode),
 		_ => 0,
 		}
 	}
 	pub fn read(&self, ofs: u64, dst: &mut [u32]) -> super::Result<usize> {
 		match self.as_ref()
 		{
-		&CacheNodeInt::File { ref fsnode, .. } => Ok( try!(fsnode.read(ofs, dst)) ),
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => page_cache.read(&**fsnode, ofs, dst),
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
 	pub fn write(&self, ofs: u64, src: &[u32]) -> super::Result<usize> {
 		match self.as_ref()
 		{
-		&CacheNodeInt::File { ref fsnode, .. } => Ok( try!(fsnode.write(ofs, src)) ),
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => {
+			try!(self.check_writable());
+			let rv = try!(page_cache.write(&**fsnode, ofs, src));
+			try!(self.touch(false, true));
+			if self.mount_options().sync {
+				try!(page_cache.flush(&**fsnode));
+			}
+			Ok(rv)
+			},
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
@@ -432,15 +961,359 @@ impl CacheHandle
 		let sf=self.clone();
 		match self.mut_as_ref()
 		{
-		&mut CacheNodeInt::File { ref mut fsnode, .. } =>{
+		&mut CacheNodeInt::File { ref mut fsnode, ref page_cache } =>{
+			try!(sf.check_writable());
 			let id=sf.inode;
-			Ok(try!(fsnode.mut_write(id,src)) )
+			// The driver relocates the file on this call, so cached pages are stale afterwards
+			try!(page_cache.flush(&**fsnode));
+			let rv = try!(fsnode.mut_write(id,src));
+			try!(page_cache.invalidate(&**fsnode));
+			try!(sf.touch(false, true));
+			Ok(rv)
 		},
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
+	/// Write to the end of the file, returning the offset written at
+	pub fn append(&self, src: &[u32]) -> super::Result<u64> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => {
+			try!(self.check_writable());
+			let rv = try!(page_cache.append(&**fsnode, src));
+			try!(self.touch(false, true));
+			if self.mount_options().sync {
+				try!(page_cache.flush(&**fsnode));
+			}
+			Ok(rv)
+			},
+		_ => Err( super::Error::Unknown("Calling append on non-file") ),
+		}
+	}
+	/// Set the size of the file in bytes (zero padding or truncating), returning the new size
+	pub fn truncate(&self, newsize: u64) -> super::Result<u64> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => {
+			try!(self.check_writable());
+			// Cached pages past the new end (or of the old size) are stale afterwards
+			try!(page_cache.flush(&**fsnode));
+			let rv = try!(fsnode.truncate(newsize));
+			try!(page_cache.invalidate(&**fsnode));
+			try!(self.touch(false, true));
+			Ok(rv)
+			},
+		_ => Err( super::Error::Unknown("Calling truncate on non-file") ),
+		}
+	}
+	/// Write back any dirty cached pages of this file
+	pub fn sync(&self) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => page_cache.flush(&**fsnode),
+		_ => Ok( () ),
+		}
+	}
+	/// Pin the page at index `idx` (in `PAGE_SIZE` units) for a memory mapping, returning its frame
+	pub fn map_page(&self, idx: u64, writable: bool) -> super::Result<::memory::Frame> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref page_cache } => page_cache.map_page(&**fsnode, idx, writable),
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	/// Release a page pinned by `map_page` (`writable` must match the `map_page` call)
+	pub fn unmap_page(&self, idx: u64, writable: bool) {
+		if let &CacheNodeInt::File { ref page_cache, .. } = self.as_ref() {
+			page_cache.unmap_page(idx, writable);
+		}
+	}
 }
 
+/// Maximum number of symbolic links followed in one path lookup (see `set_symlink_limit`)
+pub const DEFAULT_SYMLINK_LIMIT: usize = 40;
+static S_SYMLINK_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_SYMLINK_LIMIT);
+
+/// Set the maximum number of symbolic links followed by a single path lookup
+///
+/// Lookups that would follow more fail with `RecursionDepthExceeded` (which also catches loops).
+pub fn set_symlink_limit(limit: usize)
+{
+	S_SYMLINK_LIMIT.store(limit, atomic::Ordering::Relaxed);
+}
+pub fn symlink_limit() -> usize
+{
+	S_SYMLINK_LIMIT.load(atomic::Ordering::Relaxed)
+}
+
+/// Handle stack for the current process's root directory
+///
+/// Returns the stack and its length (the "floor" that `..` can't go below). `links` counts the
+/// symbolic links followed by the lookup.
+fn root_stack(links: &mut usize) -> super::Result<(Vec<CacheHandle>, usize)>
+{
+	let mut stack = vec![ try!(CacheHandle::global_root()) ];
+	let mut floor = 1;
+	try!(walk_path(&mut stack, &mut floor, links, &::process::get_root()));
+	try!(follow_links(&mut stack, &mut floor, links));
+	let floor = stack.len();
+	Ok( (stack, floor) )
+}
+
+/// Walk `path` from the directory on the top of `stack`, pushing a handle for each component
+///
+/// The stack holds the directories walked through, so `..` is handled by popping (which also
+/// returns across mountpoints, as the mounted root replaces the mountpoint on the stack).
+/// `..` never pops below `floor`. Symbolic links in the middle of the path are followed, one in
+/// the final component is left on the stack (see `follow_links`).
+fn walk_path(stack: &mut Vec<CacheHandle>, floor: &mut usize, links: &mut usize, path: &Path) -> super::Result<()>
+{
+	for seg in path
+	{
+		//log_trace!("seg = {:?}", seg);
+		// Empty segments come from a leading (or repeated) separator
+		if seg.len() == 0 || seg == "." {
+			continue ;
+		}
+		
+		// Resolve a symbolic link left by the previous segment
+		try!(follow_links(stack, floor, links));
+		
+		if seg == ".." {
+			if stack.len() > *floor {
+				stack.pop();
+			}
+			continue ;
+		}
+		
+		// Look up this component in the current node
+		let next = {
+			let cur = stack.last().unwrap();
+			if ! cur.is_dir() {
+				return Err(super::Error::NonDirComponent);
+			}
+			//log_debug!("- seg={:?} : DIR", seg);
+			let next_id = match cur.lookup(seg)
+				{
+				Ok(v) => v,
+				Err(_) => return Err(super::Error::NotFound),
+				};
+			try!(CacheHandle::from_ids( cur.mountpt, next_id ))
+			};
+		stack.push(next);
+	}
+	Ok( () )
+}
+
+/// Replace symbolic links on the top of `stack` with their targets
+///
+/// Relative targets are resolved from the directory containing the link (which is below it on
+/// the stack), absolute targets from the process root.
+fn follow_links(stack: &mut Vec<CacheHandle>, floor: &mut usize, links: &mut usize) -> super::Result<()>
+{
+	while stack.last().unwrap().is_symlink()
+	{
+		*links += 1;
+		if *links > symlink_limit() {
+			return Err(super::Error::RecursionDepthExceeded);
+		}
+		let link = stack.pop().unwrap();
+		let target = try!(link.get_target());
+		if target.len() == 0 {
+			return Err(super::Error::NotFound);
+		}
+		let linkpath = Path::new(&target);
+		//log_debug!("- SYMLINK {:?}", linkpath);
+		if linkpath.is_absolute() {
+			let (s, f) = try!(root_stack(links));
+			*stack = s;
+			*floor = f;
+		}
+		try!(walk_path(stack, floor, links, linkpath));
+	}
+	Ok( () )
+}
+
+impl NodeCache
+{
+	/// Drop unreferenced nodes, least recently used first, until at most `target` are cached
+	///
+	/// Mountpoints and nodes with unflushed pages are kept. Returns the number evicted.
+	fn evict(&mut self, target: usize) -> usize {
+		if self.nodes.len() <= target {
+			return 0;
+		}
+		let mut candidates: Vec<(usize, (usize,InodeId))> = self.nodes.iter()
+			.filter(|&(_, cn)| cn.is_evictable())
+			.map(|(&k, cn)| (cn.last_used.load(atomic::Ordering::Relaxed), k))
+			.collect();
+		candidates.sort_unstable_by_key(|c| c.0);
+		let count = ::core::cmp::min(candidates.len(), self.nodes.len() - target);
+		for &(_, k) in &candidates[..count]
+		{
+			// NOTE: Dentries pointing at the node are kept, the inode number is still valid
+			self.nodes.remove(&k);
+		}
+		count
+	}
+}
+impl CachedNode
+{
+	fn is_evictable(&self) -> bool {
+		if self.refcount.load(atomic::Ordering::Relaxed) > 0 {
+			return false;
+		}
+		match self.node
+		{
+		CacheNodeInt::Dir { ref mountpoint, .. } => mountpoint.load(atomic::Ordering::Relaxed) == 0,
+		CacheNodeInt::File { ref page_cache, .. } => !page_cache.is_dirty(),
+		_ => true,
+		}
+	}
+}
+
+/// Evict all unreferenced nodes (e.g. when memory is low), returns the number evicted
+pub fn shrink_cache() -> usize
+{
+	node_cache().evict(0)
+}
+
+/// Call `f` on each cached node on a mount matching `filter`, without holding the cache lock
+fn for_each_cached<P, F>(filter: P, mut f: F)
+where
+	P: Fn(usize) -> bool,
+	F: FnMut(usize, InodeId, &CachedNode)
+{
+	let nodes: Vec<_> = node_cache().nodes.iter()
+		.filter(|&(&(mountpt, _), _)| filter(mountpt))
+		.map(|(&(mountpt, inode), cn)| {
+			cn.refcount.fetch_add(1, atomic::Ordering::Relaxed);
+			(mountpt, inode, &**cn as *const CachedNode)
+			})
+		.collect();
+	for (mountpt, inode, ptr) in nodes
+	{
+		// SAFE: The reference taken above keeps the node cached
+		let cn = unsafe { &*ptr };
+		f(mountpt, inode, cn);
+		// NOTE: Not a `CacheHandle`, so releasing doesn't re-enter `mount::release_detached`
+		cn.refcount.fetch_sub(1, atomic::Ordering::Relaxed);
+	}
+}
+
+/// Returns true if any cached node on mount `id` has open handles
+pub fn mount_in_use(id: usize) -> bool
+{
+	node_cache().nodes.iter()
+		.any(|(&(mountpt, _), cn)| mountpt == id && cn.refcount.load(atomic::Ordering::Relaxed) > 0)
+}
+
+/// Write back dirty cached file pages of the nodes on mount `id`
+pub fn sync_mount(id: usize)
+{
+	for_each_cached(|mountpt| mountpt == id, |mountpt, inode, cn| {
+		if let CacheNodeInt::File { ref fsnode, ref page_cache } = cn.node {
+			if let Err(e) = page_cache.flush(&**fsnode) {
+				println!("warning: Flushing {}:{:#x} failed: {:?}", mountpt, inode, e);
+			}
+		}
+		});
+}
+
+/// Flush and drop all cached nodes (and lookups) of mount `id` (which must not be in use)
+pub fn purge_mount(id: usize)
+{
+	assert!( !mount_in_use(id), "purge_mount({}) - nodes still in use", id );
+	sync_mount(id);
+	let mut cache = node_cache();
+	cache.nodes.retain(|&(mountpt, _), _| mountpt != id);
+	cache.dentries.retain(|&(mountpt, _, _), _| mountpt != id);
+}
+
+/// Write back all dirty cached file pages
+///
+/// Errors are reported but do not stop the flush of other nodes.
+pub fn sync_all()
+{
+	if ! unsafe { S_NODE_CACHE.ls_is_valid() } {
+		return ;
+	}
+	for_each_cached(|_| true, |mountpt, inode, cn| {
+		if let CacheNodeInt::File { ref fsnode, ref page_cache } = cn.node {
+			if page_cache.is_dirty() {
+				if let Err(e) = page_cache.flush(&**fsnode) {
+					println!("warning: Flushing {}:{:#x} failed: {:?}", mountpt, inode, e);
+				}
+			}
+		}
+		});
+}
+
+
+/// Special file methods
+///
+/// Access is checked when the handle is opened (`special_open`), not on each read/write.
+impl CacheHandle
+{
+	pub fn get_typename(&self) -> super::Result<&str> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => Ok(fsnode.typename()),
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => {
+			let rv = try!(fsnode.read(ofs, dst));
+			try!(self.touch(true, false));
+			Ok(rv)
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => {
+			let rv = try!(fsnode.write(ofs, src));
+			try!(self.touch(false, true));
+			Ok(rv)
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_open(&self, read: bool, write: bool) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => {
+			let mut want = Mode::empty();
+			if read {
+				want |= Mode::USER_READ;
+			}
+			if write {
+				want |= Mode::USER_WRITE;
+			}
+			try!(self.check_access(want));
+			fsnode.open(read, write)
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_close(&self, read: bool, write: bool) {
+		if let &CacheNodeInt::Special { ref fsnode } = self.as_ref() {
+			fsnode.close(read, write);
+		}
+	}
+	pub fn special_ioctl(&self, cmd: u32, arg: usize) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => fsnode.ioctl(cmd, arg),
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+}
 
 /// Symbolic link methods
 impl CacheHandle
@@ -457,24 +1330,12 @@ impl CacheHandle
 impl CacheHandle
 {
 	fn as_ref(&self) -> &CacheNodeInt {
-		let lh = unsafe{&S_NODE_CACHE};
-		// SAFE: While this handle is active, the box will be present
-		unsafe {
-			let box_r = lh.get( &(self.mountpt, self.inode) ).expect("Cached node with open handle absent");
-			let cn_ref: &CachedNode = &**box_r;
-			// Dereference a raw pointer to disconnect the lifetimes
-			&*(&cn_ref.node as *const _)
-		}
+		&self.cached().node
 	}
 	fn mut_as_ref(&mut self) -> &mut CacheNodeInt {
 		// SAFE: While this handle is active, the box will be present
 		unsafe {
-			//let lh = &mut S_NODE_CACHE;
-			let box_r = S_NODE_CACHE.ls_unsafe_mut().get_mut( &(self.mountpt, self.inode) ).expect("Cached node with open handle absent");
-			let cn_ref: &mut CachedNode = &mut *box_r;
-			// Dereference a raw pointer to disconnect the lifetimes
-			&mut cn_ref.node
-			//&mut *(&cn_ref.node as *const _)
+			&mut (*(self.ptr as *mut CachedNode)).node
 		}
 	}
 }
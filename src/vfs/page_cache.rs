@@ -0,0 +1,248 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/page_cache.rs
+//! Per-node file page cache
+//!
+//! Each cached file node owns a `PageCache`, which holds whole pages of the file in frames mapped
+//! through `memory::kmap`. Reads are served from (and fill) the cache, writes only dirty the cached
+//! page and are pushed to the filesystem driver by `flush` (called by `fsync` or the flush daemon).
+//! Writes that extend the file resize it through the driver first.
+//! The same frames are handed out to `handle::File::memory_map`.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use mylib::VecMap;
+use memory::{Frame,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use super::node;
+
+/// Number of 32-bit words in a page (the VFS file API works on `u32` slices)
+pub const PAGE_WORDS: usize = PAGE_SIZE / 4;
+
+struct CachedPage
+{
+	mapping: KernelMapping,
+	/// Page has been written since it was last flushed
+	dirty: bool,
+	/// Number of `memory_map` users of this page (mapped pages are never dropped)
+	map_count: usize,
+	/// Number of those mappings that are writable (the page can change at any time while non-zero)
+	writable_maps: usize,
+}
+
+#[derive(Default)]
+struct PageCacheInner
+{
+	pages: VecMap<u64,CachedPage>,
+	/// Size of the file as seen through the cache (`None` until first queried from the driver)
+	size: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct PageCache
+{
+	inner: Mutex<PageCacheInner>,
+}
+
+impl PageCacheInner
+{
+	fn file_size(&mut self, fsnode: &node::File) -> u64 {
+		match self.size
+		{
+		Some(v) => v,
+		None => {
+			let v = fsnode.size();
+			self.size = Some(v);
+			v
+			},
+		}
+	}
+
+	/// Obtain the cached page with index `idx`, reading it from the file if not present
+	fn get_page(&mut self, fsnode: &node::File, idx: u64) -> super::Result<&mut CachedPage> {
+		if self.pages.get(&idx).is_none()
+		{
+			let mapping = match kmap::alloc_mapped(1)
+				{
+				Some(v) => v,
+				// Low on memory, drop unused nodes (and their cached pages) and try again
+				None => try!( { node::shrink_cache(); kmap::alloc_mapped(1) }.ok_or(super::Error::OutOfMemory) ),
+				};
+			let size = self.file_size(fsnode);
+			let page_ofs = idx * PAGE_SIZE as u64;
+			if page_ofs < size
+			{
+				// SAFE: Mapping is uniquely owned until it's inserted into the cache
+				let words = unsafe { mapping.as_words_mut() };
+				let valid_words = ::core::cmp::min(PAGE_WORDS as u64, (size - page_ofs + 3) / 4) as usize;
+				// The mapping is zeroed, so a short read leaves the tail as zeroes
+				try!( fsnode.read(page_ofs, &mut words[..valid_words]) );
+			}
+			self.pages.insert(idx, CachedPage { mapping: mapping, dirty: false, map_count: 0, writable_maps: 0 });
+		}
+		Ok( self.pages.get_mut(&idx).unwrap() )
+	}
+
+	fn write(&mut self, fsnode: &node::File, ofs: u64, src: &[u32]) -> super::Result<usize> {
+		if ofs % 4 != 0 {
+			return Err( super::Error::InvalidParameter );
+		}
+		let size = self.file_size(fsnode);
+		if ofs > size {
+			return Err( super::Error::InvalidParameter );
+		}
+		let end = ofs + src.len() as u64 * 4;
+		if end > size {
+			// Have the driver allocate the space now, so the write-back can't fail (or overrun) later
+			try!(fsnode.truncate(end));
+			self.size = Some(end);
+		}
+
+		let mut done = 0;
+		while done < src.len()
+		{
+			let word = ofs / 4 + done as u64;
+			let idx = word / PAGE_WORDS as u64;
+			let page_word = (word % PAGE_WORDS as u64) as usize;
+			let len = ::core::cmp::min(PAGE_WORDS - page_word, src.len() - done);
+			let page = try!(self.get_page(fsnode, idx));
+			// SAFE: Page contents are protected by the cache lock
+			let words = unsafe { page.mapping.as_words_mut() };
+			words[page_word .. page_word + len].copy_from_slice( &src[done .. done + len] );
+			page.dirty = true;
+			done += len;
+		}
+		Ok(done)
+	}
+}
+
+impl PageCache
+{
+	/// Current size of the file, including cached writes that extended it
+	pub fn size(&self, fsnode: &node::File) -> u64 {
+		self.inner.lock().file_size(fsnode)
+	}
+
+	/// Read from the file at byte offset `ofs` (must be word aligned)
+	///
+	/// Returns the number of words read, which is short at end-of-file
+	pub fn read(&self, fsnode: &node::File, ofs: u64, dst: &mut [u32]) -> super::Result<usize> {
+		if ofs % 4 != 0 {
+			return Err( super::Error::InvalidParameter );
+		}
+		let mut lh = self.inner.lock();
+		let size = lh.file_size(fsnode);
+		if ofs >= size {
+			return Ok(0);
+		}
+		let count = ::core::cmp::min(dst.len() as u64, (size - ofs + 3) / 4) as usize;
+
+		let mut done = 0;
+		while done < count
+		{
+			let word = ofs / 4 + done as u64;
+			let idx = word / PAGE_WORDS as u64;
+			let page_word = (word % PAGE_WORDS as u64) as usize;
+			let len = ::core::cmp::min(PAGE_WORDS - page_word, count - done);
+			let page = try!(lh.get_page(fsnode, idx));
+			// SAFE: Page contents are protected by the cache lock
+			let words = unsafe { page.mapping.as_words() };
+			dst[done .. done + len].copy_from_slice( &words[page_word .. page_word + len] );
+			done += len;
+		}
+		Ok(done)
+	}
+
+	/// Write to the file at byte offset `ofs` (must be word aligned)
+	///
+	/// Data is only written to the cache, and will reach the driver on the next `flush`.
+	/// Writes can only extend the file from its current end (same rule as `node::File::write`).
+	pub fn write(&self, fsnode: &node::File, ofs: u64, src: &[u32]) -> super::Result<usize> {
+		self.inner.lock().write(fsnode, ofs, src)
+	}
+	/// Write to the end of the file (atomic with respect to other writes)
+	///
+	/// Returns the offset the data was written at
+	pub fn append(&self, fsnode: &node::File, src: &[u32]) -> super::Result<u64> {
+		let mut lh = self.inner.lock();
+		let ofs = lh.file_size(fsnode);
+		try!(lh.write(fsnode, ofs, src));
+		Ok(ofs)
+	}
+
+	/// Write all dirty pages back to the filesystem driver
+	pub fn flush(&self, fsnode: &node::File) -> super::Result<()> {
+		let mut lh = self.inner.lock();
+		let size = lh.file_size(fsnode);
+		for (&idx, page) in lh.pages.iter_mut()
+		{
+			if !page.dirty {
+				continue ;
+			}
+			let page_ofs = idx * PAGE_SIZE as u64;
+			if page_ofs >= size {
+				// Page past EOF (e.g. mapped beyond the end), nothing to write
+				page.dirty = false;
+				continue ;
+			}
+			let valid_words = ::core::cmp::min(PAGE_WORDS as u64, (size - page_ofs + 3) / 4) as usize;
+			// SAFE: Page contents are protected by the cache lock
+			let words = unsafe { page.mapping.as_words() };
+			try!( fsnode.write(page_ofs, &words[..valid_words]) );
+			// Writes through a mapping aren't seen by the cache, so keep writing the page back until unmapped
+			page.dirty = page.writable_maps > 0;
+		}
+		Ok( () )
+	}
+
+	/// Drop all unmapped pages (after flushing them), forcing the next access to re-read the file
+	pub fn invalidate(&self, fsnode: &node::File) -> super::Result<()> {
+		try!(self.flush(fsnode));
+		let mut lh = self.inner.lock();
+		let unmapped: Vec<u64> = lh.pages.iter()
+			.filter(|&(_,p)| p.map_count == 0)
+			.map(|(&i,_)| i)
+			.collect();
+		for idx in unmapped {
+			lh.pages.remove(&idx);
+		}
+		lh.size = None;
+		Ok( () )
+	}
+
+	/// Returns true if any page is waiting to be written back
+	pub fn is_dirty(&self) -> bool {
+		self.inner.lock().pages.iter().any(|(_,p)| p.dirty)
+	}
+
+	/// Obtain the frame backing page `idx` for a memory mapping, pinning it in the cache
+	///
+	/// If `writable` is set the page is assumed to be written through the mapping, and stays dirty
+	/// (written back on every flush) until the mapping is released.
+	pub fn map_page(&self, fsnode: &node::File, idx: u64, writable: bool) -> super::Result<Frame> {
+		let mut lh = self.inner.lock();
+		let page = try!(lh.get_page(fsnode, idx));
+		page.map_count += 1;
+		if writable {
+			page.writable_maps += 1;
+			page.dirty = true;
+		}
+		Ok( page.mapping.frame() )
+	}
+	/// Release a pin obtained by `map_page`
+	///
+	/// Releasing a writable pin leaves the page dirty, so the last writes reach the driver.
+	pub fn unmap_page(&self, idx: u64, writable: bool) {
+		let mut lh = self.inner.lock();
+		if let Some(page) = lh.pages.get_mut(&idx) {
+			assert!(page.map_count > 0, "PageCache::unmap_page({}) - page not mapped", idx);
+			page.map_count -= 1;
+			if writable {
+				assert!(page.writable_maps > 0, "PageCache::unmap_page({}) - page not mapped writable", idx);
+				page.writable_maps -= 1;
+				page.dirty = true;
+			}
+		}
+	}
+}
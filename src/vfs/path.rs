@@ -11,7 +11,7 @@ use mylib::borrow::ToOwned;
 #[derive(Eq,PartialEq,PartialOrd,Ord)]
 pub struct Path(ByteStr);
 
-#[derive(Eq,PartialEq,PartialOrd,Ord,Default)]
+#[derive(Eq,PartialEq,PartialOrd,Ord,Default,Clone)]
 pub struct PathBuf(ByteString);
 
 impl_fmt! {
@@ -21,6 +21,21 @@ impl_fmt! {
 	Debug(self,f) for PathBuf {
 		write!(f, "PathBuf({:?})", &*self.0)
 	}
+	Display(self,f) for Path {{
+		// Non-printable bytes are escaped
+		for &b in self.0.as_bytes()
+		{
+			match b
+			{
+			32...126 => try!(write!(f, "{}", b as char)),
+			_ => try!(write!(f, "\\x{:02x}", b)),
+			}
+		}
+		Ok( () )
+	}}
+	Display(self,f) for PathBuf {
+		write!(f, "{}", &**self)
+	}
 }
 
 pub struct Components<'a>(&'a Path);
@@ -44,6 +59,78 @@ impl Path
 		// If none of the components are .. or ., it's normalised
 		!self.iter().any(|c| (c == ".." || c == "."))
 	}
+	pub fn is_empty(&self) -> bool {
+		self.0.len() == 0
+	}
+	
+	/// Return the path without its final component (`None` for `/` and the empty path)
+	pub fn parent(&self) -> Option<&Path> {
+		let bytes = self.trim_trailing();
+		match bytes.iter().rposition(|&c| c == b'/')
+		{
+		// "/foo" => "/"
+		Some(0) if bytes.len() > 1 => Some(Path::new(&bytes[..1])),
+		Some(0) => None,
+		Some(pos) => Some(Path::new(&bytes[..pos])),
+		// "foo" => "" (i.e. the directory the path is relative to)
+		None if bytes.len() > 0 => Some(Path::new("")),
+		None => None,
+		}
+	}
+	/// Return the final component of the path (`None` if it's empty or `..`)
+	pub fn file_name(&self) -> Option<&ByteStr> {
+		let bytes = self.trim_trailing();
+		let name = match bytes.iter().rposition(|&c| c == b'/')
+			{
+			Some(pos) => &bytes[pos+1..],
+			None => bytes,
+			};
+		if name.len() == 0 || name == &b".."[..] {
+			None
+		}
+		else {
+			Some(ByteStr::new(name))
+		}
+	}
+	
+	/// Create a new path by appending `other` (which replaces this path if absolute)
+	pub fn join<P: AsRef<Path>>(&self, other: P) -> PathBuf {
+		let mut rv = PathBuf::from(self);
+		rv.push(other);
+		rv
+	}
+	
+	/// Lexically remove `.`, `..` and repeated separators
+	///
+	/// `..` at the root of an absolute path is dropped, leading `..` on a relative path are kept.
+	/// NOTE: This doesn't consult the filesystem, so `a/link/..` becomes `a` even if `link` is a
+	/// symbolic link (use node lookup for physical resolution).
+	pub fn normalise(&self) -> PathBuf {
+		let mut comps: Vec<&ByteStr> = Vec::new();
+		for c in self.iter()
+		{
+			if c.len() == 0 || c == "." {
+				continue ;
+			}
+			if c == ".." {
+				match comps.last()
+				{
+				Some(l) if *l != ".." => { comps.pop(); continue ; },
+				None if self.is_absolute() => continue,
+				_ => {},
+				}
+			}
+			comps.push(c);
+		}
+		let mut rv = PathBuf::new();
+		if self.is_absolute() {
+			rv.0.extend_from_slice(b"/");
+		}
+		for c in comps {
+			rv.push(c);
+		}
+		rv
+	}
 	
 	pub fn iter(&self) -> Components {
 		Components(self)
@@ -100,6 +187,15 @@ impl Path
 			}
 		}
 	}
+	
+	/// Path bytes without trailing separators (except a lone `/`)
+	fn trim_trailing(&self) -> &[u8] {
+		let mut bytes = self.0.as_bytes();
+		while bytes.len() > 1 && bytes[bytes.len()-1] == b'/' {
+			bytes = &bytes[..bytes.len()-1];
+		}
+		bytes
+	}
 }
 
 impl AsRef<[u8]> for Path {
@@ -117,15 +213,59 @@ impl AsRef<Path> for str {
 		Path::new(self)
 	}
 }
+impl AsRef<Path> for Path {
+	fn as_ref(&self) -> &Path {
+		self
+	}
+}
+impl AsRef<Path> for ByteStr {
+	fn as_ref(&self) -> &Path {
+		Path::new(self)
+	}
+}
 
 impl PathBuf
 {
+	pub fn new() -> PathBuf {
+		PathBuf(ByteString::new())
+	}
+	
+	/// Append a path (replacing the current contents if `other` is absolute)
+	pub fn push<P: AsRef<Path>>(&mut self, other: P) {
+		let other: &Path = other.as_ref();
+		if other.is_absolute() {
+			self.0 = other.0.to_owned();
+			return ;
+		}
+		if other.is_empty() {
+			return ;
+		}
+		if self.0.len() > 0 && self.0.as_bytes()[self.0.len()-1] != b'/' {
+			self.0.extend_from_slice(b"/");
+		}
+		self.0.extend_from_slice(other.0.as_bytes());
+	}
+	/// Remove the final component, returns `false` if there was no parent
+	pub fn pop(&mut self) -> bool {
+		let len = match self.parent()
+			{
+			Some(p) => p.0.len(),
+			None => return false,
+			};
+		self.0.truncate(len);
+		true
+	}
 }
 impl<'a> From<&'a Path> for PathBuf {
 	fn from(v: &Path) -> PathBuf {
 		PathBuf(v.0.to_owned())
 	}
 }
+impl<'a> From<&'a str> for PathBuf {
+	fn from(v: &str) -> PathBuf {
+		PathBuf::from(Path::new(v))
+	}
+}
 impl ::core::ops::Deref for PathBuf {
 	type Target = Path;
 	fn deref(&self) -> &Path {
@@ -0,0 +1,192 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/pipe.rs
+//! Pipes (anonymous and named)
+//!
+//! A `Pipe` is a bounded byte queue with counts of open read and write ends. Reads block while the
+//! pipe is empty and a writer exists (and return 0 for EOF once all writers have closed), writes
+//! block while the pipe is full and fail with `BrokenPipe` once all readers have closed.
+#[allow(unused_imports)]
+use prelude::*;
+use mylib::collections::VecDeque;
+use mylib::mem::Arc;
+use process::WaitQueue;
+use spin::Mutex;
+
+/// Number of bytes a pipe can hold before writers block
+pub const PIPE_BUF_SIZE: usize = 4096;
+
+pub struct Pipe
+{
+	inner: Mutex<PipeInner>,
+	/// Woken when data is written, or the last writer closes
+	readable: WaitQueue,
+	/// Woken when data is read, or the last reader closes
+	writable: WaitQueue,
+	/// Woken when an end is opened (for `open_end`)
+	opened: WaitQueue,
+}
+struct PipeInner
+{
+	/// Unread bytes (at most `PIPE_BUF_SIZE`, allocated up front)
+	data: VecDeque<u8>,
+	readers: usize,
+	writers: usize,
+}
+
+/// Read end of an anonymous pipe
+pub struct PipeReader(Arc<Pipe>);
+/// Write end of an anonymous pipe
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Create an anonymous pipe
+pub fn pipe() -> (PipeReader, PipeWriter)
+{
+	let p = Arc::new(Pipe::new());
+	p.inner.lock().readers = 1;
+	p.inner.lock().writers = 1;
+	(PipeReader(p.clone()), PipeWriter(p))
+}
+
+impl Default for Pipe
+{
+	fn default() -> Pipe {
+		Pipe::new()
+	}
+}
+
+impl Pipe
+{
+	pub fn new() -> Pipe {
+		Pipe {
+			inner: Mutex::new(PipeInner {
+				data: VecDeque::with_capacity(PIPE_BUF_SIZE),
+				readers: 0,
+				writers: 0,
+				}),
+			readable: WaitQueue::new(),
+			writable: WaitQueue::new(),
+			opened: WaitQueue::new(),
+		}
+	}
+
+	/// Register a newly opened end (used by named pipes)
+	///
+	/// Opening only for reading blocks until there is a writer, and opening only for writing blocks
+	/// until there is a reader. Opening both ends never blocks.
+	pub fn open_end(&self, read: bool, write: bool) {
+		{
+			let mut lh = self.inner.lock();
+			if read {
+				lh.readers += 1;
+			}
+			if write {
+				lh.writers += 1;
+			}
+		}
+		self.opened.wake_all();
+		if read && !write {
+			self.opened.wait_while(|| self.inner.lock().writers == 0);
+		}
+		if write && !read {
+			self.opened.wait_while(|| self.inner.lock().readers == 0);
+		}
+	}
+	/// Release an end registered by `open_end`
+	pub fn close_end(&self, read: bool, write: bool) {
+		let mut lh = self.inner.lock();
+		if read {
+			lh.readers -= 1;
+			if lh.readers == 0 {
+				// Let blocked writers see the broken pipe
+				self.writable.wake_all();
+			}
+		}
+		if write {
+			lh.writers -= 1;
+			if lh.writers == 0 {
+				// Let blocked readers see EOF
+				self.readable.wake_all();
+			}
+		}
+	}
+
+	/// Read up to `dst.len()` bytes, blocking until at least one byte is available
+	///
+	/// Returns 0 at EOF (empty and no writers).
+	pub fn read(&self, dst: &mut [u8]) -> super::Result<usize> {
+		if dst.len() == 0 {
+			return Ok(0);
+		}
+		self.readable.wait_while(|| {
+			let lh = self.inner.lock();
+			lh.data.is_empty() && lh.writers > 0
+			});
+		let count = self.inner.lock().data.pop_front_into(dst);
+		if count > 0 {
+			self.writable.wake_all();
+		}
+		Ok(count)
+	}
+
+	/// Write all of `src`, blocking while the pipe is full
+	///
+	/// Fails with `BrokenPipe` if there are no readers. If the last reader closes part way through,
+	/// the number of bytes written so far is returned.
+	pub fn write(&self, src: &[u8]) -> super::Result<usize> {
+		let mut count = 0;
+		while count < src.len()
+		{
+			self.writable.wait_while(|| {
+				let lh = self.inner.lock();
+				lh.data.len() >= PIPE_BUF_SIZE && lh.readers > 0
+				});
+			let mut lh = self.inner.lock();
+			if lh.readers == 0 {
+				return if count == 0 { Err(super::Error::BrokenPipe) } else { Ok(count) };
+			}
+			let n = ::core::cmp::min(src.len() - count, PIPE_BUF_SIZE - lh.data.len());
+			lh.data.extend_from_slice(&src[count .. count + n]);
+			count += n;
+			self.readable.wake_all();
+		}
+		Ok(count)
+	}
+
+	/// Number of bytes waiting to be read
+	pub fn available(&self) -> usize {
+		self.inner.lock().data.len()
+	}
+}
+
+impl PipeReader
+{
+	/// Read up to `dst.len()` bytes, returns 0 once all writers have closed and the pipe is empty
+	pub fn read(&self, dst: &mut [u8]) -> super::Result<usize> {
+		self.0.read(dst)
+	}
+	pub fn available(&self) -> usize {
+		self.0.available()
+	}
+}
+impl ::core::ops::Drop for PipeReader
+{
+	fn drop(&mut self) {
+		self.0.close_end(true, false);
+	}
+}
+
+impl PipeWriter
+{
+	/// Write all of `src` (see `Pipe::write`)
+	pub fn write(&self, src: &[u8]) -> super::Result<usize> {
+		self.0.write(src)
+	}
+}
+impl ::core::ops::Drop for PipeWriter
+{
+	fn drop(&mut self) {
+		self.0.close_end(false, true);
+	}
+}
@@ -0,0 +1,376 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/procfs.rs
+//! Process/kernel information filesystem (mounted at `/proc`)
+//!
+//! Layout:
+//! - `<pid>/status`, `<pid>/maps`, `<pid>/files`, `<pid>/cmdline` - Per-process information
+//! - `mounts` - Mount table
+//! - `volumes` - Physical and logical volumes
+//! - `interrupts` - IRQ counts
+//! - `acpi` - ACPI tables
+//! - `aml` - Parsed AML namespace
+//!
+//! Files are special nodes (read with `handle::Special`) so their contents are generated on each
+//! read instead of being held in the page cache.
+#[allow(unused_imports)]
+use prelude::*;
+use super::{mount, node};
+use metadevs::storage::{self, VolumeHandle};
+use mylib::byte_str::ByteStr;
+use core::fmt::Write;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+
+/// Per-process nodes use inode `PID_INODE_BASE + pid * PID_INODE_STRIDE + index`
+const PID_INODE_BASE: node::InodeId = 0x1000;
+const PID_INODE_STRIDE: node::InodeId = 0x10;
+
+#[derive(Debug,Copy,Clone)]
+enum Global
+{
+	Mounts,
+	Volumes,
+	Interrupts,
+	Acpi,
+	Aml,
+}
+/// Global files, inode is the index plus one
+static S_GLOBALS: [(&'static str, Global); 5] = [
+	("mounts", Global::Mounts),
+	("volumes", Global::Volumes),
+	("interrupts", Global::Interrupts),
+	("acpi", Global::Acpi),
+	("aml", Global::Aml),
+	];
+
+#[derive(Debug,Copy,Clone)]
+enum PidFile
+{
+	Status,
+	Maps,
+	Files,
+	Cmdline,
+}
+/// Files in each process directory, inode offset is the index plus one (0 is the directory)
+static S_PID_FILES: [(&'static str, PidFile); 4] = [
+	("status", PidFile::Status),
+	("maps", PidFile::Maps),
+	("files", PidFile::Files),
+	("cmdline", PidFile::Cmdline),
+	];
+
+struct ProcFS
+{
+	_vh: VolumeHandle,
+	/// Mount time, used as the timestamps of all nodes
+	time: node::Timestamp,
+}
+enum ProcNode
+{
+	Root,
+	PidDir(usize),
+	Global(Global),
+	PidFile(usize, PidFile),
+}
+struct ProcRef
+{
+	inode: node::InodeId,
+	node: ProcNode,
+	time: node::Timestamp,
+}
+
+pub fn init()
+{
+	let h = mount::DriverRegistration::new("procfs", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, _vol: &VolumeHandle) -> super::Result<usize> {
+		// Not backed by a volume, only mounted by name
+		Ok(0)
+	}
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<mount::Filesystem>> {
+		Ok(Box::new(ProcFS {
+			_vh: vol,
+			time: ::time::realtime(),
+			}))
+	}
+}
+
+impl mount::Filesystem for ProcFS
+{
+	fn root_inode(&self) -> node::InodeId {
+		0
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		let pn = if id == 0 {
+				ProcNode::Root
+			}
+			else if id < PID_INODE_BASE {
+				match S_GLOBALS.get(id as usize - 1)
+				{
+				Some(&(_, g)) => ProcNode::Global(g),
+				None => return None,
+				}
+			}
+			else {
+				let pid = ((id - PID_INODE_BASE) / PID_INODE_STRIDE) as usize;
+				if ::process::get_info(pid).is_none() {
+					return None;
+				}
+				match (id - PID_INODE_BASE) % PID_INODE_STRIDE
+				{
+				0 => ProcNode::PidDir(pid),
+				i => match S_PID_FILES.get(i as usize - 1)
+					{
+					Some(&(_, f)) => ProcNode::PidFile(pid, f),
+					None => return None,
+					},
+				}
+			};
+		let is_dir = match pn
+			{
+			ProcNode::Root | ProcNode::PidDir(_) => true,
+			ProcNode::Global(_) | ProcNode::PidFile(..) => false,
+			};
+		let r = Box::new(ProcRef { inode: id, node: pn, time: self.time });
+		if is_dir {
+			Some(node::Node::Dir(r))
+		}
+		else {
+			Some(node::Node::Special(r))
+		}
+	}
+}
+
+fn pid_inode(pid: usize) -> node::InodeId
+{
+	PID_INODE_BASE + pid as node::InodeId * PID_INODE_STRIDE
+}
+
+impl ProcRef
+{
+	/// Directory entries
+	fn entries(&self) -> Vec<(node::InodeId, String)> {
+		match self.node
+		{
+		ProcNode::Root => {
+			let mut rv: Vec<_> = S_GLOBALS.iter().enumerate()
+				.map(|(i, &(name, _))| (i as node::InodeId + 1, String::from(name)))
+				.collect();
+			rv.extend( ::process::enum_pids().into_iter().map(|pid| (pid_inode(pid), format!("{}", pid))) );
+			rv
+			},
+		ProcNode::PidDir(pid) => S_PID_FILES.iter().enumerate()
+			.map(|(i, &(name, _))| (pid_inode(pid) + i as node::InodeId + 1, String::from(name)))
+			.collect(),
+		_ => Vec::new(),
+		}
+	}
+
+	/// Generate the contents of a file node
+	fn contents(&self) -> super::Result<String> {
+		let mut rv = String::new();
+		match self.node
+		{
+		ProcNode::Global(g) => match g
+			{
+			Global::Mounts => write_mounts(&mut rv),
+			Global::Volumes => write_volumes(&mut rv),
+			Global::Interrupts => write_interrupts(&mut rv),
+			Global::Acpi => write_acpi(&mut rv),
+			Global::Aml => write_aml(&mut rv),
+			},
+		ProcNode::PidFile(pid, f) => {
+			// The process might have exited since the node was opened
+			let info = try!(::process::get_info(pid).ok_or(super::Error::NotFound));
+			match f
+			{
+			PidFile::Status => {
+				let _ = writeln!(rv, "Name:\t{}", info.name);
+				let _ = writeln!(rv, "Pid:\t{}", info.pid);
+				let _ = writeln!(rv, "State:\t{}", info.status);
+				let _ = writeln!(rv, "Mode:\t{}", if info.is_user { "user" } else { "kernel" });
+				let _ = writeln!(rv, "Uid:\t{}", info.uid);
+				let _ = writeln!(rv, "Gid:\t{}", info.gid);
+				let _ = writeln!(rv, "Root:\t{}", info.root);
+				let _ = writeln!(rv, "Cwd:\t{}", info.cwd);
+				},
+			PidFile::Maps => {
+				let _ = writeln!(rv, "{}", info.memory_map);
+				},
+			PidFile::Files => {
+				// Handle ID, then mount ID:inode and open mode
+				for &(id, ref desc) in info.handles.iter() {
+					let _ = writeln!(rv, "{}\t{}", id, desc);
+				}
+				},
+			PidFile::Cmdline => {
+				for (i, arg) in info.args.iter().enumerate() {
+					let _ = write!(rv, "{}{}", if i == 0 { "" } else { " " }, arg);
+				}
+				rv.push('\n');
+				},
+			}
+			},
+		_ => return Err(super::Error::TypeMismatch),
+		}
+		Ok(rv)
+	}
+}
+
+fn write_mounts(rv: &mut String)
+{
+	for m in mount::enum_mounts()
+	{
+		let volume = if m.volume == "" { "none" } else { &m.volume[..] };
+		let _ = writeln!(rv, "{} {} {} {} {}", m.id, volume, m.path, m.filesystem, m.options);
+	}
+}
+
+fn write_volumes(rv: &mut String)
+{
+	for (idx, name) in storage::enum_pvs() {
+		let _ = writeln!(rv, "pv{} {}", idx, name);
+	}
+	for (idx, name) in storage::enum_lvs() {
+		let _ = writeln!(rv, "lv{} {}", idx, name);
+	}
+}
+
+fn write_interrupts(rv: &mut String)
+{
+	use arch::interrupts::irq::{irq_count, IRQ_COUNT};
+	for irq in 0 .. IRQ_COUNT as u8
+	{
+		let count = irq_count(irq);
+		if count > 0 {
+			let _ = writeln!(rv, "{:3}: {}", irq, count);
+		}
+	}
+}
+
+fn write_acpi(rv: &mut String)
+{
+	use arch::driver::acpi::SDT_POINTERS;
+	if let Some(ref ptrs) = *SDT_POINTERS.read()
+	{
+		for (sig, sdt) in ptrs.iter()
+		{
+			// `Sdt` is packed, so copy the fields out before formatting
+			let (length, revision) = (sdt.length, sdt.revision);
+			let _ = writeln!(rv, "{} {:6} {:8} rev={} len={:#x} @{:p}", sig.0,
+				String::from_utf8_lossy(&sig.1), String::from_utf8_lossy(&sig.2),
+				revision, length, *sdt);
+		}
+	}
+}
+
+fn write_aml(rv: &mut String)
+{
+	use arch::driver::acpi::{ACPI_TABLE, AmlValue};
+	if let Some(ref ns) = *ACPI_TABLE.namespace.read()
+	{
+		for (name, value) in ns.iter()
+		{
+			// Only plain values are shown, reading fields/methods would need the AML interpreter
+			let _ = match *value
+				{
+				AmlValue::Integer(v) | AmlValue::IntegerConstant(v) => writeln!(rv, "{} [Integer] = {:#x}", name, v),
+				AmlValue::String(ref v) => writeln!(rv, "{} [String] = {:?}", name, v),
+				_ => writeln!(rv, "{} {}", name, value.get_type_string()),
+				};
+		}
+	}
+}
+
+impl node::NodeBase for ProcRef {
+	fn get_id(&self) -> node::InodeId {
+		self.inode
+	}
+	fn get_any(&self) -> &::core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Metadata {
+		let (class, mode, size, nlink) = match self.node
+			{
+			ProcNode::Root | ProcNode::PidDir(_) => (node::NodeClass::Dir, 0o555, self.entries().len() as u64, 2),
+			// Contents are generated on read
+			_ => (node::NodeClass::Special, 0o444, 0, 1),
+			};
+		let (uid, gid) = match self.node
+			{
+			ProcNode::PidDir(pid) | ProcNode::PidFile(pid, _) => match ::process::get_info(pid)
+				{
+				Some(info) => (info.uid, info.gid),
+				None => (0, 0),
+				},
+			_ => (0, 0),
+			};
+		node::Metadata {
+			class: class,
+			size: size,
+			mode: node::Mode::from_bits_truncate(mode),
+			uid: uid,
+			gid: gid,
+			atime: self.time,
+			mtime: self.time,
+			ctime: self.time,
+			nlink: nlink,
+		}
+	}
+}
+impl node::Dir for ProcRef {
+	fn lookup(&self, name: &ByteStr) -> super::Result<node::InodeId> {
+		match self.entries().into_iter().find(|e| name == &e.1[..])
+		{
+		Some((inode, _)) => Ok(inode),
+		None => Err(super::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> super::Result<usize> {
+		let mut count = 0;
+		for (inode, name) in self.entries().into_iter().skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(inode, &mut name.bytes()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> super::Result<node::InodeId> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn link(&self, _name: &ByteStr, _node: &node::NodeBase) -> super::Result<()> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn unlink(&self, _name: &ByteStr) -> super::Result<()> {
+		Err(super::Error::ReadOnlyFilesystem)
+	}
+	fn cache_lookups(&self) -> bool {
+		// Process directories come and go
+		false
+	}
+}
+impl node::Special for ProcRef {
+	fn typename(&self) -> &str {
+		"proc"
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> super::Result<usize> {
+		let data = try!(self.contents());
+		let data = data.as_bytes();
+		if ofs >= data.len() as u64 {
+			return Ok(0);
+		}
+		let data = &data[ofs as usize ..];
+		let len = ::core::cmp::min(buf.len(), data.len());
+		buf[..len].copy_from_slice(&data[..len]);
+		Ok(len)
+	}
+}
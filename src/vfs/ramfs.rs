@@ -8,26 +8,43 @@ use vfs;
 use super::{mount, node};
 use metadevs::storage::VolumeHandle;
 use spin::Mutex;
+use core::sync::atomic::{AtomicBool,Ordering};
 use mylib::{VecMap,SparseVec};
 use mylib::byte_str::{ByteStr,ByteString};
 use mylib::mem::aref::{Aref,ArefInner,ArefBorrow};
 use mylib::mem::Arc;
 use mylib::borrow::Borrow;
-use mylib::{LazyStatic};
-use ata;
 
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
-static mut SATA: LazyStatic<ata::AtaVolume> = lazystatic_init!();
-static mut SDISK: LazyStatic<VecMap<usize,(usize,usize)>> = lazystatic_init!();
 
-pub const PRIO: u8 = 0;
+/// Number of blocks copied (or zeroed) at a time when a file's data is moved or extended
+const COPY_BLOCKS: u64 = 16;
 
+/// A ramfs node: the node data and its metadata
+struct RamNode
+{
+	meta: Mutex<RamMeta>,
+	file: RamFile,
+	/// The last link to the node has been removed, it is freed once nothing refers to it
+	unlinked: AtomicBool,
+}
+/// Settable node metadata (the rest of `node::Metadata` is derived from the node)
+struct RamMeta
+{
+	mode: node::Mode,
+	uid: u32,
+	gid: u32,
+	atime: node::Timestamp,
+	mtime: node::Timestamp,
+	ctime: node::Timestamp,
+}
 enum RamFile
 {
 	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
+	Fifo(super::pipe::Pipe),
 }
 #[derive(Default)]
 struct RamFileDir
@@ -39,13 +56,24 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
+/// File data is stored in one run of volume blocks (moved when the file outgrows it)
 #[derive(Default)]
 struct RamFileFile
 {
-	ofs: usize,
-	size: usize,
+	extent: Mutex<FileExtent>,
 }
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default,Copy,Clone)]
+struct FileExtent
+{
+	/// First volume block of the data
+	first: u64,
+	/// Number of blocks allocated
+	blocks: u64,
+	/// Size of the file in bytes
+	size: u64,
+}
+/// Filesystem, node and inode number
+struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamNode>,usize);
 
 struct RamFS
 {
@@ -53,22 +81,19 @@ struct RamFS
 }
 struct RamFSInner
 {
-	_vh: VolumeHandle,
-	// TODO: Store as much data (and metadata) as possible on the volume
-	// - Possibly by using an allocation pool backed onto the volume
-	nodes: Mutex<SparseVec<Aref<RamFile>>>,
+	vh: VolumeHandle,
+	/// Unallocated runs of volume blocks as (first, count), sorted and never adjacent
+	free: Mutex<Vec<(u64,u64)>>,
+	// TODO: Store metadata on the volume too
+	nodes: Mutex<SparseVec<Aref<RamNode>>>,
+	/// Unlinked nodes waiting for their last reference to go (see `RamFSInner::reap`)
+	dead: Mutex<Vec<usize>>,
 }
 
 pub fn init()
 {
 	let h = mount::DriverRegistration::new("ramfs", &S_DRIVER);
 	::core::mem::forget(h);
-	unsafe{
-		SATA.prep( || Default::default() );
-		SATA.ls_unsafe_mut().init2(String::from("sata"),0,2048);
-		SDISK.prep( || Default::default() );
-		SDISK.ls_unsafe_mut().insert(65535,(0,0));
-	}
 }
 
 impl mount::Driver for Driver
@@ -78,14 +103,21 @@ impl mount::Driver for Driver
 		Ok(0)
 	}
 	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<mount::Filesystem>> {
+		let free = match vol.block_count()
+			{
+			0 => Vec::new(),
+			n => vec![(0, n)],
+			};
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
-				_vh: vol,
+				vh: vol,
+				free: Mutex::new(free),
 				nodes: Default::default(),
+				dead: Default::default(),
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamNode::new(RamFile::Dir(Default::default()))) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
@@ -99,28 +131,266 @@ impl mount::Filesystem for RamFS
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		//log_trace!("RamFS::get_node_by_inode({})", id);
 		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
-			//log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
-			None
-		}
-		else {
+		// Freed inodes leave holes (and unlinked ones must not be reopened)
+		match nodes.get(id as usize)
+		{
+		Some(n) if !n.unlinked.load(Ordering::SeqCst) => {
 			let fr = Box::new(FileRef(
 				self.inner.borrow(),
-				nodes[id as usize].borrow()
+				n.borrow(),
+				id as usize
 				));
-			match *nodes[id as usize]
+			match n.file
 			{
 			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
 			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
 			RamFile::File(_) => Some(node::Node::File(fr)),//todo!("normal files"),
+			RamFile::Fifo(_) => Some(node::Node::Special(fr)),
+			}
+			},
+		//log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
+		_ => None,
+		}
+	}
+}
+
+impl RamFSInner
+{
+	/// Get a reference to a node (without holding the node table lock afterwards)
+	fn node(&self, inode: usize) -> ArefBorrow<RamNode> {
+		self.nodes.lock()[inode].borrow()
+	}
+	/// A node can be unlinked if it isn't a directory, or is an empty directory
+	///
+	/// `locked` is a directory the caller has locked, which can't be empty (it holds the entry
+	/// being renamed).
+	fn is_removable(&self, inode: usize, locked: Option<&RamFileDir>) -> bool {
+		let node = self.node(inode);
+		match node.file
+		{
+		RamFile::Dir(ref d) if locked.map(|l| l as *const _ == d as *const _).unwrap_or(false) => false,
+		RamFile::Dir(ref d) => d.ents.lock().len() == 0,
+		_ => true,
+		}
+	}
+	/// Check that `name` in `ents` can be replaced by `inode` (see `node::Dir::rename`)
+	fn check_replace(&self, ents: &VecMap<ByteString,usize>, name: &ByteStr, inode: usize, src: &RamFileDir) -> vfs::Result<()> {
+		let existing = match ents.get(name)
+			{
+			None => return Ok( () ),
+			Some(&v) if v == inode => return Ok( () ),
+			Some(&v) => v,
+			};
+		let is_dir = |i| match self.node(i).file { RamFile::Dir(_) => true, _ => false };
+		match (is_dir(inode), is_dir(existing))
+		{
+		(false, false) => Ok( () ),
+		(true, true) => if self.is_removable(existing, Some(src)) { Ok( () ) } else { Err(vfs::Error::NotEmpty) },
+		(true, false) => Err(vfs::Error::NonDirComponent),
+		(false, true) => Err(vfs::Error::TypeMismatch),
+		}
+	}
+
+	/// Drop the last link to `inode`, freeing it now if nothing else refers to it
+	fn release(&self, inode: usize) {
+		self.node(inode).unlinked.store(true, Ordering::SeqCst);
+		self.dead.lock().push(inode);
+		self.reap();
+	}
+	/// Free unlinked nodes that are no longer borrowed (by `FileRef`s, i.e. the VFS node cache)
+	fn reap(&self) {
+		let mut nodes = self.nodes.lock();
+		let mut dead = self.dead.lock();
+		let mut i = 0;
+		while i < dead.len()
+		{
+			let inode = dead[i];
+			if nodes[inode].borrow_count() > 0 {
+				i += 1;
+				continue ;
+			}
+			self.free_data(&nodes[inode]);
+			nodes.remove(inode);
+			dead.swap_remove(i);
+		}
+	}
+	/// Return the storage of a file node to the free list
+	fn free_data(&self, node: &RamNode) {
+		if let RamFile::File(ref f) = node.file {
+			let mut ext = f.extent.lock();
+			// Shrinking never touches the volume, so this can't fail
+			let _ = self.resize_extent(&mut ext, 0);
+			ext.size = 0;
+		}
+	}
+
+	/// Allocate `count` contiguous blocks (first fit), returning the first
+	fn alloc_blocks(&self, count: u64) -> vfs::Result<u64> {
+		if count == 0 {
+			return Ok(0);
+		}
+		let mut free = self.free.lock();
+		let i = match free.iter().position(|&(_, n)| n >= count)
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::OutOfSpace),
+			};
+		let first = free[i].0;
+		if free[i].1 == count {
+			free.remove(i);
+		}
+		else {
+			free[i] = (first + count, free[i].1 - count);
+		}
+		Ok(first)
+	}
+	/// Extend the allocation `first..first+count` by `extra` blocks, if the following blocks are free
+	fn grow_blocks(&self, first: u64, count: u64, extra: u64) -> bool {
+		let mut free = self.free.lock();
+		match free.iter().position(|&(f, _)| f == first + count)
+		{
+		Some(i) if free[i].1 >= extra => {
+			if free[i].1 == extra {
+				free.remove(i);
+			}
+			else {
+				free[i] = (free[i].0 + extra, free[i].1 - extra);
+			}
+			true
+			},
+		_ => false,
+		}
+	}
+	/// Return `count` blocks starting at `first` to the free list
+	fn free_blocks(&self, first: u64, count: u64) {
+		if count == 0 {
+			return ;
+		}
+		let mut free = self.free.lock();
+		let i = free.iter().position(|&(f, _)| f > first).unwrap_or(free.len());
+		assert!(i == 0 || free[i-1].0 + free[i-1].1 <= first, "ramfs: Double free of block {}", first);
+		free.insert(i, (first, count));
+		// Merge with the following run, then the preceding one
+		if i + 1 < free.len() && first + count == free[i+1].0 {
+			free[i].1 += free[i+1].1;
+			free.remove(i+1);
+		}
+		if i > 0 && free[i-1].0 + free[i-1].1 == first {
+			free[i-1].1 += free[i].1;
+			free.remove(i);
+		}
+	}
+	/// Resize `ext` to hold at least `blocks` blocks, moving the data if it can't grow in place
+	fn resize_extent(&self, ext: &mut FileExtent, blocks: u64) -> vfs::Result<()> {
+		if blocks <= ext.blocks {
+			self.free_blocks(ext.first + blocks, ext.blocks - blocks);
+			ext.blocks = blocks;
+			if blocks == 0 {
+				ext.first = 0;
+			}
+			return Ok( () );
+		}
+		if ext.blocks > 0 && self.grow_blocks(ext.first, ext.blocks, blocks - ext.blocks) {
+			ext.blocks = blocks;
+			return Ok( () );
+		}
+		let new_first = try!(self.alloc_blocks(blocks));
+		let bs = self.vh.block_size();
+		let mut buf = vec![0u8; COPY_BLOCKS as usize * bs];
+		let mut done = 0;
+		while done < ext.blocks
+		{
+			let n = ::core::cmp::min(COPY_BLOCKS, ext.blocks - done);
+			let chunk = &mut buf[.. n as usize * bs];
+			let rv = self.vh.read_blocks(ext.first + done, chunk).and_then(|_| self.vh.write_blocks(new_first + done, chunk));
+			if let Err(e) = rv {
+				self.free_blocks(new_first, blocks);
+				return Err( From::from(e) );
 			}
+			done += n;
+		}
+		self.free_blocks(ext.first, ext.blocks);
+		ext.first = new_first;
+		ext.blocks = blocks;
+		Ok( () )
+	}
+
+	/// Read `dst.len()` bytes from byte offset `ofs` of the data starting at block `first`
+	fn read_bytes(&self, first: u64, ofs: u64, dst: &mut [u8]) -> vfs::Result<()> {
+		if dst.len() == 0 {
+			return Ok( () );
+		}
+		let bs = self.vh.block_size();
+		let skip = (ofs % bs as u64) as usize;
+		let mut tmp = vec![0u8; (skip + dst.len() + bs - 1) / bs * bs];
+		try!(self.vh.read_blocks(first + ofs / bs as u64, &mut tmp));
+		dst.copy_from_slice(&tmp[skip .. skip + dst.len()]);
+		Ok( () )
+	}
+	/// Write `src` at byte offset `ofs` of the data starting at block `first` (read-modify-write)
+	fn write_bytes(&self, first: u64, ofs: u64, src: &[u8]) -> vfs::Result<()> {
+		if src.len() == 0 {
+			return Ok( () );
+		}
+		let bs = self.vh.block_size();
+		let blk = first + ofs / bs as u64;
+		let skip = (ofs % bs as u64) as usize;
+		let mut tmp = vec![0u8; (skip + src.len() + bs - 1) / bs * bs];
+		if skip != 0 || src.len() % bs != 0 {
+			try!(self.vh.read_blocks(blk, &mut tmp));
+		}
+		tmp[skip .. skip + src.len()].copy_from_slice(src);
+		try!(self.vh.write_blocks(blk, &tmp));
+		Ok( () )
+	}
+	/// Zero `len` bytes from byte offset `ofs` of the data starting at block `first`
+	fn zero_bytes(&self, first: u64, ofs: u64, len: u64) -> vfs::Result<()> {
+		let chunk = COPY_BLOCKS * self.vh.block_size() as u64;
+		let zeroes = vec![0u8; ::core::cmp::min(chunk, len) as usize];
+		let mut done = 0;
+		while done < len
+		{
+			let n = ::core::cmp::min(chunk, len - done);
+			try!(self.write_bytes(first, ofs + done, &zeroes[.. n as usize]));
+			done += n;
+		}
+		Ok( () )
+	}
+	/// Number of blocks needed for `bytes` bytes
+	fn blocks_for(&self, bytes: u64) -> u64 {
+		let bs = self.vh.block_size() as u64;
+		(bytes + bs - 1) / bs
+	}
+}
+
+impl RamNode {
+	/// New node owned by the current process, with the default mode for its type
+	fn new(file: RamFile) -> RamNode {
+		let mode = match file
+			{
+			RamFile::Dir(_) => node::Mode::from_bits_truncate(0o755),
+			RamFile::File(_) | RamFile::Fifo(_) => node::Mode::from_bits_truncate(0o644),
+			RamFile::Symlink(_) => node::Mode::all(),
+			};
+		let now = ::time::realtime();
+		RamNode {
+			meta: Mutex::new(RamMeta {
+				mode: mode,
+				uid: ::process::get_uid(),
+				gid: ::process::get_gid(),
+				atime: now,
+				mtime: now,
+				ctime: now,
+				}),
+			file: file,
+			unlinked: AtomicBool::new(false),
 		}
 	}
 }
 
 impl FileRef {
 	fn dir(&self) -> &RamFileDir {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Dir(ref e) => e,
 		_ => panic!("Called FileRef::dir() on non-dir"),
@@ -136,69 +406,75 @@ impl FileRef {
 		}
 	}*/
 	fn symlink(&self) -> &RamFileSymlink {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Symlink(ref e) => e,
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
 		}
 	}
+	fn fifo(&self) -> &super::pipe::Pipe {
+		match &self.1.file
+		{
+		&RamFile::Fifo(ref e) => e,
+		_ => panic!("Called FileRef::fifo() on non-fifo"),
+		}
+	}
 	fn file(&self) -> &RamFileFile {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::File(ref e) => e,
 		_ => panic!("Called FileRef::file() on non-file"),
 		}
 	}
-	fn mut_file(&mut self) -> &mut RamFileFile {
-		unsafe{
-			match &mut (*(self.1.__ptr.as_ptr() as *mut ArefInner<RamFile>)).data// &mut self.1.get_data()
-			{
-			&mut RamFile::File(ref mut e) => e,
-			_ => panic!("Called FileRef::file() on non-file"),
-			}
-		}
-	}
-
-	fn check_insert(&self, x1:usize,y1:usize,x2:usize,y2:usize)->bool{
-		if y1<=x2 || y2<=x1{
-			true
-		}else{
- 			false
-		}
-	}
-
-	fn allocate(&mut self, id: usize, len: usize){
-		unsafe{
-			match SDISK.get(&id)
-			{
-				Some(v) => {
-					SDISK.ls_unsafe_mut().remove(&id);
-				},
-				None => (),
-			}
-
-			for (_,blk) in SDISK.iter(){
-				let mut bput=1;
-				for (_,blk2) in SDISK.iter(){
-					if !self.check_insert(blk.0+blk.1,blk.0+blk.1+len,blk2.0,blk2.0+blk2.1){
-						bput=0;
-					}
-				}
-				if bput==1{
-					SDISK.ls_unsafe_mut().insert(id,(blk.0+blk.1,len));
-					break;
-				}
-			}
+}
+impl ::core::ops::Drop for FileRef
+{
+	fn drop(&mut self) {
+		// Release the data of an unlinked node on its last close (this borrow is the only one left).
+		// The node itself is freed by the next `reap`, once this borrow is gone.
+		if self.1.unlinked.load(Ordering::SeqCst) && self.1.__inner().borrow_count() == 1 {
+			self.0.free_data(&self.1);
 		}
 	}
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2 as node::InodeId
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Metadata {
+		let (class, size, nlink) = match self.1.file
+			{
+			RamFile::Dir(ref d) => (node::NodeClass::Dir, d.ents.lock().len() as u64, 2),
+			RamFile::File(ref f) => (node::NodeClass::File, f.extent.lock().size, 1),
+			RamFile::Symlink(ref l) => (node::NodeClass::Symlink, AsRef::<[u8]>::as_ref(&*l.target).len() as u64, 1),
+			RamFile::Fifo(ref p) => (node::NodeClass::Special, p.available() as u64, 1),
+			};
+		let meta = self.1.meta.lock();
+		node::Metadata {
+			class: class,
+			size: size,
+			mode: meta.mode,
+			uid: meta.uid,
+			gid: meta.gid,
+			atime: meta.atime,
+			mtime: meta.mtime,
+			ctime: meta.ctime,
+			nlink: nlink,
+		}
+	}
+	fn set_metadata(&self, new: &node::Metadata) -> node::Result<()> {
+		let mut meta = self.1.meta.lock();
+		meta.mode = new.mode;
+		meta.uid = new.uid;
+		meta.gid = new.gid;
+		meta.atime = new.atime;
+		meta.mtime = new.mtime;
+		meta.ctime = new.ctime;
+		Ok( () )
+	}
 }
 impl node::Dir for FileRef {
 	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
@@ -240,8 +516,10 @@ impl node::Dir for FileRef {
 				//node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
 				node::NodeType::Symlink(v) =>
 					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
+				node::NodeType::Fifo => RamFile::Fifo(Default::default()),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			self.0.reap();
+			let inode = self.0.nodes.lock().insert( Aref::new(RamNode::new(nn)) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
@@ -251,7 +529,66 @@ impl node::Dir for FileRef {
 		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.lock();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		if ! self.0.is_removable(inode, None) {
+			return Err(vfs::Error::NotEmpty);
+		}
+		lh.remove(&ByteString::from(name));
+		// NOTE: There are no hard links (`link` is unimplemented), so this was the last link
+		self.0.release(inode);
+		Ok( () )
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &node::Dir, new_name: &ByteStr) -> vfs::Result<()> {
+		let new_dir = match new_dir.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossDevice),
+			};
+		let src = self.dir();
+		let dst = new_dir.dir();
+		if src as *const _ == dst as *const _ {
+			let mut lh = src.ents.lock();
+			let inode = match lh.get(old_name)
+				{
+				Some(&v) => v,
+				None => return Err(vfs::Error::NotFound),
+				};
+			if old_name == new_name {
+				return Ok( () );
+			}
+			try!(self.0.check_replace(&lh, new_name, inode, src));
+			lh.remove(&ByteString::from(old_name));
+			if let Some(replaced) = lh.insert(ByteString::from(new_name), inode) {
+				self.0.release(replaced);
+			}
+		}
+		else {
+			// Lock in address order, so a concurrent rename the other way can't deadlock
+			let (mut src_lh, mut dst_lh) = if (src as *const RamFileDir) < (dst as *const RamFileDir) {
+					let s = src.ents.lock();
+					(s, dst.ents.lock())
+				}
+				else {
+					let d = dst.ents.lock();
+					(src.ents.lock(), d)
+				};
+			let inode = match src_lh.get(old_name)
+				{
+				Some(&v) => v,
+				None => return Err(vfs::Error::NotFound),
+				};
+			try!(self.0.check_replace(&dst_lh, new_name, inode, src));
+			src_lh.remove(&ByteString::from(old_name));
+			if let Some(replaced) = dst_lh.insert(ByteString::from(new_name), inode) {
+				self.0.release(replaced);
+			}
+		}
+		Ok( () )
 	}
 }
 impl node::Symlink for FileRef {
@@ -260,74 +597,97 @@ impl node::Symlink for FileRef {
 	}
 }
 
+impl node::Special for FileRef {
+	fn typename(&self) -> &str {
+		"fifo"
+	}
+	fn read(&self, _ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		self.fifo().read(buf)
+	}
+	fn write(&self, _ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		self.fifo().write(buf)
+	}
+	fn open(&self, read: bool, write: bool) -> node::Result<()> {
+		self.fifo().open_end(read, write);
+		Ok( () )
+	}
+	fn close(&self, read: bool, write: bool) {
+		self.fifo().close_end(read, write);
+	}
+}
+
 impl node::File for FileRef {
 	/// Returns the size (in bytes) of this file
 	fn size(&self) -> u64{
-		0
+		self.file().extent.lock().size
 	}
 	/// Update the size of the file (zero padding or truncating)
 	fn truncate(&self, newsize: u64) -> node::Result<u64>{
-		Ok(0)
+		let mut ext = self.file().extent.lock();
+		let blocks = self.0.blocks_for(newsize);
+		try!(self.0.resize_extent(&mut ext, blocks));
+		if newsize > ext.size {
+			// The blocks may hold data from before a shrink, or from a deleted file
+			let old = ext.size;
+			try!(self.0.zero_bytes(ext.first, old, newsize - old));
+		}
+		ext.size = newsize;
+		Ok(newsize)
 	}
 	/// Clear the specified range of the file (replace with zeroes)
 	fn clear(&self, ofs: u64, size: u64) -> node::Result<()>{
-		Ok(())
+		let ext = *self.file().extent.lock();
+		if ofs > ext.size || size > ext.size - ofs {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		self.0.zero_bytes(ext.first, ofs, size)
 	}
 	/// Read data from the file
 	fn read(&self, ofs: u64, buf: &mut [u32]) -> node::Result<usize>{
-		//println!("reading..");
-		let sf=self.file();
-		//println!("ofs:{} size:{}",sf.ofs,sf.size);
-		unsafe{
-			match SATA.read(PRIO, ofs, buf.len()/ata::io::SECTOR_SIZE, buf)
-			{
-				Ok(v) => Ok(v),
-				Err(e) => todo!("Error when PV fails to read: {:?}", e),
-			}
+		let ext = *self.file().extent.lock();
+		if ofs >= ext.size {
+			return Ok(0);
+		}
+		// Only return data within the file, the tail of the last word reads as zero
+		let len = ::core::cmp::min(buf.len() as u64 * 4, ext.size - ofs) as usize;
+		let mut tmp = vec![0u8; (len + 3) / 4 * 4];
+		try!(self.0.read_bytes(ext.first, ofs, &mut tmp[..len]));
+		let count = tmp.len() / 4;
+		for (w, b) in buf.iter_mut().zip(tmp.chunks(4)) {
+			*w = (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
 		}
-		//Ok(0)
+		Ok(count)
 	}
-	/// Write data to the file, can only grow the file if ofs==size
+	/// Write data to the file
+	///
+	/// The size isn't changed (grow the file with `truncate` first), but the write may run up to the
+	/// end of the allocated blocks (e.g. the padding of the last word).
 	fn write(&self, ofs: u64, buf: &[u32]) -> node::Result<usize>{
-		//println!("writing..");
-		let sf=self.file();
-		//println!("ofs:{} size:{}",sf.ofs,sf.size);
-		unsafe{
-			match SATA.write(PRIO, ofs, buf.len()/ata::io::SECTOR_SIZE, buf)
-			{
-				Ok(v) => Ok(v),
-				Err(e) => todo!("Error when PV fails to read: {:?}", e),
-			}
+		let ext = *self.file().extent.lock();
+		let end = ofs + buf.len() as u64 * 4;
+		if ofs > ext.size || end > ext.blocks * self.0.vh.block_size() as u64 {
+			return Err(vfs::Error::InvalidParameter);
 		}
-		//Ok(0)
+		try!(self.0.write_bytes(ext.first, ofs, &words_to_bytes(buf)));
+		Ok(buf.len())
 	}
-	/// Write data to the file, can only grow the file if ofs==size
-	fn mut_write(&mut self, id: node::InodeId, buf: &[u32]) -> node::Result<usize>{
-		//println!("mut writing {}..",id);
-		self.allocate(id as usize, buf.len());
-		for (sid,blk) in unsafe{SDISK.iter()}{
-			//println!("map: <{},({},{})>",sid,blk.0,blk.1);
-		}
-		let sf=self.mut_file();
-		//println!("before: ofs:{} size:{}",sf.ofs,sf.size);
-		unsafe{
-			match SDISK.get(&(id as usize)){
-				Some(v) => {
-					sf.ofs=v.0;
-					sf.size=v.1;
-				},
-				None => (),
-			}
-		}
-		//println!("after: ofs:{} size:{}",sf.ofs,sf.size);
-		unsafe{
-			match SATA.write(PRIO, sf.ofs as u64, buf.len()/ata::io::SECTOR_SIZE, buf)
-			{
-				Ok(v) => Ok(v),
-				Err(e) => todo!("Error when PV fails to read: {:?}", e),
-			}
-		}
-		//Ok(0)
+	/// Replace the contents of the file with `buf`
+	fn mut_write(&mut self, _id: node::InodeId, buf: &[u32]) -> node::Result<usize>{
+		let mut ext = self.file().extent.lock();
+		let blocks = self.0.blocks_for(buf.len() as u64 * 4);
+		try!(self.0.resize_extent(&mut ext, blocks));
+		try!(self.0.write_bytes(ext.first, 0, &words_to_bytes(buf)));
+		ext.size = buf.len() as u64 * 4;
+		Ok(buf.len())
 	}
 }
 
+/// Little-endian byte representation of `words` (the layout used by the VFS word API)
+fn words_to_bytes(words: &[u32]) -> Vec<u8>
+{
+	let mut rv = Vec::with_capacity(words.len() * 4);
+	for &w in words {
+		rv.extend_from_slice(&[w as u8, (w >> 8) as u8, (w >> 16) as u8, (w >> 24) as u8]);
+	}
+	rv
+}
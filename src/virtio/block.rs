@@ -0,0 +1,389 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/virtio/block.rs
+//! virtio-blk driver
+//!
+//! Each device is registered as a physical volume with 512-byte blocks (the virtio sector size).
+//! Requests are a header, the data buffer (described page by page) and a status byte, with the
+//! header and status kept in a per-descriptor slot so they can be addressed by the device.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+use mylib::mem::Arc;
+use memory::{ActivePageTable,PAGE_SIZE};
+use memory::kmap::{self,KernelMapping};
+use metadevs::storage;
+use device_manager::{self,pci};
+use super::{Transport,Virtqueue};
+
+const SECTOR_SIZE: usize = 512;
+
+// Feature bits
+const F_SIZE_MAX: u64 = 1 << 1;
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+const F_DISCARD: u64 = 1 << 13;
+const SUPPORTED_FEATURES: u64 = F_SIZE_MAX | F_SEG_MAX | F_RO | F_FLUSH | F_DISCARD;
+
+// Configuration layout
+const CFG_CAPACITY: usize = 0;
+const CFG_SIZE_MAX: usize = 8;
+const CFG_SEG_MAX: usize = 12;
+const CFG_MAX_DISCARD_SECTORS: usize = 36;
+
+// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_DISCARD: u32 = 11;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Per-descriptor request slot: header (16 bytes), discard segment (16 bytes), status byte
+const SLOT_SIZE: usize = 64;
+const SLOT_DISCARD: usize = 16;
+const SLOT_STATUS: usize = 32;
+/// Largest transfer per request
+const MAX_SECTORS: usize = 256;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+/// Transitional (legacy) and modern block device IDs
+static S_MATCHES: [pci::Match; 2] = [pci::Match::device(super::VENDOR_ID, 0x1001), pci::Match::device(super::VENDOR_ID, 0x1042)];
+
+/// Devices serviced by the interrupt handler
+static S_DEVICES: Mutex<Option<Vec<Arc<Device>>>> = Mutex::new(None);
+/// Next volume number (volumes are named `VIRTIO<n>`)
+static S_NEXT_VOLUME: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct Instance
+{
+	_volume: storage::PhysicalVolumeReg,
+}
+impl device_manager::DriverInstance for Instance {}
+
+struct Device
+{
+	transport: Transport,
+	queue: Virtqueue,
+	/// Completion is signalled by interrupt
+	irq: bool,
+	features: u64,
+	/// Request slots, indexed by head descriptor
+	slots: KernelMapping,
+	/// Maximum data segments per request
+	seg_max: usize,
+	/// Maximum size of a data segment
+	size_max: usize,
+	max_discard_sectors: u64,
+}
+
+pub struct Volume
+{
+	name: String,
+	dev: Arc<Device>,
+	capacity: u64,
+}
+
+impl device_manager::Driver for Driver
+{
+	fn name(&self) -> &str { "virtio-blk" }
+	fn matches(&self) -> &[pci::Match] { &S_MATCHES }
+	fn bind(&self, pdev: &pci::Device) -> Option<Box<device_manager::DriverInstance>> {
+		let mut transport = match Transport::new(pdev)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: virtio-blk {}: No usable transport", pdev.addr);
+				return None;
+				},
+			};
+		pdev.enable(!transport.is_modern(), transport.is_modern(), true);
+		if !transport.reset() {
+			println!("warning: virtio-blk {}: Reset timed out", pdev.addr);
+			return None;
+		}
+		transport.set_status(super::STATUS_ACKNOWLEDGE | super::STATUS_DRIVER);
+		let offered = transport.device_features();
+		let features = offered & (SUPPORTED_FEATURES | if transport.is_modern() { super::F_VERSION_1 } else { 0 });
+		transport.set_driver_features(features);
+		if transport.is_modern() {
+			transport.set_status(super::STATUS_ACKNOWLEDGE | super::STATUS_DRIVER | super::STATUS_FEATURES_OK);
+			if transport.status() & super::STATUS_FEATURES_OK == 0 {
+				println!("warning: virtio-blk {}: Features {:#x} not accepted", pdev.addr, features);
+				transport.set_status(super::STATUS_FAILED);
+				return None;
+			}
+		}
+
+		// - Without MSI-X the device raises its (single) interrupt through MSI instead
+		let irq = if pdev.enable_msix(handle_irq).is_some() {
+				transport.use_msix();
+				true
+			}
+			else {
+				pdev.enable_msi(handle_irq).is_some()
+			};
+		if !irq {
+			println!("log: virtio-blk {}: No MSI-X or MSI, polling for completion", pdev.addr);
+		}
+		let queue = match Virtqueue::new(&transport, 0)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: virtio-blk {}: Unable to set up the request queue", pdev.addr);
+				transport.set_status(super::STATUS_FAILED);
+				return None;
+				},
+			};
+		let slots = match kmap::alloc_mapped((queue.size() as usize * SLOT_SIZE + PAGE_SIZE - 1) / PAGE_SIZE)
+			{
+			Some(v) => v,
+			None => {
+				println!("warning: virtio-blk {}: Unable to allocate request slots", pdev.addr);
+				transport.set_status(super::STATUS_FAILED);
+				return None;
+				},
+			};
+
+		let capacity = transport.read_config_64(CFG_CAPACITY);
+		let seg_max = if features & F_SEG_MAX != 0 { transport.read_config_32(CFG_SEG_MAX) as usize } else { 0 };
+		let size_max = if features & F_SIZE_MAX != 0 { transport.read_config_32(CFG_SIZE_MAX) as usize } else { 0 };
+		let max_discard_sectors = if features & F_DISCARD != 0 { transport.read_config_32(CFG_MAX_DISCARD_SECTORS) as u64 } else { 0 };
+		let dev = Arc::new(Device {
+			// - Two descriptors are used by the header and status
+			seg_max: if seg_max == 0 { queue.size() as usize - 2 } else { ::core::cmp::min(seg_max, queue.size() as usize - 2) },
+			size_max: if size_max == 0 { 1 << 22 } else { size_max },
+			max_discard_sectors: if max_discard_sectors == 0 { 0xFFFF_FFFF } else { max_discard_sectors },
+			transport: transport,
+			queue: queue,
+			irq: irq,
+			features: features,
+			slots: slots,
+			});
+		if irq {
+			::arch::interrupts::without_interrupts(|| S_DEVICES.lock().get_or_insert_with(|| Vec::new()).push(dev.clone()));
+		}
+		dev.transport.set_status(super::STATUS_ACKNOWLEDGE | super::STATUS_DRIVER | super::STATUS_FEATURES_OK | super::STATUS_DRIVER_OK);
+
+		let name = format!("VIRTIO{}", S_NEXT_VOLUME.fetch_add(1, Ordering::Relaxed));
+		println!("log: virtio-blk {}: {} ({}), {} sectors, {}{}{}{}", pdev.addr, name,
+			if dev.transport.is_modern() { "modern" } else { "legacy" },
+			capacity, storage::SizePrinter(capacity * SECTOR_SIZE as u64),
+			if features & F_RO != 0 { ", read-only" } else { "" },
+			if features & F_FLUSH != 0 { ", flush" } else { "" },
+			if features & F_DISCARD != 0 { ", discard" } else { "" });
+		let volume = storage::register_pv(Box::new(Volume { name: name, dev: dev, capacity: capacity }));
+		Some(Box::new(Instance { _volume: volume }))
+	}
+}
+
+/// MSI-X (or MSI) handler, collects completed requests on every device
+fn handle_irq()
+{
+	if let Some(ref devs) = *S_DEVICES.lock()
+	{
+		for dev in devs.iter()
+		{
+			if dev.queue.service() {
+				dev.queue.completed.wake_all();
+			}
+		}
+	}
+}
+
+impl Device
+{
+	/// Wait until `cond` holds, polling the queue if there's no interrupt
+	fn wait_for<F: Fn()->bool>(&self, cond: F) {
+		if self.irq && ::arch::interrupts::enabled() {
+			self.queue.completed.wait_while(|| !cond());
+		}
+		else {
+			while !cond() {
+				self.queue.service();
+				::process::yield_now();
+			}
+		}
+	}
+
+	/// Largest transfer that fits in `seg_max` segments (one is spare for a misaligned buffer)
+	fn max_sectors(&self) -> usize {
+		let by_segs = (self.seg_max - 1) * PAGE_SIZE / SECTOR_SIZE;
+		::core::cmp::max(1, ::core::cmp::min(MAX_SECTORS, by_segs))
+	}
+
+	/// Physical segments of the buffer `virt`+`len`, merging contiguous pages
+	fn segments(&self, virt: usize, len: usize) -> Result<Vec<(u64, u32)>,storage::IoError> {
+		// SAFE: Only used to translate addresses
+		let pt = unsafe { ActivePageTable::new() };
+		let mut rv: Vec<(u64, u32)> = Vec::new();
+		let end = virt + len;
+		let mut virt = virt;
+		while virt < end
+		{
+			let chunk = ::core::cmp::min(end - virt, PAGE_SIZE - virt % PAGE_SIZE);
+			let phys = match pt.translate(virt)
+				{
+				Some(v) => v.get() as u64,
+				None => return Err( storage::IoError::Unknown("virtio-blk: Buffer not mapped") ),
+				};
+			let merged = match rv.last_mut()
+				{
+				Some(last) if last.0 + last.1 as u64 == phys && last.1 as usize + chunk <= self.size_max => {
+					last.1 += chunk as u32;
+					true
+					},
+				_ => false,
+				};
+			if !merged {
+				rv.push( (phys, chunk as u32) );
+			}
+			virt += chunk;
+		}
+		if rv.len() > self.seg_max {
+			return Err(storage::IoError::InvalidParameter);
+		}
+		Ok(rv)
+	}
+
+	/// Issue a request and wait for it to complete
+	fn request(&self, ty: u32, sector: u64, payload: Payload) -> Result<(),storage::IoError> {
+		let count = 2 + match payload
+			{
+			Payload::None => 0,
+			Payload::Data(data, _) => data.len(),
+			Payload::Discard(..) => 1,
+			};
+		let descs = loop
+			{
+			match self.queue.alloc(count)
+			{
+			Some(v) => break v,
+			None => self.wait_for(|| self.queue.num_free() >= count),
+			}
+			};
+		let head = descs[0];
+		let slot = self.slots.base() + head as usize * SLOT_SIZE;
+		let slot_phys = self.slots.phys().get() as u64 + (head as usize * SLOT_SIZE) as u64;
+		// SAFE: The slot belongs to the head descriptor, which this call owns
+		unsafe {
+			::core::ptr::write_volatile(slot as *mut u32, ty);
+			::core::ptr::write_volatile((slot + 4) as *mut u32, 0);
+			::core::ptr::write_volatile((slot + 8) as *mut u64, sector);
+			::core::ptr::write_volatile((slot + SLOT_STATUS) as *mut u8, 0xFF);
+		}
+		self.queue.set_desc(descs[0], slot_phys, 16, false, Some(descs[1]));
+		match payload
+		{
+		Payload::None => {},
+		Payload::Data(data, device_writes) =>
+			for (i, &(phys, len)) in data.iter().enumerate() {
+				self.queue.set_desc(descs[1 + i], phys, len, device_writes, Some(descs[2 + i]));
+			},
+		Payload::Discard(first, num) => {
+			// SAFE: As above
+			unsafe {
+				::core::ptr::write_volatile((slot + SLOT_DISCARD) as *mut u64, first);
+				::core::ptr::write_volatile((slot + SLOT_DISCARD + 8) as *mut u32, num);
+				::core::ptr::write_volatile((slot + SLOT_DISCARD + 12) as *mut u32, 0);
+			}
+			self.queue.set_desc(descs[1], slot_phys + SLOT_DISCARD as u64, 16, false, Some(descs[2]));
+			},
+		}
+		self.queue.set_desc(descs[count - 1], slot_phys + SLOT_STATUS as u64, 1, true, None);
+		self.queue.publish(&self.transport, head);
+
+		self.wait_for(|| self.queue.is_done(head));
+		self.queue.take(head);
+		// SAFE: Written by the device before completing the request
+		match unsafe { ::core::ptr::read_volatile((slot + SLOT_STATUS) as *const u8) }
+		{
+		S_OK => Ok( () ),
+		S_IOERR => Err(storage::IoError::BadBlock),
+		S_UNSUPP => Err(storage::IoError::Unknown("virtio-blk: Unsupported request")),
+		_ => Err(storage::IoError::Unknown("virtio-blk: Bad request status")),
+		}
+	}
+}
+
+/// Data attached to a request
+enum Payload<'a>
+{
+	None,
+	/// Physical data segments, and whether the device writes them (reads)
+	Data(&'a [(u64, u32)], bool),
+	/// Discard segment (first sector, sector count)
+	Discard(u64, u32),
+}
+
+impl Volume
+{
+	/// Split a transfer into requests
+	fn transfer(&self, blockidx: u64, count: usize, buf: usize, write: bool) -> Result<usize,storage::IoError> {
+		if blockidx + count as u64 > self.capacity {
+			return Err(storage::IoError::BadAddr);
+		}
+		let mut done = 0;
+		while done < count
+		{
+			let n = ::core::cmp::min(count - done, self.dev.max_sectors());
+			let segs = try!(self.dev.segments(buf + done * SECTOR_SIZE, n * SECTOR_SIZE));
+			try!(self.dev.request(if write { T_OUT } else { T_IN }, blockidx + done as u64, Payload::Data(&segs, !write)));
+			done += n;
+		}
+		Ok(count)
+	}
+}
+
+impl storage::PhysicalVolume for Volume
+{
+	fn name(&self) -> &str { &*self.name }
+	fn blocksize(&self) -> usize { SECTOR_SIZE }
+	fn capacity(&self) -> Option<u64> { Some(self.capacity) }
+
+	fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!(dst.len(), count * SECTOR_SIZE);
+		self.transfer(blockidx, count, dst.as_mut_ptr() as usize, false)
+	}
+	fn write<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, src: &'a [u8]) -> Result<usize,storage::IoError>
+	{
+		assert_eq!(src.len(), count * SECTOR_SIZE);
+		if self.dev.features & F_RO != 0 {
+			return Err(storage::IoError::ReadOnly);
+		}
+		self.transfer(blockidx, count, src.as_ptr() as usize, true)
+	}
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> Result<(),storage::IoError>
+	{
+		if blockidx + count as u64 > self.capacity {
+			return Err(storage::IoError::BadAddr);
+		}
+		// Discard is only a hint, so it's fine to do nothing
+		if self.dev.features & F_DISCARD == 0 || self.dev.features & F_RO != 0 {
+			return Ok( () );
+		}
+		let mut done = 0;
+		while done < count as u64
+		{
+			let n = ::core::cmp::min(count as u64 - done, self.dev.max_discard_sectors);
+			try!(self.dev.request(T_DISCARD, 0, Payload::Discard(blockidx + done, n as u32)));
+			done += n;
+		}
+		Ok( () )
+	}
+	fn flush(&self) -> Result<(),storage::IoError>
+	{
+		// Without the flush feature the device's cache is write-through
+		if self.dev.features & F_FLUSH == 0 {
+			return Ok( () );
+		}
+		self.dev.request(T_FLUSH, 0, Payload::None)
+	}
+}
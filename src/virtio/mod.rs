@@ -0,0 +1,540 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/virtio/mod.rs
+//! VirtIO PCI transports and split virtqueues
+//!
+//! Both the legacy (0.9.5, IO port) and modern (1.0, capability described) PCI transports are
+//! supported. Queues always use the legacy memory layout (descriptors, available ring, then the
+//! used ring on the next page), which modern devices also accept.
+#[allow(unused_imports)]
+use prelude::*;
+use spin::Mutex;
+use core::sync::atomic::{fence,Ordering};
+use mylib::mem::Arc;
+use memory::PAGE_SIZE;
+use memory::kmap::{self,KernelMapping};
+use device_manager::{self,pci,IOBinding};
+use process::WaitQueue;
+
+pub mod block;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+
+/// Device complies with the 1.0 specification (required by the modern transport)
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// Legacy register layout (IO BAR0)
+const LEG_DEVICE_FEATURES: usize = 0x00;
+const LEG_DRIVER_FEATURES: usize = 0x04;
+const LEG_QUEUE_PFN: usize = 0x08;
+const LEG_QUEUE_SIZE: usize = 0x0C;
+const LEG_QUEUE_SELECT: usize = 0x0E;
+const LEG_QUEUE_NOTIFY: usize = 0x10;
+const LEG_STATUS: usize = 0x12;
+const LEG_CONFIG_VECTOR: usize = 0x14;
+const LEG_QUEUE_VECTOR: usize = 0x16;
+/// Device configuration (moves up by 4 bytes when MSI-X is enabled)
+const LEG_CONFIG: usize = 0x14;
+const LEG_CONFIG_MSIX: usize = 0x18;
+
+// Modern common configuration layout
+const COM_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COM_DEVICE_FEATURE: usize = 0x04;
+const COM_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COM_DRIVER_FEATURE: usize = 0x0C;
+const COM_CONFIG_MSIX_VECTOR: usize = 0x10;
+const COM_STATUS: usize = 0x14;
+const COM_CONFIG_GENERATION: usize = 0x15;
+const COM_QUEUE_SELECT: usize = 0x16;
+const COM_QUEUE_SIZE: usize = 0x18;
+const COM_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COM_QUEUE_ENABLE: usize = 0x1C;
+const COM_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COM_QUEUE_DESC: usize = 0x20;
+const COM_QUEUE_DRIVER: usize = 0x28;
+const COM_QUEUE_DEVICE: usize = 0x30;
+
+// Vendor capability types
+const CAP_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+const NO_VECTOR: u16 = 0xFFFF;
+/// Largest queue used (modern devices can be asked for smaller queues)
+const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Register the virtio drivers with the device manager
+pub fn init()
+{
+	device_manager::register_driver(&block::S_DRIVER);
+}
+
+/// A region within a mapped BAR
+struct Region
+{
+	bar: Arc<IOBinding>,
+	ofs: usize,
+}
+
+/// Device access through one of the PCI transports
+pub enum Transport
+{
+	Legacy {
+		regs: IOBinding,
+		msix: bool,
+	},
+	Modern {
+		common: Region,
+		notify: Region,
+		notify_mult: u32,
+		device: Region,
+		msix: bool,
+	},
+}
+
+impl Transport
+{
+	/// Locate the device's registers, preferring the modern transport
+	pub fn new(dev: &pci::Device) -> Option<Transport> {
+		let mut bars: Vec<(u8, Arc<IOBinding>)> = Vec::new();
+		let (mut common, mut notify, mut device) = (None, None, None);
+		let mut notify_mult = 0;
+		for (_, cap) in dev.capabilities().into_iter().filter(|&(id, _)| id == CAP_VENDOR)
+		{
+			let hdr = dev.read_config(cap);
+			let cfg_type = (hdr >> 24) as u8;
+			let bar = dev.read_config(cap + 4) as u8;
+			let ofs = dev.read_config(cap + 8) as usize;
+			if cfg_type != CFG_COMMON && cfg_type != CFG_NOTIFY && cfg_type != CFG_DEVICE {
+				continue ;
+			}
+			let binding = match bars.iter().find(|&&(i, _)| i == bar).map(|&(_, ref b)| b.clone())
+				{
+				Some(v) => v,
+				None => match dev.map_bar(bar as usize)
+					{
+					Some(v) => {
+						let v = Arc::new(v);
+						bars.push( (bar, v.clone()) );
+						v
+						},
+					None => continue,
+					},
+				};
+			let region = Some(Region { bar: binding, ofs: ofs });
+			match cfg_type
+			{
+			CFG_COMMON => if common.is_none() { common = region },
+			CFG_NOTIFY => if notify.is_none() {
+				notify = region;
+				notify_mult = dev.read_config(cap + 16);
+				},
+			_ => if device.is_none() { device = region },
+			}
+		}
+		match (common, notify, device)
+		{
+		(Some(c), Some(n), Some(d)) => Some(Transport::Modern { common: c, notify: n, notify_mult: notify_mult, device: d, msix: false }),
+		_ => match dev.map_bar(0)
+			{
+			Some(regs @ IOBinding::IO(..)) => Some(Transport::Legacy { regs: regs, msix: false }),
+			_ => None,
+			},
+		}
+	}
+
+	pub fn is_modern(&self) -> bool {
+		match *self
+		{
+		Transport::Legacy { .. } => false,
+		Transport::Modern { .. } => true,
+		}
+	}
+
+	pub fn status(&self) -> u8 {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, .. } => regs.read_8(LEG_STATUS),
+			Transport::Modern { ref common, .. } => common.bar.read_8(common.ofs + COM_STATUS),
+			}
+		}
+	}
+	pub fn set_status(&self, status: u8) {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, .. } => regs.write_8(LEG_STATUS, status),
+			Transport::Modern { ref common, .. } => common.bar.write_8(common.ofs + COM_STATUS, status),
+			}
+		}
+	}
+
+	/// Reset the device, returns false if it doesn't complete
+	pub fn reset(&self) -> bool {
+		self.set_status(0);
+		(0 .. 1_000_000).any(|_| self.status() == 0)
+	}
+
+	pub fn device_features(&self) -> u64 {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, .. } => regs.read_32(LEG_DEVICE_FEATURES) as u64,
+			Transport::Modern { ref common, .. } => {
+				let (b, o) = (&common.bar, common.ofs);
+				b.write_32(o + COM_DEVICE_FEATURE_SELECT, 0);
+				let lo = b.read_32(o + COM_DEVICE_FEATURE);
+				b.write_32(o + COM_DEVICE_FEATURE_SELECT, 1);
+				let hi = b.read_32(o + COM_DEVICE_FEATURE);
+				(hi as u64) << 32 | lo as u64
+				},
+			}
+		}
+	}
+	pub fn set_driver_features(&self, features: u64) {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, .. } => regs.write_32(LEG_DRIVER_FEATURES, features as u32),
+			Transport::Modern { ref common, .. } => {
+				let (b, o) = (&common.bar, common.ofs);
+				b.write_32(o + COM_DRIVER_FEATURE_SELECT, 0);
+				b.write_32(o + COM_DRIVER_FEATURE, features as u32);
+				b.write_32(o + COM_DRIVER_FEATURE_SELECT, 1);
+				b.write_32(o + COM_DRIVER_FEATURE, (features >> 32) as u32);
+				},
+			}
+		}
+	}
+
+	/// Note that MSI-X is enabled (vector 0 is used for queues, none for configuration changes)
+	pub fn use_msix(&mut self) {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, ref mut msix } => {
+				*msix = true;
+				regs.write_16(LEG_CONFIG_VECTOR, NO_VECTOR);
+				},
+			Transport::Modern { ref common, ref mut msix, .. } => {
+				*msix = true;
+				common.bar.write_16(common.ofs + COM_CONFIG_MSIX_VECTOR, NO_VECTOR);
+				},
+			}
+		}
+	}
+	fn msix(&self) -> bool {
+		match *self
+		{
+		Transport::Legacy { msix, .. } => msix,
+		Transport::Modern { msix, .. } => msix,
+		}
+	}
+
+	/// Read a 32-bit device configuration field
+	pub fn read_config_32(&self, ofs: usize) -> u32 {
+		// SAFE: Device configuration registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, msix } => regs.read_32(if msix { LEG_CONFIG_MSIX } else { LEG_CONFIG } + ofs),
+			Transport::Modern { ref device, .. } => device.bar.read_32(device.ofs + ofs),
+			}
+		}
+	}
+	/// Read a 64-bit device configuration field (retried if the device changes it mid-read)
+	pub fn read_config_64(&self, ofs: usize) -> u64 {
+		loop
+		{
+			let gen = self.config_generation();
+			let v = (self.read_config_32(ofs + 4) as u64) << 32 | self.read_config_32(ofs) as u64;
+			if gen == self.config_generation() {
+				return v;
+			}
+		}
+	}
+	fn config_generation(&self) -> u8 {
+		match *self
+		{
+		Transport::Legacy { .. } => 0,
+		// SAFE: Reading the generation has no side effects
+		Transport::Modern { ref common, .. } => unsafe { common.bar.read_8(common.ofs + COM_CONFIG_GENERATION) },
+		}
+	}
+
+	/// Notify the device that queue `index` has new buffers
+	fn notify(&self, index: u16, notify_ofs: usize) {
+		// SAFE: Device registers
+		unsafe {
+			match *self
+			{
+			Transport::Legacy { ref regs, .. } => regs.write_16(LEG_QUEUE_NOTIFY, index),
+			Transport::Modern { ref notify, .. } => notify.bar.write_16(notify.ofs + notify_ofs, index),
+			}
+		}
+	}
+}
+
+/// A split virtqueue
+pub struct Virtqueue
+{
+	index: u16,
+	size: u16,
+	mem: KernelMapping,
+	/// Offset of the queue's notification register in the notify region (modern only)
+	notify_ofs: usize,
+	state: Mutex<QueueState>,
+	/// Woken when a request completes (or descriptors are freed)
+	pub completed: WaitQueue,
+}
+struct QueueState
+{
+	free_head: u16,
+	num_free: u16,
+	last_used: u16,
+	/// Used length of each completed chain, by head descriptor
+	done: Vec<Option<u32>>,
+}
+
+/// Byte offsets of the rings for a queue of `size` entries
+fn ring_offsets(size: u16) -> (usize, usize, usize)
+{
+	let size = size as usize;
+	let avail = 16 * size;
+	let used = (avail + 6 + 2 * size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+	(avail, used, used + 6 + 8 * size)
+}
+
+impl Virtqueue
+{
+	/// Allocate and enable queue `index`, returns None if the device doesn't have it
+	pub fn new(transport: &Transport, index: u16) -> Option<Virtqueue> {
+		let msix = transport.msix();
+		// SAFE: Device registers, queue memory is allocated before being given to the device
+		unsafe {
+			let size = match *transport
+				{
+				Transport::Legacy { ref regs, .. } => {
+					regs.write_16(LEG_QUEUE_SELECT, index);
+					regs.read_16(LEG_QUEUE_SIZE)
+					},
+				Transport::Modern { ref common, .. } => {
+					common.bar.write_16(common.ofs + COM_QUEUE_SELECT, index);
+					::core::cmp::min(common.bar.read_16(common.ofs + COM_QUEUE_SIZE), MAX_QUEUE_SIZE)
+					},
+				};
+			if size == 0 {
+				return None;
+			}
+			let (avail, used, total) = ring_offsets(size);
+			let mem = match kmap::alloc_mapped((total + PAGE_SIZE - 1) / PAGE_SIZE)
+				{
+				Some(v) => v,
+				None => return None,
+				};
+			let phys = mem.phys().get() as u64;
+			let mut notify_ofs = 0;
+			match *transport
+			{
+			Transport::Legacy { ref regs, .. } => {
+				if msix {
+					regs.write_16(LEG_QUEUE_VECTOR, 0);
+				}
+				regs.write_32(LEG_QUEUE_PFN, (phys / PAGE_SIZE as u64) as u32);
+				},
+			Transport::Modern { ref common, notify_mult, .. } => {
+				let (b, o) = (&common.bar, common.ofs);
+				b.write_16(o + COM_QUEUE_SIZE, size);
+				b.write_16(o + COM_QUEUE_MSIX_VECTOR, if msix { 0 } else { NO_VECTOR });
+				for &(reg, addr) in [ (COM_QUEUE_DESC, phys), (COM_QUEUE_DRIVER, phys + avail as u64), (COM_QUEUE_DEVICE, phys + used as u64) ].iter()
+				{
+					b.write_32(o + reg, addr as u32);
+					b.write_32(o + reg + 4, (addr >> 32) as u32);
+				}
+				notify_ofs = b.read_16(o + COM_QUEUE_NOTIFY_OFF) as usize * notify_mult as usize;
+				b.write_16(o + COM_QUEUE_ENABLE, 1);
+				},
+			}
+
+			let rv = Virtqueue {
+				index: index,
+				size: size,
+				mem: mem,
+				notify_ofs: notify_ofs,
+				state: Mutex::new(QueueState {
+					free_head: 0,
+					num_free: size,
+					last_used: 0,
+					done: vec![None; size as usize],
+					}),
+				completed: WaitQueue::new(),
+				};
+			// - Chain all descriptors into the free list
+			for i in 0 .. size {
+				rv.set_desc(i, 0, 0, false, if i + 1 < size { Some(i + 1) } else { None });
+			}
+			Some(rv)
+		}
+	}
+
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	fn desc_ptr(&self, idx: u16) -> *mut u8 {
+		(self.mem.base() + idx as usize * 16) as *mut u8
+	}
+
+	/// Fill descriptor `idx` (which must be owned by the caller)
+	pub fn set_desc(&self, idx: u16, phys: u64, len: u32, device_writes: bool, next: Option<u16>) {
+		let flags = if device_writes { DESC_F_WRITE } else { 0 } | if next.is_some() { DESC_F_NEXT } else { 0 };
+		// SAFE: Descriptor within the queue memory, not visible to the device until published
+		unsafe {
+			let p = self.desc_ptr(idx);
+			::core::ptr::write_volatile(p as *mut u64, phys);
+			::core::ptr::write_volatile(p.offset(8) as *mut u32, len);
+			::core::ptr::write_volatile(p.offset(12) as *mut u16, flags);
+			::core::ptr::write_volatile(p.offset(14) as *mut u16, next.unwrap_or(0));
+		}
+	}
+	fn desc_next(&self, idx: u16) -> Option<u16> {
+		// SAFE: Descriptor within the queue memory
+		unsafe {
+			let p = self.desc_ptr(idx);
+			if ::core::ptr::read_volatile(p.offset(12) as *const u16) & DESC_F_NEXT != 0 {
+				Some(::core::ptr::read_volatile(p.offset(14) as *const u16))
+			}
+			else {
+				None
+			}
+		}
+	}
+
+	/// Claim `count` descriptors, returns None if not enough are free
+	pub fn alloc(&self, count: usize) -> Option<Vec<u16>> {
+		::arch::interrupts::without_interrupts(|| {
+			let mut st = self.state.lock();
+			if (st.num_free as usize) < count {
+				return None;
+			}
+			let mut rv = Vec::with_capacity(count);
+			for _ in 0 .. count
+			{
+				let d = st.free_head;
+				st.free_head = self.desc_next(d).unwrap_or(0);
+				rv.push(d);
+			}
+			st.num_free -= count as u16;
+			Some(rv)
+			})
+	}
+	/// Number of free descriptors
+	pub fn num_free(&self) -> usize {
+		::arch::interrupts::without_interrupts(|| self.state.lock().num_free as usize)
+	}
+
+	/// Make the chain starting at `head` available to the device, and notify it
+	pub fn publish(&self, transport: &Transport, head: u16) {
+		let (avail, _, _) = ring_offsets(self.size);
+		::arch::interrupts::without_interrupts(|| {
+			let _st = self.state.lock();
+			// SAFE: Available ring within the queue memory (only written by the driver)
+			unsafe {
+				let ring = (self.mem.base() + avail) as *mut u16;
+				let idx = ::core::ptr::read_volatile(ring.offset(1));
+				::core::ptr::write_volatile(ring.offset(2 + (idx % self.size) as isize), head);
+				// - The entry must be visible before the index
+				fence(Ordering::SeqCst);
+				::core::ptr::write_volatile(ring.offset(1), idx.wrapping_add(1));
+			}
+			fence(Ordering::SeqCst);
+			transport.notify(self.index, self.notify_ofs);
+			});
+	}
+
+	/// Collect completed chains from the used ring, returns true if any completed
+	pub fn service(&self) -> bool {
+		let (_, used, _) = ring_offsets(self.size);
+		::arch::interrupts::without_interrupts(|| {
+			let mut st = self.state.lock();
+			let ring = (self.mem.base() + used) as *const u16;
+			let mut any = false;
+			loop
+			{
+				// SAFE: Used ring within the queue memory
+				let idx = unsafe { ::core::ptr::read_volatile(ring.offset(1)) };
+				if idx == st.last_used {
+					break ;
+				}
+				fence(Ordering::SeqCst);
+				let ent = unsafe { (ring.offset(2) as *const u32).offset(2 * (st.last_used % self.size) as isize) };
+				// SAFE: Used ring entry written by the device before the index
+				let (id, len) = unsafe { (::core::ptr::read_volatile(ent), ::core::ptr::read_volatile(ent.offset(1))) };
+				match st.done.get_mut(id as usize)
+				{
+				Some(d) => *d = Some(len),
+				None => println!("warning: virtio: Queue {} used bad descriptor {}", self.index, id),
+				}
+				st.last_used = st.last_used.wrapping_add(1);
+				any = true;
+			}
+			any
+			})
+	}
+
+	/// Returns true if the chain starting at `head` has completed
+	pub fn is_done(&self, head: u16) -> bool {
+		::arch::interrupts::without_interrupts(|| self.state.lock().done[head as usize].is_some())
+	}
+
+	/// Returns the used length if the chain starting at `head` has completed, freeing the chain
+	pub fn take(&self, head: u16) -> Option<u32> {
+		let rv = ::arch::interrupts::without_interrupts(|| {
+			let mut st = self.state.lock();
+			let len = match st.done[head as usize].take()
+				{
+				Some(v) => v,
+				None => return None,
+				};
+			// - Return the chain to the free list
+			let mut last = head;
+			let mut count = 1;
+			while let Some(n) = self.desc_next(last) {
+				last = n;
+				count += 1;
+			}
+			let free_head = st.free_head;
+			// SAFE: The chain is owned by the caller, and was completed by the device
+			unsafe {
+				let p = self.desc_ptr(last);
+				::core::ptr::write_volatile(p.offset(12) as *mut u16, DESC_F_NEXT);
+				::core::ptr::write_volatile(p.offset(14) as *mut u16, free_head);
+			}
+			st.free_head = head;
+			st.num_free += count;
+			Some(len)
+			});
+		if rv.is_some() {
+			self.completed.wake_all();
+		}
+		rv
+	}
+}